@@ -0,0 +1,283 @@
+//! An in-memory `DbClient` for testing
+
+use crate::db::DbClient;
+use async_trait::async_trait;
+use autopush_common::db::{
+    DynamoDbBroadcast, DynamoDbIdempotencyKey, DynamoDbUser, StaleUsersPage,
+};
+use autopush_common::errors::Result as DbResult;
+use autopush_common::notification::Notification;
+use autopush_common::util::sec_since_epoch;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Records the calls made to the database, and returns a fixed user record
+#[derive(Default)]
+pub struct MockDbClient {
+    pub user: Option<DynamoDbUser>,
+    /// The users which are searched for stale node IDs
+    pub users: Vec<DynamoDbUser>,
+    /// The UAID each page of the search for stale users started after
+    pub stale_user_scans: Mutex<Vec<Option<String>>>,
+    pub stored_messages: Mutex<Vec<Notification>>,
+    pub removed_node_ids: Mutex<Vec<String>>,
+    pub removed_fallback_routers: Mutex<Vec<Uuid>>,
+    pub broadcasts: Mutex<Vec<DynamoDbBroadcast>>,
+    pub idempotency_keys: Mutex<HashMap<String, DynamoDbIdempotencyKey>>,
+    /// Simulate a database failure when storing messages
+    pub fail_store_message: bool,
+    /// Simulate a database failure when reading the user
+    pub fail_get_user: bool,
+    /// Simulate the database being unreachable during health checks
+    pub fail_health_check: bool,
+    /// Simulate the user reconnecting after their record was read, so the
+    /// stored connection time no longer matches
+    pub reconnected_at: Option<u64>,
+}
+
+impl MockDbClient {
+    /// Create a mock database which contains the user
+    pub fn with_user(user: DynamoDbUser) -> Self {
+        MockDbClient {
+            user: Some(user),
+            ..Default::default()
+        }
+    }
+
+    /// Get the messages which have been stored
+    pub fn stored_messages(&self) -> Vec<Notification> {
+        self.stored_messages.lock().unwrap().clone()
+    }
+
+    /// Get the node IDs which have been removed
+    pub fn removed_node_ids(&self) -> Vec<String> {
+        self.removed_node_ids.lock().unwrap().clone()
+    }
+
+    /// Get the users whose fallback router has been removed
+    pub fn removed_fallback_routers(&self) -> Vec<Uuid> {
+        self.removed_fallback_routers.lock().unwrap().clone()
+    }
+
+    /// Get the broadcasts which have been stored
+    pub fn broadcasts(&self) -> Vec<DynamoDbBroadcast> {
+        self.broadcasts.lock().unwrap().clone()
+    }
+}
+
+#[async_trait(?Send)]
+impl DbClient for MockDbClient {
+    fn current_message_month(&self) -> String {
+        "message_2020_01".to_string()
+    }
+
+    async fn get_user(&self, _uaid: &Uuid) -> DbResult<Option<DynamoDbUser>> {
+        if self.fail_get_user {
+            return Err("Simulated database failure".into());
+        }
+
+        Ok(self.user.clone())
+    }
+
+    async fn store_message(
+        &self,
+        _uaid: &Uuid,
+        _message_month: String,
+        message: Notification,
+    ) -> DbResult<()> {
+        if self.fail_store_message {
+            return Err("Simulated database failure".into());
+        }
+
+        // Messages with the same sort key overwrite each other, like in
+        // DynamoDB. This collapses messages with the same topic.
+        let mut stored_messages = self.stored_messages.lock().unwrap();
+        stored_messages.retain(|stored| stored.sort_key() != message.sort_key());
+        stored_messages.push(message);
+        Ok(())
+    }
+
+    async fn remove_node_id(
+        &self,
+        _uaid: &Uuid,
+        node_id: String,
+        connected_at: u64,
+    ) -> DbResult<bool> {
+        if let Some(reconnected_at) = self.reconnected_at {
+            if reconnected_at != connected_at {
+                return Ok(false);
+            }
+        }
+
+        self.removed_node_ids.lock().unwrap().push(node_id);
+        Ok(true)
+    }
+
+    async fn remove_fallback_router(&self, uaid: &Uuid) -> DbResult<bool> {
+        self.removed_fallback_routers.lock().unwrap().push(*uaid);
+        Ok(true)
+    }
+
+    async fn remove_message(
+        &self,
+        _uaid: &Uuid,
+        _message_month: &str,
+        sort_key: String,
+    ) -> DbResult<bool> {
+        let mut stored_messages = self.stored_messages.lock().unwrap();
+        let stored_count = stored_messages.len();
+        stored_messages.retain(|stored| stored.sort_key() != sort_key);
+
+        Ok(stored_messages.len() != stored_count)
+    }
+
+    async fn message_exists(
+        &self,
+        _uaid: &Uuid,
+        _message_month: &str,
+        sort_key: String,
+    ) -> DbResult<bool> {
+        Ok(self
+            .stored_messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|stored| stored.sort_key() == sort_key))
+    }
+
+    async fn drop_user_messages(&self, _uaid: &Uuid, _message_month: &str) -> DbResult<usize> {
+        let mut stored_messages = self.stored_messages.lock().unwrap();
+        let dropped = stored_messages.len();
+        stored_messages.clear();
+
+        Ok(dropped)
+    }
+
+    async fn channel_message_keys(
+        &self,
+        _uaid: &Uuid,
+        _message_month: &str,
+        channel_id: &Uuid,
+    ) -> DbResult<Vec<String>> {
+        // Messages are stored in the order they were sent
+        Ok(self
+            .stored_messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|stored| stored.topic.is_none() && stored.channel_id == *channel_id)
+            .map(|stored| stored.sort_key())
+            .collect())
+    }
+
+    async fn find_stale_users(
+        &self,
+        connected_before: u64,
+        start_uaid: Option<String>,
+        limit: i64,
+    ) -> DbResult<StaleUsersPage> {
+        self.stale_user_scans
+            .lock()
+            .unwrap()
+            .push(start_uaid.clone());
+
+        // Read the users in order, like DynamoDB scanning a page of the table
+        let start = match start_uaid {
+            Some(uaid) => {
+                self.users
+                    .iter()
+                    .position(|user| user.uaid.to_simple().to_string() == uaid)
+                    .expect("Unknown start UAID")
+                    + 1
+            }
+            None => 0,
+        };
+        let page: Vec<&DynamoDbUser> = self.users.iter().skip(start).take(limit as usize).collect();
+        let next_uaid = if start + page.len() < self.users.len() {
+            page.last().map(|user| user.uaid.to_simple().to_string())
+        } else {
+            None
+        };
+
+        Ok(StaleUsersPage {
+            users: page
+                .into_iter()
+                .filter(|user| user.node_id.is_some() && user.connected_at < connected_before)
+                .cloned()
+                .collect(),
+            next_uaid,
+        })
+    }
+
+    async fn put_broadcast(&self, _table_name: &str, broadcast: DynamoDbBroadcast) -> DbResult<()> {
+        let mut broadcasts = self.broadcasts.lock().unwrap();
+        broadcasts.retain(|stored| stored.broadcast_id != broadcast.broadcast_id);
+        broadcasts.push(broadcast);
+        Ok(())
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        _table_name: &str,
+        key: DynamoDbIdempotencyKey,
+    ) -> DbResult<Option<DynamoDbIdempotencyKey>> {
+        let mut keys = self.idempotency_keys.lock().unwrap();
+
+        match keys.get(&key.idempotency_key) {
+            Some(claimed) if !claimed.expired(sec_since_epoch()) => Ok(Some(claimed.clone())),
+            _ => {
+                keys.insert(key.idempotency_key.clone(), key);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn get_idempotency_key(
+        &self,
+        _table_name: &str,
+        idempotency_key: String,
+    ) -> DbResult<Option<DynamoDbIdempotencyKey>> {
+        Ok(self
+            .idempotency_keys
+            .lock()
+            .unwrap()
+            .get(&idempotency_key)
+            .filter(|key| !key.expired(sec_since_epoch()))
+            .cloned())
+    }
+
+    async fn put_idempotency_key(
+        &self,
+        _table_name: &str,
+        key: DynamoDbIdempotencyKey,
+    ) -> DbResult<()> {
+        self.idempotency_keys
+            .lock()
+            .unwrap()
+            .insert(key.idempotency_key.clone(), key);
+        Ok(())
+    }
+
+    async fn release_idempotency_key(
+        &self,
+        _table_name: &str,
+        idempotency_key: String,
+        message_id: String,
+    ) -> DbResult<()> {
+        let mut keys = self.idempotency_keys.lock().unwrap();
+
+        if keys.get(&idempotency_key).map(|key| &key.message_id) == Some(&message_id) {
+            keys.remove(&idempotency_key);
+        }
+        Ok(())
+    }
+
+    async fn health_check(&self) -> DbResult<()> {
+        if self.fail_health_check {
+            return Err("Simulated database failure".into());
+        }
+
+        Ok(())
+    }
+}
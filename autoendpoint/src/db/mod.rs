@@ -0,0 +1,271 @@
+//! Database access used while routing notifications
+//!
+//! The routers only use the database through the `DbClient` trait, so
+//! another storage backend can be added by implementing it. `DynamoStorage`
+//! is the production implementation, and `mock::MockDbClient` is an
+//! in-memory implementation which the router tests run against.
+
+use async_trait::async_trait;
+use autopush_common::db::{
+    DynamoDbBroadcast, DynamoDbIdempotencyKey, DynamoDbUser, DynamoStorage, StaleUsersPage,
+};
+use autopush_common::errors::Result as DbResult;
+use autopush_common::notification::Notification;
+use futures::compat::Future01CompatExt;
+use uuid::Uuid;
+
+#[cfg(test)]
+pub mod mock;
+
+/// The database operations needed by the routers. This is implemented by
+/// `DynamoStorage`, and is mocked out in tests. Implementations must treat
+/// messages with the same sort key as the same message, so topic messages
+/// replace each other.
+#[async_trait(?Send)]
+pub trait DbClient: Send + Sync {
+    /// Get the message table which new messages are stored in
+    fn current_message_month(&self) -> String;
+
+    /// Read a user record, or `None` if the user does not exist (ex. they
+    /// were deleted)
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<DynamoDbUser>>;
+
+    /// Store a single message for the user. A message with a topic replaces
+    /// any stored message with the same channel and topic.
+    async fn store_message(
+        &self,
+        uaid: &Uuid,
+        message_month: String,
+        message: Notification,
+    ) -> DbResult<()>;
+
+    /// Remove the node ID from a user's record, if the node ID and connection
+    /// time still match. Returns false if the user has since reconnected.
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: String,
+        connected_at: u64,
+    ) -> DbResult<bool>;
+
+    /// Remove the fallback router from the user's router data. Returns false
+    /// if the user has no fallback router.
+    async fn remove_fallback_router(&self, uaid: &Uuid) -> DbResult<bool>;
+
+    /// Remove a stored message for the user. Returns false if the message
+    /// was not stored (ex. it was already delivered).
+    async fn remove_message(
+        &self,
+        uaid: &Uuid,
+        message_month: &str,
+        sort_key: String,
+    ) -> DbResult<bool>;
+
+    /// Check if a message is still stored for the user, and has not expired
+    async fn message_exists(
+        &self,
+        uaid: &Uuid,
+        message_month: &str,
+        sort_key: String,
+    ) -> DbResult<bool>;
+
+    /// Remove every message stored for the user. Returns the number of
+    /// messages removed.
+    async fn drop_user_messages(&self, uaid: &Uuid, message_month: &str) -> DbResult<usize>;
+
+    /// Find the sort keys of the unexpired, non-topic messages stored for the
+    /// user's channel, oldest first
+    async fn channel_message_keys(
+        &self,
+        uaid: &Uuid,
+        message_month: &str,
+        channel_id: &Uuid,
+    ) -> DbResult<Vec<String>>;
+
+    /// Find a page of the users who still have a node ID, but last connected
+    /// before `connected_before` (milliseconds since the epoch). At most
+    /// `limit` users are read, starting after `start_uaid`.
+    async fn find_stale_users(
+        &self,
+        connected_before: u64,
+        start_uaid: Option<String>,
+        limit: i64,
+    ) -> DbResult<StaleUsersPage>;
+
+    /// Store a broadcast value, replacing its earlier value
+    async fn put_broadcast(&self, table_name: &str, broadcast: DynamoDbBroadcast) -> DbResult<()>;
+
+    /// Claim an idempotency key, unless another notification has claimed it
+    /// and it has not expired. Returns the other notification's claim, or
+    /// `None` if this claim succeeded.
+    async fn claim_idempotency_key(
+        &self,
+        table_name: &str,
+        key: DynamoDbIdempotencyKey,
+    ) -> DbResult<Option<DynamoDbIdempotencyKey>>;
+
+    /// Get an unexpired idempotency key
+    async fn get_idempotency_key(
+        &self,
+        table_name: &str,
+        idempotency_key: String,
+    ) -> DbResult<Option<DynamoDbIdempotencyKey>>;
+
+    /// Store an idempotency key, replacing any earlier claim
+    async fn put_idempotency_key(
+        &self,
+        table_name: &str,
+        key: DynamoDbIdempotencyKey,
+    ) -> DbResult<()>;
+
+    /// Remove an idempotency key, if it is still claimed by the message
+    async fn release_idempotency_key(
+        &self,
+        table_name: &str,
+        idempotency_key: String,
+        message_id: String,
+    ) -> DbResult<()>;
+
+    /// Check that the database can be reached
+    async fn health_check(&self) -> DbResult<()>;
+}
+
+#[async_trait(?Send)]
+impl DbClient for DynamoStorage {
+    fn current_message_month(&self) -> String {
+        self.current_message_month.clone()
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<DynamoDbUser>> {
+        DynamoStorage::find_user(self, uaid).compat().await
+    }
+
+    async fn store_message(
+        &self,
+        uaid: &Uuid,
+        message_month: String,
+        message: Notification,
+    ) -> DbResult<()> {
+        DynamoStorage::store_message(self, uaid, message_month, message)
+            .compat()
+            .await
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: String,
+        connected_at: u64,
+    ) -> DbResult<bool> {
+        DynamoStorage::remove_node_id(self, uaid, node_id, connected_at)
+            .compat()
+            .await
+    }
+
+    async fn remove_fallback_router(&self, uaid: &Uuid) -> DbResult<bool> {
+        DynamoStorage::remove_fallback_router(self, uaid)
+            .compat()
+            .await
+    }
+
+    async fn remove_message(
+        &self,
+        uaid: &Uuid,
+        message_month: &str,
+        sort_key: String,
+    ) -> DbResult<bool> {
+        DynamoStorage::remove_message(self, message_month, uaid, sort_key)
+            .compat()
+            .await
+    }
+
+    async fn message_exists(
+        &self,
+        uaid: &Uuid,
+        message_month: &str,
+        sort_key: String,
+    ) -> DbResult<bool> {
+        DynamoStorage::message_exists(self, message_month, uaid, sort_key)
+            .compat()
+            .await
+    }
+
+    async fn drop_user_messages(&self, uaid: &Uuid, message_month: &str) -> DbResult<usize> {
+        DynamoStorage::drop_user_messages(self, uaid, message_month)
+            .compat()
+            .await
+    }
+
+    async fn channel_message_keys(
+        &self,
+        uaid: &Uuid,
+        message_month: &str,
+        channel_id: &Uuid,
+    ) -> DbResult<Vec<String>> {
+        DynamoStorage::channel_message_keys(self, uaid, channel_id, message_month)
+            .compat()
+            .await
+    }
+
+    async fn find_stale_users(
+        &self,
+        connected_before: u64,
+        start_uaid: Option<String>,
+        limit: i64,
+    ) -> DbResult<StaleUsersPage> {
+        DynamoStorage::find_stale_users(self, connected_before, start_uaid, limit)
+            .compat()
+            .await
+    }
+
+    async fn put_broadcast(&self, table_name: &str, broadcast: DynamoDbBroadcast) -> DbResult<()> {
+        DynamoStorage::put_broadcast(self, table_name, broadcast)
+            .compat()
+            .await
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        table_name: &str,
+        key: DynamoDbIdempotencyKey,
+    ) -> DbResult<Option<DynamoDbIdempotencyKey>> {
+        DynamoStorage::claim_idempotency_key(self, table_name, key)
+            .compat()
+            .await
+    }
+
+    async fn get_idempotency_key(
+        &self,
+        table_name: &str,
+        idempotency_key: String,
+    ) -> DbResult<Option<DynamoDbIdempotencyKey>> {
+        DynamoStorage::get_idempotency_key(self, table_name, idempotency_key)
+            .compat()
+            .await
+    }
+
+    async fn put_idempotency_key(
+        &self,
+        table_name: &str,
+        key: DynamoDbIdempotencyKey,
+    ) -> DbResult<()> {
+        DynamoStorage::put_idempotency_key(self, table_name, key)
+            .compat()
+            .await
+    }
+
+    async fn release_idempotency_key(
+        &self,
+        table_name: &str,
+        idempotency_key: String,
+        message_id: String,
+    ) -> DbResult<()> {
+        DynamoStorage::release_idempotency_key(self, table_name, idempotency_key, message_id)
+            .compat()
+            .await
+    }
+
+    async fn health_check(&self) -> DbResult<()> {
+        DynamoStorage::health_check(self).compat().await
+    }
+}
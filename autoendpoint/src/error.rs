@@ -1,26 +1,85 @@
 //! Error types and transformations
 
+use crate::routers::RouterError;
 use crate::server::VapidError;
 use actix_web::{
-    dev::{HttpResponseBuilder, ServiceResponse},
+    dev::{HttpResponseBuilder, Service, ServiceRequest, ServiceResponse},
     error::{PayloadError, ResponseError},
     http::StatusCode,
     middleware::errhandlers::ErrorHandlerResponse,
+    web::Data,
     HttpResponse, Result,
 };
 use backtrace::Backtrace;
+use rand::Rng;
 use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 use std::error::Error;
 use std::fmt::{self, Display};
+use std::future::Future;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Common `Result` type.
 pub type ApiResult<T> = Result<T, ApiError>;
 
-/// How long the client should wait before retrying a conflicting write.
+/// How long the client should wait before retrying a conflicting write, if the
+/// error doesn't specify how long to wait.
 pub const RETRY_AFTER: u8 = 10;
 
+/// The default documentation for errors, linked to in error responses
+pub const DEFAULT_MORE_INFO_URL: &str =
+    "http://autopush.readthedocs.io/en/latest/http.html#error-codes";
+
+/// The documentation linked to in error responses, registered as app data.
+/// This is configurable so that deployments can link to their own
+/// documentation.
+#[derive(Clone, Debug)]
+pub struct MoreInfoUrl(pub String);
+
+/// How long a client should wait before retrying. A random jitter is added
+/// to the wait, so clients which failed at the same time don't all retry at
+/// the same time.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Backoff {
+    /// The wait before the jitter is added
+    pub base: Duration,
+    /// The random jitter which was added to the wait
+    pub jitter: Duration,
+}
+
+impl Backoff {
+    /// Wait `base`, plus a random jitter of up to `max_jitter`. The jitter is
+    /// in whole seconds, because `Retry-After` is.
+    pub fn with_jitter(base: Duration, max_jitter: Duration) -> Self {
+        let max_jitter = max_jitter.as_secs();
+        let jitter = if max_jitter == 0 {
+            0
+        } else {
+            rand::thread_rng().gen_range(0, max_jitter + 1)
+        };
+
+        Backoff {
+            base,
+            jitter: Duration::from_secs(jitter),
+        }
+    }
+
+    /// Get the total wait, including the jitter
+    pub fn retry_after(&self) -> Duration {
+        self.base + self.jitter
+    }
+}
+
+impl From<Duration> for Backoff {
+    fn from(base: Duration) -> Self {
+        Backoff {
+            base,
+            jitter: Duration::from_secs(0),
+        }
+    }
+}
+
 /// The main error type.
 #[derive(Debug)]
 pub struct ApiError {
@@ -38,6 +97,57 @@ impl ApiError {
             resp.into_body(),
         )))
     }
+
+    /// Render the error response, linking to the given documentation
+    pub fn render(&self, more_info: &str) -> HttpResponse {
+        let mut builder = HttpResponse::build(self.kind.status());
+        builder.header("Retry-After", self.kind.retry_after().as_secs().to_string());
+
+        // Tell the client how much of the wait is jitter, so it doesn't
+        // mistake the jitter for the server's actual backoff
+        if let Some(backoff) = self.kind.backoff() {
+            builder.header("X-Backoff-Jitter", backoff.jitter.as_secs().to_string());
+        }
+
+        builder.json(ErrorBody {
+            error: self,
+            more_info,
+        })
+    }
+}
+
+/// Middleware which renders API errors with the configured documentation
+/// URL. Errors are rendered with the default URL when they are returned, since
+/// the request is not available then.
+pub fn render_more_info<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let response = srv.call(req);
+
+    async move {
+        let response = response.await?;
+        let rendered = response
+            .response()
+            .error()
+            .and_then(|error| error.as_error::<ApiError>())
+            .map(|error| {
+                let more_info = response
+                    .request()
+                    .app_data::<Data<MoreInfoUrl>>()
+                    .map(|url| url.0.clone())
+                    .unwrap_or_else(|| DEFAULT_MORE_INFO_URL.to_string());
+                error.render(&more_info)
+            });
+
+        Ok(match rendered {
+            Some(rendered) => response.into_response(rendered.into_body()),
+            None => response,
+        })
+    }
 }
 
 /// The possible errors this application could encounter
@@ -59,6 +169,9 @@ pub enum ApiErrorKind {
     #[error(transparent)]
     VapidError(#[from] VapidError),
 
+    #[error(transparent)]
+    Router(#[from] RouterError),
+
     #[error(transparent)]
     Uuid(#[from] uuid::Error),
 
@@ -74,6 +187,13 @@ pub enum ApiErrorKind {
     #[error("Invalid token")]
     InvalidToken,
 
+    #[error("Invalid message ID")]
+    InvalidMessageId,
+
+    /// The message was already delivered or removed
+    #[error("Message not found")]
+    MessageNotFound,
+
     #[error("No such subscription")]
     NoSubscription,
 
@@ -81,6 +201,31 @@ pub enum ApiErrorKind {
     #[error("{0}")]
     InvalidEncryption(String),
 
+    /// The topic starts with a prefix reserved for system messages
+    #[error("Topic must not start with the reserved prefix {0}")]
+    ReservedTopic(String),
+
+    /// The content encoding is known, but has been disabled
+    #[error("The {0} content encoding is no longer supported")]
+    UnsupportedEncoding(String),
+
+    /// The payload compression is not supported
+    #[error("Unsupported payload compression: {0}")]
+    UnsupportedCompression(String),
+
+    /// The `Push-Receipt` header is not an HTTP(S) URL
+    #[error("Invalid Push-Receipt header, must be an HTTP(S) URL")]
+    InvalidPushReceipt,
+
+    /// The `Push-Receipt` URL is not one of this service's receipt resources
+    /// or on an allowed host
+    #[error("Push-Receipt must be a receipt resource of this service or on an allowed host")]
+    PushReceiptNotAllowed,
+
+    /// An encryption header is longer than the max length
+    #[error("{0} header must not be longer than {1} characters")]
+    CryptoHeaderTooLong(&'static str, usize),
+
     #[error("Data payload must be smaller than {} bytes", .0)]
     PayloadTooLarge(usize),
 
@@ -88,6 +233,43 @@ pub enum ApiErrorKind {
     #[error("Invalid API version")]
     InvalidApiVersion,
 
+    /// The sender has sent too many messages, and may retry after the given
+    /// duration
+    #[error("Too many messages, try again later")]
+    TooManyMessages(Backoff),
+
+    /// The VAPID `sub` claim is not in the configured allowlist
+    #[error("VAPID subject is not allowed to send notifications")]
+    VapidSubjectNotAllowed,
+
+    /// The admin routes are disabled, or the admin credentials are wrong
+    #[error("Invalid admin credentials")]
+    InvalidAdminAuth,
+
+    /// The broadcast ID or version can't be used
+    #[error("Invalid broadcast: {0}")]
+    InvalidBroadcast(String),
+
+    /// The user does not exist
+    #[error("User not found")]
+    UserNotFound,
+
+    /// The user is not connected to a node
+    #[error("User is not connected")]
+    UserNotConnected,
+
+    /// The user's node could not be reached, or did not accept the request
+    #[error("Unable to reach the user's node")]
+    NodeUnavailable,
+
+    /// The server is shutting down and is not routing new notifications
+    #[error("Server is shutting down")]
+    ShuttingDown,
+
+    /// The message ID can not be used in the `Location` header
+    #[error("Unable to create the message location")]
+    InvalidMessageLocation,
+
     #[error("{0}")]
     Internal(String),
 }
@@ -97,26 +279,82 @@ impl ApiErrorKind {
     pub fn status(&self) -> StatusCode {
         match self {
             ApiErrorKind::PayloadError(e) => e.status_code(),
+            ApiErrorKind::Router(e) => e.status(),
 
             ApiErrorKind::Validation(_)
             | ApiErrorKind::InvalidEncryption(_)
+            | ApiErrorKind::UnsupportedEncoding(_)
+            | ApiErrorKind::UnsupportedCompression(_)
+            | ApiErrorKind::InvalidPushReceipt
+            | ApiErrorKind::PushReceiptNotAllowed
+            | ApiErrorKind::ReservedTopic(_)
+            | ApiErrorKind::InvalidBroadcast(_)
+            | ApiErrorKind::CryptoHeaderTooLong(..)
             | ApiErrorKind::TokenHashValidation(_)
             | ApiErrorKind::Uuid(_) => StatusCode::BAD_REQUEST,
 
             ApiErrorKind::NoSubscription => StatusCode::GONE,
 
-            ApiErrorKind::VapidError(_) | ApiErrorKind::Jwt(_) => StatusCode::UNAUTHORIZED,
+            ApiErrorKind::VapidError(_) | ApiErrorKind::Jwt(_) | ApiErrorKind::InvalidAdminAuth => {
+                StatusCode::UNAUTHORIZED
+            }
+
+            ApiErrorKind::VapidSubjectNotAllowed => StatusCode::FORBIDDEN,
+
+            ApiErrorKind::TooManyMessages(_) => StatusCode::TOO_MANY_REQUESTS,
 
-            ApiErrorKind::InvalidToken | ApiErrorKind::InvalidApiVersion => StatusCode::NOT_FOUND,
+            ApiErrorKind::InvalidToken
+            | ApiErrorKind::InvalidMessageId
+            | ApiErrorKind::MessageNotFound
+            | ApiErrorKind::UserNotFound
+            | ApiErrorKind::UserNotConnected
+            | ApiErrorKind::InvalidApiVersion => StatusCode::NOT_FOUND,
 
             ApiErrorKind::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
 
+            ApiErrorKind::ShuttingDown | ApiErrorKind::NodeUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+
             ApiErrorKind::Io(_)
             | ApiErrorKind::Metrics(_)
             | ApiErrorKind::Database(_)
+            | ApiErrorKind::InvalidMessageLocation
             | ApiErrorKind::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    /// Get the associated error number
+    pub fn errno(&self) -> Option<usize> {
+        match self {
+            ApiErrorKind::Router(e) => e.errno(),
+            ApiErrorKind::PayloadTooLarge(_) => Some(104),
+            ApiErrorKind::VapidError(_) | ApiErrorKind::Jwt(_) => Some(109),
+            ApiErrorKind::VapidSubjectNotAllowed => Some(116),
+            ApiErrorKind::TooManyMessages(_) => Some(117),
+            ApiErrorKind::CryptoHeaderTooLong(..) => Some(118),
+            ApiErrorKind::UnsupportedEncoding(_) => Some(119),
+            ApiErrorKind::ReservedTopic(_) => Some(120),
+            _ => None,
+        }
+    }
+
+    /// Get how long the client should wait before retrying, if the error
+    /// specifies it
+    pub fn backoff(&self) -> Option<Backoff> {
+        match self {
+            ApiErrorKind::Router(e) => e.backoff(),
+            ApiErrorKind::TooManyMessages(backoff) => Some(*backoff),
+            _ => None,
+        }
+    }
+
+    /// Get how long the client should wait before retrying
+    pub fn retry_after(&self) -> Duration {
+        self.backoff()
+            .map(|backoff| backoff.retry_after())
+            .unwrap_or_else(|| Duration::from_secs(RETRY_AFTER.into()))
+    }
 }
 
 // Print out the error and backtrace, including source errors
@@ -174,40 +412,147 @@ impl From<ApiError> for HttpResponse {
 
 impl ResponseError for ApiError {
     fn error_response(&self) -> HttpResponse {
-        // To return a descriptive error response, this would work. We do not
-        // unfortunately do that so that we can retain Sync 1.1 backwards compatibility
-        // as the Python one does.
-        // HttpResponse::build(self.status).json(self)
-        //
-        // So instead we translate our error to a backwards compatible one
-        HttpResponse::build(self.kind.status())
-            .header("Retry-After", RETRY_AFTER.to_string())
-            .finish()
+        self.render(DEFAULT_MORE_INFO_URL)
     }
 }
 
-// TODO: Use the same schema as documented here?
-//       https://autopush.readthedocs.io/en/latest/http.html#response
+/// The body of an error response, linking to the given documentation
+struct ErrorBody<'a> {
+    error: &'a ApiError,
+    more_info: &'a str,
+}
+
 impl Serialize for ApiError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let status = self.kind.status();
-        let size = if status == StatusCode::UNAUTHORIZED {
-            2
-        } else {
+        ErrorBody {
+            error: self,
+            more_info: DEFAULT_MORE_INFO_URL,
+        }
+        .serialize(serializer)
+    }
+}
+
+// Uses the same schema as documented here:
+// https://autopush.readthedocs.io/en/latest/http.html#response
+impl<'a> Serialize for ErrorBody<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let status = self.error.kind.status();
+        let errno = self.error.kind.errno();
+        let mut size = if status == StatusCode::UNAUTHORIZED {
             3
+        } else {
+            4
         };
+        if errno.is_some() {
+            size += 1;
+        }
 
         let mut map = serializer.serialize_map(Some(size))?;
-        map.serialize_entry("status", &status.as_u16())?;
-        map.serialize_entry("reason", status.canonical_reason().unwrap_or(""))?;
+        map.serialize_entry("code", &status.as_u16())?;
+
+        if let Some(errno) = errno {
+            map.serialize_entry("errno", &errno)?;
+        }
+
+        map.serialize_entry("error", status.canonical_reason().unwrap_or(""))?;
 
         if status != StatusCode::UNAUTHORIZED {
-            map.serialize_entry("errors", &self.kind.to_string())?;
+            map.serialize_entry("message", &self.error.kind.to_string())?;
         }
 
+        map.serialize_entry("more_info", self.more_info)?;
+
         map.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{render_more_info, ApiError, ApiErrorKind, MoreInfoUrl, DEFAULT_MORE_INFO_URL};
+    use actix_web::{test, web, App, HttpResponse};
+    use serde_json::json;
+
+    /// Respond with an API error
+    async fn fail() -> Result<HttpResponse, ApiError> {
+        Err(ApiErrorKind::NoSubscription.into())
+    }
+
+    /// Errors without an errno leave it out of the body
+    #[test]
+    fn error_body_without_errno() {
+        let error = ApiError::from(ApiErrorKind::NoSubscription);
+
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({
+                "code": 410,
+                "error": "Gone",
+                "message": "No such subscription",
+                "more_info": DEFAULT_MORE_INFO_URL
+            })
+        );
+    }
+
+    /// Errors with an errno include it in the body
+    #[test]
+    fn error_body_with_errno() {
+        let error = ApiError::from(ApiErrorKind::VapidSubjectNotAllowed);
+
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({
+                "code": 403,
+                "errno": 116,
+                "error": "Forbidden",
+                "message": "VAPID subject is not allowed to send notifications",
+                "more_info": DEFAULT_MORE_INFO_URL
+            })
+        );
+    }
+
+    /// Error responses link to the configured documentation
+    #[actix_rt::test]
+    async fn error_body_configured_more_info() {
+        let mut app = test::init_service(
+            App::new()
+                .data(MoreInfoUrl("https://push.example.com/errors".to_string()))
+                .wrap_fn(render_more_info)
+                .route("/", web::get().to(fail)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            body,
+            json!({
+                "code": 410,
+                "error": "Gone",
+                "message": "No such subscription",
+                "more_info": "https://push.example.com/errors"
+            })
+        );
+    }
+
+    /// Error responses link to the default documentation if none is
+    /// configured
+    #[actix_rt::test]
+    async fn error_body_default_more_info() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(render_more_info)
+                .route("/", web::get().to(fail)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["more_info"], DEFAULT_MORE_INFO_URL);
+    }
+}
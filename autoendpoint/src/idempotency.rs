@@ -0,0 +1,340 @@
+//! Remembering recent notifications sent with an `Idempotency-Key`, so
+//! retried requests are not delivered twice. The keys are kept in the
+//! database, so a retry is recognized whichever server it reaches.
+
+use crate::db::DbClient;
+use crate::routers::RouterResponse;
+use crate::server::extractors::notification::Notification;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use autopush_common::db::DynamoDbIdempotencyKey;
+use autopush_common::errors::Result as DbResult;
+use autopush_common::util::sec_since_epoch;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Claims the idempotency key of each notification before it is stored, and
+/// remembers the response to the notification, until the key expires
+pub struct IdempotencyCache {
+    ddb: Arc<dyn DbClient>,
+    /// The table the keys are stored in
+    table_name: String,
+    /// How long a key is remembered for. Zero disables the cache.
+    window: Duration,
+}
+
+/// The response to a notification, as stored with its idempotency key
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct StoredResponse {
+    status: u16,
+    headers: HashMap<String, String>,
+    body: Option<String>,
+}
+
+impl From<&RouterResponse> for StoredResponse {
+    fn from(response: &RouterResponse) -> Self {
+        StoredResponse {
+            status: response.status.as_u16(),
+            headers: response
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            body: response.body.clone(),
+        }
+    }
+}
+
+impl From<StoredResponse> for HttpResponse {
+    fn from(response: StoredResponse) -> Self {
+        let status =
+            StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut builder = HttpResponse::build(status);
+
+        for (name, value) in response.headers {
+            builder.header(name.as_str(), value);
+        }
+
+        builder.body(response.body.unwrap_or_default())
+    }
+}
+
+impl IdempotencyCache {
+    /// Create a new `IdempotencyCache`
+    pub fn new(ddb: Arc<dyn DbClient>, table_name: String, window: Duration) -> Self {
+        IdempotencyCache {
+            ddb,
+            table_name,
+            window,
+        }
+    }
+
+    /// Claim the notification's idempotency key before storing it. Returns
+    /// the message ID of an earlier notification which claimed the key, if
+    /// the key hasn't expired, in which case the notification should not be
+    /// stored.
+    pub async fn claim(&self, notification: &Notification) -> DbResult<Option<String>> {
+        let key = match self.key(notification, None) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        let claimed = self
+            .ddb
+            .claim_idempotency_key(&self.table_name, key)
+            .await?;
+        Ok(claimed.map(|key| key.message_id))
+    }
+
+    /// Release the notification's claim on its idempotency key, so a retry
+    /// can be stored. This is done if storing the notification failed.
+    pub async fn release(&self, notification: &Notification) -> DbResult<()> {
+        let key = match self.key(notification, None) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        self.ddb
+            .release_idempotency_key(&self.table_name, key.idempotency_key, key.message_id)
+            .await
+    }
+
+    /// Get the response to an earlier notification with the same idempotency
+    /// key, if the key hasn't expired
+    pub async fn get(&self, notification: &Notification) -> DbResult<Option<HttpResponse>> {
+        let key = match self.key(notification, None) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        let response = self
+            .ddb
+            .get_idempotency_key(&self.table_name, key.idempotency_key)
+            .await?
+            .and_then(|key| key.response)
+            .and_then(|response| serde_json::from_str::<StoredResponse>(&response).ok());
+        Ok(response.map(HttpResponse::from))
+    }
+
+    /// Remember the response to a notification, if it has an idempotency key
+    pub async fn insert(
+        &self,
+        notification: &Notification,
+        response: &RouterResponse,
+    ) -> DbResult<()> {
+        let response = serde_json::to_string(&StoredResponse::from(response))?;
+        let key = match self.key(notification, Some(response)) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        self.ddb.put_idempotency_key(&self.table_name, key).await
+    }
+
+    /// Get the record of the notification's idempotency key, if it has one
+    /// and the cache is enabled. Keys are scoped to the subscription, so
+    /// senders can't see each other's responses.
+    fn key(
+        &self,
+        notification: &Notification,
+        response: Option<String>,
+    ) -> Option<DynamoDbIdempotencyKey> {
+        if self.window == Duration::from_secs(0) {
+            return None;
+        }
+
+        let idempotency_key = notification.headers.idempotency_key.as_ref()?;
+        let subscription = &notification.subscription;
+
+        Some(DynamoDbIdempotencyKey {
+            idempotency_key: format!(
+                "{}:{}:{}",
+                subscription.user.uaid.to_simple(),
+                subscription.channel_id.to_simple(),
+                idempotency_key
+            ),
+            message_id: notification.message_id.clone(),
+            response,
+            expiry: sec_since_epoch() + self.window.as_secs(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyCache;
+    use crate::db::mock::MockDbClient;
+    use crate::routers::{RouterResponse, RouterType};
+    use crate::server::extractors::notification::{Notification, NotificationBuilder};
+    use actix_web::http::StatusCode;
+    use autopush_common::db::{DynamoDbIdempotencyKey, DynamoDbUser};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    const KEY: &str = "test-key";
+
+    /// Create a cache which remembers keys for a minute
+    fn make_cache(ddb: Arc<MockDbClient>) -> IdempotencyCache {
+        IdempotencyCache::new(ddb, "idempotency".to_string(), Duration::from_secs(60))
+    }
+
+    /// Create a notification with the idempotency key
+    fn make_notification(message_id: &str, idempotency_key: Option<&str>) -> Notification {
+        let builder = NotificationBuilder::new()
+            .channel_id(Uuid::nil())
+            .message_id(message_id)
+            .ttl(60);
+        match idempotency_key {
+            Some(key) => builder.idempotency_key(key),
+            None => builder,
+        }
+        .build()
+        .unwrap()
+    }
+
+    /// Create a response for a stored notification
+    fn make_response() -> RouterResponse {
+        let mut headers = HashMap::new();
+        headers.insert("Location", "https://example.com/m/first-id".to_string());
+
+        RouterResponse {
+            status: StatusCode::ACCEPTED,
+            headers,
+            body: None,
+        }
+    }
+
+    /// The first request with a key is not a duplicate
+    #[actix_rt::test]
+    async fn first_request() {
+        let cache = make_cache(Arc::new(MockDbClient::default()));
+
+        let response = cache
+            .get(&make_notification("first-id", Some(KEY)))
+            .await
+            .unwrap();
+        assert!(response.is_none());
+    }
+
+    /// A duplicate request gets the original response
+    #[actix_rt::test]
+    async fn duplicate_request() {
+        let cache = make_cache(Arc::new(MockDbClient::default()));
+        let notification = make_notification("first-id", Some(KEY));
+        cache.insert(&notification, &make_response()).await.unwrap();
+
+        let response = cache
+            .get(&make_notification("second-id", Some(KEY)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            response.headers().get("Location").unwrap(),
+            "https://example.com/m/first-id"
+        );
+        assert!(cache
+            .get(&make_notification("second-id", Some("other-key")))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    /// Keys are forgotten once they expire
+    #[actix_rt::test]
+    async fn expired_key() {
+        let ddb = Arc::new(MockDbClient::default());
+        let cache = make_cache(ddb.clone());
+        let notification = make_notification("first-id", Some(KEY));
+        cache.insert(&notification, &make_response()).await.unwrap();
+        for key in ddb.idempotency_keys.lock().unwrap().values_mut() {
+            key.expiry = 0;
+        }
+
+        assert!(cache.get(&notification).await.unwrap().is_none());
+        assert_eq!(
+            cache
+                .claim(&make_notification("second-id", Some(KEY)))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    /// The first notification claims the key, and later notifications get
+    /// its message ID. The response is stored with the claim, so both are
+    /// kept in a single record.
+    #[actix_rt::test]
+    async fn claim_message_id() {
+        let ddb = Arc::new(MockDbClient::default());
+        let cache = make_cache(ddb.clone());
+        let first = make_notification("first-id", Some(KEY));
+
+        assert_eq!(cache.claim(&first).await.unwrap(), None);
+        assert_eq!(
+            cache
+                .claim(&make_notification("second-id", Some(KEY)))
+                .await
+                .unwrap(),
+            Some("first-id".to_string())
+        );
+
+        cache.insert(&first, &make_response()).await.unwrap();
+        let keys: Vec<DynamoDbIdempotencyKey> = ddb
+            .idempotency_keys
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].message_id, "first-id");
+        assert!(keys[0].response.is_some());
+    }
+
+    /// A released claim lets a retry claim the key, but another
+    /// notification's claim is not released
+    #[actix_rt::test]
+    async fn release_claim() {
+        let cache = make_cache(Arc::new(MockDbClient::default()));
+        let first = make_notification("first-id", Some(KEY));
+        let second = make_notification("second-id", Some(KEY));
+        cache.claim(&first).await.unwrap();
+
+        cache.release(&second).await.unwrap();
+        assert_eq!(
+            cache.claim(&second).await.unwrap(),
+            Some("first-id".to_string())
+        );
+
+        cache.release(&first).await.unwrap();
+        assert_eq!(cache.claim(&second).await.unwrap(), None);
+    }
+
+    /// Notifications without a key, or a window of zero, don't use the
+    /// database
+    #[actix_rt::test]
+    async fn disabled() {
+        let ddb = Arc::new(MockDbClient::default());
+        let cache = make_cache(ddb.clone());
+        let notification = make_notification("first-id", None);
+        cache.insert(&notification, &make_response()).await.unwrap();
+        assert_eq!(cache.claim(&notification).await.unwrap(), None);
+
+        let cache = IdempotencyCache::new(
+            ddb.clone(),
+            "idempotency".to_string(),
+            Duration::from_secs(0),
+        );
+        let notification = make_notification("first-id", Some(KEY));
+        cache.insert(&notification, &make_response()).await.unwrap();
+        assert_eq!(cache.claim(&notification).await.unwrap(), None);
+        assert_eq!(cache.claim(&notification).await.unwrap(), None);
+
+        assert!(ddb.idempotency_keys.lock().unwrap().is_empty());
+    }
+}
@@ -4,6 +4,7 @@ use crate::error::ApiResult;
 
 use slog::{self, slog_o, Drain};
 use slog_mozlog_json::MozLogJson;
+use uuid::Uuid;
 
 // TODO: Merge back into common code? Removes hostname and adds envlogger
 pub fn init_logging(json: bool) -> ApiResult<()> {
@@ -40,3 +41,105 @@ pub fn reset_logging() {
     let logger = slog::Logger::root(slog::Discard, slog_o!());
     slog_scope::set_global_logger(logger).cancel_reset();
 }
+
+/// Get the UAID as it is written to the logs. Release builds log a hash of
+/// the UAID, which still correlates a user's logs without recording their ID.
+pub fn log_uaid(uaid: &Uuid) -> String {
+    if cfg!(debug_assertions) {
+        uaid.to_string()
+    } else {
+        hash_uaid(uaid)
+    }
+}
+
+/// Hash the UAID, keeping enough of the hash to tell users apart
+fn hash_uaid(uaid: &Uuid) -> String {
+    hex::encode(&openssl::sha::sha256(uaid.as_bytes())[..8])
+}
+
+/// A drain which records the key-values of each log record, for use in tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct TestLogDrain {
+    records: std::sync::Arc<std::sync::Mutex<Vec<std::collections::HashMap<String, String>>>>,
+}
+
+#[cfg(test)]
+impl TestLogDrain {
+    /// Create a logger which logs to this drain
+    pub fn logger(&self) -> slog::Logger {
+        slog::Logger::root(self.clone(), slog_o!())
+    }
+
+    /// Get the key-values of the records logged so far
+    pub fn records(&self) -> Vec<std::collections::HashMap<String, String>> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Drain for TestLogDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        use slog::KV;
+
+        let mut key_values = KeyValues::default();
+        record.kv().serialize(record, &mut key_values).unwrap();
+        values.serialize(record, &mut key_values).unwrap();
+        self.records.lock().unwrap().push(key_values.0);
+        Ok(())
+    }
+}
+
+/// Collects key-values as strings
+#[cfg(test)]
+#[derive(Default)]
+struct KeyValues(std::collections::HashMap<String, String>);
+
+#[cfg(test)]
+impl slog::Serializer for KeyValues {
+    fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+        self.0.insert(key.to_string(), val.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_uaid, log_uaid};
+    use uuid::Uuid;
+
+    /// The hashed UAID is stable, and doesn't contain the UAID
+    #[test]
+    fn hashed_uaid() {
+        let uaid = Uuid::new_v4();
+        let hashed = hash_uaid(&uaid);
+
+        assert_eq!(hashed, hash_uaid(&uaid));
+        assert_ne!(hashed, hash_uaid(&Uuid::new_v4()));
+        assert_eq!(hashed.len(), 16);
+        assert!(!hashed.contains(&uaid.to_simple().to_string()));
+    }
+
+    /// Debug builds log the UAID as-is, to make debugging easier
+    #[test]
+    #[cfg(debug_assertions)]
+    fn debug_build_uaid() {
+        let uaid = Uuid::new_v4();
+        assert_eq!(log_uaid(&uaid), uaid.to_string());
+    }
+
+    /// Release builds log the hashed UAID
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn release_build_uaid() {
+        let uaid = Uuid::new_v4();
+        assert_eq!(log_uaid(&uaid), hash_uaid(&uaid));
+    }
+}
@@ -3,11 +3,17 @@
 #[macro_use]
 extern crate slog_scope;
 
+mod db;
 mod error;
+mod idempotency;
 mod logging;
 mod metrics;
+mod rate_limit;
+mod routers;
 mod server;
 mod settings;
+mod shutdown;
+mod stale_nodes;
 mod tags;
 
 use docopt::Docopt;
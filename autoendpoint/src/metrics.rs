@@ -159,6 +159,47 @@ impl Metrics {
     }
 }
 
+/// Sends a timing metric when dropped, so the time is still recorded if the
+/// timed code returns early. Tags may be added once they are known, e.g. the
+/// outcome of the timed operation.
+pub struct TimerGuard<'a> {
+    client: &'a StatsdClient,
+    label: &'static str,
+    start: Instant,
+    tags: Vec<(&'static str, &'static str)>,
+}
+
+impl<'a> TimerGuard<'a> {
+    /// Start timing
+    pub fn start(client: &'a StatsdClient, label: &'static str) -> Self {
+        TimerGuard {
+            client,
+            label,
+            start: Instant::now(),
+            tags: Vec::new(),
+        }
+    }
+
+    /// Add a tag to the timing metric
+    pub fn tag(&mut self, key: &'static str, value: &'static str) {
+        self.tags.push((key, value));
+    }
+}
+
+impl Drop for TimerGuard<'_> {
+    fn drop(&mut self) {
+        let lapse = self.start.elapsed().as_millis() as u64;
+        let mut tagged = self.client.time_with_tags(self.label, lapse);
+        for (key, value) in &self.tags {
+            tagged = tagged.with_tag(key, value);
+        }
+
+        if let Err(e) = tagged.try_send() {
+            warn!("⚠️ Metric {} error: {:?}", self.label, e);
+        }
+    }
+}
+
 pub fn metrics_from_req(req: &HttpRequest) -> StatsdClient {
     req.app_data::<Data<ServerState>>()
         .expect("Could not get state in metrics_from_req")
@@ -185,3 +226,168 @@ pub fn metrics_from_opts(opts: &Settings) -> Result<StatsdClient, MetricError> {
         })
         .build())
 }
+
+/// A metric sink which records the metrics sent through it, for use in tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct TestMetricSink {
+    metrics: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+/// A metric sent to a `TestMetricSink`
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedMetric {
+    /// The metric name, without the client's prefix
+    pub name: String,
+    pub value: String,
+    /// The statsd metric type, ex. `c` for counters
+    pub kind: String,
+    pub tags: Vec<(String, String)>,
+}
+
+#[cfg(test)]
+impl RecordedMetric {
+    /// Parse a metric in the statsd format, ex.
+    /// `autoendpoint.name:1|c|#key:value`
+    fn parse(metric: &str) -> Option<Self> {
+        let (name, rest) = metric.split_at(metric.find(':')?);
+        let mut parts = rest[1..].split('|');
+        let value = parts.next()?.to_string();
+        let kind = parts.next()?.to_string();
+        let tags = parts
+            .find(|part| part.starts_with('#'))
+            .map(|tags| {
+                tags[1..]
+                    .split(',')
+                    .map(|tag| {
+                        let mut tag = tag.splitn(2, ':');
+                        let key = tag.next().unwrap_or_default().to_string();
+                        let value = tag.next().unwrap_or_default().to_string();
+                        (key, value)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let name = name.splitn(2, '.').nth(1).unwrap_or(name).to_string();
+
+        Some(RecordedMetric {
+            name,
+            value,
+            kind,
+            tags,
+        })
+    }
+
+    /// Check if the metric has exactly these tags, in any order
+    fn has_tags(&self, tags: &[(&str, &str)]) -> bool {
+        let mut expected = tags.to_vec();
+        let mut actual: Vec<_> = self
+            .tags
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        expected.sort();
+        actual.sort();
+
+        expected == actual
+    }
+}
+
+#[cfg(test)]
+impl TestMetricSink {
+    /// Create a client which sends metrics to this sink
+    pub fn client(&self) -> StatsdClient {
+        StatsdClient::from_sink("autoendpoint", self.clone())
+    }
+
+    /// Get the metrics which have been sent, in the statsd format
+    pub fn metrics(&self) -> Vec<String> {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Get the metrics which have been sent, split into their name, value,
+    /// type and tags
+    pub fn recorded(&self) -> Vec<RecordedMetric> {
+        self.metrics()
+            .iter()
+            .map(|metric| {
+                RecordedMetric::parse(metric)
+                    .unwrap_or_else(|| panic!("Invalid statsd metric: {}", metric))
+            })
+            .collect()
+    }
+
+    /// Assert that the counter was sent with exactly these tags, and that
+    /// the values sent add up to `value`
+    pub fn assert_counter(&self, name: &str, tags: &[(&str, &str)], value: i64) {
+        let recorded = self.recorded();
+        let counters: Vec<_> = recorded
+            .iter()
+            .filter(|metric| metric.kind == "c" && metric.name == name && metric.has_tags(tags))
+            .collect();
+        assert!(
+            !counters.is_empty(),
+            "Counter {} with tags {:?} was not sent, metrics = {:?}",
+            name,
+            tags,
+            self.metrics()
+        );
+
+        let total: i64 = counters
+            .iter()
+            .map(|metric| metric.value.parse::<i64>().unwrap())
+            .sum();
+        assert_eq!(
+            total, value,
+            "Counter {} with tags {:?} has the wrong value",
+            name, tags
+        );
+    }
+}
+
+#[cfg(test)]
+impl cadence::MetricSink for TestMetricSink {
+    fn emit(&self, metric: &str) -> std::io::Result<usize> {
+        self.metrics.lock().unwrap().push(metric.to_string());
+        Ok(metric.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TestMetricSink;
+    use cadence::Counted;
+
+    /// Counters are matched by name and tags, and their values are summed
+    #[test]
+    fn assert_counter() {
+        let sink = TestMetricSink::default();
+        let client = sink.client();
+        client.incr("plain").unwrap();
+        client.incr("plain").unwrap();
+        client
+            .count_with_tags("tagged", 5)
+            .with_tag("a", "1")
+            .with_tag("b", "2")
+            .send();
+        client
+            .count_with_tags("tagged", 3)
+            .with_tag("a", "1")
+            .send();
+
+        sink.assert_counter("plain", &[], 2);
+        sink.assert_counter("tagged", &[("b", "2"), ("a", "1")], 5);
+        sink.assert_counter("tagged", &[("a", "1")], 3);
+    }
+
+    /// A counter which was not sent fails the assertion
+    #[test]
+    #[should_panic(expected = "was not sent")]
+    fn assert_counter_missing() {
+        let sink = TestMetricSink::default();
+        sink.client().incr("other").unwrap();
+
+        sink.assert_counter("expected", &[], 1);
+    }
+}
@@ -0,0 +1,426 @@
+//! Limiting how many messages a sender may send within a sliding window, and
+//! how many notifications a user may receive
+
+use crate::error::{ApiErrorKind, ApiResult, Backoff};
+use crate::server::extractors::subscription::Subscription;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Stores the messages recently sent by each sender
+pub trait RateLimitStore: Send + Sync {
+    /// Record a message from the sender, unless `limit` messages were already
+    /// sent within the `window` before `now`. If the limit has been reached,
+    /// returns how long until the sender may send another message.
+    fn try_record(
+        &self,
+        key: &str,
+        now: Instant,
+        window: Duration,
+        limit: usize,
+    ) -> Result<(), Duration>;
+}
+
+/// A `RateLimitStore` local to this server
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    /// The send times within the window, oldest first
+    messages: Mutex<HashMap<String, VecDeque<Instant>>>,
+    /// When senders without messages in the window were last removed
+    swept_at: Mutex<Option<Instant>>,
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+    fn try_record(
+        &self,
+        key: &str,
+        now: Instant,
+        window: Duration,
+        limit: usize,
+    ) -> Result<(), Duration> {
+        let mut messages = self.messages.lock().unwrap();
+
+        // Forget idle senders once per window, so a key isn't kept for every
+        // sender ever seen
+        let mut swept_at = self.swept_at.lock().unwrap();
+        let sweep_due = swept_at.map_or(true, |swept_at| {
+            now.checked_duration_since(swept_at)
+                .map_or(false, |elapsed| elapsed >= window)
+        });
+        if sweep_due {
+            messages.retain(|_, sent| {
+                sent.back().map_or(false, |&newest| {
+                    now.checked_duration_since(newest)
+                        .map_or(true, |elapsed| elapsed < window)
+                })
+            });
+            *swept_at = Some(now);
+        }
+        drop(swept_at);
+
+        let sent = messages.entry(key.to_string()).or_default();
+
+        // Forget the messages which are outside of the window
+        while let Some(&oldest) = sent.front() {
+            if now.duration_since(oldest) < window {
+                break;
+            }
+            sent.pop_front();
+        }
+
+        if sent.len() >= limit {
+            // Another message can be sent once the oldest leaves the window
+            let oldest = sent.front().copied().unwrap_or(now);
+            return Err(window - now.duration_since(oldest));
+        }
+
+        sent.push_back(now);
+        Ok(())
+    }
+}
+
+/// Limits how many messages each sender may send within a sliding window.
+/// Senders are identified by their VAPID public key, or the UAID if the
+/// notification is not VAPID-authenticated.
+pub struct RateLimiter {
+    store: Box<dyn RateLimitStore>,
+    /// The max messages a sender may send within the window. Zero disables
+    /// the limit.
+    limit: usize,
+    window: Duration,
+    /// The max random jitter added to the wait of limited senders
+    retry_jitter: Duration,
+}
+
+impl RateLimiter {
+    /// Create a new `RateLimiter`
+    pub fn new(
+        store: Box<dyn RateLimitStore>,
+        limit: usize,
+        window: Duration,
+        retry_jitter: Duration,
+    ) -> Self {
+        RateLimiter {
+            store,
+            limit,
+            window,
+            retry_jitter,
+        }
+    }
+
+    /// Record a message sent to the subscription, or return an error if the
+    /// sender has sent too many messages
+    pub fn check(&self, subscription: &Subscription) -> ApiResult<()> {
+        self.check_at(&Self::sender_key(subscription), Instant::now())
+    }
+
+    /// Get the key which identifies the sender of a notification
+    fn sender_key(subscription: &Subscription) -> String {
+        match &subscription.vapid {
+            Some(vapid) => format!("vapid:{}", vapid.header.public_key),
+            None => format!("uaid:{}", subscription.user.uaid.to_simple()),
+        }
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> ApiResult<()> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+
+        self.store
+            .try_record(key, now, self.window, self.limit)
+            .map_err(|retry_after| {
+                ApiErrorKind::TooManyMessages(Backoff::with_jitter(retry_after, self.retry_jitter))
+                    .into()
+            })
+    }
+}
+
+/// The tokens available to a single UAID
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits how many notifications each UAID may receive, using a token bucket.
+/// Each UAID may receive `burst` notifications at once, and the bucket is
+/// refilled at `rate` notifications per second.
+pub struct UaidRateLimiter {
+    /// Zero disables the limit
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<Uuid, TokenBucket>>,
+    /// When full buckets were last removed
+    swept_at: Mutex<Option<Instant>>,
+}
+
+impl UaidRateLimiter {
+    /// Create a new `UaidRateLimiter`
+    pub fn new(rate: f64, burst: u32) -> Self {
+        UaidRateLimiter {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+            swept_at: Mutex::new(None),
+        }
+    }
+
+    /// Take a token for a notification to the UAID. If there are no tokens
+    /// left, returns how long until the next token is available.
+    pub fn try_acquire(&self, uaid: &Uuid) -> Result<(), Duration> {
+        self.try_acquire_at(uaid, Instant::now())
+    }
+
+    fn try_acquire_at(&self, uaid: &Uuid, now: Instant) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // A full bucket is the same as a new bucket, so full buckets are
+        // removed each time an empty bucket could have refilled
+        let mut swept_at = self.swept_at.lock().unwrap();
+        let refill_time = Duration::from_secs_f64(self.burst / self.rate);
+        let sweep_due = swept_at.map_or(true, |swept_at| {
+            now.checked_duration_since(swept_at)
+                .map_or(false, |elapsed| elapsed >= refill_time)
+        });
+        if sweep_due {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                let elapsed = now
+                    .checked_duration_since(bucket.updated)
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                bucket.tokens + elapsed * rate < burst
+            });
+            *swept_at = Some(now);
+        }
+        drop(swept_at);
+
+        let bucket = buckets.entry(*uaid).or_insert(TokenBucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        // Refill the tokens since the last notification
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / self.rate;
+            return Err(Duration::from_secs(wait.ceil() as u64));
+        }
+
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryRateLimitStore, RateLimitStore, RateLimiter, UaidRateLimiter};
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    const SENDER: &str = "vapid:test-key";
+
+    /// Create a limiter which allows 3 messages per minute
+    fn make_limiter() -> RateLimiter {
+        RateLimiter::new(
+            Box::new(MemoryRateLimitStore::default()),
+            3,
+            Duration::from_secs(60),
+            Duration::from_secs(0),
+        )
+    }
+
+    /// Messages past the limit are rejected with a 429 and a Retry-After
+    #[test]
+    fn limit_exceeded() {
+        let limiter = make_limiter();
+        let start = Instant::now();
+
+        for i in 0..3 {
+            assert!(limiter
+                .check_at(SENDER, start + Duration::from_secs(i * 10))
+                .is_ok());
+        }
+
+        let error = limiter
+            .check_at(SENDER, start + Duration::from_secs(30))
+            .unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.kind.errno(), Some(117));
+        let response = error.error_response();
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "30");
+        assert_eq!(response.headers().get("X-Backoff-Jitter").unwrap(), "0");
+    }
+
+    /// A random jitter of up to the configured max is added to Retry-After,
+    /// and reported in X-Backoff-Jitter
+    #[test]
+    fn retry_jitter() {
+        let limiter = RateLimiter::new(
+            Box::new(MemoryRateLimitStore::default()),
+            1,
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+        );
+        let start = Instant::now();
+        assert!(limiter.check_at(SENDER, start).is_ok());
+
+        for _ in 0..20 {
+            let response = limiter
+                .check_at(SENDER, start + Duration::from_secs(30))
+                .unwrap_err()
+                .error_response();
+            let header = |name: &str| {
+                response
+                    .headers()
+                    .get(name)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .parse::<u64>()
+                    .unwrap()
+            };
+            let retry_after = header("Retry-After");
+            let jitter = header("X-Backoff-Jitter");
+
+            assert!(jitter <= 5);
+            assert_eq!(retry_after, 30 + jitter);
+        }
+    }
+
+    /// Messages are allowed again once older messages leave the window
+    #[test]
+    fn sliding_window() {
+        let limiter = make_limiter();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(SENDER, start).is_ok());
+        }
+        assert!(limiter
+            .check_at(SENDER, start + Duration::from_secs(59))
+            .is_err());
+        assert!(limiter
+            .check_at(SENDER, start + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    /// Each sender has their own limit
+    #[test]
+    fn separate_senders() {
+        let limiter = make_limiter();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(SENDER, start).is_ok());
+        }
+        assert!(limiter.check_at("uaid:other", start).is_ok());
+    }
+
+    /// A limit of zero disables rate limiting
+    #[test]
+    fn disabled() {
+        let limiter = RateLimiter::new(
+            Box::new(MemoryRateLimitStore::default()),
+            0,
+            Duration::from_secs(60),
+            Duration::from_secs(0),
+        );
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter.check_at(SENDER, start).is_ok());
+        }
+    }
+
+    /// Senders without messages in the window are removed from the store
+    #[test]
+    fn idle_senders_removed() {
+        let store = MemoryRateLimitStore::default();
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+
+        assert!(store.try_record("idle", start, window, 3).is_ok());
+        assert!(store
+            .try_record("active", start + Duration::from_secs(30), window, 3)
+            .is_ok());
+        assert!(store
+            .try_record("active", start + window, window, 3)
+            .is_ok());
+
+        let messages = store.messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages["active"].len(), 2);
+    }
+
+    /// A UAID may receive a burst of notifications, then must wait for the
+    /// bucket to refill
+    #[test]
+    fn uaid_burst_exhausted() {
+        let limiter = UaidRateLimiter::new(0.5, 3);
+        let uaid = Uuid::new_v4();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at(&uaid, start).is_ok());
+        }
+        assert_eq!(
+            limiter.try_acquire_at(&uaid, start),
+            Err(Duration::from_secs(2))
+        );
+        assert!(limiter.try_acquire_at(&Uuid::new_v4(), start).is_ok());
+
+        // One token is added every 2 seconds
+        assert!(limiter
+            .try_acquire_at(&uaid, start + Duration::from_secs(2))
+            .is_ok());
+        assert!(limiter
+            .try_acquire_at(&uaid, start + Duration::from_secs(2))
+            .is_err());
+    }
+
+    /// Buckets which have refilled are removed
+    #[test]
+    fn uaid_full_buckets_removed() {
+        let limiter = UaidRateLimiter::new(1.0, 2);
+        let idle = Uuid::new_v4();
+        let active = Uuid::new_v4();
+        let start = Instant::now();
+
+        assert!(limiter.try_acquire_at(&idle, start).is_ok());
+        for _ in 0..2 {
+            assert!(limiter
+                .try_acquire_at(&active, start + Duration::from_secs(1))
+                .is_ok());
+        }
+        assert!(limiter
+            .try_acquire_at(&active, start + Duration::from_secs(2))
+            .is_ok());
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert!(buckets.contains_key(&active));
+    }
+
+    /// A rate of zero disables the UAID limit
+    #[test]
+    fn uaid_disabled() {
+        let limiter = UaidRateLimiter::new(0.0, 1);
+        let uaid = Uuid::new_v4();
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter.try_acquire_at(&uaid, start).is_ok());
+        }
+    }
+}
@@ -0,0 +1,4 @@
+//! A notification router for Fire OS devices, using Amazon Device Messaging
+
+pub mod router;
+pub mod settings;
@@ -0,0 +1,396 @@
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::routers::adm::settings::AdmSettings;
+use crate::routers::common::build_message_data;
+use crate::routers::{Router, RouterError, RouterResponse};
+use crate::server::extractors::notification::Notification;
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use autopush_common::util::sec_since_epoch;
+use cadence::{Counted, StatsdClient};
+use serde::Deserialize;
+use serde_json::json;
+use slog::slog_debug;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use url::Url;
+
+/// The min and max TTL ADM will accept (1 minute to 31 days)
+const MIN_TTL: u64 = 60;
+const MAX_TTL: u64 = 31 * 24 * 60 * 60;
+
+/// The max size of notification data ADM will accept
+const MAX_DATA_BYTES: usize = 6144;
+
+/// The response body of an OAuth token request
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// An OAuth access token and when it expires
+struct AccessToken {
+    token: String,
+    expires_at: u64,
+}
+
+/// The error body returned by ADM
+#[derive(Deserialize)]
+struct AdmErrorResponse {
+    reason: String,
+}
+
+/// Routes notifications to Fire OS devices via Amazon Device Messaging
+pub struct AdmRouter {
+    token_url: Url,
+    base_url: Url,
+    settings: AdmSettings,
+    http: reqwest::Client,
+    metrics: StatsdClient,
+    access_token: Mutex<Option<AccessToken>>,
+}
+
+impl AdmRouter {
+    /// Create a new `AdmRouter`
+    pub fn new(
+        settings: &AdmSettings,
+        http: reqwest::Client,
+        metrics: StatsdClient,
+    ) -> ApiResult<Self> {
+        let base_url = Url::parse(&settings.base_url)
+            .map_err(|e| ApiErrorKind::Internal(format!("Invalid ADM URL: {}", e)))?;
+        let token_url = base_url
+            .join("auth/O2/token")
+            .map_err(|e| ApiErrorKind::Internal(format!("Invalid ADM URL: {}", e)))?;
+
+        Ok(AdmRouter {
+            token_url,
+            base_url,
+            settings: settings.clone(),
+            http,
+            metrics,
+            access_token: Mutex::new(None),
+        })
+    }
+
+    /// Get the OAuth access token, requesting a new one if the cached token
+    /// is missing or has expired
+    async fn get_access_token(&self) -> ApiResult<String> {
+        let now = sec_since_epoch();
+
+        if let Some(access_token) = &*self.access_token.lock().unwrap() {
+            if now < access_token.expires_at {
+                return Ok(access_token.token.clone());
+            }
+        }
+
+        trace!("Requesting a new ADM access token");
+        let response = self
+            .http
+            .post(self.token_url.clone())
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("scope", "messaging:push"),
+                ("client_id", self.settings.client_id.as_str()),
+                ("client_secret", self.settings.client_secret.as_str()),
+            ])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| RouterError::Upstream {
+                service: "ADM",
+                message: format!("Unable to get access token: {}", e),
+            })?
+            .json::<TokenResponse>()
+            .await
+            .map_err(|e| RouterError::Upstream {
+                service: "ADM",
+                message: format!("Invalid access token response: {}", e),
+            })?;
+
+        *self.access_token.lock().unwrap() = Some(AccessToken {
+            token: response.access_token.clone(),
+            expires_at: now + response.expires_in,
+        });
+
+        Ok(response.access_token)
+    }
+
+    /// Map an error response from ADM onto a router error
+    fn map_error(status: StatusCode, reason: String) -> RouterError {
+        match reason.as_str() {
+            "Unregistered" | "InvalidRegistrationId" => RouterError::NotRegistered {
+                service: "ADM",
+                reason,
+            },
+            _ => RouterError::Upstream {
+                service: "ADM",
+                message: format!("{}: {}", status, reason),
+            },
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Router for AdmRouter {
+    async fn route_notification(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        let user = &notification.subscription.user;
+        slog_debug!(notification.logger(), "Sending ADM notification");
+
+        let token = user
+            .router_data
+            .as_ref()
+            .and_then(|data| data.get("token"))
+            .and_then(|token| token.as_str())
+            .ok_or(ApiErrorKind::NoSubscription)?;
+        let ttl = max(
+            min(notification.headers.ttl.unwrap_or(0) as u64, MAX_TTL),
+            MIN_TTL,
+        );
+        let url = self
+            .base_url
+            .join(&format!("messaging/registrations/{}/messages", token))
+            .map_err(|_| ApiErrorKind::NoSubscription)?;
+        let message = json!({
+            "data": build_message_data(notification),
+            "expiresAfter": ttl
+        });
+
+        let response = self
+            .http
+            .post(url)
+            .bearer_auth(self.get_access_token().await?)
+            .header(
+                "X-Amzn-Type-Version",
+                "com.amazon.device.messaging.ADMMessage@1.0",
+            )
+            .header(
+                "X-Amzn-Accept-Type",
+                "com.amazon.device.messaging.ADMSendResult@1.0",
+            )
+            .json(&message)
+            .send()
+            .await
+            .map_err(|e| RouterError::Upstream {
+                service: "ADM",
+                message: e.to_string(),
+            })?;
+
+        let status = response.status();
+        let request_id = response
+            .headers()
+            .get("X-Amzn-RequestId")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        if status != StatusCode::OK {
+            let reason = response
+                .json::<AdmErrorResponse>()
+                .await
+                .map(|body| body.reason)
+                .unwrap_or_default();
+            debug!(
+                "ADM rejected the notification: {} {}", status, reason;
+                "user" => ?user, "request_id" => &request_id
+            );
+            return Err(Self::map_error(status, reason).into());
+        }
+
+        trace!("ADM accepted the notification"; "request_id" => &request_id);
+        self.metrics
+            .incr_with_tags("notification.bridge.sent")
+            .with_tag("platform", "adm")
+            .send();
+
+        let mut headers = HashMap::new();
+        headers.insert("TTL", ttl.to_string());
+
+        Ok(RouterResponse {
+            status: StatusCode::CREATED,
+            headers,
+            body: None,
+        })
+    }
+
+    /// ADM is healthy if an access token can be requested
+    async fn health_check(&self) -> ApiResult<()> {
+        self.get_access_token().await.map(|_| ())
+    }
+
+    fn max_data_bytes(&self) -> usize {
+        MAX_DATA_BYTES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessToken, AdmRouter};
+    use crate::error::{ApiErrorKind, ApiResult};
+    use crate::routers::adm::settings::AdmSettings;
+    use crate::routers::{Router, RouterDispatch, RouterError, RouterResponse, RouterType};
+    use crate::server::extractors::notification::{Notification, NotificationBuilder};
+    use actix_web::http::StatusCode;
+    use autopush_common::db::DynamoDbUser;
+    use autopush_common::util::sec_since_epoch;
+    use cadence::{NopMetricSink, StatsdClient};
+    use mockito::Matcher;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    const ADM_TOKEN: &str = "test-adm-token";
+    const CHANNEL_ID: &str = "deadbeef-13f9-4639-87f9-2ff731824f34";
+
+    /// Create a router for testing, using the mock server as the ADM API
+    fn make_router() -> AdmRouter {
+        let settings = AdmSettings {
+            base_url: mockito::server_url(),
+            client_id: "test-client-id".to_string(),
+            client_secret: "test-client-secret".to_string(),
+        };
+
+        AdmRouter::new(
+            &settings,
+            reqwest::Client::new(),
+            StatsdClient::from_sink("autoendpoint", NopMetricSink),
+        )
+        .unwrap()
+    }
+
+    /// Cache an access token in the router
+    fn set_access_token(router: &AdmRouter, token: &str, expires_at: u64) {
+        *router.access_token.lock().unwrap() = Some(AccessToken {
+            token: token.to_string(),
+            expires_at,
+        });
+    }
+
+    /// Create a notification for a user with an ADM registration
+    fn make_notification() -> Notification {
+        let mut router_data = HashMap::new();
+        router_data.insert("token".to_string(), json!(ADM_TOKEN));
+
+        NotificationBuilder::new()
+            .user(DynamoDbUser {
+                router_type: "adm".to_string(),
+                router_data: Some(router_data),
+                ..Default::default()
+            })
+            .channel_id(Uuid::parse_str(CHANNEL_ID).unwrap())
+            .message_id("test-message-id")
+            .ttl(120)
+            .build()
+            .unwrap()
+    }
+
+    /// Mock the ADM message endpoint
+    fn mock_adm(access_token: &str) -> mockito::Mock {
+        mockito::mock(
+            "POST",
+            format!("/messaging/registrations/{}/messages", ADM_TOKEN).as_str(),
+        )
+        .match_header("Authorization", format!("Bearer {}", access_token).as_str())
+    }
+
+    /// Assert that a result is a specific router error
+    fn assert_router_error(result: ApiResult<RouterResponse>, expected: RouterError) {
+        match result.unwrap_err().kind {
+            ApiErrorKind::Router(error) => assert_eq!(error.to_string(), expected.to_string()),
+            kind => panic!("Expected a router error, got {:?}", kind),
+        }
+    }
+
+    /// A notification is translated into the ADM format, using the cached
+    /// access token
+    #[actix_rt::test]
+    async fn successful_routing() {
+        let router = make_router();
+        set_access_token(&router, "cached-token", sec_since_epoch() + 3600);
+        let notification = make_notification();
+        let adm_mock = mock_adm("cached-token")
+            .match_body(Matcher::Json(json!({
+                "data": {
+                    "chid": CHANNEL_ID
+                },
+                "expiresAfter": 120
+            })))
+            .with_status(200)
+            .with_header("X-Amzn-RequestId", "test-request-id")
+            .with_body(r#"{"registrationID":"test-adm-token"}"#)
+            .create();
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {:?}", result);
+        adm_mock.assert();
+    }
+
+    /// An expired access token is refreshed before sending
+    #[actix_rt::test]
+    async fn refreshes_expired_access_token() {
+        let router = make_router();
+        set_access_token(&router, "expired-token", 0);
+        let notification = make_notification();
+        let token_mock = mockito::mock("POST", "/auth/O2/token")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("grant_type".to_string(), "client_credentials".to_string()),
+                Matcher::UrlEncoded("client_id".to_string(), "test-client-id".to_string()),
+            ]))
+            .with_status(200)
+            .with_body(r#"{"access_token":"new-token","expires_in":3600}"#)
+            .create();
+        let adm_mock = mock_adm("new-token").with_status(200).create();
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {:?}", result);
+        token_mock.assert();
+        adm_mock.assert();
+    }
+
+    /// Unregistered devices are reported so the user can be cleaned up
+    #[actix_rt::test]
+    async fn unregistered() {
+        let router = make_router();
+        set_access_token(&router, "cached-token", sec_since_epoch() + 3600);
+        let notification = make_notification();
+        let _adm_mock = mock_adm("cached-token")
+            .with_status(410)
+            .with_body(r#"{"reason":"Unregistered"}"#)
+            .create();
+
+        let result = router.route_notification(&notification).await;
+        assert_router_error(
+            result,
+            RouterError::NotRegistered {
+                service: "ADM",
+                reason: "Unregistered".to_string(),
+            },
+        );
+    }
+
+    /// The dispatcher rejects data over the ADM limit
+    #[test]
+    fn data_limit() {
+        let mut dispatch =
+            RouterDispatch::new(StatsdClient::from_sink("autoendpoint", NopMetricSink));
+        dispatch.register(RouterType::Adm, Box::new(make_router()));
+        let mut notification = make_notification();
+
+        notification.data = Some(base64::encode_config(
+            &[0; 6144][..],
+            base64::URL_SAFE_NO_PAD,
+        ));
+        assert!(dispatch.dry_run(&notification).is_ok());
+
+        notification.data = Some(base64::encode_config(
+            &[0; 6144 + 1][..],
+            base64::URL_SAFE_NO_PAD,
+        ));
+        let error = dispatch.dry_run(&notification).unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            error.kind.to_string(),
+            "Data payload must be smaller than 6144 bytes for the adm router"
+        );
+    }
+}
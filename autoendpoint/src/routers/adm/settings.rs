@@ -0,0 +1,31 @@
+use serde::Deserialize;
+
+/// Settings for `AdmRouter`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AdmSettings {
+    /// The base URL of the ADM API
+    pub base_url: String,
+    /// The OAuth client ID used to authenticate with ADM
+    pub client_id: String,
+    /// The OAuth client secret used to authenticate with ADM
+    pub client_secret: String,
+}
+
+impl Default for AdmSettings {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.amazon.com".to_string(),
+            client_id: String::new(),
+            client_secret: String::new(),
+        }
+    }
+}
+
+impl AdmSettings {
+    /// Check if the OAuth credentials are set. The router is only enabled if
+    /// they are.
+    pub fn is_configured(&self) -> bool {
+        !self.client_id.is_empty() && !self.client_secret.is_empty()
+    }
+}
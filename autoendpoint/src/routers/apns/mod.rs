@@ -0,0 +1,5 @@
+//! A notification router for iOS devices, using the Apple Push Notification
+//! service
+
+pub mod router;
+pub mod settings;
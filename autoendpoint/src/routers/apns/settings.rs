@@ -0,0 +1,41 @@
+use serde::Deserialize;
+
+/// Settings for `ApnsRouter`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ApnsSettings {
+    /// The base URL of the APNS API
+    pub base_url: String,
+    /// The app bundle ID which notifications are sent to
+    pub topic: String,
+    /// The contents of the `.p8` private key used to sign authentication
+    /// tokens
+    pub key: String,
+    /// The ID of the signing key
+    pub key_id: String,
+    /// The ID of the team which owns the signing key
+    pub team_id: String,
+}
+
+impl Default for ApnsSettings {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.push.apple.com".to_string(),
+            topic: String::new(),
+            key: String::new(),
+            key_id: String::new(),
+            team_id: String::new(),
+        }
+    }
+}
+
+impl ApnsSettings {
+    /// Check if the signing key and app are set. The router is only enabled
+    /// if they are.
+    pub fn is_configured(&self) -> bool {
+        !self.topic.is_empty()
+            && !self.key.is_empty()
+            && !self.key_id.is_empty()
+            && !self.team_id.is_empty()
+    }
+}
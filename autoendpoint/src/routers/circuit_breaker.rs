@@ -0,0 +1,287 @@
+//! A per-node circuit breaker, so repeatedly failing nodes are not sent
+//! requests which will most likely fail
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When the circuit breaker opens and how long it stays open
+#[derive(Clone, Debug)]
+pub struct BreakerPolicy {
+    /// How many consecutive failures open the breaker
+    pub failure_threshold: u32,
+    /// The failures must happen within this window to open the breaker
+    pub failure_window: Duration,
+    /// How long the breaker stays open before a request is allowed through to
+    /// probe the node
+    pub cooldown: Duration,
+}
+
+/// The state of the breaker for a single node
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum BreakerState {
+    /// Requests are allowed. Failures are counted from the first failure.
+    Closed {
+        failures: u32,
+        first_failure: Instant,
+    },
+    /// Requests are not allowed until the cooldown has passed
+    Open { opened_at: Instant },
+    /// A single probe request has been allowed through. The time the breaker
+    /// opened is kept in case the probe is abandoned.
+    HalfOpen { opened_at: Instant },
+}
+
+/// Tracks failing requests to each node and stops sending requests to nodes
+/// which keep failing
+pub struct CircuitBreaker {
+    policy: BreakerPolicy,
+    /// Nodes without an entry have no recent failures
+    states: Mutex<HashMap<String, BreakerState>>,
+}
+
+/// Allows a request to a node until it is dropped. If the request is probing
+/// the node and is dropped without its outcome being recorded (ex. the send
+/// was shed or the error doesn't say anything about the node), another
+/// request may probe the node.
+pub struct BreakerPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    node_id: String,
+    /// When the breaker opened, if this request is probing the node
+    probe: Option<Instant>,
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(opened_at) = self.probe {
+            self.breaker.release_probe(&self.node_id, opened_at);
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// Create a new `CircuitBreaker`
+    pub fn new(policy: BreakerPolicy) -> Self {
+        CircuitBreaker {
+            policy,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if a request may be sent to the node. Once the cooldown has
+    /// passed, a single request is allowed through to probe the node. The
+    /// permit must be kept until the outcome of the request is recorded.
+    pub fn allow_request(&self, node_id: &str) -> Option<BreakerPermit<'_>> {
+        self.allow_request_at(node_id, Instant::now())
+    }
+
+    /// Record a successful request to the node. Returns true if this closed
+    /// the breaker.
+    pub fn record_success(&self, node_id: &str) -> bool {
+        let previous = self.states.lock().unwrap().remove(node_id);
+
+        match previous {
+            Some(BreakerState::HalfOpen { .. }) => true,
+            _ => false,
+        }
+    }
+
+    /// Record a failed request to the node. Returns true if this opened the
+    /// breaker.
+    pub fn record_failure(&self, node_id: &str) -> bool {
+        self.record_failure_at(node_id, Instant::now())
+    }
+
+    fn allow_request_at(&self, node_id: &str, now: Instant) -> Option<BreakerPermit<'_>> {
+        let mut states = self.states.lock().unwrap();
+
+        let probe = match states.get(node_id) {
+            None | Some(BreakerState::Closed { .. }) => None,
+            Some(BreakerState::Open { opened_at })
+                if now.duration_since(*opened_at) >= self.policy.cooldown =>
+            {
+                let opened_at = *opened_at;
+                states.insert(node_id.to_string(), BreakerState::HalfOpen { opened_at });
+                Some(opened_at)
+            }
+            // Wait for the cooldown or the probe request to finish
+            Some(BreakerState::Open { .. }) | Some(BreakerState::HalfOpen { .. }) => return None,
+        };
+
+        Some(BreakerPermit {
+            breaker: self,
+            node_id: node_id.to_string(),
+            probe,
+        })
+    }
+
+    /// Let another request probe the node if the probe finished without its
+    /// outcome being recorded. The breaker stays open, but its cooldown has
+    /// already passed.
+    fn release_probe(&self, node_id: &str, opened_at: Instant) {
+        let mut states = self.states.lock().unwrap();
+
+        if states.get(node_id) == Some(&BreakerState::HalfOpen { opened_at }) {
+            states.insert(node_id.to_string(), BreakerState::Open { opened_at });
+        }
+    }
+
+    fn record_failure_at(&self, node_id: &str, now: Instant) -> bool {
+        let mut states = self.states.lock().unwrap();
+
+        let state = match states.get(node_id) {
+            Some(BreakerState::Closed {
+                failures,
+                first_failure,
+            }) if now.duration_since(*first_failure) < self.policy.failure_window => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                    first_failure: *first_failure,
+                }
+            }
+            // The probe failed, so wait for another cooldown
+            Some(BreakerState::HalfOpen { .. }) => BreakerState::Open { opened_at: now },
+            // The node is already known to be failing
+            Some(BreakerState::Open { .. }) => return false,
+            // No failures within the window, so start counting again
+            None | Some(BreakerState::Closed { .. }) => BreakerState::Closed {
+                failures: 1,
+                first_failure: now,
+            },
+        };
+
+        let (state, opened) = match state {
+            BreakerState::Closed { failures, .. } if failures >= self.policy.failure_threshold => {
+                (BreakerState::Open { opened_at: now }, true)
+            }
+            BreakerState::Open { .. } => (state, true),
+            state => (state, false),
+        };
+        states.insert(node_id.to_string(), state);
+
+        opened
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BreakerPolicy, CircuitBreaker};
+    use std::time::{Duration, Instant};
+
+    const NODE_ID: &str = "http://node:8081";
+
+    /// Create a breaker which opens after 3 failures within a minute
+    fn make_breaker() -> CircuitBreaker {
+        CircuitBreaker::new(BreakerPolicy {
+            failure_threshold: 3,
+            failure_window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+        })
+    }
+
+    /// The breaker opens after enough failures, lets a probe through after
+    /// the cooldown, and closes once the probe succeeds
+    #[test]
+    fn closed_open_half_open_closed() {
+        let breaker = make_breaker();
+        let start = Instant::now();
+
+        // Closed
+        assert!(!breaker.record_failure_at(NODE_ID, start));
+        assert!(!breaker.record_failure_at(NODE_ID, start));
+        assert!(breaker.allow_request_at(NODE_ID, start).is_some());
+
+        // Open
+        assert!(breaker.record_failure_at(NODE_ID, start));
+        assert!(breaker
+            .allow_request_at(NODE_ID, start + Duration::from_secs(29))
+            .is_none());
+
+        // Half-open, only the probe is allowed
+        let probe = breaker.allow_request_at(NODE_ID, start + Duration::from_secs(30));
+        assert!(probe.is_some());
+        assert!(breaker
+            .allow_request_at(NODE_ID, start + Duration::from_secs(30))
+            .is_none());
+
+        // Closed
+        assert!(breaker.record_success(NODE_ID));
+        drop(probe);
+        assert!(breaker
+            .allow_request_at(NODE_ID, start + Duration::from_secs(30))
+            .is_some());
+    }
+
+    /// A failed probe opens the breaker for another cooldown
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = make_breaker();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            breaker.record_failure_at(NODE_ID, start);
+        }
+        let probe_time = start + Duration::from_secs(30);
+        let probe = breaker.allow_request_at(NODE_ID, probe_time);
+        assert!(probe.is_some());
+        assert!(breaker.record_failure_at(NODE_ID, probe_time));
+        drop(probe);
+
+        assert!(breaker
+            .allow_request_at(NODE_ID, probe_time + Duration::from_secs(29))
+            .is_none());
+        assert!(breaker
+            .allow_request_at(NODE_ID, probe_time + Duration::from_secs(30))
+            .is_some());
+    }
+
+    /// A probe which finishes without an outcome lets another request probe
+    /// the node, without waiting for another cooldown
+    #[test]
+    fn abandoned_probe_released() {
+        let breaker = make_breaker();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            breaker.record_failure_at(NODE_ID, start);
+        }
+        let probe_time = start + Duration::from_secs(30);
+        let probe = breaker.allow_request_at(NODE_ID, probe_time);
+        assert!(probe.is_some());
+        assert!(breaker.allow_request_at(NODE_ID, probe_time).is_none());
+        drop(probe);
+
+        // Only one new probe is allowed
+        let probe = breaker.allow_request_at(NODE_ID, probe_time);
+        assert!(probe.is_some());
+        assert!(breaker.allow_request_at(NODE_ID, probe_time).is_none());
+        assert!(breaker.record_success(NODE_ID));
+    }
+
+    /// Failures spread out over more than the window don't open the breaker
+    #[test]
+    fn failures_outside_window() {
+        let breaker = make_breaker();
+        let start = Instant::now();
+
+        assert!(!breaker.record_failure_at(NODE_ID, start));
+        assert!(!breaker.record_failure_at(NODE_ID, start + Duration::from_secs(30)));
+        assert!(!breaker.record_failure_at(NODE_ID, start + Duration::from_secs(61)));
+        assert!(breaker
+            .allow_request_at(NODE_ID, start + Duration::from_secs(61))
+            .is_some());
+    }
+
+    /// A success resets the failure count
+    #[test]
+    fn success_resets_failures() {
+        let breaker = make_breaker();
+        let start = Instant::now();
+
+        breaker.record_failure_at(NODE_ID, start);
+        breaker.record_failure_at(NODE_ID, start);
+        assert!(!breaker.record_success(NODE_ID));
+        assert!(!breaker.record_failure_at(NODE_ID, start));
+        assert!(breaker.allow_request_at(NODE_ID, start).is_some());
+    }
+}
@@ -0,0 +1,31 @@
+//! Utilities shared by the bridge routers
+
+use crate::server::extractors::notification::Notification;
+use std::collections::HashMap;
+
+/// Convert a notification into the data fields sent through a bridge. The
+/// encryption headers are only included if there is a payload, so the user
+/// agent can decrypt it.
+pub fn build_message_data(notification: &Notification) -> HashMap<&'static str, String> {
+    let mut data = HashMap::new();
+    data.insert("chid", notification.subscription.channel_id.to_string());
+
+    if let Some(body) = &notification.data {
+        let headers = &notification.headers;
+        let fields = [
+            ("con", &headers.content_encoding),
+            ("enc", &headers.encryption),
+            ("cryptokey", &headers.crypto_key),
+            ("enckey", &headers.encryption_key),
+        ];
+
+        data.insert("body", body.clone());
+        for &(key, value) in fields.iter() {
+            if let Some(value) = value {
+                data.insert(key, value.clone());
+            }
+        }
+    }
+
+    data
+}
@@ -0,0 +1,4 @@
+//! A notification router for Android devices, using Firebase Cloud Messaging
+
+pub mod router;
+pub mod settings;
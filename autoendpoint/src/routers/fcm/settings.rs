@@ -0,0 +1,51 @@
+use serde::Deserialize;
+
+/// Which FCM API notifications are sent through
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FcmApi {
+    /// The legacy HTTP API, authenticated with a server key
+    Legacy,
+    /// The HTTP v1 API, authenticated with a service account
+    V1,
+}
+
+/// Settings for `FcmRouter`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct FcmSettings {
+    /// The base URL of the FCM API
+    pub base_url: String,
+    /// Which FCM API to use
+    pub api: FcmApi,
+    /// The server key used to authenticate with the legacy API
+    pub server_key: String,
+    /// The ID of the Firebase project, used with the v1 API
+    pub project_id: String,
+    /// The contents of the Google service account JSON key used to
+    /// authenticate with the v1 API
+    pub service_account: String,
+}
+
+impl Default for FcmSettings {
+    fn default() -> Self {
+        Self {
+            base_url: "https://fcm.googleapis.com".to_string(),
+            api: FcmApi::Legacy,
+            server_key: String::new(),
+            project_id: String::new(),
+            service_account: String::new(),
+        }
+    }
+}
+
+impl FcmSettings {
+    /// Check if the credentials for the selected API are set. The router is
+    /// only enabled if they are.
+    pub fn is_configured(&self) -> bool {
+        match self.api {
+            FcmApi::Legacy => !self.server_key.is_empty(),
+            FcmApi::V1 => !self.project_id.is_empty() && !self.service_account.is_empty(),
+        }
+    }
+}
@@ -0,0 +1,894 @@
+//! Routers route notifications to user agents
+
+use crate::error::{ApiError, ApiResult, Backoff};
+use crate::metrics::TimerGuard;
+use crate::server::extractors::notification::Notification;
+use crate::server::extractors::notification_headers::Urgency;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use async_trait::async_trait;
+use cadence::{Counted, StatsdClient};
+use futures::future;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+use uuid::Uuid;
+
+pub mod adm;
+pub mod apns;
+pub mod circuit_breaker;
+pub mod common;
+pub mod fcm;
+pub mod node_error;
+pub mod node_limiter;
+pub mod retry;
+pub mod webpush;
+
+#[async_trait(?Send)]
+pub trait Router: Send + Sync {
+    /// Route a notification to the user
+    async fn route_notification(&self, notification: &Notification) -> ApiResult<RouterResponse>;
+
+    /// Route a notification only if it can be delivered to the user now,
+    /// returning `None` if the user can't be reached. This is used when the
+    /// user has a fallback router, instead of storing the notification.
+    async fn route_direct(&self, notification: &Notification) -> ApiResult<Option<RouterResponse>> {
+        self.route_notification(notification).await.map(Some)
+    }
+
+    /// Handle the user's fallback router failing to route a notification
+    /// which `route_direct` could not deliver. The fallback router's error is
+    /// returned to the sender by default.
+    async fn route_fallback_failed(
+        &self,
+        _notification: &Notification,
+        error: ApiError,
+    ) -> ApiResult<RouterResponse> {
+        Err(error)
+    }
+
+    /// Check that the services this router depends on can be reached
+    async fn health_check(&self) -> ApiResult<()> {
+        Ok(())
+    }
+
+    /// The maximum size of notification data, in bytes, this router accepts.
+    /// `RouterDispatch` rejects larger notifications before routing them.
+    fn max_data_bytes(&self) -> usize {
+        4096
+    }
+
+    /// Whether this router's registrations can carry notification data.
+    /// `RouterDispatch` rejects notifications with data for routers which
+    /// can't.
+    fn supports_data(&self) -> bool {
+        true
+    }
+}
+
+/// Lets a router be registered with `RouterDispatch` while it is also used
+/// directly
+#[async_trait(?Send)]
+impl<R: Router> Router for Arc<R> {
+    async fn route_notification(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        (**self).route_notification(notification).await
+    }
+
+    async fn route_direct(&self, notification: &Notification) -> ApiResult<Option<RouterResponse>> {
+        (**self).route_direct(notification).await
+    }
+
+    async fn route_fallback_failed(
+        &self,
+        notification: &Notification,
+        error: ApiError,
+    ) -> ApiResult<RouterResponse> {
+        (**self).route_fallback_failed(notification, error).await
+    }
+
+    async fn health_check(&self) -> ApiResult<()> {
+        (**self).health_check().await
+    }
+
+    fn max_data_bytes(&self) -> usize {
+        (**self).max_data_bytes()
+    }
+
+    fn supports_data(&self) -> bool {
+        (**self).supports_data()
+    }
+}
+
+/// The type of router which a user is reached through
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RouterType {
+    WebPush,
+    Fcm,
+    Apns,
+    Adm,
+}
+
+impl FromStr for RouterType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "webpush" => Ok(RouterType::WebPush),
+            "fcm" => Ok(RouterType::Fcm),
+            "apns" => Ok(RouterType::Apns),
+            "adm" => Ok(RouterType::Adm),
+            _ => Err(()),
+        }
+    }
+}
+
+impl RouterType {
+    /// The name of the router type, as stored in user records
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RouterType::WebPush => "webpush",
+            RouterType::Fcm => "fcm",
+            RouterType::Apns => "apns",
+            RouterType::Adm => "adm",
+        }
+    }
+}
+
+impl Display for RouterType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Selects the router for a notification based on the user's router type.
+///
+/// A user may also have a fallback router, set by the `fallback_router` item
+/// in their router data. Notifications for these users are only routed
+/// through their router if they can be delivered immediately (ex. a WebPush
+/// user is connected to a node), otherwise the fallback router is used.
+pub struct RouterDispatch {
+    routers: HashMap<RouterType, Box<dyn Router>>,
+    metrics: StatsdClient,
+}
+
+impl RouterDispatch {
+    /// Create a new `RouterDispatch` without any routers registered
+    pub fn new(metrics: StatsdClient) -> Self {
+        RouterDispatch {
+            routers: HashMap::new(),
+            metrics,
+        }
+    }
+
+    /// Register the router which handles a router type, replacing any router
+    /// previously registered for it
+    pub fn register(&mut self, router_type: RouterType, router: Box<dyn Router>) {
+        self.routers.insert(router_type, router);
+    }
+
+    /// Route a notification using the router registered for the user's
+    /// router type
+    pub async fn route(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        // Time the delivery, including when the request is cancelled
+        let mut timer = TimerGuard::start(&self.metrics, "notification.route.latency");
+        timer.tag(
+            "router_type",
+            notification.subscription.router_type.as_str(),
+        );
+        let result = self.route_with_fallback(notification).await;
+
+        let outcome = match &result {
+            Ok(response) if response.status == StatusCode::ACCEPTED => "stored",
+            Ok(_) => "delivered",
+            Err(_) => "error",
+        };
+        timer.tag("outcome", outcome);
+
+        result
+    }
+
+    /// Route a notification, using the user's fallback router if they have
+    /// one and can't be reached directly
+    async fn route_with_fallback(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        let router = self.select_router(notification.subscription.router_type, notification)?;
+
+        let fallback_router_type = match Self::fallback_router_type(notification) {
+            Some(router_type) => router_type,
+            None => return router.route_notification(notification).await,
+        };
+        let fallback_router = self.select_router(fallback_router_type, notification)?;
+
+        if let Some(response) = router.route_direct(notification).await? {
+            self.record_delivered_via("node");
+            return Ok(response);
+        }
+
+        debug!(
+            "User can't be reached directly, falling back to the {} router",
+            fallback_router_type
+        );
+        match fallback_router.route_notification(notification).await {
+            Ok(response) => {
+                self.record_delivered_via("bridge");
+                Ok(response)
+            }
+            // Let the user's router decide what to do, since the user's
+            // registration with it may still be valid
+            Err(error) => router.route_fallback_failed(notification, error).await,
+        }
+    }
+
+    /// Check the health of each router
+    pub async fn health_check(&self) -> HashMap<RouterType, ApiResult<()>> {
+        let checks = self
+            .routers
+            .iter()
+            .map(
+                |(&router_type, router)| async move { (router_type, router.health_check().await) },
+            );
+
+        future::join_all(checks).await.into_iter().collect()
+    }
+
+    /// Check that a notification could be routed, and report what would
+    /// happen to it, without routing it
+    pub fn dry_run(&self, notification: &Notification) -> ApiResult<DryRunReport> {
+        self.select_router(notification.subscription.router_type, notification)?;
+
+        Ok(DryRunReport {
+            router_type: notification.subscription.router_type.to_string(),
+            destination: Self::dry_run_destination(notification),
+            channel_id: notification.subscription.channel_id,
+            ttl: notification.headers.ttl.unwrap_or(0),
+            topic: notification.headers.topic.clone(),
+            urgency: notification.headers.urgency,
+            data_bytes: Self::data_bytes(notification),
+            vapid: notification.subscription.vapid.is_some(),
+        })
+    }
+
+    /// Get where a notification would be sent. WebPush notifications are sent
+    /// to the user's node if they are connected, otherwise they are stored
+    /// (or dropped if the TTL is 0). Other notifications are sent to a bridge.
+    fn dry_run_destination(notification: &Notification) -> &'static str {
+        if notification.subscription.router_type != RouterType::WebPush {
+            return "bridge";
+        }
+
+        if notification.subscription.user.node_id.is_some() {
+            "node"
+        } else if notification.headers.ttl.unwrap_or(0) == 0 {
+            "dropped"
+        } else {
+            "storage"
+        }
+    }
+
+    /// Get the router for the router type, checking that it will accept the
+    /// notification data
+    fn select_router(
+        &self,
+        router_type: RouterType,
+        notification: &Notification,
+    ) -> ApiResult<&dyn Router> {
+        let router = self
+            .routers
+            .get(&router_type)
+            .ok_or(RouterError::NotConfigured(router_type))?;
+
+        let data_bytes = Self::data_bytes(notification);
+        if data_bytes > 0 && !router.supports_data() {
+            return Err(RouterError::DataNotSupported(router_type).into());
+        }
+
+        let max_data_bytes = router.max_data_bytes();
+        if data_bytes > max_data_bytes {
+            return Err(RouterError::PayloadTooLarge {
+                router_type,
+                max_bytes: max_data_bytes,
+            }
+            .into());
+        }
+
+        Ok(router.as_ref())
+    }
+
+    /// Get the user's fallback router type, if they have one
+    pub fn fallback_router_type(notification: &Notification) -> Option<RouterType> {
+        notification
+            .subscription
+            .user
+            .router_data
+            .as_ref()?
+            .get("fallback_router")?
+            .as_str()?
+            .parse()
+            .ok()
+    }
+
+    /// Record how a notification for a user with a fallback router was
+    /// delivered
+    fn record_delivered_via(&self, delivered_via: &str) {
+        self.metrics
+            .incr_with_tags("notification.fallback.delivered")
+            .with_tag("delivered_via", delivered_via)
+            .send();
+    }
+
+    /// Get the size of the notification data. The data is unpadded base64, so
+    /// the decoded size can be calculated without decoding it.
+    fn data_bytes(notification: &Notification) -> usize {
+        notification
+            .data
+            .as_ref()
+            .map(|data| data.len() * 3 / 4)
+            .unwrap_or(0)
+    }
+}
+
+/// What would happen to a notification, reported by a dry run instead of
+/// routing the notification
+#[derive(Debug, Serialize)]
+pub struct DryRunReport {
+    pub router_type: String,
+    /// Where the notification would be sent: `node`, `storage`, `dropped`,
+    /// or `bridge`
+    pub destination: &'static str,
+    pub channel_id: Uuid,
+    pub ttl: i64,
+    pub topic: Option<String>,
+    pub urgency: Urgency,
+    pub data_bytes: usize,
+    /// If the notification was authenticated with VAPID
+    pub vapid: bool,
+}
+
+/// The response returned when a router routes a notification
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RouterResponse {
+    pub status: StatusCode,
+    pub headers: HashMap<&'static str, String>,
+    pub body: Option<String>,
+}
+
+impl From<RouterResponse> for HttpResponse {
+    fn from(router_response: RouterResponse) -> Self {
+        let mut builder = HttpResponse::build(router_response.status);
+
+        for (key, value) in router_response.headers {
+            builder.header(key, value);
+        }
+
+        builder.body(router_response.body.unwrap_or_default())
+    }
+}
+
+/// Errors which may occur while routing a notification
+#[derive(Debug, Error)]
+pub enum RouterError {
+    #[error("Database error while saving notification")]
+    SaveDb {
+        source: autopush_common::errors::Error,
+        /// How long the client should wait before retrying
+        retry_after: Backoff,
+    },
+
+    #[error("User was deleted during routing")]
+    UserWasDeleted,
+
+    #[error("{service} registration is no longer valid: {reason}")]
+    NotRegistered {
+        service: &'static str,
+        reason: String,
+    },
+
+    #[error("Data payload must be smaller than {max_bytes} bytes for the {router_type} router")]
+    PayloadTooLarge {
+        router_type: RouterType,
+        max_bytes: usize,
+    },
+
+    #[error("{service} is rate limiting notifications, try again later")]
+    TooManyRequests { service: &'static str },
+
+    #[error("Too many notifications for this user, try again later")]
+    UserRateLimited {
+        /// How long the client should wait before retrying
+        retry_after: Backoff,
+    },
+
+    #[error("Too many messages are stored for this subscription (max {max_messages})")]
+    ChannelStorageFull { max_messages: usize },
+
+    #[error("The {0} router does not support data payloads for this registration")]
+    DataNotSupported(RouterType),
+
+    #[error("No router is configured for the {0} router type")]
+    NotConfigured(RouterType),
+
+    #[error("{service} returned an error: {message}")]
+    Upstream {
+        service: &'static str,
+        message: String,
+    },
+}
+
+impl RouterError {
+    /// Get the associated HTTP status code
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RouterError::SaveDb { .. } | RouterError::TooManyRequests { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+
+            RouterError::UserWasDeleted | RouterError::NotRegistered { .. } => StatusCode::GONE,
+
+            RouterError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+
+            RouterError::DataNotSupported(_) => StatusCode::BAD_REQUEST,
+
+            RouterError::UserRateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+
+            RouterError::ChannelStorageFull { .. } => StatusCode::INSUFFICIENT_STORAGE,
+
+            RouterError::NotConfigured(_) => StatusCode::INTERNAL_SERVER_ERROR,
+
+            RouterError::Upstream { .. } => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Get the associated error number
+    pub fn errno(&self) -> Option<usize> {
+        match self {
+            RouterError::SaveDb { .. } => Some(201),
+
+            RouterError::UserWasDeleted => Some(105),
+
+            RouterError::NotRegistered { .. } => Some(106),
+
+            RouterError::PayloadTooLarge { .. } => Some(104),
+
+            RouterError::DataNotSupported(_) => Some(121),
+
+            RouterError::TooManyRequests { .. } => Some(202),
+
+            RouterError::UserRateLimited { .. } => Some(122),
+
+            RouterError::ChannelStorageFull { .. } => Some(203),
+
+            RouterError::NotConfigured(_) => Some(999),
+
+            RouterError::Upstream { .. } => Some(902),
+        }
+    }
+
+    /// Get how long the client should wait before retrying, if the error
+    /// specifies it
+    pub fn backoff(&self) -> Option<Backoff> {
+        match self {
+            RouterError::SaveDb { retry_after, .. }
+            | RouterError::UserRateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Router, RouterDispatch, RouterError, RouterResponse, RouterType};
+    use crate::error::{ApiError, ApiErrorKind, ApiResult, DEFAULT_MORE_INFO_URL};
+    use crate::metrics::TestMetricSink;
+    use crate::server::extractors::notification::{Notification, NotificationBuilder};
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use async_trait::async_trait;
+    use cadence::{NopMetricSink, StatsdClient};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    /// A router which responds with its router type
+    struct StubRouter(RouterType);
+
+    #[async_trait(?Send)]
+    impl Router for StubRouter {
+        async fn route_notification(&self, _: &Notification) -> ApiResult<RouterResponse> {
+            Ok(RouterResponse {
+                status: StatusCode::OK,
+                headers: HashMap::new(),
+                body: Some(self.0.to_string()),
+            })
+        }
+
+        fn max_data_bytes(&self) -> usize {
+            100
+        }
+    }
+
+    /// A router which stores every notification
+    struct StoringRouter;
+
+    #[async_trait(?Send)]
+    impl Router for StoringRouter {
+        async fn route_notification(&self, _: &Notification) -> ApiResult<RouterResponse> {
+            Ok(RouterResponse {
+                status: StatusCode::ACCEPTED,
+                headers: HashMap::new(),
+                body: None,
+            })
+        }
+    }
+
+    /// A router whose registrations can't carry data
+    struct DatalessRouter;
+
+    #[async_trait(?Send)]
+    impl Router for DatalessRouter {
+        async fn route_notification(&self, _: &Notification) -> ApiResult<RouterResponse> {
+            Ok(RouterResponse {
+                status: StatusCode::CREATED,
+                headers: HashMap::new(),
+                body: None,
+            })
+        }
+
+        fn supports_data(&self) -> bool {
+            false
+        }
+    }
+
+    /// A router which fails the test if it is asked to route a notification
+    struct NoRouteRouter;
+
+    #[async_trait(?Send)]
+    impl Router for NoRouteRouter {
+        async fn route_notification(&self, _: &Notification) -> ApiResult<RouterResponse> {
+            panic!("Dry runs must not route notifications");
+        }
+
+        fn max_data_bytes(&self) -> usize {
+            100
+        }
+    }
+
+    /// Create a dispatcher without any routers
+    fn make_dispatch() -> RouterDispatch {
+        RouterDispatch::new(StatsdClient::from_sink("autoendpoint", NopMetricSink))
+    }
+
+    /// Create a notification for a user of the router type
+    fn make_notification(router_type: RouterType) -> Notification {
+        NotificationBuilder::new()
+            .router_type(router_type)
+            .message_id("test-message-id")
+            .ttl(60)
+            .build()
+            .unwrap()
+    }
+
+    /// Each notification is routed by the router for the user's router type
+    #[actix_rt::test]
+    async fn dispatch_to_registered_router() {
+        let router_types = [
+            RouterType::WebPush,
+            RouterType::Fcm,
+            RouterType::Apns,
+            RouterType::Adm,
+        ];
+        let mut dispatch = make_dispatch();
+        for &router_type in router_types.iter() {
+            dispatch.register(router_type, Box::new(StubRouter(router_type)));
+        }
+
+        for &router_type in router_types.iter() {
+            let response = dispatch
+                .route(&make_notification(router_type))
+                .await
+                .unwrap();
+            assert_eq!(response.body, Some(router_type.to_string()));
+        }
+    }
+
+    /// Data over the router's limit is rejected with a 413 and an errno
+    #[actix_rt::test]
+    async fn dispatch_payload_too_large() {
+        let mut dispatch = make_dispatch();
+        dispatch.register(
+            RouterType::WebPush,
+            Box::new(StubRouter(RouterType::WebPush)),
+        );
+        let mut notification = make_notification(RouterType::WebPush);
+        notification.data = Some(base64::encode_config(
+            &[0; 101][..],
+            base64::URL_SAFE_NO_PAD,
+        ));
+
+        let error = dispatch.route(&notification).await.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = serde_json::to_value(&error).unwrap();
+        assert_eq!(body["code"], 413);
+        assert_eq!(body["errno"], 104);
+        assert_eq!(
+            body["message"],
+            "Data payload must be smaller than 100 bytes for the webpush router"
+        );
+
+        // Data at the limit is accepted
+        notification.data = Some(base64::encode_config(
+            &[0; 100][..],
+            base64::URL_SAFE_NO_PAD,
+        ));
+        assert!(dispatch.route(&notification).await.is_ok());
+    }
+
+    /// Routers which support data accept notifications with data
+    #[actix_rt::test]
+    async fn dispatch_data_to_data_router() {
+        let mut dispatch = make_dispatch();
+        dispatch.register(
+            RouterType::WebPush,
+            Box::new(StubRouter(RouterType::WebPush)),
+        );
+        let mut notification = make_notification(RouterType::WebPush);
+        notification.data = Some("dGVzdC1kYXRh".to_string());
+
+        assert!(dispatch.route(&notification).await.is_ok());
+    }
+
+    /// Notifications with data are rejected with a 400 and an errno if the
+    /// router can't carry data, but notifications without data are routed
+    #[actix_rt::test]
+    async fn dispatch_data_to_dataless_router() {
+        let mut dispatch = make_dispatch();
+        dispatch.register(RouterType::Fcm, Box::new(DatalessRouter));
+        let mut notification = make_notification(RouterType::Fcm);
+        notification.data = Some("dGVzdC1kYXRh".to_string());
+
+        let error = dispatch.route(&notification).await.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+        let body = serde_json::to_value(&error).unwrap();
+        assert_eq!(body["errno"], 121);
+        assert_eq!(
+            body["message"],
+            "The fcm router does not support data payloads for this registration"
+        );
+
+        notification.data = None;
+        let response = dispatch.route(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+    }
+
+    /// Get the route latency metric, checking that it was recorded once
+    fn route_latency(metrics: &TestMetricSink) -> String {
+        let latencies: Vec<_> = metrics
+            .metrics()
+            .into_iter()
+            .filter(|metric| metric.starts_with("autoendpoint.notification.route.latency:"))
+            .collect();
+        assert_eq!(
+            latencies.len(),
+            1,
+            "Expected one latency, got {:?}",
+            latencies
+        );
+        assert!(latencies[0].contains("|ms"));
+
+        latencies[0].clone()
+    }
+
+    /// The route latency is tagged with the router type and whether the
+    /// notification was delivered or stored
+    #[actix_rt::test]
+    async fn route_latency_success() {
+        let metrics = TestMetricSink::default();
+        let mut dispatch = RouterDispatch::new(metrics.client());
+        dispatch.register(RouterType::Fcm, Box::new(StubRouter(RouterType::Fcm)));
+        dispatch.register(RouterType::WebPush, Box::new(StoringRouter));
+
+        dispatch
+            .route(&make_notification(RouterType::Fcm))
+            .await
+            .unwrap();
+        let latency = route_latency(&metrics);
+        assert!(latency.contains("router_type:fcm"));
+        assert!(latency.contains("outcome:delivered"));
+
+        let metrics = TestMetricSink::default();
+        dispatch.metrics = metrics.client();
+        dispatch
+            .route(&make_notification(RouterType::WebPush))
+            .await
+            .unwrap();
+        let latency = route_latency(&metrics);
+        assert!(latency.contains("router_type:webpush"));
+        assert!(latency.contains("outcome:stored"));
+    }
+
+    /// The route latency is recorded when routing fails
+    #[actix_rt::test]
+    async fn route_latency_error() {
+        let metrics = TestMetricSink::default();
+        let dispatch = RouterDispatch::new(metrics.client());
+
+        dispatch
+            .route(&make_notification(RouterType::Apns))
+            .await
+            .unwrap_err();
+        let latency = route_latency(&metrics);
+        assert!(latency.contains("router_type:apns"));
+        assert!(latency.contains("outcome:error"));
+    }
+
+    /// A dry run reports what would happen without routing the notification
+    #[test]
+    fn dry_run_skips_routing() {
+        let mut dispatch = make_dispatch();
+        dispatch.register(RouterType::Fcm, Box::new(NoRouteRouter));
+        let mut notification = make_notification(RouterType::Fcm);
+        notification.data = Some(base64::encode_config(&[0; 30][..], base64::URL_SAFE_NO_PAD));
+
+        let report = dispatch.dry_run(&notification).unwrap();
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "router_type": "fcm",
+                "destination": "bridge",
+                "channel_id": notification.subscription.channel_id,
+                "ttl": 60,
+                "topic": null,
+                "urgency": "normal",
+                "data_bytes": 30,
+                "vapid": false
+            })
+        );
+    }
+
+    /// A dry run of a WebPush notification reports if it would be sent to the
+    /// user's node, stored, or dropped
+    #[test]
+    fn dry_run_webpush_destination() {
+        let mut dispatch = make_dispatch();
+        dispatch.register(RouterType::WebPush, Box::new(NoRouteRouter));
+        let mut notification = make_notification(RouterType::WebPush);
+
+        let report = dispatch.dry_run(&notification).unwrap();
+        assert_eq!(report.destination, "storage");
+        assert_eq!(report.ttl, 60);
+
+        notification.headers.ttl = Some(0);
+        let report = dispatch.dry_run(&notification).unwrap();
+        assert_eq!(report.destination, "dropped");
+
+        notification.subscription.user.node_id = Some("http://node:8081".to_string());
+        let report = dispatch.dry_run(&notification).unwrap();
+        assert_eq!(report.destination, "node");
+    }
+
+    /// A dry run reports the errors routing would return
+    #[test]
+    fn dry_run_errors() {
+        let mut dispatch = make_dispatch();
+        dispatch.register(RouterType::WebPush, Box::new(NoRouteRouter));
+        let mut notification = make_notification(RouterType::WebPush);
+        notification.data = Some(base64::encode_config(
+            &[0; 101][..],
+            base64::URL_SAFE_NO_PAD,
+        ));
+
+        let error = dispatch.dry_run(&notification).unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let error = dispatch
+            .dry_run(&make_notification(RouterType::Adm))
+            .unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// Database errors are reported in the standard error format
+    #[test]
+    fn save_db_error_body() {
+        let error = ApiError::from(RouterError::SaveDb {
+            source: "test-error".into(),
+            retry_after: Duration::from_secs(10).into(),
+        });
+
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "code": 503,
+                "errno": 201,
+                "error": "Service Unavailable",
+                "message": "Database error while saving notification",
+                "more_info": DEFAULT_MORE_INFO_URL
+            })
+        );
+    }
+
+    /// Deleted users are reported in the standard error format
+    #[test]
+    fn user_was_deleted_error_body() {
+        let error = ApiError::from(RouterError::UserWasDeleted);
+
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "code": 410,
+                "errno": 105,
+                "error": "Gone",
+                "message": "User was deleted during routing",
+                "more_info": DEFAULT_MORE_INFO_URL
+            })
+        );
+    }
+
+    /// Database errors tell the client when to retry
+    #[test]
+    fn save_db_retry_after() {
+        let error = ApiError::from(RouterError::SaveDb {
+            source: "test-error".into(),
+            retry_after: Duration::from_secs(30).into(),
+        });
+
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "30");
+    }
+
+    /// Rate limited users have their own errno, so senders can tell the
+    /// per-user limit apart from their own message quota
+    #[test]
+    fn user_rate_limited_errno() {
+        let error = ApiError::from(RouterError::UserRateLimited {
+            retry_after: Duration::from_secs(10).into(),
+        });
+        let sender_error = ApiError::from(ApiErrorKind::TooManyMessages(
+            Duration::from_secs(10).into(),
+        ));
+
+        assert_eq!(error.kind.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.kind.errno(), Some(122));
+        assert_ne!(error.kind.errno(), sender_error.kind.errno());
+    }
+
+    /// Router types are parsed from the user record, and unknown types are
+    /// rejected
+    #[test]
+    fn parse_router_type() {
+        for &router_type in [
+            RouterType::WebPush,
+            RouterType::Fcm,
+            RouterType::Apns,
+            RouterType::Adm,
+        ]
+        .iter()
+        {
+            assert_eq!(router_type.to_string().parse(), Ok(router_type));
+        }
+
+        assert_eq!("unknown".parse::<RouterType>(), Err(()));
+    }
+
+    /// A router type without a registered router is a server error
+    #[actix_rt::test]
+    async fn dispatch_to_unregistered_router() {
+        let mut dispatch = make_dispatch();
+        dispatch.register(
+            RouterType::WebPush,
+            Box::new(StubRouter(RouterType::WebPush)),
+        );
+
+        let result = dispatch.route(&make_notification(RouterType::Adm)).await;
+        match result.unwrap_err().kind {
+            ApiErrorKind::Router(error) => {
+                assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+                assert_eq!(
+                    error.to_string(),
+                    RouterError::NotConfigured(RouterType::Adm).to_string()
+                );
+            }
+            kind => panic!("Expected a router error, got {:?}", kind),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Classifying the errors of requests to connection nodes
+
+use std::error::Error;
+use std::io;
+
+/// The category of a failed request to a connection node
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NodeErrorKind {
+    /// The node did not respond in time
+    Timeout,
+    /// The node refused the connection
+    ConnectionRefused,
+    /// The node's host name could not be resolved
+    Dns,
+    /// The connection failed or was closed before the node responded
+    Connection,
+    /// The request or response body could not be sent or read
+    Body,
+    /// The request could not be built, or the response was unexpected
+    Other,
+}
+
+impl NodeErrorKind {
+    /// Classify a failed request to a node
+    pub fn from_error(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            return NodeErrorKind::Timeout;
+        }
+        if error.is_body() || error.is_decode() {
+            return NodeErrorKind::Body;
+        }
+        if error.is_builder() || error.is_redirect() || error.is_status() {
+            return NodeErrorKind::Other;
+        }
+
+        // The remaining errors happened while sending the request. Look for
+        // the cause in the errors which reqwest and hyper wrap.
+        let mut source = error.source();
+        while let Some(cause) = source {
+            if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+                if io_error.kind() == io::ErrorKind::ConnectionRefused {
+                    return NodeErrorKind::ConnectionRefused;
+                }
+            }
+            if cause.to_string().starts_with("dns error") {
+                return NodeErrorKind::Dns;
+            }
+
+            source = cause.source();
+        }
+
+        NodeErrorKind::Connection
+    }
+
+    /// Check if the node can't be reached, meaning it should no longer be
+    /// used for the user
+    pub fn is_unreachable(self) -> bool {
+        match self {
+            NodeErrorKind::Timeout
+            | NodeErrorKind::ConnectionRefused
+            | NodeErrorKind::Dns
+            | NodeErrorKind::Connection => true,
+            NodeErrorKind::Body | NodeErrorKind::Other => false,
+        }
+    }
+
+    /// The category used to tag metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeErrorKind::Timeout => "timeout",
+            NodeErrorKind::ConnectionRefused => "connection_refused",
+            NodeErrorKind::Dns => "dns",
+            NodeErrorKind::Connection => "connection",
+            NodeErrorKind::Body => "body",
+            NodeErrorKind::Other => "other",
+        }
+    }
+}
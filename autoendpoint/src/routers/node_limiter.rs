@@ -0,0 +1,220 @@
+//! Limiting how many notifications are sent to each node at the same time, so
+//! a node with many connected users is not overwhelmed
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Limits the concurrent sends to each node
+pub struct NodeSendLimiter {
+    /// The max concurrent sends to each node. Zero disables the limit.
+    permits: usize,
+    /// How long to wait for a permit before giving up
+    wait_timeout: Duration,
+    /// The permits for each node. Nodes without an entry have no sends in
+    /// flight or waiting for a permit.
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// Allows a send to a node until it is dropped
+pub struct SendPermit<'a> {
+    limiter: &'a NodeSendLimiter,
+    /// `None` if the limit is disabled
+    node: Option<(String, Arc<Semaphore>)>,
+}
+
+impl Drop for SendPermit<'_> {
+    fn drop(&mut self) {
+        if let Some((node_id, semaphore)) = self.node.take() {
+            semaphore.add_permits(1);
+            self.limiter.remove_if_idle(&node_id, semaphore);
+        }
+    }
+}
+
+impl NodeSendLimiter {
+    /// Create a new `NodeSendLimiter`
+    pub fn new(permits: usize, wait_timeout: Duration) -> Self {
+        NodeSendLimiter {
+            permits,
+            wait_timeout,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a permit to send to the node, if fewer than the max sends are in
+    /// flight
+    pub fn try_acquire(&self, node_id: &str) -> Option<SendPermit<'_>> {
+        if self.permits == 0 {
+            return Some(self.disabled_permit());
+        }
+
+        let semaphore = self.semaphore(node_id);
+        let acquired = match semaphore.try_acquire() {
+            Ok(permit) => {
+                // The permit is given back when the `SendPermit` is dropped
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        };
+
+        self.finish_acquire(node_id, semaphore, acquired)
+    }
+
+    /// Take a permit to send to the node, waiting up to the wait timeout for
+    /// another send to finish. Returns `None` if no permit became available.
+    pub async fn acquire(&self, node_id: &str) -> Option<SendPermit<'_>> {
+        if self.permits == 0 {
+            return Some(self.disabled_permit());
+        }
+
+        let semaphore = self.semaphore(node_id);
+        let acquired = match tokio::time::timeout(self.wait_timeout, semaphore.acquire()).await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        };
+
+        self.finish_acquire(node_id, semaphore, acquired)
+    }
+
+    /// Get the node's semaphore, creating it if the node has none
+    fn semaphore(&self, node_id: &str) -> Arc<Semaphore> {
+        self.semaphores
+            .lock()
+            .unwrap()
+            .entry(node_id.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.permits)))
+            .clone()
+    }
+
+    /// Remove the node's semaphore if no other sends are in flight or
+    /// waiting for a permit. They are only given the semaphore while the map
+    /// is locked, so only the map and the caller hold it when the node is
+    /// idle.
+    fn remove_if_idle(&self, node_id: &str, semaphore: Arc<Semaphore>) {
+        let mut semaphores = self.semaphores.lock().unwrap();
+
+        if Arc::strong_count(&semaphore) == 2 {
+            semaphores.remove(node_id);
+        }
+    }
+
+    /// Wrap a permit taken from the node's semaphore, so it is given back
+    /// when dropped. If no permit was taken, the semaphore is cleaned up.
+    fn finish_acquire(
+        &self,
+        node_id: &str,
+        semaphore: Arc<Semaphore>,
+        acquired: bool,
+    ) -> Option<SendPermit<'_>> {
+        if !acquired {
+            self.remove_if_idle(node_id, semaphore);
+            return None;
+        }
+
+        Some(SendPermit {
+            limiter: self,
+            node: Some((node_id.to_string(), semaphore)),
+        })
+    }
+
+    /// Create a permit for when the limit is disabled
+    fn disabled_permit(&self) -> SendPermit<'_> {
+        SendPermit {
+            limiter: self,
+            node: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodeSendLimiter;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const NODE_ID: &str = "http://node1:8081";
+
+    /// Permits are limited per node, and released when dropped
+    #[test]
+    fn limit_per_node() {
+        let limiter = NodeSendLimiter::new(2, Duration::from_millis(0));
+
+        let first = limiter.try_acquire(NODE_ID);
+        let second = limiter.try_acquire(NODE_ID);
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(limiter.try_acquire(NODE_ID).is_none());
+        assert!(limiter.try_acquire("http://node2:8081").is_some());
+
+        drop(first);
+        assert!(limiter.try_acquire(NODE_ID).is_some());
+    }
+
+    /// A limit of zero disables the limit
+    #[test]
+    fn disabled() {
+        let limiter = NodeSendLimiter::new(0, Duration::from_millis(0));
+        let permits: Vec<_> = (0..10).map(|_| limiter.try_acquire(NODE_ID)).collect();
+
+        assert!(permits.iter().all(Option::is_some));
+    }
+
+    /// Concurrent sends never exceed the limit, and queued sends get a permit
+    /// once a send finishes
+    #[actix_rt::test]
+    async fn concurrent_sends() {
+        let limiter = Arc::new(NodeSendLimiter::new(2, Duration::from_secs(5)));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(Mutex::new(0));
+
+        let sends = (0..6).map(|_| {
+            let limiter = limiter.clone();
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+
+            async move {
+                let _permit = limiter.acquire(NODE_ID).await.unwrap();
+                let count = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                {
+                    let mut max_in_flight = max_in_flight.lock().unwrap();
+                    *max_in_flight = (*max_in_flight).max(count);
+                }
+                actix_rt::time::delay_for(Duration::from_millis(20)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        futures::future::join_all(sends).await;
+
+        assert_eq!(*max_in_flight.lock().unwrap(), 2);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    /// Waiting for a permit gives up after the wait timeout
+    #[actix_rt::test]
+    async fn wait_timeout() {
+        let limiter = NodeSendLimiter::new(1, Duration::from_millis(20));
+        let _permit = limiter.try_acquire(NODE_ID).unwrap();
+
+        assert!(limiter.acquire(NODE_ID).await.is_none());
+    }
+
+    /// A node's semaphore is removed once no sends are in flight or waiting
+    #[actix_rt::test]
+    async fn idle_nodes_removed() {
+        let limiter = NodeSendLimiter::new(1, Duration::from_millis(20));
+        let permit = limiter.try_acquire(NODE_ID).unwrap();
+        assert!(limiter.try_acquire(NODE_ID).is_none());
+        assert!(limiter.acquire(NODE_ID).await.is_none());
+        assert_eq!(limiter.semaphores.lock().unwrap().len(), 1);
+
+        drop(permit);
+        assert!(limiter.semaphores.lock().unwrap().is_empty());
+    }
+}
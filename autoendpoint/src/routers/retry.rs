@@ -0,0 +1,66 @@
+//! Retrying failed requests with exponential backoff
+
+use rand::Rng;
+use std::time::Duration;
+
+/// How failed requests are retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How many times a failed request is retried
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled after each retry
+    pub base_delay: Duration,
+    /// The max random delay added to each retry, so requests which failed at
+    /// the same time are not retried at the same time
+    pub max_jitter: Duration,
+}
+
+impl RetryPolicy {
+    /// Get the delay before a retry. The first retry is retry 0.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let max_jitter = self.max_jitter.as_millis() as u64;
+        let jitter = if max_jitter == 0 {
+            0
+        } else {
+            rand::thread_rng().gen_range(0, max_jitter + 1)
+        };
+
+        self.base_delay * 2u32.pow(retry) + Duration::from_millis(jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::time::Duration;
+
+    /// The delay doubles after each retry
+    #[test]
+    fn exponential_delay() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(50),
+            max_jitter: Duration::from_millis(0),
+        };
+
+        assert_eq!(policy.delay(0), Duration::from_millis(50));
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+    }
+
+    /// Jitter adds up to the max jitter to the delay
+    #[test]
+    fn delay_with_jitter() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(50),
+            max_jitter: Duration::from_millis(10),
+        };
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(110));
+        }
+    }
+}
@@ -0,0 +1,3124 @@
+use crate::db::DbClient;
+use crate::error::{ApiError, ApiErrorKind, ApiResult, Backoff};
+use crate::idempotency::IdempotencyCache;
+use crate::logging;
+use crate::metrics::TimerGuard;
+use crate::rate_limit::UaidRateLimiter;
+use crate::routers::circuit_breaker::CircuitBreaker;
+use crate::routers::node_error::NodeErrorKind;
+use crate::routers::node_limiter::NodeSendLimiter;
+use crate::routers::retry::RetryPolicy;
+use crate::routers::{Router, RouterError, RouterResponse};
+use crate::server::extractors::notification::{Notification, NotificationBody};
+use crate::server::extractors::subscription::Subscription;
+use crate::server::request_id::REQUEST_ID_HEADER;
+use crate::settings::is_allowed_host;
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use autopush_common::db::{DynamoDbBroadcast, DynamoDbUser};
+use cadence::{Counted, Histogrammed, StatsdClient};
+use fernet::MultiFernet;
+use futures::{stream, StreamExt};
+use reqwest::{RequestBuilder, Response};
+use slog::{slog_debug, slog_trace};
+use std::cmp::max;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+use uuid::Uuid;
+
+/// The router for desktop user agents.
+///
+/// These agents are connected via an Autopush connection server. The correct
+/// server is located via the database routing table. If the server is busy or
+/// not available, the notification is stored in the database.
+pub struct WebPushRouter {
+    pub ddb: Arc<dyn DbClient>,
+    pub metrics: StatsdClient,
+    pub http: reqwest::Client,
+    pub endpoint_url: Url,
+    /// The endpoint URLs used for the `Location` header of notifications
+    /// sent from each region, so status checks stay in the region
+    pub regional_endpoint_urls: HashMap<String, Url>,
+    pub max_data_bytes: usize,
+    /// How requests to a node are retried after a connection error
+    pub retry_policy: RetryPolicy,
+    /// How long to wait for a node to respond to a request
+    pub node_request_timeout: Duration,
+    /// How many notifications of a broadcast are routed, or nodes told about
+    /// a broadcast change, at the same time
+    pub broadcast_concurrency: usize,
+    /// Encrypts the message IDs of broadcast notifications
+    pub fernet: Arc<MultiFernet>,
+    /// Stops requests to nodes which keep failing
+    pub breaker: CircuitBreaker,
+    /// Limits how many notifications are sent to each node at the same time
+    pub node_limiter: NodeSendLimiter,
+    /// The hosts notifications may be sent to as nodes. Empty allows any
+    /// host.
+    pub node_allowed_hosts: Vec<String>,
+    /// How long clients should wait before retrying if the notification
+    /// could not be stored
+    pub db_retry_after: Duration,
+    /// The max random jitter added to `db_retry_after`
+    pub db_retry_jitter: Duration,
+    /// Limits how many notifications are routed to each user
+    pub uaid_limiter: Arc<UaidRateLimiter>,
+    /// The max random jitter added to the wait of rate limited users
+    pub uaid_retry_jitter: Duration,
+    /// Stops retries with the same idempotency key from being stored twice
+    pub idempotency: Arc<IdempotencyCache>,
+    /// How many non-topic messages may be stored for each channel. Zero
+    /// allows any number.
+    pub max_channel_messages: usize,
+    /// Whether the oldest messages of a full channel are removed to make
+    /// room, instead of rejecting new messages
+    pub evict_channel_messages: bool,
+}
+
+#[async_trait(?Send)]
+impl Router for WebPushRouter {
+    async fn route_notification(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        // Time the whole route, including when the request is cancelled
+        let mut timer = TimerGuard::start(&self.metrics, "notification.route.time");
+        let result = self.route(notification).await;
+
+        match &result {
+            Ok(response) => {
+                timer.tag("destination", Self::destination_tag(response));
+                timer.tag("outcome", "success");
+            }
+            Err(_) => timer.tag("outcome", "error"),
+        }
+
+        result
+    }
+
+    /// Send the notification to the node the user is connected to, without
+    /// storing it if the user is not connected or the node is busy. The
+    /// user's rate limit and the sender's preference for an asynchronous
+    /// response apply as they do for `route_notification`.
+    async fn route_direct(&self, notification: &Notification) -> ApiResult<Option<RouterResponse>> {
+        self.check_uaid_limit(notification)?;
+
+        if notification.headers.respond_async && !Self::is_deliver_now(notification) {
+            return self.route_async(notification).await.map(Some);
+        }
+
+        self.try_deliver(notification).await
+    }
+
+    /// Store the notification if the fallback bridge failed, so the user
+    /// receives it when they reconnect. If the bridge no longer knows about
+    /// the user, notifications are no longer sent to it. The user's WebPush
+    /// registration is kept.
+    async fn route_fallback_failed(
+        &self,
+        notification: &Notification,
+        error: ApiError,
+    ) -> ApiResult<RouterResponse> {
+        if let ApiErrorKind::Router(RouterError::NotRegistered { .. }) = &error.kind {
+            slog_debug!(
+                notification.logger(),
+                "Removing the user's invalid fallback router"
+            );
+            self.ddb
+                .remove_fallback_router(&notification.subscription.user.uaid)
+                .await
+                .map_err(ApiErrorKind::Database)?;
+        } else {
+            slog_debug!(
+                notification.logger(),
+                "Fallback router failed, storing notification: {}",
+                error
+            );
+        }
+
+        self.store_or_drop(notification).await
+    }
+
+    /// WebPush is healthy if the database can be reached, because
+    /// notifications are stored there
+    async fn health_check(&self) -> ApiResult<()> {
+        self.ddb
+            .health_check()
+            .await
+            .map_err(|e| ApiErrorKind::Database(e).into())
+    }
+
+    fn max_data_bytes(&self) -> usize {
+        self.max_data_bytes
+    }
+}
+
+/// What happened to a broadcast notification for a single subscription
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BroadcastOutcome {
+    Delivered,
+    Stored,
+    /// The user or subscription no longer exists
+    Gone,
+    Failed,
+}
+
+/// The outcome of a broadcast for each subscription
+#[derive(Debug, Default)]
+pub struct BroadcastSummary {
+    /// The outcomes by channel ID
+    pub outcomes: HashMap<Uuid, BroadcastOutcome>,
+}
+
+impl BroadcastSummary {
+    /// Count the subscriptions with the outcome
+    pub fn count(&self, outcome: BroadcastOutcome) -> usize {
+        self.outcomes
+            .values()
+            .filter(|&&other| other == outcome)
+            .count()
+    }
+}
+
+impl WebPushRouter {
+    /// Route the same notification to many subscriptions. The notifications
+    /// for each user are routed as a batch, and users are routed
+    /// concurrently, up to `broadcast_concurrency` at a time.
+    pub async fn route_broadcast(
+        &self,
+        subscriptions: &[Subscription],
+        notification: &NotificationBody,
+    ) -> BroadcastSummary {
+        let mut user_notifications: HashMap<Uuid, Vec<Notification>> = HashMap::new();
+        for subscription in subscriptions {
+            user_notifications
+                .entry(subscription.user.uaid)
+                .or_default()
+                .push(notification.for_subscription(subscription.clone(), &self.fernet));
+        }
+
+        let user_outcomes: Vec<Vec<_>> = stream::iter(user_notifications.values())
+            .map(|notifications| async move {
+                let results = self.route_batch(notifications).await;
+                notifications
+                    .iter()
+                    .zip(results)
+                    .map(|(notification, result)| {
+                        (
+                            notification.subscription.channel_id,
+                            Self::broadcast_outcome(result),
+                        )
+                    })
+                    .collect()
+            })
+            .buffer_unordered(max(self.broadcast_concurrency, 1))
+            .collect()
+            .await;
+
+        BroadcastSummary {
+            outcomes: user_outcomes.into_iter().flatten().collect(),
+        }
+    }
+
+    /// Get what happened to a broadcast notification from its routing result
+    fn broadcast_outcome(result: ApiResult<RouterResponse>) -> BroadcastOutcome {
+        match result {
+            Ok(response) if response.status == StatusCode::CREATED => BroadcastOutcome::Delivered,
+            Ok(_) => BroadcastOutcome::Stored,
+            Err(error) if error.kind.status() == StatusCode::GONE => BroadcastOutcome::Gone,
+            Err(error) => {
+                debug!("Error while routing broadcast notification: {}", error);
+                BroadcastOutcome::Failed
+            }
+        }
+    }
+
+    /// Route several notifications for the same user. If the user's node
+    /// supports batches (the `batch_delivery` item in the user's router data
+    /// is true), the notifications are sent to the node in a single request.
+    /// Otherwise the notifications are routed one at a time.
+    pub async fn route_batch(
+        &self,
+        notifications: &[Notification],
+    ) -> Vec<ApiResult<RouterResponse>> {
+        let user = match notifications.first() {
+            Some(notification) => &notification.subscription.user,
+            None => return Vec::new(),
+        };
+        if notifications.len() < 2 || !Self::supports_batches(user) {
+            return self.route_each(notifications).await;
+        }
+        let node_id = match self.allowed_node_id(user).await {
+            Ok(Some(node_id)) => node_id,
+            _ => return self.route_each(notifications).await,
+        };
+
+        // Each notification counts towards the user's rate limit
+        let limits: Vec<_> = notifications
+            .iter()
+            .map(|notification| self.check_uaid_limit(notification))
+            .collect();
+        let allowed: Vec<_> = notifications
+            .iter()
+            .zip(&limits)
+            .filter(|(_, limit)| limit.is_ok())
+            .map(|(notification, _)| notification.clone())
+            .collect();
+        let mut results = self
+            .deliver_batch(&allowed, user, node_id)
+            .await
+            .into_iter();
+
+        limits
+            .into_iter()
+            .map(|limit| match limit {
+                Ok(()) => results
+                    .next()
+                    .expect("There is a result for each allowed notification"),
+                Err(error) => Err(error),
+            })
+            .collect()
+    }
+
+    /// Route each notification separately
+    async fn route_each(&self, notifications: &[Notification]) -> Vec<ApiResult<RouterResponse>> {
+        let mut results = Vec::with_capacity(notifications.len());
+        for notification in notifications {
+            results.push(self.route_notification(notification).await);
+        }
+
+        results
+    }
+
+    /// Check if the user's node accepts batches of notifications
+    fn supports_batches(user: &DynamoDbUser) -> bool {
+        user.router_data
+            .as_ref()
+            .and_then(|data| data.get("batch_delivery"))
+            .and_then(|value| value.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// Send the notifications to the node in a single request. If the node
+    /// does not accept the batch, the notifications are stored instead of
+    /// trying the node again.
+    async fn deliver_batch(
+        &self,
+        notifications: &[Notification],
+        user: &DynamoDbUser,
+        node_id: &str,
+    ) -> Vec<ApiResult<RouterResponse>> {
+        if notifications.is_empty() {
+            return Vec::new();
+        }
+
+        if let Some(_permit) = self.breaker.allow_request(node_id) {
+            trace!(
+                "Sending {} notifications to node in a batch",
+                notifications.len()
+            );
+            match self.send_batch_limited(notifications, node_id).await {
+                Some(Ok(response)) => {
+                    self.record_node_response(node_id, &response);
+
+                    if response.status() == 200 {
+                        trace!("Node received the batch");
+                        return notifications
+                            .iter()
+                            .map(|notification| self.make_delivered_response(notification))
+                            .collect();
+                    }
+
+                    trace!("Node did not receive the batch, response = {:?}", response);
+                    if let Err(error) = self.handle_node_rejection(user, node_id, &response).await {
+                        debug!("Error while handling a rejected batch: {}", error);
+                    }
+                }
+                Some(Err(error)) => {
+                    debug!("Error while sending webpush batch: {}", error);
+                    if let Err(error) = self.handle_node_error(user, node_id, &error).await {
+                        debug!("Error while removing node ID: {}", error);
+                    }
+                }
+                None => {}
+            }
+        } else {
+            trace!(
+                "Circuit breaker is open for node {}, skipping batch",
+                node_id
+            );
+        }
+
+        let mut results = Vec::with_capacity(notifications.len());
+        for notification in notifications {
+            results.push(self.store_or_drop(notification).await);
+        }
+
+        results
+    }
+
+    /// Route the notification to the user's node, or store it if the user
+    /// is not connected
+    async fn route(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        let user = &notification.subscription.user;
+        let log = notification.logger();
+        slog_debug!(log, "Routing WebPush notification");
+        self.check_uaid_limit(notification)?;
+
+        // The notification must be delivered now or never, so it is not stored
+        if Self::is_deliver_now(notification) {
+            return self.deliver_or_drop(notification).await;
+        }
+
+        if notification.headers.respond_async {
+            return self.route_async(notification).await;
+        }
+
+        // Check if there is a node connected to the client
+        if let Some(node_id) = self.allowed_node_id(user).await? {
+            slog_trace!(log, "User has a node ID, sending notification to node");
+
+            // Try to send the notification to the node, unless it keeps failing
+            // or is too busy
+            let permit = self.breaker.allow_request(node_id);
+            let result = if permit.is_some() {
+                self.send_notification_limited(notification, node_id).await
+            } else {
+                slog_trace!(
+                    log,
+                    "Circuit breaker is open for node {}, skipping send",
+                    node_id
+                );
+                None
+            };
+
+            match result {
+                Some(Ok(response)) => {
+                    self.record_node_response(node_id, &response);
+
+                    // The node might be busy, make sure it accepted the notification
+                    if response.status() == 200 {
+                        // The node has received the notification
+                        slog_trace!(log, "Node received notification");
+                        return self.make_delivered_response(notification);
+                    }
+
+                    slog_trace!(
+                        log,
+                        "Node did not receive the notification, response = {:?}",
+                        response
+                    );
+                    self.handle_node_rejection(user, node_id, &response).await?;
+                }
+                Some(Err(error)) => {
+                    slog_debug!(log, "Error while sending webpush notification: {}", error);
+                    self.handle_node_error(user, node_id, &error).await?
+                }
+                None => {}
+            }
+        }
+
+        // Save notification, node is not present or busy
+        slog_trace!(log, "Node is not present or busy, storing notification");
+        if let Some(response) = self.store_notification_once(notification).await? {
+            return Ok(response);
+        }
+
+        // Retrieve the user data again, they may have reconnected or the node
+        // is no longer busy. The notification has already been stored, so if
+        // the user can no longer be found they were deleted during routing.
+        // If the user can't be read, the notification is still stored and is
+        // delivered when the user next connects.
+        slog_trace!(log, "Re-fetching user to trigger notification check");
+        let user = match self.ddb.get_user(&user.uaid).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(RouterError::UserWasDeleted.into()),
+            Err(error) => {
+                slog_debug!(log, "Unable to re-fetch the user: {}", error);
+                return self.make_stored_response(notification);
+            }
+        };
+
+        // Try to notify the node the user is currently connected to
+        let node_id = match self.allowed_node_id(&user).await? {
+            Some(id) => id,
+            // The user is not connected to a node, nothing more to do
+            None => return self.make_stored_response(notification),
+        };
+
+        let _permit = match self.breaker.allow_request(node_id) {
+            Some(permit) => permit,
+            None => {
+                slog_trace!(
+                    log,
+                    "Circuit breaker is open for node {}, skipping check",
+                    node_id
+                );
+                return self.make_stored_response(notification);
+            }
+        };
+
+        // Notify the node to check for messages
+        slog_trace!(log, "Notifying node to check for messages");
+        match self
+            .trigger_notification_check(&user.uaid, &node_id, notification.request_id.as_deref())
+            .await
+        {
+            Ok(response) => {
+                slog_trace!(log, "Response = {:?}", response);
+                self.record_node_response(node_id, &response);
+                if response.status() == 200 {
+                    slog_trace!(log, "Node has delivered the message");
+                    self.make_delivered_response(notification)
+                } else {
+                    slog_trace!(
+                        log,
+                        "Node has not delivered the message, returning stored response"
+                    );
+                    self.make_stored_response(notification)
+                }
+            }
+            Err(error) => {
+                slog_debug!(log, "Error while triggering notification check: {}", error);
+                self.handle_node_error(&user, node_id, &error).await?;
+                self.make_stored_response(notification)
+            }
+        }
+    }
+
+    /// Protect the node and database from users receiving too many
+    /// notifications
+    fn check_uaid_limit(&self, notification: &Notification) -> ApiResult<()> {
+        let uaid = &notification.subscription.user.uaid;
+        if let Err(retry_after) = self.uaid_limiter.try_acquire(uaid) {
+            slog_debug!(
+                notification.logger(),
+                "Rate limiting notifications to the user"
+            );
+            return Err(RouterError::UserRateLimited {
+                retry_after: Backoff::with_jitter(retry_after, self.uaid_retry_jitter),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// The sender doesn't want to wait for delivery, so store the
+    /// notification and let the node know about it in the background
+    async fn route_async(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        let user = &notification.subscription.user;
+        slog_trace!(
+            notification.logger(),
+            "Sender prefers an asynchronous response, storing notification"
+        );
+        if let Some(response) = self.store_notification_once(notification).await? {
+            return Ok(response);
+        }
+
+        if let Some(node_id) = self.allowed_node_id(user).await? {
+            self.spawn_notification_check(&user.uaid, node_id, notification.request_id.as_deref());
+        }
+
+        self.make_stored_response(notification)
+    }
+
+    /// Store the notification for the user to receive when they reconnect,
+    /// or drop it if it must be delivered now
+    async fn store_or_drop(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        if Self::is_deliver_now(notification) {
+            return self.make_dropped_response(notification);
+        }
+
+        if let Some(response) = self.store_notification_once(notification).await? {
+            return Ok(response);
+        }
+        self.make_stored_response(notification)
+    }
+
+    /// Check if the notification has a TTL of 0, meaning it should be
+    /// delivered immediately or discarded (RFC 8030 section 5.2)
+    fn is_deliver_now(notification: &Notification) -> bool {
+        notification.headers.ttl.unwrap_or(0) == 0
+    }
+
+    /// Send the notification to the node the user is connected to, dropping
+    /// it if the user is not connected or the node does not accept it
+    async fn deliver_or_drop(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        match self.try_deliver(notification).await? {
+            Some(response) => Ok(response),
+            None => {
+                slog_trace!(
+                    notification.logger(),
+                    "Dropping notification with a TTL of 0"
+                );
+                self.make_dropped_response(notification)
+            }
+        }
+    }
+
+    /// Send the notification to the node the user is connected to. Returns
+    /// `None` if the user is not connected or the node does not accept it.
+    async fn try_deliver(&self, notification: &Notification) -> ApiResult<Option<RouterResponse>> {
+        let user = &notification.subscription.user;
+        let log = notification.logger();
+        let node_id = match self.allowed_node_id(user).await? {
+            Some(node_id) => node_id,
+            None => {
+                slog_trace!(log, "User is not connected to a node");
+                return Ok(None);
+            }
+        };
+        let _permit = match self.breaker.allow_request(node_id) {
+            Some(permit) => permit,
+            None => {
+                slog_trace!(
+                    log,
+                    "Circuit breaker is open for node {}, skipping send",
+                    node_id
+                );
+                return Ok(None);
+            }
+        };
+
+        let result = match self.send_notification_limited(notification, node_id).await {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        match result {
+            Ok(response) => {
+                self.record_node_response(node_id, &response);
+
+                if response.status() == 200 {
+                    slog_trace!(log, "Node received notification");
+                    return self.make_delivered_response(notification).map(Some);
+                }
+
+                self.handle_node_rejection(user, node_id, &response).await?;
+            }
+            Err(error) => {
+                slog_debug!(log, "Error while sending webpush notification: {}", error);
+                self.handle_node_error(user, node_id, &error).await?
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Get the user's node ID, if notifications may be sent to the node. A
+    /// node ID which isn't allowed, such as a corrupted record pointing to an
+    /// external host, is removed as if the node were gone.
+    async fn allowed_node_id<'a>(&self, user: &'a DynamoDbUser) -> ApiResult<Option<&'a String>> {
+        let node_id = match &user.node_id {
+            Some(node_id) => node_id,
+            None => return Ok(None),
+        };
+
+        if self.is_allowed_node(node_id) {
+            return Ok(Some(node_id));
+        }
+
+        warn!(
+            "Node ID is not allowed, removing it";
+            "uaid" => logging::log_uaid(&user.uaid), "node_id" => node_id
+        );
+        self.metrics.incr("notification.node.invalid").ok();
+        self.remove_node_id(user, node_id.clone()).await?;
+        Ok(None)
+    }
+
+    /// Check if the node ID is an HTTP(S) URL on an allowed host
+    fn is_allowed_node(&self, node_id: &str) -> bool {
+        let url = match Url::parse(node_id) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return false,
+        };
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return false;
+        }
+
+        self.node_allowed_hosts.is_empty() || is_allowed_host(host, &self.node_allowed_hosts)
+    }
+
+    /// Send the notification to the node
+    async fn send_notification(
+        &self,
+        notification: &Notification,
+        node_id: &str,
+    ) -> Result<Response, reqwest::Error> {
+        let mut timer = TimerGuard::start(&self.metrics, "notification.node.send.time");
+        let url = format!("{}/push/{}", node_id, notification.subscription.user.uaid);
+        let request_id = notification.request_id.as_deref();
+        let notification = notification.serialize_for_delivery();
+
+        let result = self
+            .send_with_retry("push", || {
+                let request = self
+                    .http
+                    .put(&url)
+                    .json(&notification)
+                    .timeout(self.node_request_timeout);
+                Self::with_request_id(request, request_id)
+            })
+            .await;
+
+        let outcome = match &result {
+            Ok(response) if response.status() == 200 => "success",
+            // The node is busy
+            Ok(_) => "rejected",
+            Err(_) => "error",
+        };
+        timer.tag("outcome", outcome);
+
+        result
+    }
+
+    /// Send the notification to the node, unless too many notifications are
+    /// already being sent to it. If no permit is available within the wait
+    /// timeout, the send is shed and `None` is returned.
+    async fn send_notification_limited(
+        &self,
+        notification: &Notification,
+        node_id: &str,
+    ) -> Option<Result<Response, reqwest::Error>> {
+        let _permit = match self.node_limiter.acquire(node_id).await {
+            Some(permit) => permit,
+            None => {
+                debug!("Node {} is too busy, skipping send", node_id);
+                self.metrics.incr("notification.node.shed").ok();
+                return None;
+            }
+        };
+
+        Some(self.send_notification(notification, node_id).await)
+    }
+
+    /// Send several notifications for the same user to the node as a JSON
+    /// array, unless too many notifications are already being sent to it
+    async fn send_batch_limited(
+        &self,
+        notifications: &[Notification],
+        node_id: &str,
+    ) -> Option<Result<Response, reqwest::Error>> {
+        let _permit = match self.node_limiter.acquire(node_id).await {
+            Some(permit) => permit,
+            None => {
+                debug!("Node {} is too busy, skipping batch", node_id);
+                self.metrics.incr("notification.node.shed").ok();
+                return None;
+            }
+        };
+
+        let url = format!(
+            "{}/push/{}",
+            node_id, notifications[0].subscription.user.uaid
+        );
+        let batch: Vec<_> = notifications
+            .iter()
+            .map(Notification::serialize_for_delivery)
+            .collect();
+
+        // The batch is sent in one request, so it is correlated with the
+        // first notification's request
+        let request_id = notifications[0].request_id.as_deref();
+
+        let result = self
+            .send_with_retry("push", || {
+                let request = self
+                    .http
+                    .put(&url)
+                    .json(&batch)
+                    .timeout(self.node_request_timeout);
+                Self::with_request_id(request, request_id)
+            })
+            .await;
+        Some(result)
+    }
+
+    /// Ask the user's node to check for their stored notifications (ex.
+    /// after the notifications were migrated). Returns false if the user is
+    /// not connected to a node.
+    pub async fn notify_node(&self, user: &DynamoDbUser) -> ApiResult<bool> {
+        let node_id = match self.allowed_node_id(user).await? {
+            Some(node_id) => node_id,
+            None => return Ok(false),
+        };
+
+        match self
+            .trigger_notification_check(&user.uaid, node_id, None)
+            .await
+        {
+            Ok(response) if response.status() == 200 => {
+                self.record_node_response(node_id, &response);
+                Ok(true)
+            }
+            Ok(response) => {
+                self.record_node_response(node_id, &response);
+                self.handle_node_rejection(user, node_id, &response).await?;
+                if response.status() == 404 {
+                    return Ok(false);
+                }
+
+                Err(ApiErrorKind::NodeUnavailable.into())
+            }
+            Err(error) => {
+                debug!(
+                    "Error while asking the node to check notifications: {}",
+                    error
+                );
+                self.handle_node_error(user, node_id, &error).await?;
+                Err(ApiErrorKind::NodeUnavailable.into())
+            }
+        }
+    }
+
+    /// Tell each node that a broadcast value changed, so it can update its
+    /// connected clients. The nodes are told concurrently, and nodes which
+    /// don't accept the change are skipped, since the value is already
+    /// stored. Returns how many nodes accepted the change.
+    pub async fn notify_broadcast_change(
+        &self,
+        nodes: &[String],
+        broadcast: &DynamoDbBroadcast,
+    ) -> usize {
+        let mut broadcasts = HashMap::new();
+        broadcasts.insert(&broadcast.broadcast_id, &broadcast.version);
+        let body = serde_json::json!({ "broadcasts": broadcasts });
+        let body = &body;
+
+        let accepted: Vec<bool> = stream::iter(nodes)
+            .map(|node_id| async move {
+                let url = format!("{}/broadcast", node_id);
+                let result = self
+                    .send_with_retry("broadcast", || {
+                        self.http
+                            .put(&url)
+                            .json(body)
+                            .timeout(self.node_request_timeout)
+                    })
+                    .await
+                    .and_then(|response| response.error_for_status());
+
+                match result {
+                    Ok(_) => {
+                        self.metrics.incr("broadcast.node.sent").ok();
+                        true
+                    }
+                    Err(error) => {
+                        debug!(
+                            "Error while notifying node {} of a broadcast change: {}",
+                            node_id, error
+                        );
+                        self.metrics.incr("broadcast.node.error").ok();
+                        false
+                    }
+                }
+            })
+            .buffer_unordered(max(self.broadcast_concurrency, 1))
+            .collect()
+            .await;
+
+        accepted.into_iter().filter(|&accepted| accepted).count()
+    }
+
+    /// Notify the node to check for notifications for the user
+    async fn trigger_notification_check(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        request_id: Option<&str>,
+    ) -> Result<Response, reqwest::Error> {
+        let url = format!("{}/notif/{}", node_id, uaid);
+
+        self.send_with_retry("notif", || {
+            let request = self.http.put(&url).timeout(self.node_request_timeout);
+            Self::with_request_id(request, request_id)
+        })
+        .await
+    }
+
+    /// Forward the request ID to the node, so its logs can be correlated with
+    /// ours
+    fn with_request_id(request: RequestBuilder, request_id: Option<&str>) -> RequestBuilder {
+        match request_id {
+            Some(request_id) => request.header(REQUEST_ID_HEADER, request_id),
+            None => request,
+        }
+    }
+
+    /// Send a request to a node. Connection errors and timeouts are retried
+    /// with exponential backoff, but error responses from the node are not.
+    async fn send_with_retry(
+        &self,
+        request_type: &'static str,
+        make_request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let mut retries = 0;
+
+        loop {
+            match self.send_timed(request_type, make_request()).await {
+                // Errors while building the request won't be fixed by retrying
+                Err(error) if !error.is_builder() && retries < self.retry_policy.max_retries => {
+                    let delay = self.retry_policy.delay(retries);
+                    debug!(
+                        "Error while sending {} request to node, retrying in {:?}: {}",
+                        request_type, delay, error
+                    );
+                    self.metrics
+                        .incr_with_tags("notification.node.retry")
+                        .with_tag("request", request_type)
+                        .send();
+
+                    actix_rt::time::delay_for(delay).await;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a single request to a node, timing how long the node takes to
+    /// respond
+    async fn send_timed(
+        &self,
+        request_type: &'static str,
+        request: RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let mut timer = TimerGuard::start(&self.metrics, "notification.node.request.latency");
+        timer.tag("request", request_type);
+        let result = request.send().await;
+
+        let outcome = match &result {
+            Ok(response) if response.status().is_success() => "success",
+            Ok(_) => "rejected",
+            Err(_) => "error",
+        };
+        timer.tag("outcome", outcome);
+
+        result
+    }
+
+    /// Record a failed request to the node. If the node can't be reached, we
+    /// should stop sending notifications to it for this user. Other errors,
+    /// such as an unreadable response, don't mean the user has moved, so the
+    /// node is kept.
+    async fn handle_node_error(
+        &self,
+        user: &DynamoDbUser,
+        node_id: &str,
+        error: &reqwest::Error,
+    ) -> ApiResult<()> {
+        let kind = NodeErrorKind::from_error(error);
+        self.metrics
+            .incr_with_tags("notification.node.error")
+            .with_tag("category", kind.as_str())
+            .send();
+
+        if !kind.is_unreachable() {
+            return Ok(());
+        }
+
+        self.record_node_failure(node_id);
+        self.remove_node_id(user, node_id.to_string()).await
+    }
+
+    /// Record the node's response to a request. The node is healthy if it
+    /// delivered the notification (200), the user is no longer connected (404)
+    /// or it is busy (503). Any other server error counts as a failure.
+    fn record_node_response(&self, node_id: &str, response: &Response) {
+        match response.status().as_u16() {
+            200 | 404 | 503 => self.record_node_success(node_id),
+            status if status >= 500 => self.record_node_failure(node_id),
+            _ => {}
+        }
+    }
+
+    /// Record that the node responded, closing its circuit breaker if open
+    fn record_node_success(&self, node_id: &str) {
+        if self.breaker.record_success(node_id) {
+            debug!("Closing circuit breaker for node {}", node_id);
+            self.metrics.incr("notification.node.breaker.closed").ok();
+        }
+    }
+
+    /// Record that a request to the node failed, opening its circuit breaker
+    /// if it keeps failing
+    fn record_node_failure(&self, node_id: &str) {
+        if self.breaker.record_failure(node_id) {
+            debug!("Opening circuit breaker for node {}", node_id);
+            self.metrics.incr("notification.node.breaker.opened").ok();
+        }
+    }
+
+    /// Notify the node to check for notifications for the user, without
+    /// waiting for the response
+    fn spawn_notification_check(&self, uaid: &Uuid, node_id: &str, request_id: Option<&str>) {
+        let url = format!("{}/notif/{}", node_id, uaid);
+        // The request is detached, so a hung node must not keep it alive
+        let request = self.http.put(&url).timeout(self.node_request_timeout);
+        let request = Self::with_request_id(request, request_id).send();
+
+        actix_rt::spawn(async move {
+            if let Err(error) = request.await {
+                debug!("Error while triggering notification check: {}", error);
+            }
+        });
+    }
+
+    /// Store a notification in the database, unless a notification with the
+    /// same idempotency key was already stored. In that case, the notification
+    /// is not stored and the response for the original notification is
+    /// returned.
+    async fn store_notification_once(
+        &self,
+        notification: &Notification,
+    ) -> ApiResult<Option<RouterResponse>> {
+        let claimed = self
+            .idempotency
+            .claim(notification)
+            .await
+            .map_err(|e| self.save_db_error(e))?;
+        if let Some(message_id) = claimed {
+            debug!("Notification with the same idempotency key was already stored");
+            let original = Notification {
+                message_id,
+                ..notification.clone()
+            };
+            return self.make_stored_response(&original).map(Some);
+        }
+
+        if let Err(error) = self.store_notification(notification).await {
+            if let Err(e) = self.idempotency.release(notification).await {
+                debug!("Unable to release the idempotency key: {}", e);
+            }
+            return Err(error);
+        }
+
+        Ok(None)
+    }
+
+    /// Store a notification in the database. A notification with a topic
+    /// replaces any stored notification with the same topic (RFC 8030 section
+    /// 5.4).
+    async fn store_notification(&self, notification: &Notification) -> ApiResult<()> {
+        let user = &notification.subscription.user;
+        let message_month = user
+            .current_month
+            .clone()
+            .unwrap_or_else(|| self.ddb.current_message_month());
+        self.make_channel_room(notification, &message_month).await?;
+
+        self.ddb
+            .store_message(&user.uaid, message_month, notification.clone().into())
+            .await
+            .map_err(|e| self.save_db_error(e))
+    }
+
+    /// Make sure storing the notification won't put more than
+    /// `max_channel_messages` messages in the channel, by evicting the oldest
+    /// messages. If eviction is disabled, the notification is rejected
+    /// instead. Topic messages replace each other, so they aren't limited.
+    async fn make_channel_room(
+        &self,
+        notification: &Notification,
+        message_month: &str,
+    ) -> ApiResult<()> {
+        if self.max_channel_messages == 0 || notification.is_topic() {
+            return Ok(());
+        }
+
+        let uaid = &notification.subscription.user.uaid;
+        let sort_keys = self
+            .ddb
+            .channel_message_keys(uaid, message_month, &notification.subscription.channel_id)
+            .await
+            .map_err(|e| self.save_db_error(e))?;
+        if sort_keys.len() < self.max_channel_messages {
+            return Ok(());
+        }
+
+        if !self.evict_channel_messages {
+            return Err(RouterError::ChannelStorageFull {
+                max_messages: self.max_channel_messages,
+            }
+            .into());
+        }
+
+        let excess = sort_keys.len() + 1 - self.max_channel_messages;
+        for sort_key in sort_keys.into_iter().take(excess) {
+            let removed = self
+                .ddb
+                .remove_message(uaid, message_month, sort_key)
+                .await
+                .map_err(|e| self.save_db_error(e))?;
+            if removed {
+                self.metrics.incr("store.evicted").ok();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create the error for a database failure while storing a notification
+    fn save_db_error(&self, source: autopush_common::errors::Error) -> ApiError {
+        RouterError::SaveDb {
+            source,
+            retry_after: Backoff::with_jitter(self.db_retry_after, self.db_retry_jitter),
+        }
+        .into()
+    }
+
+    /// Handle a node which did not accept the notification, so it will be
+    /// stored. A 404 means the user is no longer connected to the node, so the
+    /// node ID is removed. A 503 means the node is busy. The node ID is kept
+    /// for any other response, since the user may still be connected.
+    async fn handle_node_rejection(
+        &self,
+        user: &DynamoDbUser,
+        node_id: &str,
+        response: &Response,
+    ) -> ApiResult<()> {
+        match response.status().as_u16() {
+            404 => {
+                trace!("User is no longer connected to the node");
+                return self.remove_node_id(user, node_id.to_string()).await;
+            }
+            503 => {
+                trace!("Node is busy");
+                self.metrics.incr("notification.node.busy").ok();
+            }
+            status => {
+                debug!("Node rejected the notification with status {}", status);
+                self.metrics
+                    .incr_with_tags("notification.node.rejected")
+                    .with_tag("status", &status.to_string())
+                    .send();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the node ID from a user. This is done if the user is no longer
+    /// connected to the node. If the user has reconnected since their record
+    /// was read, the new connection is left alone.
+    async fn remove_node_id(&self, user: &DynamoDbUser, node_id: String) -> ApiResult<()> {
+        self.metrics.incr("updates.client.host_gone").ok();
+
+        let removed = self
+            .ddb
+            .remove_node_id(&user.uaid, node_id, user.connected_at)
+            .await
+            .map_err(ApiErrorKind::Database)?;
+
+        if !removed {
+            debug!(
+                "User reconnected, keeping the new node ID";
+                "uaid" => logging::log_uaid(&user.uaid)
+            );
+            self.metrics
+                .incr("updates.client.host_gone.reconnected")
+                .ok();
+        }
+
+        Ok(())
+    }
+
+    /// Update metrics and create a response for when a notification has been directly forwarded to
+    /// an autopush server. The push message resource was created, so this is a 201 (RFC 8030
+    /// section 5).
+    fn make_delivered_response(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        self.spawn_push_receipt(notification);
+        self.make_response(notification, "Direct", StatusCode::CREATED)
+    }
+
+    /// Tell the application server that the notification was delivered, if
+    /// it asked for a receipt (RFC 8030 section 6.1), without waiting for the
+    /// response. A failed receipt doesn't affect the notification.
+    fn spawn_push_receipt(&self, notification: &Notification) {
+        let receipt_url = match &notification.headers.push_receipt {
+            Some(receipt_url) => receipt_url,
+            None => return,
+        };
+        let request = self
+            .http
+            .post(receipt_url)
+            .json(&serde_json::json!({ "message_id": notification.message_id }))
+            .timeout(self.node_request_timeout);
+        let request = Self::with_request_id(request, notification.request_id.as_deref()).send();
+        let metrics = self.metrics.clone();
+
+        actix_rt::spawn(async move {
+            let result = request
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => {
+                    metrics.incr("notification.receipt.sent").ok();
+                }
+                Err(error) => {
+                    debug!("Error while sending push receipt: {}", error);
+                    metrics.incr("notification.receipt.failed").ok();
+                }
+            }
+        });
+    }
+
+    /// Update metrics and create a response for when a notification has been stored in the database
+    /// for future transmission. Delivery is still pending, so this is a 202.
+    fn make_stored_response(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        self.make_response(notification, "Stored", StatusCode::ACCEPTED)
+    }
+
+    /// Update metrics and create a response for when a notification with a TTL
+    /// of 0 could not be delivered immediately, and so was discarded. This is a
+    /// 201 like the Python implementation, but with a `TTL` of 0 and no
+    /// `Location` or `X-Message-Id` since the message was not stored (RFC 8030
+    /// section 5.2).
+    fn make_dropped_response(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        let mut response = self.make_response(notification, "Dropped", StatusCode::CREATED)?;
+        response.headers.remove("Location");
+        response.headers.remove("X-Message-Id");
+        Ok(response)
+    }
+
+    /// Get the metric tag for where a notification went, based on the
+    /// response. Dropped notifications have no message resource.
+    fn destination_tag(response: &RouterResponse) -> &'static str {
+        if !response.headers.contains_key("Location") {
+            "Dropped"
+        } else if response.status == StatusCode::CREATED {
+            "Direct"
+        } else {
+            "Stored"
+        }
+    }
+
+    /// Update metrics and create a response after routing a notification
+    fn make_response(
+        &self,
+        notification: &Notification,
+        destination_tag: &str,
+        status: StatusCode,
+    ) -> ApiResult<RouterResponse> {
+        let location = self.message_location(notification)?;
+        let data_len = notification.data.as_ref().map(String::len).unwrap_or(0);
+        self.metrics
+            .count_with_tags("notification.message_data", data_len as i64)
+            .with_tag("destination", destination_tag)
+            .send();
+        // The distribution of sizes is used for capacity planning
+        self.metrics
+            .histogram_with_tags("notification.message_size", data_len as u64)
+            .with_tag("destination", destination_tag)
+            .send();
+
+        let mut headers = HashMap::new();
+        headers.insert("Location", location);
+        // Senders can read the message ID without parsing the `Location`
+        headers.insert("X-Message-Id", notification.message_id.clone());
+        headers.insert("TTL", notification.headers.ttl.unwrap_or(0).to_string());
+
+        Ok(RouterResponse {
+            status,
+            headers,
+            body: None,
+        })
+    }
+
+    /// Get the URL of the push message resource, in the region the
+    /// notification was sent from. Message IDs are URL-safe base64, so
+    /// anything else would produce a bogus `Location` header. The message ID
+    /// is still percent-encoded, so it can never change more than the last
+    /// path segment.
+    fn message_location(&self, notification: &Notification) -> ApiResult<String> {
+        let message_id = &notification.message_id;
+        let is_url_safe = !message_id.is_empty()
+            && message_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '=');
+
+        let endpoint_url = notification
+            .region
+            .as_ref()
+            .and_then(|region| self.regional_endpoint_urls.get(region))
+            .unwrap_or(&self.endpoint_url);
+        let location = if is_url_safe {
+            endpoint_url.join("/m/").ok().and_then(|mut url| {
+                url.path_segments_mut()
+                    .ok()?
+                    .pop_if_empty()
+                    .push(message_id);
+                Some(url)
+            })
+        } else {
+            None
+        };
+
+        location.map(|url| url.to_string()).ok_or_else(|| {
+            error!("Message ID is not URL-safe"; "message_id" => message_id);
+            ApiErrorKind::InvalidMessageLocation.into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BroadcastOutcome, WebPushRouter};
+    use crate::db::mock::MockDbClient;
+    use crate::error::{ApiErrorKind, ApiResult};
+    use crate::idempotency::IdempotencyCache;
+    use crate::metrics::TestMetricSink;
+    use crate::rate_limit::UaidRateLimiter;
+    use crate::routers::circuit_breaker::{BreakerPolicy, CircuitBreaker};
+    use crate::routers::node_limiter::NodeSendLimiter;
+    use crate::routers::retry::RetryPolicy;
+    use crate::routers::{Router, RouterDispatch, RouterError, RouterResponse, RouterType};
+    use crate::server::extractors::notification::{
+        Notification, NotificationBuilder, DELIVERY_SCHEMA_VERSION,
+    };
+    use crate::server::extractors::subscription::Subscription;
+    use crate::settings::Settings;
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use async_trait::async_trait;
+    use autopush_common::db::{DynamoDbBroadcast, DynamoDbUser};
+    use cadence::{NopMetricSink, StatsdClient};
+    use fernet::{Fernet, MultiFernet};
+    use mockito::Matcher;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use url::Url;
+    use uuid::Uuid;
+
+    const CHANNEL_ID: &str = "deadbeef-13f9-4639-87f9-2ff731824f34";
+
+    /// A bridge router which accepts every notification
+    struct BridgeRouter;
+
+    #[async_trait(?Send)]
+    impl Router for BridgeRouter {
+        async fn route_notification(&self, _: &Notification) -> ApiResult<RouterResponse> {
+            Ok(RouterResponse {
+                status: StatusCode::CREATED,
+                headers: HashMap::new(),
+                body: Some("bridge".to_string()),
+            })
+        }
+    }
+
+    /// A bridge router which fails to route notifications, either because it
+    /// no longer knows about the user or because the bridge is down
+    struct FailingBridgeRouter {
+        unregistered: bool,
+    }
+
+    #[async_trait(?Send)]
+    impl Router for FailingBridgeRouter {
+        async fn route_notification(&self, _: &Notification) -> ApiResult<RouterResponse> {
+            if self.unregistered {
+                return Err(RouterError::NotRegistered {
+                    service: "FCM",
+                    reason: "UNREGISTERED".to_string(),
+                }
+                .into());
+            }
+
+            Err(RouterError::Upstream {
+                service: "FCM",
+                message: "Internal error".to_string(),
+            }
+            .into())
+        }
+    }
+
+    /// Create a dispatcher with the WebPush router and an FCM bridge router
+    fn make_fallback_dispatch(router: WebPushRouter, metrics: &TestMetricSink) -> RouterDispatch {
+        let mut dispatch = RouterDispatch::new(metrics.client());
+        dispatch.register(RouterType::WebPush, Box::new(router));
+        dispatch.register(RouterType::Fcm, Box::new(BridgeRouter));
+        dispatch
+    }
+
+    /// Create a dispatcher with the WebPush router and a failing FCM bridge
+    /// router
+    fn make_failing_fallback_dispatch(router: WebPushRouter, unregistered: bool) -> RouterDispatch {
+        let mut dispatch =
+            RouterDispatch::new(StatsdClient::from_sink("autoendpoint", NopMetricSink));
+        dispatch.register(RouterType::WebPush, Box::new(router));
+        dispatch.register(
+            RouterType::Fcm,
+            Box::new(FailingBridgeRouter { unregistered }),
+        );
+        dispatch
+    }
+
+    /// Add an FCM fallback to the user
+    fn add_fcm_fallback(user: &mut DynamoDbUser) {
+        let mut router_data = HashMap::new();
+        router_data.insert("fallback_router".to_string(), json!("fcm"));
+        user.router_data = Some(router_data);
+    }
+
+    /// Create a router for testing, using the mock server as the node
+    fn make_router(ddb: Arc<MockDbClient>) -> WebPushRouter {
+        WebPushRouter {
+            ddb: ddb.clone(),
+            metrics: StatsdClient::from_sink("autoendpoint", NopMetricSink),
+            http: reqwest::Client::new(),
+            endpoint_url: Url::parse("https://example.com/").unwrap(),
+            regional_endpoint_urls: HashMap::new(),
+            max_data_bytes: 4096,
+            retry_policy: RetryPolicy {
+                max_retries: 2,
+                base_delay: Duration::from_millis(1),
+                max_jitter: Duration::from_millis(1),
+            },
+            node_request_timeout: Duration::from_secs(1),
+            broadcast_concurrency: 2,
+            fernet: Arc::new(MultiFernet::new(vec![
+                Fernet::new(&Fernet::generate_key()).unwrap()
+            ])),
+            breaker: CircuitBreaker::new(BreakerPolicy {
+                failure_threshold: 1,
+                failure_window: Duration::from_secs(60),
+                cooldown: Duration::from_secs(60),
+            }),
+            node_limiter: NodeSendLimiter::new(0, Duration::from_millis(0)),
+            node_allowed_hosts: Vec::new(),
+            db_retry_after: Duration::from_secs(10),
+            db_retry_jitter: Duration::from_secs(0),
+            uaid_limiter: Arc::new(UaidRateLimiter::new(0.0, 1)),
+            uaid_retry_jitter: Duration::from_secs(0),
+            idempotency: Arc::new(IdempotencyCache::new(
+                ddb.clone(),
+                "idempotency".to_string(),
+                Duration::from_secs(0),
+            )),
+            max_channel_messages: 0,
+            evict_channel_messages: true,
+        }
+    }
+
+    /// Create a user which is connected to the mock node
+    fn make_user() -> DynamoDbUser {
+        DynamoDbUser {
+            node_id: Some(mockito::server_url()),
+            ..Default::default()
+        }
+    }
+
+    /// Start a node which closes the first `failures` connections without
+    /// responding, then accepts every request. Returns the node ID.
+    fn start_flaky_node(failures: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_id = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                if i < failures {
+                    continue;
+                }
+
+                let mut buffer = [0; 4096];
+                let _ = stream.read(&mut buffer);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                );
+            }
+        });
+
+        node_id
+    }
+
+    /// Make the mock node redirect requests for the path back to itself, so
+    /// the requests fail with too many redirects
+    fn mock_redirect_loop(path: &str) -> mockito::Mock {
+        mockito::mock("PUT", path)
+            .with_status(307)
+            .with_header("Location", &format!("{}{}", mockito::server_url(), path))
+            .create()
+    }
+
+    /// Start a node which accepts connections but never responds. Returns the
+    /// node ID.
+    fn start_slow_node() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_id = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            // Keep the connections open so the requests time out
+            let _streams: Vec<_> = listener.incoming().collect();
+        });
+
+        node_id
+    }
+
+    /// Create a notification for the user
+    fn make_notification(user: DynamoDbUser, respond_async: bool) -> Notification {
+        NotificationBuilder::new()
+            .user(user)
+            .channel_id(Uuid::parse_str(CHANNEL_ID).unwrap())
+            .message_id("test-message-id")
+            .ttl(60)
+            .respond_async(respond_async)
+            .build()
+            .unwrap()
+    }
+
+    /// A notification is sent directly to the node the user is connected to
+    #[actix_rt::test]
+    async fn direct_delivery() {
+        let user = make_user();
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let notification = make_notification(user.clone(), false);
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "https://example.com/m/test-message-id"
+        );
+        assert_eq!(response.headers.get("TTL").unwrap(), "60");
+        node_mock.assert();
+    }
+
+    /// A notification to a node which is too busy is stored instead
+    #[actix_rt::test]
+    async fn busy_node_sheds_to_storage() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        router.node_limiter = NodeSendLimiter::new(1, Duration::from_millis(10));
+        let notification = make_notification(user.clone(), false);
+        let push_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .expect(0)
+            .create();
+        let _notif_mock = mockito::mock("PUT", format!("/notif/{}", user.uaid).as_str())
+            .with_status(202)
+            .create();
+
+        // Another send to the node is in flight
+        let node_id = user.node_id.unwrap();
+        let _permit = router.node_limiter.try_acquire(&node_id).unwrap();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert!(ddb.removed_node_ids().is_empty());
+        assert!(metrics
+            .metrics()
+            .contains(&"autoendpoint.notification.node.shed:1|c".to_string()));
+        push_mock.assert();
+    }
+
+    /// Notifications are sent to nodes on an allowed host
+    #[actix_rt::test]
+    async fn allowed_node_host() {
+        let user = make_user();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.node_allowed_hosts = vec!["127.0.0.1".to_string()];
+        let notification = make_notification(user.clone(), false);
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(ddb.removed_node_ids().is_empty());
+        node_mock.assert();
+    }
+
+    /// A node ID on a host which isn't allowed is removed without sending it
+    /// a request, and the notification is stored
+    #[actix_rt::test]
+    async fn disallowed_node_host() {
+        let node_id = "http://evil.example.com:8080".to_string();
+        let user = DynamoDbUser {
+            node_id: Some(node_id.clone()),
+            ..Default::default()
+        };
+        let metrics = TestMetricSink::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        router.node_allowed_hosts = vec!["127.0.0.1".to_string(), ".internal".to_string()];
+        let notification = make_notification(user, false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert_eq!(ddb.removed_node_ids()[0], node_id);
+        assert!(metrics
+            .metrics()
+            .contains(&"autoendpoint.notification.node.invalid:1|c".to_string()));
+    }
+
+    /// Only HTTP(S) URLs on an allowed host, or a subdomain of an allowed
+    /// domain, are allowed
+    #[test]
+    fn node_host_allowlist() {
+        let mut router = make_router(Arc::new(MockDbClient::default()));
+        router.node_allowed_hosts = vec!["127.0.0.1".to_string(), ".internal".to_string()];
+
+        assert!(router.is_allowed_node("http://127.0.0.1:8081"));
+        assert!(router.is_allowed_node("https://node1.internal:8081"));
+        assert!(!router.is_allowed_node("http://internal.example.com"));
+        assert!(!router.is_allowed_node("http://evil.example.com/?127.0.0.1"));
+        assert!(!router.is_allowed_node("ftp://127.0.0.1"));
+        assert!(!router.is_allowed_node("not a url"));
+    }
+
+    /// The message ID is returned in `X-Message-Id`, alongside the `Location`
+    /// of the message resource
+    #[actix_rt::test]
+    async fn message_id_header() {
+        let user = make_user();
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "https://example.com/m/test-message-id"
+        );
+        assert_eq!(
+            response.headers.get("X-Message-Id").unwrap(),
+            &notification.message_id
+        );
+    }
+
+    /// The request ID is forwarded to the node
+    #[actix_rt::test]
+    async fn forwards_request_id() {
+        let user = make_user();
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let mut notification = make_notification(user.clone(), false);
+        notification.request_id = Some("test-request-id".to_string());
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .match_header("X-Request-Id", "test-request-id")
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        node_mock.assert();
+    }
+
+    /// A message ID which isn't URL-safe is an internal error, not a panic
+    #[actix_rt::test]
+    async fn unsafe_message_id() {
+        let user = make_user();
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let mut notification = make_notification(user.clone(), false);
+        notification.message_id = "//evil.example.com/x?y".to_string();
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let error = router.route_notification(&notification).await.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// Message IDs with reserved characters are rejected with a clean error,
+    /// while padded base64 message IDs are kept as-is
+    #[test]
+    fn message_location_reserved_characters() {
+        let user = DynamoDbUser::default();
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let mut notification = make_notification(user, false);
+
+        for message_id in &["", "a/b", "a?b", "a#b", "a%2Fb", "../m", "a b", "a\nb"] {
+            notification.message_id = message_id.to_string();
+
+            match router.message_location(&notification).unwrap_err().kind {
+                ApiErrorKind::InvalidMessageLocation => {}
+                kind => panic!("Expected an invalid location error, got {:?}", kind),
+            }
+        }
+
+        notification.message_id = "gAAAAAB-_0==".to_string();
+        assert_eq!(
+            router.message_location(&notification).unwrap(),
+            "https://example.com/m/gAAAAAB-_0=="
+        );
+    }
+
+    /// Create two notifications for a user whose node may support batches
+    fn make_batch(supports_batches: bool) -> (DynamoDbUser, Vec<Notification>) {
+        let mut user = make_user();
+        let mut router_data = HashMap::new();
+        router_data.insert("batch_delivery".to_string(), json!(supports_batches));
+        user.router_data = Some(router_data);
+
+        let notifications = (0..2)
+            .map(|_| {
+                let mut notification = make_notification(user.clone(), false);
+                notification.subscription.channel_id = Uuid::new_v4();
+                notification
+            })
+            .collect();
+
+        (user, notifications)
+    }
+
+    /// A node which supports batches receives the notifications in a single
+    /// request
+    #[actix_rt::test]
+    async fn batched_node() {
+        let (user, notifications) = make_batch(true);
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .match_body(Matcher::Regex(r"^\[\{.*\},\{.*\}\]$".to_string()))
+            .with_status(200)
+            .expect(1)
+            .create();
+
+        let responses = router.route_batch(&notifications).await;
+        assert_eq!(responses.len(), 2);
+        for response in responses {
+            assert_eq!(response.unwrap().status, StatusCode::CREATED);
+        }
+        node_mock.assert();
+    }
+
+    /// A node which doesn't support batches receives each notification
+    /// separately
+    #[actix_rt::test]
+    async fn non_batched_node() {
+        let (user, notifications) = make_batch(false);
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .match_body(Matcher::Regex(r"^\{.*\}$".to_string()))
+            .with_status(200)
+            .expect(2)
+            .create();
+
+        let responses = router.route_batch(&notifications).await;
+        assert_eq!(responses.len(), 2);
+        for response in responses {
+            assert_eq!(response.unwrap().status, StatusCode::CREATED);
+        }
+        node_mock.assert();
+    }
+
+    /// A batch the node doesn't accept is stored, without trying the node
+    /// again for each notification
+    #[actix_rt::test]
+    async fn busy_batched_node() {
+        let (user, notifications) = make_batch(true);
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(503)
+            .expect(1)
+            .create();
+
+        let responses = router.route_batch(&notifications).await;
+        for response in responses {
+            assert_eq!(response.unwrap().status, StatusCode::ACCEPTED);
+        }
+        assert_eq!(ddb.stored_messages().len(), 2);
+        node_mock.assert();
+    }
+
+    /// A broadcast to several of a user's subscriptions is sent to their node
+    /// in a single batch
+    #[actix_rt::test]
+    async fn broadcast_batches_user_notifications() {
+        let (user, notifications) = make_batch(true);
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .match_body(Matcher::Regex(r"^\[\{.*\},\{.*\}\]$".to_string()))
+            .with_status(200)
+            .expect(1)
+            .create();
+        let subscriptions: Vec<_> = notifications
+            .into_iter()
+            .map(|notification| notification.subscription)
+            .collect();
+        let body = NotificationBuilder::new().ttl(60).build_body().unwrap();
+
+        let summary = router.route_broadcast(&subscriptions, &body).await;
+        assert_eq!(summary.count(BroadcastOutcome::Delivered), 2);
+        node_mock.assert();
+    }
+
+    /// The node is sent the delivery schema version with the notification
+    #[actix_rt::test]
+    async fn node_receives_schema_version() {
+        let user = make_user();
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let notification = make_notification(user.clone(), false);
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .match_body(Matcher::PartialJson(json!({
+                "schema": DELIVERY_SCHEMA_VERSION,
+                "version": "test-message-id"
+            })))
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        node_mock.assert();
+    }
+
+    /// A node which doesn't know the user means the user disconnected, so
+    /// the node ID is removed and the notification is stored
+    #[actix_rt::test]
+    async fn node_user_gone() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(404)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert_eq!(ddb.removed_node_ids(), vec![mockito::server_url()]);
+        metrics.assert_counter("updates.client.host_gone", &[], 1);
+    }
+
+    /// A user who reconnected while the notification was being routed keeps
+    /// their new node ID
+    #[actix_rt::test]
+    async fn node_user_reconnected() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let ddb = Arc::new(MockDbClient {
+            reconnected_at: Some(user.connected_at + 1),
+            ..MockDbClient::with_user(user.clone())
+        });
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(404)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert!(ddb.removed_node_ids().is_empty());
+        assert!(
+            metrics
+                .metrics()
+                .iter()
+                .any(|metric| metric
+                    .starts_with("autoendpoint.updates.client.host_gone.reconnected:"))
+        );
+    }
+
+    /// The node ID is removed if the user is still on the same connection
+    #[actix_rt::test]
+    async fn node_user_gone_same_connection() {
+        let user = make_user();
+        let ddb = Arc::new(MockDbClient {
+            reconnected_at: Some(user.connected_at),
+            ..MockDbClient::with_user(user.clone())
+        });
+        let router = make_router(ddb.clone());
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(404)
+            .create();
+
+        router.route_notification(&notification).await.unwrap();
+        assert_eq!(ddb.removed_node_ids(), vec![mockito::server_url()]);
+    }
+
+    /// A busy node keeps the user's node ID and the notification is stored
+    #[actix_rt::test]
+    async fn node_busy() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(503)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert!(ddb.removed_node_ids().is_empty());
+        let metrics = metrics.metrics();
+        assert!(metrics
+            .iter()
+            .any(|metric| metric.starts_with("autoendpoint.notification.node.busy:")));
+        assert!(!metrics
+            .iter()
+            .any(|metric| metric.starts_with("autoendpoint.updates.client.host_gone:")));
+    }
+
+    /// A node error other than busy keeps the user's node ID, and the
+    /// notification is stored
+    #[actix_rt::test]
+    async fn node_other_error() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(500)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert!(ddb.removed_node_ids().is_empty());
+        let metrics = metrics.metrics();
+        assert!(metrics
+            .contains(&"autoendpoint.notification.node.rejected:1|c|#status:500".to_string()));
+        assert!(!metrics
+            .iter()
+            .any(|metric| metric.starts_with("autoendpoint.notification.node.busy:")));
+    }
+
+    /// The route, node send and node request times are recorded, tagged by
+    /// destination and outcome
+    #[actix_rt::test]
+    async fn records_route_timing() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.metrics = metrics.client();
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        router.route_notification(&notification).await.unwrap();
+
+        let metrics = metrics.metrics();
+        let route_time = metrics
+            .iter()
+            .find(|metric| metric.starts_with("autoendpoint.notification.route.time:"))
+            .expect("Route time was not recorded");
+        assert!(route_time.contains("|ms"));
+        assert!(route_time.contains("destination:Direct"));
+        assert!(route_time.contains("outcome:success"));
+        let send_time = metrics
+            .iter()
+            .find(|metric| metric.starts_with("autoendpoint.notification.node.send.time:"))
+            .expect("Node send time was not recorded");
+        assert!(send_time.contains("outcome:success"));
+        let request_latency = metrics
+            .iter()
+            .find(|metric| metric.starts_with("autoendpoint.notification.node.request.latency:"))
+            .expect("Node request latency was not recorded");
+        assert!(request_latency.contains("|ms"));
+        assert!(request_latency.contains("request:push"));
+        assert!(request_latency.contains("outcome:success"));
+    }
+
+    /// A notification for a disconnected user is stored, and the receipt URL
+    /// is still returned
+    #[actix_rt::test]
+    async fn stored_delivery() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let notification = make_notification(user, false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "https://example.com/m/test-message-id"
+        );
+        assert_eq!(response.headers.get("TTL").unwrap(), "60");
+        assert_eq!(ddb.stored_messages().len(), 1);
+    }
+
+    /// Connection errors are retried until the node accepts the notification
+    #[actix_rt::test]
+    async fn retry_node_connection_errors() {
+        let user = DynamoDbUser {
+            node_id: Some(start_flaky_node(2)),
+            ..Default::default()
+        };
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let notification = make_notification(user, false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(ddb.stored_messages().is_empty());
+        assert!(ddb.removed_node_ids().is_empty());
+    }
+
+    /// The notification check is retried, and each retry is recorded
+    #[actix_rt::test]
+    async fn retry_notification_check() {
+        // The user connected to the node after the notification was received
+        let user = DynamoDbUser {
+            node_id: Some(start_flaky_node(2)),
+            ..Default::default()
+        };
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let notification = make_notification(
+            DynamoDbUser {
+                node_id: None,
+                ..user
+            },
+            false,
+        );
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(ddb.removed_node_ids().is_empty());
+        let retries = metrics
+            .metrics()
+            .into_iter()
+            .filter(|metric| metric.starts_with("autoendpoint.notification.node.retry:"))
+            .count();
+        assert_eq!(retries, 2);
+    }
+
+    /// Once the retries are used up, the node is removed and the notification
+    /// is stored
+    #[actix_rt::test]
+    async fn retries_exhausted() {
+        let node_id = start_flaky_node(std::usize::MAX);
+        let user = DynamoDbUser {
+            node_id: Some(node_id.clone()),
+            ..Default::default()
+        };
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let notification = make_notification(user, false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert_eq!(ddb.removed_node_ids()[0], node_id);
+    }
+
+    /// A node which doesn't respond in time is removed and the notification
+    /// is stored
+    #[actix_rt::test]
+    async fn node_request_timeout() {
+        let node_id = start_slow_node();
+        let user = DynamoDbUser {
+            node_id: Some(node_id.clone()),
+            ..Default::default()
+        };
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        router.http = Settings::default().http_client_builder().build().unwrap();
+        router.node_request_timeout = Duration::from_millis(50);
+        let notification = make_notification(user, false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert_eq!(ddb.removed_node_ids()[0], node_id);
+        assert!(metrics
+            .metrics()
+            .contains(&"autoendpoint.notification.node.error:1|c|#category:timeout".to_string()));
+    }
+
+    /// A node which refuses the connection is removed and the notification
+    /// is stored
+    #[actix_rt::test]
+    async fn node_connection_refused() {
+        // Nothing listens on the port once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_id = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let user = DynamoDbUser {
+            node_id: Some(node_id.clone()),
+            ..Default::default()
+        };
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let notification = make_notification(user, false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert_eq!(ddb.removed_node_ids()[0], node_id);
+        let metrics = metrics.metrics();
+        assert!(metrics.contains(
+            &"autoendpoint.notification.node.error:1|c|#category:connection_refused".to_string()
+        ));
+        // Each failed attempt is timed
+        let failed_requests = metrics
+            .iter()
+            .filter(|metric| metric.starts_with("autoendpoint.notification.node.request.latency:"))
+            .filter(|metric| metric.contains("outcome:error"))
+            .count();
+        assert_eq!(
+            failed_requests,
+            router.retry_policy.max_retries as usize + 1
+        );
+    }
+
+    /// An error which doesn't mean the node is unreachable doesn't remove
+    /// the node
+    #[actix_rt::test]
+    async fn node_error_keeps_node() {
+        let user = make_user();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mock_redirect_loop(&format!("/push/{}", user.uaid));
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert!(ddb.removed_node_ids().is_empty());
+        assert!(metrics
+            .metrics()
+            .contains(&"autoendpoint.notification.node.error:1|c|#category:other".to_string()));
+    }
+
+    /// A failing node opens the circuit breaker
+    #[actix_rt::test]
+    async fn failing_node_opens_breaker() {
+        let user = DynamoDbUser {
+            node_id: Some(start_flaky_node(std::usize::MAX)),
+            ..Default::default()
+        };
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let notification = make_notification(user, false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert!(metrics
+            .metrics()
+            .iter()
+            .any(|metric| metric.starts_with("autoendpoint.notification.node.breaker.opened:")));
+    }
+
+    /// A node which keeps responding with server errors opens the circuit
+    /// breaker, so later notifications are stored without contacting it
+    #[actix_rt::test]
+    async fn node_server_errors_open_breaker() {
+        let user = make_user();
+        let node_id = user.node_id.clone().unwrap();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        router.breaker = CircuitBreaker::new(BreakerPolicy {
+            failure_threshold: 3,
+            failure_window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+        });
+        // The first notification fails twice (the send and the notification
+        // check), and the second opens the breaker when its send fails
+        let push_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(500)
+            .expect(2)
+            .create();
+        let notif_mock = mockito::mock("PUT", format!("/notif/{}", user.uaid).as_str())
+            .with_status(500)
+            .expect(1)
+            .create();
+
+        for _ in 0..3 {
+            let notification = make_notification(user.clone(), false);
+            let response = router.route_notification(&notification).await.unwrap();
+            assert_eq!(response.status, StatusCode::ACCEPTED);
+        }
+
+        assert_eq!(ddb.stored_messages().len(), 3);
+        assert!(ddb.removed_node_ids().is_empty());
+        assert!(router.breaker.allow_request(&node_id).is_none());
+        assert!(metrics
+            .metrics()
+            .iter()
+            .any(|metric| metric.starts_with("autoendpoint.notification.node.breaker.opened:")));
+        push_mock.assert();
+        notif_mock.assert();
+    }
+
+    /// While the circuit breaker is open, the node is skipped and the
+    /// notification is stored
+    #[actix_rt::test]
+    async fn open_breaker_skips_node() {
+        let user = make_user();
+        let node_id = user.node_id.clone().unwrap();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        router.breaker.record_failure(&node_id);
+        let notification = make_notification(user.clone(), false);
+        let push_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .expect(0)
+            .create();
+        let notif_mock = mockito::mock("PUT", format!("/notif/{}", user.uaid).as_str())
+            .expect(0)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert!(ddb.removed_node_ids().is_empty());
+        push_mock.assert();
+        notif_mock.assert();
+    }
+
+    /// Once the cooldown has passed, a successful delivery to the node closes
+    /// the circuit breaker
+    #[actix_rt::test]
+    async fn successful_probe_closes_breaker() {
+        let user = make_user();
+        let node_id = user.node_id.clone().unwrap();
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.metrics = metrics.client();
+        router.breaker = CircuitBreaker::new(BreakerPolicy {
+            failure_threshold: 1,
+            failure_window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(0),
+        });
+        router.breaker.record_failure(&node_id);
+        let notification = make_notification(user.clone(), false);
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(metrics
+            .metrics()
+            .iter()
+            .any(|metric| metric.starts_with("autoendpoint.notification.node.breaker.closed:")));
+        assert!(router.breaker.allow_request(&node_id).is_some());
+        node_mock.assert();
+    }
+
+    /// A probe which fails with an error that doesn't mean the node is
+    /// unreachable lets the next request probe the node
+    #[actix_rt::test]
+    async fn probe_error_releases_breaker() {
+        let user = make_user();
+        let node_id = user.node_id.clone().unwrap();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.breaker = CircuitBreaker::new(BreakerPolicy {
+            failure_threshold: 1,
+            failure_window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(0),
+        });
+        router.breaker.record_failure(&node_id);
+        let notification = make_notification(user.clone(), false);
+        let _push_mock = mock_redirect_loop(&format!("/push/{}", user.uaid));
+        let _notif_mock = mock_redirect_loop(&format!("/notif/{}", user.uaid));
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert!(ddb.removed_node_ids().is_empty());
+        assert!(router.breaker.allow_request(&node_id).is_some());
+    }
+
+    /// A probe which is shed by the node limiter lets the next request probe
+    /// the node
+    #[actix_rt::test]
+    async fn shed_probe_releases_breaker() {
+        let user = make_user();
+        let node_id = user.node_id.clone().unwrap();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.breaker = CircuitBreaker::new(BreakerPolicy {
+            failure_threshold: 1,
+            failure_window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(0),
+        });
+        router.breaker.record_failure(&node_id);
+        router.node_limiter = NodeSendLimiter::new(1, Duration::from_millis(10));
+        let notification = make_notification(user.clone(), false);
+        let push_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .expect(0)
+            .create();
+        // The notification check probes the node once the send is shed
+        let notif_mock = mockito::mock("PUT", format!("/notif/{}", user.uaid).as_str())
+            .with_status(202)
+            .create();
+
+        // Another send to the node is in flight
+        let _permit = router.node_limiter.try_acquire(&node_id).unwrap();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        push_mock.assert();
+        notif_mock.assert();
+    }
+
+    /// Only the latest stored notification for a topic is kept
+    #[actix_rt::test]
+    async fn topic_messages_collapse() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let mut notification = make_notification(user, false);
+        notification.headers.topic = Some("test-topic".to_string());
+
+        router.route_notification(&notification).await.unwrap();
+        notification.message_id = "second-message-id".to_string();
+        router.route_notification(&notification).await.unwrap();
+
+        let stored_messages = ddb.stored_messages();
+        assert_eq!(stored_messages.len(), 1);
+        assert_eq!(stored_messages[0].version, "second-message-id");
+        assert_eq!(stored_messages[0].topic, Some("test-topic".to_string()));
+    }
+
+    /// Notifications with different topics, or without a topic, are each
+    /// stored separately
+    #[actix_rt::test]
+    async fn distinct_messages_accumulate() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let mut notification = make_notification(user, false);
+
+        for (i, topic) in [None, None, Some("topic-a"), Some("topic-b")]
+            .iter()
+            .enumerate()
+        {
+            notification.message_id = format!("message-{}", i);
+            notification.sort_key_timestamp = i as u64 + 1;
+            notification.headers.topic = topic.map(str::to_string);
+            router.route_notification(&notification).await.unwrap();
+        }
+
+        assert_eq!(ddb.stored_messages().len(), 4);
+    }
+
+    /// The data size is recorded as a histogram, tagged by destination
+    #[actix_rt::test]
+    async fn records_message_size() {
+        let user = DynamoDbUser::default();
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.metrics = metrics.client();
+        let mut notification = make_notification(user, false);
+        notification.data = Some("a".repeat(100));
+
+        router.route_notification(&notification).await.unwrap();
+
+        let metrics = metrics.metrics();
+        assert!(
+            metrics.contains(
+                &"autoendpoint.notification.message_size:100|h|#destination:Stored".to_string()
+            ),
+            "metrics = {:?}",
+            metrics
+        );
+        assert!(metrics.contains(
+            &"autoendpoint.notification.message_data:100|c|#destination:Stored".to_string()
+        ));
+    }
+
+    /// The response records the data size, tagged by destination
+    #[test]
+    fn make_response_metrics() {
+        let user = DynamoDbUser::default();
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.metrics = metrics.client();
+        let mut notification = make_notification(user, false);
+        notification.data = Some("a".repeat(10));
+
+        let response = router
+            .make_response(&notification, "Direct", StatusCode::CREATED)
+            .unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        metrics.assert_counter(
+            "notification.message_data",
+            &[("destination", "Direct")],
+            10,
+        );
+    }
+
+    /// Removing a node ID counts the user as gone from the node
+    #[actix_rt::test]
+    async fn remove_node_id_metrics() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+
+        router
+            .remove_node_id(&user, mockito::server_url())
+            .await
+            .unwrap();
+        assert_eq!(ddb.removed_node_ids(), vec![mockito::server_url()]);
+        metrics.assert_counter("updates.client.host_gone", &[], 1);
+        assert!(!metrics
+            .recorded()
+            .iter()
+            .any(|metric| metric.name == "updates.client.host_gone.reconnected"));
+    }
+
+    /// The first notification with an idempotency key is stored
+    #[actix_rt::test]
+    async fn idempotent_first_write() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.idempotency = Arc::new(IdempotencyCache::new(
+            ddb.clone(),
+            "idempotency".to_string(),
+            Duration::from_secs(60),
+        ));
+        let mut notification = make_notification(user, false);
+        notification.headers.idempotency_key = Some("test-key".to_string());
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "https://example.com/m/test-message-id"
+        );
+        assert_eq!(ddb.stored_messages().len(), 1);
+    }
+
+    /// A retry with the same idempotency key is not stored again, and gets
+    /// the original message ID
+    #[actix_rt::test]
+    async fn idempotent_duplicate_write() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.idempotency = Arc::new(IdempotencyCache::new(
+            ddb.clone(),
+            "idempotency".to_string(),
+            Duration::from_secs(60),
+        ));
+        let mut notification = make_notification(user, false);
+        notification.headers.idempotency_key = Some("test-key".to_string());
+        router.route_notification(&notification).await.unwrap();
+
+        notification.message_id = "retry-message-id".to_string();
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "https://example.com/m/test-message-id"
+        );
+        assert_eq!(ddb.stored_messages().len(), 1);
+    }
+
+    /// The `Location` uses the endpoint URL of the notification's region,
+    /// or the default endpoint URL if the region has none
+    #[actix_rt::test]
+    async fn regional_location() {
+        let user = DynamoDbUser::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.regional_endpoint_urls = vec![(
+            "eu-west".to_string(),
+            Url::parse("https://eu-west.example.com/").unwrap(),
+        )]
+        .into_iter()
+        .collect();
+        let mut notification = make_notification(user, false);
+
+        for (region, location) in &[
+            (
+                Some("eu-west"),
+                "https://eu-west.example.com/m/test-message-id",
+            ),
+            (Some("us-east"), "https://example.com/m/test-message-id"),
+            (None, "https://example.com/m/test-message-id"),
+        ] {
+            notification.region = region.map(str::to_string);
+
+            let response = router.route_notification(&notification).await.unwrap();
+            assert_eq!(response.headers.get("Location").unwrap(), location);
+        }
+    }
+
+    /// Route a notification with the sort key timestamp to a user who isn't
+    /// connected, so it is stored
+    async fn store_channel_message(
+        router: &WebPushRouter,
+        user: &DynamoDbUser,
+        sort_key_timestamp: u64,
+    ) -> ApiResult<RouterResponse> {
+        let mut notification = make_notification(user.clone(), false);
+        notification.message_id = format!("message-{}", sort_key_timestamp);
+        notification.sort_key_timestamp = sort_key_timestamp;
+        router.route_notification(&notification).await
+    }
+
+    /// The oldest messages are evicted from a full channel to make room
+    #[actix_rt::test]
+    async fn channel_limit_evicts_oldest() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        router.max_channel_messages = 2;
+
+        for sort_key_timestamp in 1..=3 {
+            let response = store_channel_message(&router, &user, sort_key_timestamp)
+                .await
+                .unwrap();
+            assert_eq!(response.status, StatusCode::ACCEPTED);
+        }
+
+        let stored: Vec<_> = ddb
+            .stored_messages()
+            .into_iter()
+            .map(|message| message.sortkey_timestamp)
+            .collect();
+        assert_eq!(stored, vec![Some(2), Some(3)]);
+        assert!(metrics
+            .metrics()
+            .contains(&"autoendpoint.store.evicted:1|c".to_string()));
+    }
+
+    /// A full channel rejects new messages with a 507 if eviction is
+    /// disabled, but topic messages still replace each other
+    #[actix_rt::test]
+    async fn channel_limit_without_eviction() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.max_channel_messages = 2;
+        router.evict_channel_messages = false;
+        store_channel_message(&router, &user, 1).await.unwrap();
+        store_channel_message(&router, &user, 2).await.unwrap();
+
+        let error = store_channel_message(&router, &user, 3).await.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(error.kind.errno(), Some(203));
+        assert_eq!(ddb.stored_messages().len(), 2);
+
+        let mut notification = make_notification(user, false);
+        notification.headers.topic = Some("test-topic".to_string());
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 3);
+    }
+
+    /// A database failure while storing the notification is a 503 which tells
+    /// the client when to retry
+    #[actix_rt::test]
+    async fn store_failure_retry_after() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient {
+            fail_store_message: true,
+            ..MockDbClient::with_user(user.clone())
+        });
+        let router = make_router(ddb);
+        let notification = make_notification(user, false);
+
+        let error = router.route_notification(&notification).await.unwrap_err();
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after = response.headers().get("Retry-After").unwrap();
+        assert_eq!(retry_after.to_str().unwrap().parse::<u64>(), Ok(10));
+    }
+
+    /// A user who can't be found after the notification was stored was
+    /// deleted during routing
+    #[actix_rt::test]
+    async fn user_deleted_during_routing() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::default());
+        let router = make_router(ddb.clone());
+        let notification = make_notification(user, false);
+
+        let error = router.route_notification(&notification).await.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::GONE);
+        assert_eq!(error.kind.errno(), Some(105));
+    }
+
+    /// A database error while re-reading the user still reports the
+    /// notification as stored, instead of as the user being deleted
+    #[actix_rt::test]
+    async fn refetch_user_error() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient {
+            fail_get_user: true,
+            ..MockDbClient::with_user(user.clone())
+        });
+        let router = make_router(ddb.clone());
+        let notification = make_notification(user, false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+    }
+
+    /// The database retry jitter is added to Retry-After, and stays within
+    /// the configured max
+    #[actix_rt::test]
+    async fn store_failure_retry_jitter() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient {
+            fail_store_message: true,
+            ..MockDbClient::with_user(user.clone())
+        });
+        let mut router = make_router(ddb);
+        router.db_retry_jitter = Duration::from_secs(20);
+        let notification = make_notification(user, false);
+
+        for _ in 0..20 {
+            let error = router.route_notification(&notification).await.unwrap_err();
+            let response = error.error_response();
+            let header = |name: &str| {
+                let value = response.headers().get(name).unwrap();
+                value.to_str().unwrap().parse::<u64>().unwrap()
+            };
+            let retry_after = header("Retry-After");
+            let jitter = header("X-Backoff-Jitter");
+
+            assert!(jitter <= 20);
+            assert_eq!(retry_after, 10 + jitter);
+        }
+    }
+
+    /// Notifications past a user's burst are rejected with a 429 and a
+    /// Retry-After, without being stored
+    #[actix_rt::test]
+    async fn user_rate_limited() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let mut router = make_router(ddb.clone());
+        router.uaid_limiter = Arc::new(UaidRateLimiter::new(0.1, 2));
+        let notification = make_notification(user, false);
+
+        for _ in 0..2 {
+            let response = router.route_notification(&notification).await.unwrap();
+            assert_eq!(response.status, StatusCode::ACCEPTED);
+        }
+
+        let error = router.route_notification(&notification).await.unwrap_err();
+        assert_eq!(error.kind.errno(), Some(122));
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "10");
+        assert_eq!(ddb.stored_messages().len(), 2);
+    }
+
+    /// A notification with a TTL of 0 is delivered if the user is connected
+    #[actix_rt::test]
+    async fn ttl_zero_delivered() {
+        let user = make_user();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let mut notification = make_notification(user.clone(), false);
+        notification.headers.ttl = Some(0);
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.headers.get("TTL").unwrap(), "0");
+        assert!(response.headers.get("Location").is_some());
+        assert!(ddb.stored_messages().is_empty());
+        node_mock.assert();
+    }
+
+    /// A notification with a TTL of 0 is dropped if the user is not
+    /// connected, instead of being stored
+    #[actix_rt::test]
+    async fn ttl_zero_dropped() {
+        let user = DynamoDbUser::default();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let mut notification = make_notification(user, false);
+        notification.headers.ttl = Some(0);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.headers.get("TTL").unwrap(), "0");
+        assert!(response.headers.get("Location").is_none());
+        assert!(ddb.stored_messages().is_empty());
+    }
+
+    /// A notification with a TTL of 0 is dropped if the node is busy, and the
+    /// node is not asked to check for stored notifications
+    #[actix_rt::test]
+    async fn ttl_zero_node_busy() {
+        let user = make_user();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let mut notification = make_notification(user.clone(), false);
+        notification.headers.ttl = Some(0);
+        let _push_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(503)
+            .create();
+        let notif_mock = mockito::mock("PUT", format!("/notif/{}", user.uaid).as_str())
+            .expect(0)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(response.headers.get("Location").is_none());
+        assert!(ddb.stored_messages().is_empty());
+        notif_mock.assert();
+    }
+
+    /// A user with a dead node is reached through their fallback router, and
+    /// the notification is not stored
+    #[actix_rt::test]
+    async fn dead_node_falls_back_to_bridge() {
+        let node_id = start_flaky_node(std::usize::MAX);
+        let mut user = DynamoDbUser {
+            node_id: Some(node_id.clone()),
+            ..Default::default()
+        };
+        add_fcm_fallback(&mut user);
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let dispatch = make_fallback_dispatch(make_router(ddb.clone()), &metrics);
+        let notification = make_notification(user, false);
+
+        let response = dispatch.route(&notification).await.unwrap();
+        assert_eq!(response.body, Some("bridge".to_string()));
+        assert!(ddb.stored_messages().is_empty());
+        assert_eq!(ddb.removed_node_ids(), vec![node_id]);
+        assert!(metrics.metrics().iter().any(|metric| metric
+            .starts_with("autoendpoint.notification.fallback.delivered:")
+            && metric.contains("delivered_via:bridge")));
+    }
+
+    /// An invalid fallback registration only removes the user's fallback
+    /// router, and the notification is stored for the user
+    #[actix_rt::test]
+    async fn unregistered_fallback_removes_fallback_router() {
+        let mut user = DynamoDbUser::default();
+        add_fcm_fallback(&mut user);
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let dispatch = make_failing_fallback_dispatch(make_router(ddb.clone()), true);
+        let notification = make_notification(user.clone(), false);
+
+        let response = dispatch.route(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert_eq!(ddb.removed_fallback_routers(), vec![user.uaid]);
+    }
+
+    /// If the fallback bridge fails, the notification is stored instead of
+    /// returning the bridge's error, and the fallback router is kept
+    #[actix_rt::test]
+    async fn failed_fallback_stores() {
+        let mut user = DynamoDbUser::default();
+        add_fcm_fallback(&mut user);
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let dispatch = make_failing_fallback_dispatch(make_router(ddb.clone()), false);
+        let notification = make_notification(user, false);
+
+        let response = dispatch.route(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert!(ddb.removed_fallback_routers().is_empty());
+    }
+
+    /// The user's rate limit applies before the direct delivery attempt, so
+    /// a limited user is not reached through the node or the bridge
+    #[actix_rt::test]
+    async fn fallback_user_rate_limited() {
+        let mut user = make_user();
+        add_fcm_fallback(&mut user);
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.uaid_limiter = Arc::new(UaidRateLimiter::new(0.1, 1));
+        router.uaid_limiter.try_acquire(&user.uaid).unwrap();
+        let dispatch = make_fallback_dispatch(router, &metrics);
+        let notification = make_notification(user.clone(), false);
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .expect(0)
+            .create();
+
+        let error = dispatch.route(&notification).await.unwrap_err();
+        assert_eq!(error.kind.errno(), Some(122));
+        assert!(ddb.stored_messages().is_empty());
+        assert!(!metrics
+            .metrics()
+            .iter()
+            .any(|metric| metric.starts_with("autoendpoint.notification.fallback.delivered:")));
+        node_mock.assert();
+    }
+
+    /// A sender which prefers an asynchronous response has the notification
+    /// stored for a user with a fallback router, instead of waiting for the
+    /// node or the bridge
+    #[actix_rt::test]
+    async fn fallback_respond_async_stores() {
+        let mut user = make_user();
+        add_fcm_fallback(&mut user);
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let dispatch = make_fallback_dispatch(make_router(ddb.clone()), &metrics);
+        let notification = make_notification(user.clone(), true);
+        let push_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .expect(0)
+            .create();
+
+        let response = dispatch.route(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(response.body, None);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        push_mock.assert();
+    }
+
+    /// A user with a live node is reached through the node, even if they have
+    /// a fallback router
+    #[actix_rt::test]
+    async fn live_node_skips_fallback() {
+        let mut user = make_user();
+        add_fcm_fallback(&mut user);
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let dispatch = make_fallback_dispatch(make_router(ddb.clone()), &metrics);
+        let notification = make_notification(user.clone(), false);
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let response = dispatch.route(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.body, None);
+        assert!(metrics.metrics().iter().any(|metric| metric
+            .starts_with("autoendpoint.notification.fallback.delivered:")
+            && metric.contains("delivered_via:node")));
+        node_mock.assert();
+    }
+
+    /// The fallback is opt-in, so a user with a dead node and no fallback
+    /// router has the notification stored instead of bridged
+    #[actix_rt::test]
+    async fn dead_node_without_fallback_stores() {
+        let node_id = start_flaky_node(std::usize::MAX);
+        let user = DynamoDbUser {
+            node_id: Some(node_id.clone()),
+            ..Default::default()
+        };
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let dispatch = make_fallback_dispatch(make_router(ddb.clone()), &metrics);
+        let notification = make_notification(user, false);
+
+        let response = dispatch.route(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(response.body, None);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        assert!(!metrics
+            .metrics()
+            .iter()
+            .any(|metric| metric.starts_with("autoendpoint.notification.fallback.delivered:")));
+    }
+
+    /// A broadcast is delivered to the connected users and stored for the
+    /// others
+    #[actix_rt::test]
+    async fn broadcast_online_and_offline() {
+        // The stored user record is used when re-fetching offline users
+        let ddb = Arc::new(MockDbClient::with_user(DynamoDbUser::default()));
+        let router = make_router(ddb.clone());
+        let online_users = vec![make_user(), make_user(), make_user()];
+        let node_mocks: Vec<_> = online_users
+            .iter()
+            .map(|user| {
+                mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+                    .with_status(200)
+                    .create()
+            })
+            .collect();
+        let offline_users = vec![DynamoDbUser::default(), DynamoDbUser::default()];
+        let subscriptions: Vec<_> = online_users
+            .into_iter()
+            .chain(offline_users)
+            .map(|user| make_notification(user, false).subscription)
+            .map(|subscription| Subscription {
+                channel_id: Uuid::new_v4(),
+                ..subscription
+            })
+            .collect();
+        let body = NotificationBuilder::new().ttl(60).build_body().unwrap();
+
+        let summary = router.route_broadcast(&subscriptions, &body).await;
+        assert_eq!(summary.outcomes.len(), 5);
+        assert_eq!(summary.count(BroadcastOutcome::Delivered), 3);
+        assert_eq!(summary.count(BroadcastOutcome::Stored), 2);
+        assert_eq!(summary.count(BroadcastOutcome::Gone), 0);
+        assert_eq!(ddb.stored_messages().len(), 2);
+        for node_mock in node_mocks {
+            node_mock.assert();
+        }
+    }
+
+    /// Wait for a background task to record the metric, returning false if
+    /// it isn't recorded in time
+    async fn wait_for_metric(metrics: &TestMetricSink, prefix: &str) -> bool {
+        for _ in 0..100 {
+            if metrics
+                .metrics()
+                .iter()
+                .any(|metric| metric.starts_with(prefix))
+            {
+                return true;
+            }
+
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        false
+    }
+
+    /// Create a notification which asks for a receipt, and a path on the
+    /// mock server for the receipt
+    fn make_receipt_notification(
+        user: DynamoDbUser,
+        respond_async: bool,
+    ) -> (Notification, String) {
+        let receipt_path = format!("/receipt/{}", Uuid::new_v4());
+        let mut notification = make_notification(user, respond_async);
+        notification.headers.push_receipt =
+            Some(format!("{}{}", mockito::server_url(), receipt_path));
+
+        (notification, receipt_path)
+    }
+
+    /// A receipt is sent once the node confirms delivery
+    #[actix_rt::test]
+    async fn push_receipt_on_delivery() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.metrics = metrics.client();
+        let (notification, receipt_path) = make_receipt_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+        let receipt_mock = mockito::mock("POST", receipt_path.as_str())
+            .match_body(Matcher::Json(
+                json!({ "message_id": notification.message_id }),
+            ))
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(wait_for_metric(&metrics, "autoendpoint.notification.receipt.sent:").await);
+        receipt_mock.assert();
+    }
+
+    /// A failed receipt is recorded, but the notification is still delivered
+    #[actix_rt::test]
+    async fn push_receipt_failure() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.metrics = metrics.client();
+        let (notification, receipt_path) = make_receipt_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+        let _receipt_mock = mockito::mock("POST", receipt_path.as_str())
+            .with_status(500)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(wait_for_metric(&metrics, "autoendpoint.notification.receipt.failed:").await);
+    }
+
+    /// An unreachable receipt URL is recorded as a failed receipt
+    #[actix_rt::test]
+    async fn push_receipt_unreachable() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.metrics = metrics.client();
+        let (mut notification, _) = make_receipt_notification(user.clone(), false);
+        notification.headers.push_receipt = Some("http://localhost:1/receipt".to_string());
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(wait_for_metric(&metrics, "autoendpoint.notification.receipt.failed:").await);
+    }
+
+    /// No receipt is sent until the notification is delivered, and the
+    /// receipt URL is stored with the notification
+    #[actix_rt::test]
+    async fn push_receipt_not_sent_when_stored() {
+        let user = make_user();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(ddb.clone());
+        router.metrics = metrics.client();
+        let (notification, receipt_path) = make_receipt_notification(user, true);
+        let receipt_mock = mockito::mock("POST", receipt_path.as_str())
+            .expect(0)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(
+            ddb.stored_messages()[0].receipt_url,
+            notification.headers.push_receipt
+        );
+        assert!(!wait_for_metric(&metrics, "autoendpoint.notification.receipt.").await);
+        receipt_mock.assert();
+    }
+
+    /// Delivered notifications without a push receipt don't send one
+    #[actix_rt::test]
+    async fn no_push_receipt() {
+        let user = make_user();
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        router.metrics = metrics.client();
+        let notification = make_notification(user.clone(), false);
+        let _node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert!(!wait_for_metric(&metrics, "autoendpoint.notification.receipt.").await);
+    }
+
+    /// The node of a connected user is asked to check for notifications
+    #[actix_rt::test]
+    async fn notify_node_connected() {
+        let user = make_user();
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        let notif_mock = mockito::mock("PUT", format!("/notif/{}", user.uaid).as_str())
+            .with_status(200)
+            .create();
+
+        assert!(router.notify_node(&user).await.unwrap());
+        notif_mock.assert();
+    }
+
+    /// Users without a node, or who have left their node, are not notified
+    #[actix_rt::test]
+    async fn notify_node_not_connected() {
+        let user = DynamoDbUser::default();
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+        assert!(!router.notify_node(&user).await.unwrap());
+
+        let user = make_user();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let _notif_mock = mockito::mock("PUT", format!("/notif/{}", user.uaid).as_str())
+            .with_status(404)
+            .create();
+        assert!(!router.notify_node(&user).await.unwrap());
+        assert_eq!(ddb.removed_node_ids(), vec![mockito::server_url()]);
+    }
+
+    /// A node which can't be reached is reported as unavailable
+    #[actix_rt::test]
+    async fn notify_node_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_id = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let user = DynamoDbUser {
+            node_id: Some(node_id),
+            ..Default::default()
+        };
+        let router = make_router(Arc::new(MockDbClient::with_user(user.clone())));
+
+        let error = router.notify_node(&user).await.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    /// A broadcast change is sent to every node, and nodes which can't be
+    /// reached are skipped
+    #[actix_rt::test]
+    async fn notify_broadcast_change() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable_node = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let metrics = TestMetricSink::default();
+        let mut router = make_router(Arc::new(MockDbClient::default()));
+        router.metrics = metrics.client();
+        let broadcast = DynamoDbBroadcast {
+            broadcast_id: "test/flag".to_string(),
+            version: "v2".to_string(),
+            updated_at: 0,
+        };
+        let node_mock = mockito::mock("PUT", "/broadcast")
+            .match_body(Matcher::Json(
+                json!({ "broadcasts": { "test/flag": "v2" } }),
+            ))
+            .with_status(200)
+            .create();
+
+        let nodes = vec![mockito::server_url(), unreachable_node];
+        let notified = router.notify_broadcast_change(&nodes, &broadcast).await;
+        assert_eq!(notified, 1);
+        node_mock.assert();
+
+        let metrics = metrics.metrics();
+        assert!(metrics.contains(&"autoendpoint.broadcast.node.sent:1|c".to_string()));
+        assert!(metrics.contains(&"autoendpoint.broadcast.node.error:1|c".to_string()));
+    }
+
+    /// Asynchronous responses skip the direct send and store the notification
+    #[actix_rt::test]
+    async fn respond_async_skips_node() {
+        let user = make_user();
+        let ddb = Arc::new(MockDbClient::with_user(user.clone()));
+        let router = make_router(ddb.clone());
+        let notification = make_notification(user.clone(), true);
+        let node_mock = mockito::mock("PUT", format!("/push/{}", user.uaid).as_str())
+            .expect(0)
+            .create();
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(ddb.stored_messages().len(), 1);
+        node_mock.assert();
+    }
+}
@@ -0,0 +1,81 @@
+use crate::server::headers::util::get_header;
+use actix_http::{Payload, PayloadStream};
+use actix_web::{FromRequest, HttpRequest};
+use futures::future;
+
+/// Extracts whether the sender only wants to validate the notification. This
+/// is requested with the `dryRun=true` or `dryrun=1` query parameters, or the
+/// `Dry-Run: true` or `Prefer: dry-run` headers. A dry run validates the notification and selects its router, but
+/// does not deliver or store it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DryRun(pub bool);
+
+impl FromRequest for DryRun {
+    type Error = ();
+    type Future = future::Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload<PayloadStream>) -> Self::Future {
+        let query_dry_run = url::form_urlencoded::parse(req.query_string().as_bytes())
+            .any(|(key, value)| (key == "dryRun" || key == "dryrun") && Self::is_enabled(&value));
+        let header_dry_run = get_header(req, "dry-run").map_or(false, Self::is_enabled);
+        let prefer_dry_run = get_header(req, "prefer")
+            .map(|prefer| {
+                prefer
+                    .split(',')
+                    .any(|preference| preference.trim().eq_ignore_ascii_case("dry-run"))
+            })
+            .unwrap_or(false);
+
+        future::ok(DryRun(query_dry_run || header_dry_run || prefer_dry_run))
+    }
+}
+
+impl DryRun {
+    /// Check if a query parameter or header value enables the dry run
+    fn is_enabled(value: &str) -> bool {
+        value == "1" || value.eq_ignore_ascii_case("true")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DryRun;
+    use actix_web::test::TestRequest;
+    use actix_web::FromRequest;
+
+    /// Extract the dry run flag from a test request
+    async fn extract(req: TestRequest) -> bool {
+        let (req, mut payload) = req.to_http_parts();
+        DryRun::from_request(&req, &mut payload).await.unwrap().0
+    }
+
+    /// The query parameter requests a dry run
+    #[actix_rt::test]
+    async fn query_param() {
+        assert!(extract(TestRequest::post().uri("/wpush/v1/token?dryRun=true")).await);
+        assert!(!extract(TestRequest::post().uri("/wpush/v1/token?dryRun=false")).await);
+        assert!(extract(TestRequest::post().uri("/wpush/v1/token?dryrun=1")).await);
+        assert!(!extract(TestRequest::post().uri("/wpush/v1/token?dryrun=0")).await);
+    }
+
+    /// The Dry-Run header requests a dry run
+    #[actix_rt::test]
+    async fn dry_run_header() {
+        assert!(extract(TestRequest::post().header("Dry-Run", "true")).await);
+        assert!(!extract(TestRequest::post().header("Dry-Run", "false")).await);
+    }
+
+    /// The Prefer header requests a dry run, alongside other preferences
+    #[actix_rt::test]
+    async fn prefer_header() {
+        assert!(extract(TestRequest::post().header("Prefer", "respond-async, dry-run")).await);
+        assert!(!extract(TestRequest::post().header("Prefer", "respond-async")).await);
+    }
+
+    /// Requests are not dry runs by default
+    #[actix_rt::test]
+    async fn default() {
+        assert!(!extract(TestRequest::post()).await);
+    }
+}
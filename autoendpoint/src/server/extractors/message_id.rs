@@ -0,0 +1,208 @@
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::server::ServerState;
+use actix_http::{Payload, PayloadStream};
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpRequest};
+use fernet::MultiFernet;
+use futures::future;
+use uuid::Uuid;
+
+/// A notification's message ID, which identifies the stored notification. The
+/// message ID is encrypted and signed when used in the receipt URL
+/// (`/m/{message_id}`), so it can't be forged or modified.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MessageId {
+    /// A topic message, which replaces earlier messages with the same topic
+    WithTopic {
+        uaid: Uuid,
+        channel_id: Uuid,
+        topic: String,
+    },
+    WithoutTopic {
+        uaid: Uuid,
+        channel_id: Uuid,
+        timestamp: u64,
+    },
+}
+
+impl FromRequest for MessageId {
+    type Error = ApiError;
+    type Future = future::Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload<PayloadStream>) -> Self::Future {
+        let message_id = req
+            .match_info()
+            .get("message_id")
+            .expect("{message_id} must be part of the path");
+        let state = req
+            .app_data::<Data<ServerState>>()
+            .expect("No server state found");
+
+        future::ready(MessageId::decode(&state.fernet, message_id))
+    }
+}
+
+impl MessageId {
+    /// Encode and encrypt the message ID.
+    ///
+    /// For topic messages, a sort_key version of 01 is used, and the topic
+    /// is included for reference:
+    ///
+    ///     Encrypted('01' : uaid.hex : channel_id.hex : topic)
+    ///
+    /// For non-topic messages, a sort_key version of 02 is used:
+    ///
+    ///     Encrypted('02' : uaid.hex : channel_id.hex : timestamp)
+    pub fn encode(&self, fernet: &MultiFernet) -> String {
+        let message_id = match self {
+            MessageId::WithTopic {
+                uaid,
+                channel_id,
+                topic,
+            } => format!(
+                "01:{}:{}:{}",
+                uaid.to_simple_ref(),
+                channel_id.to_simple_ref(),
+                topic
+            ),
+            MessageId::WithoutTopic {
+                uaid,
+                channel_id,
+                timestamp,
+            } => format!(
+                "02:{}:{}:{}",
+                uaid.to_simple_ref(),
+                channel_id.to_simple_ref(),
+                timestamp
+            ),
+        };
+
+        fernet.encrypt(message_id.as_bytes())
+    }
+
+    /// Decrypt and decode the message ID. Message IDs which can't be
+    /// decrypted (ex. they were modified) are rejected.
+    pub fn decode(fernet: &MultiFernet, message_id: &str) -> ApiResult<Self> {
+        let decrypted_bytes = fernet
+            .decrypt(message_id)
+            .map_err(|_| ApiErrorKind::InvalidMessageId)?;
+        let decrypted =
+            String::from_utf8(decrypted_bytes).map_err(|_| ApiErrorKind::InvalidMessageId)?;
+
+        let parts: Vec<_> = decrypted.splitn(4, ':').collect();
+        if parts.len() != 4 {
+            return Err(ApiErrorKind::InvalidMessageId.into());
+        }
+
+        let uaid = Uuid::parse_str(parts[1]).map_err(|_| ApiErrorKind::InvalidMessageId)?;
+        let channel_id = Uuid::parse_str(parts[2]).map_err(|_| ApiErrorKind::InvalidMessageId)?;
+
+        match parts[0] {
+            "01" => Ok(MessageId::WithTopic {
+                uaid,
+                channel_id,
+                topic: parts[3].to_string(),
+            }),
+            "02" => Ok(MessageId::WithoutTopic {
+                uaid,
+                channel_id,
+                timestamp: parts[3]
+                    .parse()
+                    .map_err(|_| ApiErrorKind::InvalidMessageId)?,
+            }),
+            _ => Err(ApiErrorKind::InvalidMessageId.into()),
+        }
+    }
+
+    /// Get the UAID of the user the message was sent to
+    pub fn uaid(&self) -> Uuid {
+        match self {
+            MessageId::WithTopic { uaid, .. } | MessageId::WithoutTopic { uaid, .. } => *uaid,
+        }
+    }
+
+    /// Get the sort key the message is stored under in the database
+    pub fn sort_key(&self) -> String {
+        match self {
+            MessageId::WithTopic {
+                channel_id, topic, ..
+            } => format!("01:{}:{}", channel_id.to_hyphenated_ref(), topic),
+            MessageId::WithoutTopic {
+                channel_id,
+                timestamp,
+                ..
+            } => format!("02:{}:{}", timestamp, channel_id.to_hyphenated_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageId;
+    use crate::error::ApiErrorKind;
+    use fernet::{Fernet, MultiFernet};
+    use uuid::Uuid;
+
+    const UAID: &str = "deadbeef-0000-0000-0000-000000000001";
+    const CHANNEL_ID: &str = "deadbeef-13f9-4639-87f9-2ff731824f34";
+
+    /// Create the fernet used to encrypt message IDs
+    fn make_fernet() -> MultiFernet {
+        MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()])
+    }
+
+    /// Topic message IDs can be decoded after encoding
+    #[test]
+    fn round_trip_with_topic() {
+        let fernet = make_fernet();
+        let message_id = MessageId::WithTopic {
+            uaid: Uuid::parse_str(UAID).unwrap(),
+            channel_id: Uuid::parse_str(CHANNEL_ID).unwrap(),
+            topic: "test-topic".to_string(),
+        };
+
+        let decoded = MessageId::decode(&fernet, &message_id.encode(&fernet)).unwrap();
+        assert_eq!(decoded, message_id);
+        assert_eq!(decoded.sort_key(), format!("01:{}:test-topic", CHANNEL_ID));
+    }
+
+    /// Non-topic message IDs can be decoded after encoding
+    #[test]
+    fn round_trip_without_topic() {
+        let fernet = make_fernet();
+        let message_id = MessageId::WithoutTopic {
+            uaid: Uuid::parse_str(UAID).unwrap(),
+            channel_id: Uuid::parse_str(CHANNEL_ID).unwrap(),
+            timestamp: 1_600_000_000_000,
+        };
+
+        let decoded = MessageId::decode(&fernet, &message_id.encode(&fernet)).unwrap();
+        assert_eq!(decoded, message_id);
+        assert_eq!(decoded.uaid(), Uuid::parse_str(UAID).unwrap());
+        assert_eq!(
+            decoded.sort_key(),
+            format!("02:1600000000000:{}", CHANNEL_ID)
+        );
+    }
+
+    /// A modified message ID is rejected
+    #[test]
+    fn tampered_message_id() {
+        let fernet = make_fernet();
+        let message_id = MessageId::WithoutTopic {
+            uaid: Uuid::parse_str(UAID).unwrap(),
+            channel_id: Uuid::parse_str(CHANNEL_ID).unwrap(),
+            timestamp: 1_600_000_000_000,
+        };
+        let mut encoded = message_id.encode(&fernet).into_bytes();
+        let index = encoded.len() / 2;
+        encoded[index] = if encoded[index] == b'A' { b'B' } else { b'A' };
+        let encoded = String::from_utf8(encoded).unwrap();
+
+        match MessageId::decode(&fernet, &encoded).unwrap_err().kind {
+            ApiErrorKind::InvalidMessageId => {}
+            kind => panic!("Expected an invalid message ID error, got {:?}", kind),
+        }
+    }
+}
@@ -1,8 +1,11 @@
 //! Actix extractors (`FromRequest`). These extractors transform and validate
 //! the incoming request data.
 
+pub mod dry_run;
+pub mod message_id;
 pub mod notification;
 pub mod notification_headers;
 pub mod subscription;
 pub mod token_info;
 pub mod user;
+pub mod vapid;
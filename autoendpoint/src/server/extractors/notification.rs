@@ -1,22 +1,101 @@
-use crate::error::{ApiError, ApiErrorKind};
-use crate::server::extractors::notification_headers::NotificationHeaders;
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::logging;
+use crate::routers::RouterType;
+use crate::server::extractors::message_id::MessageId;
+use crate::server::extractors::notification_headers::{NotificationHeaders, Urgency};
 use crate::server::extractors::subscription::Subscription;
+use crate::server::headers::util::get_header;
+use crate::server::request_id::RequestId;
 use crate::server::ServerState;
+use actix_http::encoding::Decoder;
 use actix_web::dev::{Payload, PayloadStream};
-use actix_web::web::Data;
+use actix_web::error::PayloadError;
+use actix_web::http::ContentEncoding;
+use actix_web::web::{Bytes, Data};
 use actix_web::{FromRequest, HttpRequest};
-use autopush_common::util::sec_since_epoch;
+use autopush_common::db::DynamoDbUser;
+use autopush_common::util::{ms_since_epoch, sec_since_epoch};
 use cadence::Counted;
-use futures::{future, FutureExt, StreamExt};
+use fernet::MultiFernet;
+use futures::{future, FutureExt, Stream, StreamExt};
+use slog::slog_o;
+use std::collections::HashMap;
+use std::pin::Pin;
+use uuid::Uuid;
+
+/// The header which names the compression applied to the request body for
+/// transport. `Content-Encoding` is the WebPush encryption scheme, and the
+/// HTTP/1 parser only understands `chunked` in `Transfer-Encoding`, so the
+/// compression is given separately.
+pub const PAYLOAD_COMPRESSION_HEADER: &str = "x-payload-compression";
+
+/// The header which names the region the request was sent from, set by the
+/// load balancer
+pub const REGION_HEADER: &str = "x-region";
+
+/// The request body, after any transport compression has been removed
+type PayloadBody = Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>>;
+
+/// The version of the format used to deliver notifications to the connection
+/// server, so nodes can support several formats during a rolling upgrade.
+/// Increment this when the fields sent to the node change. This is sent as
+/// `schema` because `version` is the message ID.
+pub const DELIVERY_SCHEMA_VERSION: u64 = 1;
 
 /// Extracts notification data from `Subscription` and request data
+#[derive(Clone)]
 pub struct Notification {
+    pub message_id: String,
     pub subscription: Subscription,
     pub headers: NotificationHeaders,
     pub timestamp: u64,
+    pub sort_key_timestamp: u64,
+    pub data: Option<String>,
+    /// The ID of the request which sent the notification, used to correlate
+    /// the logs of the endpoint and the connection node
+    pub request_id: Option<String>,
+    /// The region the notification was sent from, which selects the
+    /// endpoint URL of its `Location` header
+    pub region: Option<String>,
+}
+
+/// The parts of a notification which don't depend on the subscription, so the
+/// same notification can be sent to many subscriptions
+#[derive(Clone)]
+pub struct NotificationBody {
+    pub headers: NotificationHeaders,
+    pub timestamp: u64,
+    pub sort_key_timestamp: u64,
     pub data: Option<String>,
 }
 
+impl NotificationBody {
+    /// Create the notification for a subscription
+    pub fn for_subscription(
+        &self,
+        subscription: Subscription,
+        fernet: &MultiFernet,
+    ) -> Notification {
+        let message_id = Notification::generate_message_id(
+            fernet,
+            &subscription,
+            &self.headers,
+            self.sort_key_timestamp,
+        );
+
+        Notification {
+            message_id,
+            subscription,
+            headers: self.headers.clone(),
+            timestamp: self.timestamp,
+            sort_key_timestamp: self.sort_key_timestamp,
+            data: self.data.clone(),
+            request_id: None,
+            region: None,
+        }
+    }
+}
+
 impl FromRequest for Notification {
     type Error = ApiError;
     type Future = future::LocalBoxFuture<'static, Result<Self, Self::Error>>;
@@ -24,7 +103,7 @@ impl FromRequest for Notification {
 
     fn from_request(req: &HttpRequest, payload: &mut Payload<PayloadStream>) -> Self::Future {
         let req = req.clone();
-        let mut payload = payload.take();
+        let payload = payload.take();
 
         async move {
             let subscription = Subscription::extract(&req).await?;
@@ -32,16 +111,22 @@ impl FromRequest for Notification {
                 .await
                 .expect("No server state found");
 
-            // Read data
-            let mut data = Vec::new();
-            while let Some(item) = payload.next().await {
-                data.extend_from_slice(&item.map_err(ApiErrorKind::PayloadError)?);
-
-                // Make sure the payload isn't too big
-                let max_bytes = state.settings.max_data_bytes;
-                if data.len() > max_bytes {
-                    return Err(ApiErrorKind::PayloadTooLarge(max_bytes).into());
-                }
+            let payload = Self::decompress_payload(&req, payload)?;
+            let data = Self::read_payload(payload, state.settings.max_data_bytes).await?;
+            let headers = NotificationHeaders::from_request(
+                &req,
+                !data.is_empty(),
+                state.settings.max_ttl,
+                state.settings.max_crypto_header_len,
+                &state.settings.content_encodings,
+            )?;
+            headers.assert_topic_not_reserved(&state.settings.reserved_topic_prefixes)?;
+            headers.assert_push_receipt_allowed(
+                &state.settings.receipt_url(),
+                &state.settings.push_receipt_allowed_hosts,
+            )?;
+            if !data.is_empty() {
+                headers.validate_payload(&data)?;
             }
 
             // Convert data to base64
@@ -50,8 +135,12 @@ impl FromRequest for Notification {
             } else {
                 Some(base64::encode_config(data, base64::URL_SAFE_NO_PAD))
             };
-
-            let headers = NotificationHeaders::from_request(&req, data.is_some())?;
+            NotificationHeaders::record_ttl_clamping(
+                &req,
+                state.settings.max_ttl,
+                subscription.router_type,
+                &state.metrics,
+            );
 
             // Record the encoding if we have an encrypted payload
             if let Some(encoding) = &headers.content_encoding {
@@ -63,13 +152,648 @@ impl FromRequest for Notification {
                 }
             }
 
+            let sort_key_timestamp = ms_since_epoch();
+            let message_id = Self::generate_message_id(
+                &state.fernet,
+                &subscription,
+                &headers,
+                sort_key_timestamp,
+            );
+
             Ok(Notification {
+                message_id,
                 subscription,
                 headers,
                 timestamp: sec_since_epoch(),
+                sort_key_timestamp,
                 data,
+                request_id: RequestId::of(&req),
+                region: Self::region(&req),
             })
         }
         .boxed_local()
     }
 }
+
+impl From<Notification> for autopush_common::notification::Notification {
+    fn from(notification: Notification) -> Self {
+        // Topic messages are stored under their topic instead of a timestamp,
+        // so they replace earlier messages with the same topic
+        let sortkey_timestamp = if notification.is_topic() {
+            None
+        } else {
+            Some(notification.sort_key_timestamp)
+        };
+        let topic = notification.headers.topic.clone();
+        // The encryption headers only apply to the payload, so bodyless
+        // notifications don't store them
+        let headers: HashMap<String, String> = if notification.data.is_some() {
+            notification.headers.clone().into()
+        } else {
+            HashMap::new()
+        };
+
+        autopush_common::notification::Notification {
+            channel_id: notification.subscription.channel_id,
+            version: notification.message_id,
+            ttl: notification.headers.ttl.unwrap_or(0) as u64,
+            topic,
+            timestamp: notification.timestamp,
+            receipt_url: notification.headers.push_receipt.clone(),
+            data: notification.data,
+            sortkey_timestamp,
+            headers: if headers.is_empty() {
+                None
+            } else {
+                Some(headers)
+            },
+        }
+    }
+}
+
+impl Notification {
+    /// Check if the notification has a topic. Topic notifications replace
+    /// undelivered notifications with the same topic (RFC 8030 section 5.4),
+    /// but still expire according to their own TTL.
+    pub fn is_topic(&self) -> bool {
+        self.headers.topic.is_some()
+    }
+
+    /// Get the request ID for logging, or an empty string if there is none
+    pub fn request_id(&self) -> &str {
+        self.request_id.as_deref().unwrap_or_default()
+    }
+
+    /// Create a logger which adds the notification's UAID, channel ID, message
+    /// ID and request ID to every record, so all the logs about the
+    /// notification can be correlated. The UAID is hashed in release builds.
+    pub fn logger(&self) -> slog::Logger {
+        self.logger_from(&slog_scope::logger())
+    }
+
+    /// Add the notification's logging context to `parent`
+    fn logger_from(&self, parent: &slog::Logger) -> slog::Logger {
+        parent.new(slog_o!(
+            "uaid" => logging::log_uaid(&self.subscription.user.uaid),
+            "channel_id" => self.subscription.channel_id.to_string(),
+            "message_id" => self.message_id.clone(),
+            "request_id" => self.request_id().to_string(),
+        ))
+    }
+
+    /// Get the region the request was sent from, if the load balancer named
+    /// it
+    fn region(req: &HttpRequest) -> Option<String> {
+        get_header(req, REGION_HEADER)
+            .map(|region| region.trim().to_ascii_lowercase())
+            .filter(|region| !region.is_empty())
+    }
+
+    /// Remove the transport compression from the request body, if the sender
+    /// compressed it with gzip or deflate
+    fn decompress_payload(
+        req: &HttpRequest,
+        payload: Payload<PayloadStream>,
+    ) -> ApiResult<PayloadBody> {
+        let encoding = match get_header(req, PAYLOAD_COMPRESSION_HEADER).map(str::trim) {
+            None => return Ok(Box::pin(payload)),
+            Some(compression) if compression.eq_ignore_ascii_case("identity") => {
+                return Ok(Box::pin(payload))
+            }
+            Some(compression) if compression.eq_ignore_ascii_case("gzip") => ContentEncoding::Gzip,
+            Some(compression) if compression.eq_ignore_ascii_case("deflate") => {
+                ContentEncoding::Deflate
+            }
+            Some(compression) => {
+                return Err(ApiErrorKind::UnsupportedCompression(compression.to_string()).into())
+            }
+        };
+
+        Ok(Box::pin(Decoder::new(payload, encoding)))
+    }
+
+    /// Read the raw (encrypted) payload, stopping as soon as it is larger than
+    /// `max_bytes`. Compressed payloads are checked after decompression.
+    async fn read_payload(
+        mut payload: impl Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+        max_bytes: usize,
+    ) -> ApiResult<Vec<u8>> {
+        let mut data = Vec::new();
+        while let Some(item) = payload.next().await {
+            data.extend_from_slice(&item.map_err(ApiErrorKind::PayloadError)?);
+
+            // Make sure the payload isn't too big
+            if data.len() > max_bytes {
+                return Err(ApiErrorKind::PayloadTooLarge(max_bytes).into());
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Generate a message-id suitable for accessing the message
+    fn generate_message_id(
+        fernet: &MultiFernet,
+        subscription: &Subscription,
+        headers: &NotificationHeaders,
+        sort_key_timestamp: u64,
+    ) -> String {
+        let uaid = subscription.user.uaid;
+        let channel_id = subscription.channel_id;
+
+        let message_id = match &headers.topic {
+            Some(topic) => MessageId::WithTopic {
+                uaid,
+                channel_id,
+                topic: topic.clone(),
+            },
+            None => MessageId::WithoutTopic {
+                uaid,
+                channel_id,
+                timestamp: sort_key_timestamp,
+            },
+        };
+
+        message_id.encode(fernet)
+    }
+
+    /// Serialize the notification for delivery to the connection server. Some
+    /// fields in `autopush_common`'s `Notification` are marked with
+    /// `#[serde(skip_serializing)]` so they are not shown to the UA. These
+    /// fields are still required when delivering to the connection server, so
+    /// we can't simply convert this notification type to that one and
+    /// serialize via serde.
+    ///
+    /// The delivered fields are:
+    /// - `schema`: `DELIVERY_SCHEMA_VERSION`
+    /// - `channelID`, `version` (the message ID) and `timestamp`
+    /// - `ttl`: the TTL in seconds, after clamping to the max TTL
+    /// - `topic`: the topic, or null
+    /// - `urgency`: one of `very-low`, `low`, `normal` or `high`
+    /// - `data` and `headers`: the encrypted payload and its encryption
+    ///   headers, only present if there is a payload
+    pub fn serialize_for_delivery(&self) -> HashMap<&'static str, serde_json::Value> {
+        let mut map = HashMap::new();
+
+        map.insert(
+            "schema",
+            serde_json::to_value(DELIVERY_SCHEMA_VERSION).unwrap(),
+        );
+        map.insert(
+            "channelID",
+            serde_json::to_value(&self.subscription.channel_id).unwrap(),
+        );
+        map.insert("version", serde_json::to_value(&self.message_id).unwrap());
+        map.insert(
+            "ttl",
+            serde_json::to_value(self.headers.ttl.unwrap_or(0)).unwrap(),
+        );
+        map.insert("topic", serde_json::to_value(&self.headers.topic).unwrap());
+        map.insert(
+            "urgency",
+            serde_json::to_value(self.headers.urgency).unwrap(),
+        );
+        map.insert("timestamp", serde_json::to_value(self.timestamp).unwrap());
+
+        if let Some(data) = &self.data {
+            map.insert("data", serde_json::to_value(data).unwrap());
+
+            let headers: HashMap<String, String> = self.headers.clone().into();
+            map.insert("headers", serde_json::to_value(headers).unwrap());
+        }
+
+        map
+    }
+}
+
+/// Builds a notification without a request, running the same validation as
+/// the `Notification` extractor. This is used to replay notifications, and by
+/// tests.
+#[allow(dead_code)] // Not every setter is used outside of tests yet
+pub struct NotificationBuilder {
+    user: DynamoDbUser,
+    router_type: RouterType,
+    channel_id: Uuid,
+    message_id: Option<String>,
+    data: Option<Vec<u8>>,
+    headers: NotificationHeaders,
+}
+
+#[allow(dead_code)] // Not every setter is used outside of tests yet
+impl NotificationBuilder {
+    /// Start building a notification for a new WebPush user
+    pub fn new() -> Self {
+        NotificationBuilder {
+            user: DynamoDbUser::default(),
+            router_type: RouterType::WebPush,
+            channel_id: Uuid::new_v4(),
+            message_id: None,
+            data: None,
+            headers: NotificationHeaders {
+                ttl: None,
+                topic: None,
+                urgency: Urgency::default(),
+                respond_async: false,
+                content_encoding: None,
+                encryption: None,
+                encryption_key: None,
+                crypto_key: None,
+                idempotency_key: None,
+                push_receipt: None,
+            },
+        }
+    }
+
+    /// Send the notification to this user. The router type is taken from the
+    /// user record.
+    pub fn user(mut self, user: DynamoDbUser) -> Self {
+        self.router_type = user.router_type.parse().unwrap_or(RouterType::WebPush);
+        self.user = user;
+        self
+    }
+
+    pub fn uaid(mut self, uaid: Uuid) -> Self {
+        self.user.uaid = uaid;
+        self
+    }
+
+    pub fn router_type(mut self, router_type: RouterType) -> Self {
+        self.router_type = router_type;
+        self.user.router_type = router_type.to_string();
+        self
+    }
+
+    pub fn channel_id(mut self, channel_id: Uuid) -> Self {
+        self.channel_id = channel_id;
+        self
+    }
+
+    /// Defaults to a random message ID
+    pub fn message_id(mut self, message_id: impl Into<String>) -> Self {
+        self.message_id = Some(message_id.into());
+        self
+    }
+
+    /// The raw (encrypted) payload
+    pub fn data(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    pub fn ttl(mut self, ttl: i64) -> Self {
+        self.headers.ttl = Some(ttl);
+        self
+    }
+
+    pub fn topic(mut self, topic: impl Into<String>) -> Self {
+        self.headers.topic = Some(topic.into());
+        self
+    }
+
+    pub fn urgency(mut self, urgency: Urgency) -> Self {
+        self.headers.urgency = urgency;
+        self
+    }
+
+    pub fn respond_async(mut self, respond_async: bool) -> Self {
+        self.headers.respond_async = respond_async;
+        self
+    }
+
+    pub fn content_encoding(mut self, content_encoding: impl Into<String>) -> Self {
+        self.headers.content_encoding = Some(content_encoding.into());
+        self
+    }
+
+    pub fn encryption(mut self, encryption: impl Into<String>) -> Self {
+        self.headers.encryption = Some(encryption.into());
+        self
+    }
+
+    pub fn encryption_key(mut self, encryption_key: impl Into<String>) -> Self {
+        self.headers.encryption_key = Some(encryption_key.into());
+        self
+    }
+
+    pub fn crypto_key(mut self, crypto_key: impl Into<String>) -> Self {
+        self.headers.crypto_key = Some(crypto_key.into());
+        self
+    }
+
+    pub fn idempotency_key(mut self, idempotency_key: impl Into<String>) -> Self {
+        self.headers.idempotency_key = Some(idempotency_key.into());
+        self
+    }
+
+    /// Validate the headers and payload, and build the notification
+    pub fn build(self) -> ApiResult<Notification> {
+        let body = Self::make_body(self.headers, self.data)?;
+
+        Ok(Notification {
+            message_id: self
+                .message_id
+                .unwrap_or_else(|| Uuid::new_v4().to_simple().to_string()),
+            subscription: Subscription {
+                user: self.user,
+                router_type: self.router_type,
+                channel_id: self.channel_id,
+                vapid: None,
+            },
+            headers: body.headers,
+            timestamp: body.timestamp,
+            sort_key_timestamp: body.sort_key_timestamp,
+            data: body.data,
+            request_id: None,
+            region: None,
+        })
+    }
+
+    /// Validate the headers and payload, and build the parts of the
+    /// notification which don't depend on the subscription. The user, channel
+    /// and message ID are ignored.
+    pub fn build_body(self) -> ApiResult<NotificationBody> {
+        Self::make_body(self.headers, self.data)
+    }
+
+    fn make_body(
+        headers: NotificationHeaders,
+        data: Option<Vec<u8>>,
+    ) -> ApiResult<NotificationBody> {
+        let data = data.filter(|data| !data.is_empty());
+        let headers = headers.validated(data.is_some())?;
+        if let Some(data) = &data {
+            headers.validate_payload(data)?;
+        }
+
+        Ok(NotificationBody {
+            headers,
+            timestamp: sec_since_epoch(),
+            sort_key_timestamp: ms_since_epoch(),
+            data: data.map(|data| base64::encode_config(data, base64::URL_SAFE_NO_PAD)),
+        })
+    }
+}
+
+impl Default for NotificationBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Notification, NotificationBuilder, DELIVERY_SCHEMA_VERSION, PAYLOAD_COMPRESSION_HEADER,
+        REGION_HEADER,
+    };
+    use crate::error::ApiErrorKind;
+    use crate::logging::{log_uaid, TestLogDrain};
+    use crate::routers::RouterType;
+    use crate::server::extractors::notification_headers::Urgency;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use autopush_common::db::DynamoDbUser;
+    use serde_json::json;
+    use slog::slog_debug;
+    use uuid::Uuid;
+
+    const MAX_BYTES: usize = 4096;
+    /// "test-data", gzipped
+    const GZIPPED_DATA: [u8; 29] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x2b, 0x49, 0x2d, 0x2e, 0xd1,
+        0x4d, 0x49, 0x2c, 0x49, 0x04, 0x00, 0x03, 0x6a, 0x98, 0x2b, 0x09, 0x00, 0x00, 0x00,
+    ];
+    /// 5000 zero bytes, gzipped
+    const GZIPPED_ZEROS: [u8; 40] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0xed, 0xc1, 0x31, 0x01, 0x00,
+        0x00, 0x00, 0xc2, 0xa0, 0xf5, 0x4f, 0x6d, 0x0a, 0x3f, 0xa0, 0x00, 0x00, 0x00, 0x00, 0x80,
+        0xb7, 0x01, 0xa8, 0x0e, 0xe5, 0xd8, 0x88, 0x13, 0x00, 0x00,
+    ];
+
+    /// A payload at the limit is read in full
+    #[actix_rt::test]
+    async fn payload_under_limit() {
+        let (_, payload) = TestRequest::post()
+            .set_payload(vec![0u8; MAX_BYTES])
+            .to_http_parts();
+
+        let data = Notification::read_payload(payload, MAX_BYTES)
+            .await
+            .unwrap();
+        assert_eq!(data.len(), MAX_BYTES);
+    }
+
+    /// A payload over the limit is rejected with a 413 and an errno
+    #[actix_rt::test]
+    async fn payload_over_limit() {
+        let (_, payload) = TestRequest::post()
+            .set_payload(vec![0u8; MAX_BYTES + 1])
+            .to_http_parts();
+
+        let error = Notification::read_payload(payload, MAX_BYTES)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error.kind.errno(), Some(104));
+        match error.kind {
+            ApiErrorKind::PayloadTooLarge(MAX_BYTES) => {}
+            kind => panic!("Expected a PayloadTooLarge error, got {:?}", kind),
+        }
+    }
+
+    /// A gzipped payload is decompressed
+    #[actix_rt::test]
+    async fn gzipped_payload() {
+        let (req, payload) = TestRequest::post()
+            .header(PAYLOAD_COMPRESSION_HEADER, "gzip")
+            .set_payload(&GZIPPED_DATA[..])
+            .to_http_parts();
+
+        let payload = Notification::decompress_payload(&req, payload).unwrap();
+        let data = Notification::read_payload(payload, MAX_BYTES)
+            .await
+            .unwrap();
+        assert_eq!(data, b"test-data");
+    }
+
+    /// The size limit applies to the decompressed payload
+    #[actix_rt::test]
+    async fn gzipped_payload_over_limit() {
+        let (req, payload) = TestRequest::post()
+            .header(PAYLOAD_COMPRESSION_HEADER, "gzip")
+            .set_payload(&GZIPPED_ZEROS[..])
+            .to_http_parts();
+
+        let payload = Notification::decompress_payload(&req, payload).unwrap();
+        let error = Notification::read_payload(payload, MAX_BYTES)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    /// Unknown compression is rejected
+    #[test]
+    fn unsupported_compression() {
+        let (req, payload) = TestRequest::post()
+            .header(PAYLOAD_COMPRESSION_HEADER, "br")
+            .to_http_parts();
+
+        let error = Notification::decompress_payload(&req, payload)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+        match error.kind {
+            ApiErrorKind::UnsupportedCompression(compression) => assert_eq!(compression, "br"),
+            kind => panic!("Expected an UnsupportedCompression error, got {:?}", kind),
+        }
+    }
+
+    /// The serialized notification includes the schema version, and the
+    /// effective TTL and urgency
+    #[test]
+    fn serialize_for_delivery_ttl_and_urgency() {
+        let notification = NotificationBuilder::new()
+            .ttl(3600)
+            .urgency(Urgency::VeryLow)
+            .build()
+            .unwrap();
+
+        let serialized = notification.serialize_for_delivery();
+        // Changing the schema version must be a deliberate change
+        assert_eq!(DELIVERY_SCHEMA_VERSION, 1);
+        assert_eq!(serialized["schema"], json!(DELIVERY_SCHEMA_VERSION));
+        assert_eq!(serialized["ttl"], json!(3600));
+        assert_eq!(serialized["urgency"], json!("very-low"));
+        assert!(!serialized.contains_key("data"));
+    }
+
+    /// The builder sets the notification fields and encodes the payload
+    #[test]
+    fn builder() {
+        let uaid = Uuid::new_v4();
+        let channel_id = Uuid::new_v4();
+        let notification = NotificationBuilder::new()
+            .uaid(uaid)
+            .router_type(RouterType::Fcm)
+            .channel_id(channel_id)
+            .message_id("test-message-id")
+            .ttl(60)
+            .topic("test-topic")
+            .content_encoding("aesgcm")
+            .encryption("salt=AQIDBAUGBwgJCgsMDQ4PEA")
+            .crypto_key(
+                "dh=BAoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISIjJCUmJygpKissLS4vMDEyMzQ1Njc4OTo7PD0-P0BBQkNERUZHSEk",
+            )
+            .data(&b"test-data"[..])
+            .build()
+            .unwrap();
+
+        assert_eq!(notification.message_id, "test-message-id");
+        assert_eq!(notification.subscription.user.uaid, uaid);
+        assert_eq!(notification.subscription.user.router_type, "fcm");
+        assert_eq!(notification.subscription.router_type, RouterType::Fcm);
+        assert_eq!(notification.subscription.channel_id, channel_id);
+        assert_eq!(notification.headers.ttl, Some(60));
+        assert_eq!(notification.headers.topic, Some("test-topic".to_string()));
+        assert_eq!(notification.data, Some("dGVzdC1kYXRh".to_string()));
+    }
+
+    /// The builder runs the same validation as the extractor
+    #[test]
+    fn builder_validation_failure() {
+        let result = NotificationBuilder::new()
+            .content_encoding("aesgcm")
+            .data(&b"test-data"[..])
+            .build();
+
+        match result.map(|_| ()).unwrap_err().kind {
+            ApiErrorKind::InvalidEncryption(message) => {
+                assert_eq!(message, "Missing Encryption header")
+            }
+            kind => panic!("Expected an encryption error, got {:?}", kind),
+        }
+    }
+
+    /// Notifications with a topic are topic notifications
+    #[test]
+    fn is_topic() {
+        let notification = NotificationBuilder::new().build().unwrap();
+        assert!(!notification.is_topic());
+
+        let notification = NotificationBuilder::new()
+            .topic("test-topic")
+            .build()
+            .unwrap();
+        assert!(notification.is_topic());
+    }
+
+    /// The region is read from the header, ignoring case and blank values
+    #[test]
+    fn region_header() {
+        let req = TestRequest::default()
+            .header(REGION_HEADER, " EU-West ")
+            .to_http_request();
+        assert_eq!(Notification::region(&req), Some("eu-west".to_string()));
+
+        let req = TestRequest::default()
+            .header(REGION_HEADER, " ")
+            .to_http_request();
+        assert_eq!(Notification::region(&req), None);
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(Notification::region(&req), None);
+    }
+
+    /// The notification's logging context is added to each record
+    #[test]
+    fn logging_context() {
+        let uaid = Uuid::new_v4();
+        let channel_id = Uuid::new_v4();
+        let mut notification = NotificationBuilder::new()
+            .uaid(uaid)
+            .channel_id(channel_id)
+            .message_id("test-message-id")
+            .build()
+            .unwrap();
+        notification.request_id = Some("test-request-id".to_string());
+        let drain = TestLogDrain::default();
+
+        let log = notification.logger_from(&drain.logger());
+        slog_debug!(log, "Routing notification"; "node_id" => "http://node:8081");
+
+        let records = drain.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["uaid"], log_uaid(&uaid));
+        assert_eq!(records[0]["channel_id"], channel_id.to_string());
+        assert_eq!(records[0]["message_id"], "test-message-id");
+        assert_eq!(records[0]["request_id"], "test-request-id");
+        assert_eq!(records[0]["node_id"], "http://node:8081");
+    }
+
+    /// Stored topic notifications keep their topic and TTL, and are stored
+    /// under the topic instead of a timestamp
+    #[test]
+    fn stored_topic_ttl() {
+        let notification = NotificationBuilder::new()
+            .topic("test-topic")
+            .ttl(120)
+            .build()
+            .unwrap();
+        let channel_id = notification.subscription.channel_id;
+
+        let stored: autopush_common::notification::Notification = notification.into();
+        assert_eq!(stored.topic, Some("test-topic".to_string()));
+        assert_eq!(stored.ttl, 120);
+        assert_eq!(stored.sortkey_timestamp, None);
+        assert_eq!(
+            stored.sort_key(),
+            format!("01:{}:test-topic", channel_id.to_hyphenated())
+        );
+
+        let notification = NotificationBuilder::new().ttl(60).build().unwrap();
+        let stored: autopush_common::notification::Notification = notification.into();
+        assert_eq!(stored.topic, None);
+        assert_eq!(stored.ttl, 60);
+        assert!(stored.sortkey_timestamp.is_some());
+    }
+}
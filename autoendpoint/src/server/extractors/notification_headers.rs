@@ -1,21 +1,39 @@
 use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::routers::RouterType;
 use crate::server::headers::crypto_key::CryptoKeyHeader;
 use crate::server::headers::util::{get_header, get_owned_header};
+use crate::settings::is_allowed_host;
 use actix_web::HttpRequest;
+use cadence::{Counted, StatsdClient};
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde::Serialize;
 use std::cmp::min;
-use validator::Validate;
+use std::collections::HashMap;
+use std::str::FromStr;
+use url::Url;
+use validator::{Validate, ValidationError, ValidationErrors};
 use validator_derive::Validate;
 
+/// The size of the `salt` in the Encryption header
+const SALT_BYTES: usize = 16;
+/// The size of the `dh` key (an uncompressed P-256 point) in the Crypto-Key
+/// and Encryption-Key headers
+const DH_BYTES: usize = 65;
+/// The size of the fixed part of the aes128gcm header block at the start of
+/// the payload: the salt, the record size, and the key ID length
+const AES128GCM_HEADER_BYTES: usize = SALT_BYTES + 4 + 1;
+/// The smallest record size allowed by RFC 8188 section 2.1
+const AES128GCM_MIN_RECORD_SIZE: u32 = 18;
+/// The content encodings which `validate_encryption` knows how to validate
+const KNOWN_ENCODINGS: [&str; 3] = ["aesgcm128", "aesgcm", "aes128gcm"];
+
 lazy_static! {
     static ref VALID_BASE64_URL: Regex = Regex::new(r"^[0-9A-Za-z\-_]+=*$").unwrap();
 }
 
-const MAX_TTL: i64 = 60 * 60 * 24 * 60;
-
 /// Extractor and validator for notification headers
-#[derive(Debug, Eq, PartialEq, Validate)]
+#[derive(Clone, Debug, Eq, PartialEq, Validate)]
 pub struct NotificationHeaders {
     // TTL is a signed value so that validation can catch negative inputs
     #[validate(range(min = 0, message = "TTL must be greater than 0", code = "114"))]
@@ -35,52 +53,300 @@ pub struct NotificationHeaders {
     )]
     pub topic: Option<String>,
 
+    /// Defaults to normal if the header is not present
+    pub urgency: Urgency,
+
+    /// The sender does not want to wait for the notification to be delivered
+    /// (RFC 8030 section 5.4, `Prefer: respond-async`)
+    pub respond_async: bool,
+
     // These fields are validated separately, because the validation is complex
     // and based upon the content encoding
     pub content_encoding: Option<String>,
     pub encryption: Option<String>,
     pub encryption_key: Option<String>,
     pub crypto_key: Option<String>,
+
+    /// Identifies retries of the same notification, so they are not
+    /// delivered twice
+    pub idempotency_key: Option<String>,
+
+    /// Where to report that the notification was delivered (RFC 8030
+    /// section 5.1, `Push-Receipt`)
+    pub push_receipt: Option<String>,
+}
+
+/// The urgency of a notification, as defined by RFC 8030 section 5.3
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+    VeryLow,
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for Urgency {
+    fn default() -> Self {
+        Urgency::Normal
+    }
+}
+
+impl FromStr for Urgency {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "very-low" => Ok(Urgency::VeryLow),
+            "low" => Ok(Urgency::Low),
+            "normal" => Ok(Urgency::Normal),
+            "high" => Ok(Urgency::High),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<NotificationHeaders> for HashMap<String, String> {
+    fn from(headers: NotificationHeaders) -> Self {
+        let mut map = HashMap::new();
+
+        map.insert_opt("encoding", headers.content_encoding);
+        map.insert_opt("encryption", headers.encryption);
+        map.insert_opt("encryption_key", headers.encryption_key);
+        map.insert_opt("crypto_key", headers.crypto_key);
+
+        map
+    }
+}
+
+/// Extension trait for inserting optional values into a map
+trait InsertOpt<K, V> {
+    /// Insert an item only if it exists
+    fn insert_opt(&mut self, key: impl Into<K>, value: Option<impl Into<V>>);
+}
+
+impl<K: Eq + std::hash::Hash, V> InsertOpt<K, V> for HashMap<K, V> {
+    fn insert_opt(&mut self, key: impl Into<K>, value: Option<impl Into<V>>) {
+        if let Some(value) = value {
+            self.insert(key.into(), value.into());
+        }
+    }
 }
 
 impl NotificationHeaders {
     /// Extract the notification headers from a request.
     /// This can not be implemented as a `FromRequest` impl because we need to
     /// know if the payload has data, without actually advancing the payload
-    /// stream.
-    pub fn from_request(req: &HttpRequest, has_data: bool) -> ApiResult<Self> {
+    /// stream. TTLs above `max_ttl` are reduced to `max_ttl`.
+    ///
+    /// The encryption headers are only validated if there is a payload. A
+    /// notification with an empty body carries no encrypted data, so it is
+    /// accepted with any (or no) `Content-Encoding` and crypto headers. This
+    /// allows "tickle" notifications such as a bodyless `aes128gcm` message.
+    ///
+    /// The `Encryption`, `Encryption-Key` and `Crypto-Key` headers must not be
+    /// longer than `max_crypto_header_len` characters. A payload must use one
+    /// of the `content_encodings`.
+    pub fn from_request(
+        req: &HttpRequest,
+        has_data: bool,
+        max_ttl: i64,
+        max_crypto_header_len: usize,
+        content_encodings: &[String],
+    ) -> ApiResult<Self> {
         // Collect raw headers
-        let ttl = get_header(req, "ttl")
-            .and_then(|ttl| ttl.parse().ok())
+        let ttl = Self::requested_ttl(req)
             // Enforce a maximum TTL, but don't error
-            .map(|ttl| min(ttl, MAX_TTL));
+            .map(|ttl| min(ttl, max_ttl));
         let topic = get_owned_header(req, "topic");
+        let urgency = Self::parse_urgency(req)?;
+        let respond_async = get_header(req, "prefer")
+            .map(|prefer| {
+                prefer
+                    .split(',')
+                    .any(|preference| preference.trim().eq_ignore_ascii_case("respond-async"))
+            })
+            .unwrap_or(false);
         let content_encoding = get_owned_header(req, "content-encoding");
         let encryption = get_owned_header(req, "encryption");
         let encryption_key = get_owned_header(req, "encryption-key");
         let crypto_key = get_owned_header(req, "crypto-key");
+        Self::assert_max_len("Encryption", encryption.as_deref(), max_crypto_header_len)?;
+        Self::assert_max_len(
+            "Encryption-Key",
+            encryption_key.as_deref(),
+            max_crypto_header_len,
+        )?;
+        Self::assert_max_len("Crypto-Key", crypto_key.as_deref(), max_crypto_header_len)?;
+        let idempotency_key = get_owned_header(req, "idempotency-key");
+        let push_receipt = get_owned_header(req, "push-receipt");
+        Self::assert_valid_push_receipt(push_receipt.as_deref())?;
+        if has_data {
+            Self::assert_encoding_enabled(content_encoding.as_deref(), content_encodings)?;
+        }
 
         let headers = NotificationHeaders {
             ttl,
             topic,
+            urgency,
+            respond_async,
             content_encoding,
             encryption,
             encryption_key,
             crypto_key,
+            idempotency_key,
+            push_receipt,
+        };
+
+        headers.validated(has_data)
+    }
+
+    /// Assert that the content encoding, if present, has not been disabled.
+    /// Unknown encodings are left to `validate_encryption` to reject.
+    fn assert_encoding_enabled(
+        content_encoding: Option<&str>,
+        content_encodings: &[String],
+    ) -> ApiResult<()> {
+        match content_encoding {
+            Some(encoding)
+                if KNOWN_ENCODINGS.contains(&encoding)
+                    && !content_encodings.iter().any(|enabled| enabled == encoding) =>
+            {
+                Err(ApiErrorKind::UnsupportedEncoding(encoding.to_string()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Assert that the topic, if present, doesn't start with one of the
+    /// prefixes reserved for system messages
+    pub fn assert_topic_not_reserved(&self, reserved_topic_prefixes: &[String]) -> ApiResult<()> {
+        let topic = match &self.topic {
+            Some(topic) => topic,
+            None => return Ok(()),
+        };
+
+        match reserved_topic_prefixes
+            .iter()
+            .find(|prefix| !prefix.is_empty() && topic.starts_with(prefix.as_str()))
+        {
+            Some(prefix) => Err(ApiErrorKind::ReservedTopic(prefix.clone()).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Assert that the push receipt, if present, points at one of this
+    /// service's receipt resources or an allowed host. Otherwise anyone could
+    /// make the service send requests to any host, including internal ones.
+    pub fn assert_push_receipt_allowed(
+        &self,
+        receipt_url: &Url,
+        allowed_hosts: &[String],
+    ) -> ApiResult<()> {
+        let push_receipt = match &self.push_receipt {
+            Some(push_receipt) => push_receipt,
+            None => return Ok(()),
+        };
+        let url = Url::parse(push_receipt).map_err(|_| ApiErrorKind::InvalidPushReceipt)?;
+
+        let is_own_receipt =
+            url.origin() == receipt_url.origin() && url.path().starts_with(receipt_url.path());
+        let on_allowed_host = url
+            .host_str()
+            .map(|host| is_allowed_host(host, allowed_hosts))
+            .unwrap_or(false);
+
+        if is_own_receipt || on_allowed_host {
+            Ok(())
+        } else {
+            Err(ApiErrorKind::PushReceiptNotAllowed.into())
+        }
+    }
+
+    /// Assert that the push receipt, if present, is an HTTP(S) URL which
+    /// receipts can be sent to
+    fn assert_valid_push_receipt(push_receipt: Option<&str>) -> ApiResult<()> {
+        let push_receipt = match push_receipt {
+            Some(push_receipt) => push_receipt,
+            None => return Ok(()),
         };
 
+        match Url::parse(push_receipt) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+            _ => Err(ApiErrorKind::InvalidPushReceipt.into()),
+        }
+    }
+
+    /// Assert that the header, if present, is not longer than `max_len`
+    /// characters
+    fn assert_max_len(
+        header_name: &'static str,
+        header: Option<&str>,
+        max_len: usize,
+    ) -> ApiResult<()> {
+        match header {
+            Some(header) if header.len() > max_len => {
+                Err(ApiErrorKind::CryptoHeaderTooLong(header_name, max_len).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Validate the headers, including the encryption headers if there is a
+    /// payload
+    pub fn validated(self, has_data: bool) -> ApiResult<Self> {
         // Validate encryption if there is a message body
         if has_data {
-            headers.validate_encryption()?;
+            self.validate_encryption()?;
         }
 
         // Validate the other headers
-        match headers.validate() {
-            Ok(_) => Ok(headers),
+        match self.validate() {
+            Ok(_) => Ok(self),
             Err(e) => Err(ApiError::from(e)),
         }
     }
 
+    /// Record a metric if the TTL requested by the sender was reduced to
+    /// `max_ttl`, so operators can see how often senders exceed it
+    pub fn record_ttl_clamping(
+        req: &HttpRequest,
+        max_ttl: i64,
+        router_type: RouterType,
+        metrics: &StatsdClient,
+    ) {
+        if Self::requested_ttl(req).map_or(false, |ttl| ttl > max_ttl) {
+            metrics
+                .incr_with_tags("notification.ttl.clamped")
+                .with_tag("router_type", &router_type.to_string())
+                .send();
+        }
+    }
+
+    /// Get the TTL requested by the sender, before the max TTL is enforced
+    fn requested_ttl(req: &HttpRequest) -> Option<i64> {
+        get_header(req, "ttl").and_then(|ttl| ttl.parse().ok())
+    }
+
+    /// Parse the urgency header, defaulting to normal urgency if the header is
+    /// not present
+    fn parse_urgency(req: &HttpRequest) -> ApiResult<Urgency> {
+        let urgency = match get_header(req, "urgency") {
+            Some(urgency) => urgency,
+            None => return Ok(Urgency::default()),
+        };
+
+        urgency.parse().map_err(|_| {
+            let mut error = ValidationError::new("115");
+            error.message = Some("Urgency must be very-low, low, normal, or high".into());
+            error.add_param("value".into(), &urgency);
+
+            let mut errors = ValidationErrors::new();
+            errors.add("urgency", error);
+            errors.into()
+        })
+    }
+
     /// Validate the encryption headers according to the various WebPush
     /// standard versions
     fn validate_encryption(&self) -> ApiResult<()> {
@@ -103,11 +369,59 @@ impl NotificationHeaders {
         Ok(())
     }
 
+    /// Validate the start of the (encrypted) payload. For aes128gcm, the
+    /// payload starts with a header block (RFC 8188 section 2.1), so the record
+    /// size and key ID length are checked. Other encodings are not checked.
+    pub fn validate_payload(&self, data: &[u8]) -> ApiResult<()> {
+        if self.content_encoding.as_deref() != Some("aes128gcm") {
+            return Ok(());
+        }
+
+        if data.len() < AES128GCM_HEADER_BYTES {
+            return Err(ApiErrorKind::InvalidEncryption(
+                "aes128gcm payload is too short to contain a header".to_string(),
+            )
+            .into());
+        }
+
+        let mut record_size = [0; 4];
+        record_size.copy_from_slice(&data[SALT_BYTES..SALT_BYTES + 4]);
+        if u32::from_be_bytes(record_size) < AES128GCM_MIN_RECORD_SIZE {
+            return Err(ApiErrorKind::InvalidEncryption(format!(
+                "Invalid aes128gcm record size, must be at least {}",
+                AES128GCM_MIN_RECORD_SIZE
+            ))
+            .into());
+        }
+
+        let keyid_len = usize::from(data[AES128GCM_HEADER_BYTES - 1]);
+        if data.len() < AES128GCM_HEADER_BYTES + keyid_len {
+            return Err(ApiErrorKind::InvalidEncryption(
+                "aes128gcm payload is too short to contain the key ID".to_string(),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
     /// Validates encryption headers according to
     /// draft-ietf-webpush-encryption-01
     fn validate_encryption_01_rules(&self) -> ApiResult<()> {
-        Self::assert_base64_item_exists("Encryption", self.encryption.as_deref(), "salt")?;
-        Self::assert_base64_item_exists("Encryption-Key", self.encryption_key.as_deref(), "dh")?;
+        Self::assert_base64_item_exists(
+            "Encryption",
+            self.encryption.as_deref(),
+            "salt",
+            None,
+            SALT_BYTES,
+        )?;
+        Self::assert_base64_item_exists(
+            "Encryption-Key",
+            self.encryption_key.as_deref(),
+            "dh",
+            None,
+            DH_BYTES,
+        )?;
         Self::assert_not_exists("aesgcm128 Crypto-Key", self.crypto_key.as_deref(), "dh")?;
 
         Ok(())
@@ -116,17 +430,38 @@ impl NotificationHeaders {
     /// Validates encryption headers according to
     /// draft-ietf-webpush-encryption-04
     fn validate_encryption_04_rules(&self) -> ApiResult<()> {
-        Self::assert_base64_item_exists("Encryption", self.encryption.as_deref(), "salt")?;
+        Self::assert_base64_item_exists(
+            "Encryption",
+            self.encryption.as_deref(),
+            "salt",
+            None,
+            SALT_BYTES,
+        )?;
 
         if self.encryption_key.is_some() {
             return Err(ApiErrorKind::InvalidEncryption(
-                "Encryption-Key header is not valid for webpush draft 02 or later".to_string(),
+                "Encryption-Key header is not valid for aesgcm (webpush encryption draft 04), \
+                 use Crypto-Key instead"
+                    .to_string(),
             )
             .into());
         }
 
         if self.crypto_key.is_some() {
-            Self::assert_base64_item_exists("Crypto-Key", self.crypto_key.as_deref(), "dh")?;
+            // The dh is in the Crypto-Key group with the same key ID as the salt
+            let keyid = self
+                .encryption
+                .as_deref()
+                .and_then(CryptoKeyHeader::parse)
+                .and_then(|header| header.get_by_key("keyid").map(str::to_string));
+
+            Self::assert_base64_item_exists(
+                "Crypto-Key",
+                self.crypto_key.as_deref(),
+                "dh",
+                keyid.as_deref(),
+                DH_BYTES,
+            )?;
         }
 
         Ok(())
@@ -143,26 +478,30 @@ impl NotificationHeaders {
         Ok(())
     }
 
-    /// Assert that the given key exists in the header and is valid base64.
+    /// Assert that the given key exists in the header and is valid base64
+    /// which decodes to `expected_bytes` bytes. If a key ID is given, the item
+    /// in the group with that key ID is checked.
     fn assert_base64_item_exists(
         header_name: &str,
         header: Option<&str>,
         key: &str,
+        keyid: Option<&str>,
+        expected_bytes: usize,
     ) -> ApiResult<()> {
         let header = header.ok_or_else(|| {
             ApiErrorKind::InvalidEncryption(format!("Missing {} header", header_name))
         })?;
-        let header_data = CryptoKeyHeader::parse(header).ok_or_else(|| {
-            ApiErrorKind::InvalidEncryption(format!("Invalid {} header", header_name))
+        let header_data = CryptoKeyHeader::try_parse(header).map_err(|e| {
+            ApiErrorKind::InvalidEncryption(format!("Invalid {} header: {}", header_name, e))
         })?;
-        let salt = header_data.get_by_key(key).ok_or_else(|| {
+        let salt = header_data.get_by_key_in_group(key, keyid).ok_or_else(|| {
             ApiErrorKind::InvalidEncryption(format!(
                 "Missing {} value in {} header",
                 key, header_name
             ))
         })?;
 
-        if !VALID_BASE64_URL.is_match(salt) {
+        if !is_valid_base64_url(salt) {
             return Err(ApiErrorKind::InvalidEncryption(format!(
                 "Invalid {} value in {} header",
                 key, header_name
@@ -170,6 +509,18 @@ impl NotificationHeaders {
             .into());
         }
 
+        let decoded_bytes =
+            base64::decode_config(salt.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                .map(|value| value.len())
+                .unwrap_or(0);
+        if decoded_bytes != expected_bytes {
+            return Err(ApiErrorKind::InvalidEncryption(format!(
+                "Invalid {} value in {} header, expected {} bytes",
+                key, header_name, expected_bytes
+            ))
+            .into());
+        }
+
         Ok(())
     }
 
@@ -180,8 +531,8 @@ impl NotificationHeaders {
             None => return Ok(()),
         };
 
-        let header_data = CryptoKeyHeader::parse(header).ok_or_else(|| {
-            ApiErrorKind::InvalidEncryption(format!("Invalid {} header", header_name))
+        let header_data = CryptoKeyHeader::try_parse(header).map_err(|e| {
+            ApiErrorKind::InvalidEncryption(format!("Invalid {} header: {}", header_name, e))
         })?;
 
         if header_data.get_by_key(key).is_some() {
@@ -196,12 +547,45 @@ impl NotificationHeaders {
     }
 }
 
+/// Check that the value is URL-safe base64 which can be decoded. Padding is
+/// optional, but must be the right length if present.
+fn is_valid_base64_url(value: &str) -> bool {
+    if !VALID_BASE64_URL.is_match(value) {
+        return false;
+    }
+
+    // Each 4 characters encode 3 bytes, so a single leftover character can't
+    // encode a whole byte
+    let data_len = value.trim_end_matches('=').len();
+    let padding_len = value.len() - data_len;
+    data_len % 4 != 1 && (padding_len == 0 || padding_len == (4 - data_len % 4) % 4)
+}
+
 #[cfg(test)]
 mod tests {
+    use super::is_valid_base64_url;
     use super::NotificationHeaders;
-    use super::MAX_TTL;
+    use super::Urgency;
     use crate::error::{ApiErrorKind, ApiResult};
+    use crate::metrics::TestMetricSink;
+    use crate::routers::RouterType;
+    use crate::settings::Settings;
+    use actix_web::http::StatusCode;
     use actix_web::test::TestRequest;
+    use url::Url;
+
+    const MAX_TTL: i64 = 60 * 60 * 24 * 60;
+    const MAX_HEADER_LEN: usize = 4096;
+    /// A 16 byte salt
+    const SALT: &str = "AQIDBAUGBwgJCgsMDQ4PEA";
+    /// A 65 byte dh key
+    const DH: &str =
+        "BAoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISIjJCUmJygpKissLS4vMDEyMzQ1Njc4OTo7PD0-P0BBQkNERUZHSEk";
+
+    /// The content encodings which are enabled by default
+    fn content_encodings() -> Vec<String> {
+        Settings::default().content_encodings
+    }
 
     /// Assert that a result is a validation error and check its serialization
     /// against the JSON value.
@@ -233,7 +617,13 @@ mod tests {
     #[test]
     fn valid_ttl() {
         let req = TestRequest::post().header("TTL", "10").to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap().ttl, Some(10));
@@ -243,7 +633,13 @@ mod tests {
     #[test]
     fn negative_ttl() {
         let req = TestRequest::post().header("TTL", "-1").to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
 
         assert_validation_error(
             result,
@@ -266,19 +662,108 @@ mod tests {
         let req = TestRequest::post()
             .header("TTL", (MAX_TTL + 1).to_string())
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap().ttl, Some(MAX_TTL));
     }
 
+    /// Reducing the TTL to the max is recorded, tagged with the router type
+    #[test]
+    fn ttl_clamping_metric() {
+        let metrics = TestMetricSink::default();
+        let client = metrics.client();
+
+        for &ttl in [MAX_TTL - 1, MAX_TTL].iter() {
+            let req = TestRequest::post()
+                .header("TTL", ttl.to_string())
+                .to_http_request();
+            NotificationHeaders::record_ttl_clamping(&req, MAX_TTL, RouterType::Fcm, &client);
+        }
+        let req = TestRequest::post().to_http_request();
+        NotificationHeaders::record_ttl_clamping(&req, MAX_TTL, RouterType::Fcm, &client);
+        assert!(metrics.metrics().is_empty());
+
+        let req = TestRequest::post()
+            .header("TTL", (MAX_TTL + 1).to_string())
+            .to_http_request();
+        NotificationHeaders::record_ttl_clamping(&req, MAX_TTL, RouterType::Fcm, &client);
+        assert_eq!(
+            metrics.metrics(),
+            vec!["autoendpoint.notification.ttl.clamped:1|c|#router_type:fcm"]
+        );
+    }
+
+    /// The configured max TTL is used instead of the default
+    #[test]
+    fn configured_maximum_ttl() {
+        let req = TestRequest::post().header("TTL", "120").to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            60,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().ttl, Some(60));
+    }
+
+    /// Negative TTL values are rejected with a configured max, instead of being
+    /// clamped
+    #[test]
+    fn negative_ttl_configured_maximum() {
+        let req = TestRequest::post().header("TTL", "-1").to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            60,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        match result.unwrap_err().kind {
+            ApiErrorKind::Validation(errors) => assert!(errors.field_errors().contains_key("ttl")),
+            kind => panic!("Expected a validation error, got {:?}", kind),
+        }
+    }
+
+    /// TTL values under the configured max are not changed
+    #[test]
+    fn under_configured_maximum_ttl() {
+        let req = TestRequest::post().header("TTL", "30").to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            60,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().ttl, Some(30));
+    }
+
     /// A valid topic results in no errors
     #[test]
     fn valid_topic() {
         let req = TestRequest::post()
             .header("TOPIC", "test-topic")
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap().topic, Some("test-topic".to_string()));
@@ -290,7 +775,13 @@ mod tests {
         let req = TestRequest::post()
             .header("TOPIC", "test-topic-which-is-too-long-1234")
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
 
         assert_validation_error(
             result,
@@ -307,86 +798,1067 @@ mod tests {
         );
     }
 
-    /// If there is a payload, there must be a content encoding header
+    /// Each valid urgency value is parsed
     #[test]
-    fn payload_without_content_encoding() {
+    fn valid_urgency() {
+        let values = [
+            ("very-low", Urgency::VeryLow),
+            ("low", Urgency::Low),
+            ("normal", Urgency::Normal),
+            ("high", Urgency::High),
+        ];
+
+        for &(value, expected) in values.iter() {
+            let req = TestRequest::post()
+                .header("Urgency", value)
+                .to_http_request();
+            let result = NotificationHeaders::from_request(
+                &req,
+                false,
+                MAX_TTL,
+                MAX_HEADER_LEN,
+                &content_encodings(),
+            );
+
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap().urgency, expected);
+        }
+    }
+
+    /// The urgency defaults to normal if the header is not present
+    #[test]
+    fn missing_urgency() {
         let req = TestRequest::post().to_http_request();
-        let result = NotificationHeaders::from_request(&req, true);
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
 
-        assert_encryption_error(result, "Missing Content-Encoding header");
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().urgency, Urgency::Normal);
     }
 
-    /// Valid 01 draft encryption passes validation
+    /// Unknown urgency values return an error
     #[test]
-    fn valid_01_encryption() {
+    fn invalid_urgency() {
         let req = TestRequest::post()
-            .header("Content-Encoding", "aesgcm128")
-            .header("Encryption", "salt=foo")
-            .header("Encryption-Key", "dh=bar")
+            .header("Urgency", "urgent")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_validation_error(
+            result,
+            serde_json::json!({
+                "urgency": [{
+                    "code": "115",
+                    "message": "Urgency must be very-low, low, normal, or high",
+                    "params": {
+                        "value": "urgent"
+                    }
+                }]
+            }),
+        );
+    }
+
+    /// The respond-async preference is detected among other preferences
+    #[test]
+    fn respond_async() {
+        let req = TestRequest::post()
+            .header("Prefer", "wait=5, respond-async")
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, true);
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+        assert!(result.unwrap().respond_async);
+    }
+
+    /// Without a Prefer header, the response is synchronous
+    #[test]
+    fn missing_prefer() {
+        let req = TestRequest::post().to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
 
         assert!(result.is_ok());
+        assert!(!result.unwrap().respond_async);
+    }
+
+    /// The idempotency key is captured
+    #[test]
+    fn idempotency_key() {
+        let req = TestRequest::post()
+            .header("Idempotency-Key", "test-key")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
         assert_eq!(
-            result.unwrap(),
-            NotificationHeaders {
-                ttl: None,
-                topic: None,
-                content_encoding: Some("aesgcm128".to_string()),
-                encryption: Some("salt=foo".to_string()),
-                encryption_key: Some("dh=bar".to_string()),
-                crypto_key: None
-            }
+            result.unwrap().idempotency_key,
+            Some("test-key".to_string())
         );
     }
 
-    /// Valid 04 draft encryption passes validation
+    /// A topic with a reserved prefix is rejected with a 400 and errno
     #[test]
-    fn valid_04_encryption() {
+    fn reserved_topic_prefix() {
         let req = TestRequest::post()
-            .header("Content-Encoding", "aesgcm")
-            .header("Encryption", "salt=foo")
-            .header("Crypto-Key", "dh=bar")
+            .header("Topic", "__system")
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, true);
+        let headers = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        )
+        .unwrap();
 
-        assert!(result.is_ok());
+        let error = headers
+            .assert_topic_not_reserved(&["__".to_string()])
+            .unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.kind.errno(), Some(120));
         assert_eq!(
-            result.unwrap(),
-            NotificationHeaders {
-                ttl: None,
-                topic: None,
-                content_encoding: Some("aesgcm".to_string()),
-                encryption: Some("salt=foo".to_string()),
-                encryption_key: None,
-                crypto_key: Some("dh=bar".to_string())
-            }
+            error.kind.to_string(),
+            "Topic must not start with the reserved prefix __"
         );
+
+        // No topics are reserved by default
+        let reserved_topic_prefixes = Settings::default().reserved_topic_prefixes;
+        assert!(headers
+            .assert_topic_not_reserved(&reserved_topic_prefixes)
+            .is_ok());
     }
 
-    /// Valid 06 draft encryption passes validation
+    /// Topics without a reserved prefix are accepted
     #[test]
-    fn valid_06_encryption() {
+    fn unreserved_topic() {
+        let reserved_topic_prefixes = vec!["__".to_string()];
+        for topic in &["test-topic", "_single", "trailing__"] {
+            let req = TestRequest::post()
+                .header("Topic", *topic)
+                .to_http_request();
+            let headers = NotificationHeaders::from_request(
+                &req,
+                false,
+                MAX_TTL,
+                MAX_HEADER_LEN,
+                &content_encodings(),
+            )
+            .unwrap();
+
+            assert!(headers
+                .assert_topic_not_reserved(&reserved_topic_prefixes)
+                .is_ok());
+        }
+
+        // Notifications without a topic are accepted
+        let req = TestRequest::post().to_http_request();
+        let headers = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        )
+        .unwrap();
+        assert!(headers
+            .assert_topic_not_reserved(&reserved_topic_prefixes)
+            .is_ok());
+    }
+
+    /// The push receipt URL is captured
+    #[test]
+    fn push_receipt() {
         let req = TestRequest::post()
-            .header("Content-Encoding", "aes128gcm")
-            .header("Encryption", "notsalt=foo")
-            .header("Crypto-Key", "notdh=bar")
+            .header("Push-Receipt", "https://example.com/receipts/1")
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, true);
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
 
-        assert!(result.is_ok());
         assert_eq!(
-            result.unwrap(),
-            NotificationHeaders {
-                ttl: None,
-                topic: None,
-                content_encoding: Some("aes128gcm".to_string()),
-                encryption: Some("notsalt=foo".to_string()),
-                encryption_key: None,
-                crypto_key: Some("notdh=bar".to_string())
-            }
+            result.unwrap().push_receipt,
+            Some("https://example.com/receipts/1".to_string())
         );
     }
 
-    // TODO: Add negative test cases for encryption validation?
+    /// A push receipt which isn't an HTTP(S) URL is rejected
+    #[test]
+    fn invalid_push_receipt() {
+        for push_receipt in &["not a url", "ftp://example.com/receipts/1"] {
+            let req = TestRequest::post()
+                .header("Push-Receipt", *push_receipt)
+                .to_http_request();
+            let error = NotificationHeaders::from_request(
+                &req,
+                false,
+                MAX_TTL,
+                MAX_HEADER_LEN,
+                &content_encodings(),
+            )
+            .unwrap_err();
+
+            assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+            match error.kind {
+                ApiErrorKind::InvalidPushReceipt => {}
+                kind => panic!("Expected an invalid push receipt error, got {:?}", kind),
+            }
+        }
+    }
+
+    /// Parse the headers of a request with the push receipt URL
+    fn push_receipt_headers(push_receipt: &str) -> NotificationHeaders {
+        let req = TestRequest::post()
+            .header("Push-Receipt", push_receipt)
+            .to_http_request();
+
+        NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        )
+        .unwrap()
+    }
+
+    /// Push receipts to this service's receipt resources or an allowed host
+    /// are accepted
+    #[test]
+    fn allowed_push_receipt() {
+        let receipt_url = Url::parse("https://push.example.com/receipts/").unwrap();
+        let allowed_hosts = vec![
+            "receipts.example.org".to_string(),
+            ".example.net".to_string(),
+        ];
+
+        for push_receipt in &[
+            "https://push.example.com/receipts/1",
+            "https://receipts.example.org/1",
+            "https://app.example.net/receipts/1",
+        ] {
+            assert!(push_receipt_headers(push_receipt)
+                .assert_push_receipt_allowed(&receipt_url, &allowed_hosts)
+                .is_ok());
+        }
+        assert!(push_receipt_headers("https://push.example.com/receipts/1")
+            .assert_push_receipt_allowed(&receipt_url, &[])
+            .is_ok());
+    }
+
+    /// Push receipts to internal hosts or hosts which aren't allowed are
+    /// rejected, so the service can't be used to send requests to them
+    #[test]
+    fn push_receipt_not_allowed() {
+        let receipt_url = Url::parse("https://push.example.com/receipts/").unwrap();
+        let allowed_hosts = vec!["receipts.example.org".to_string()];
+
+        for push_receipt in &[
+            // Internal hosts
+            "http://127.0.0.1:8081/push",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://localhost/receipts/1",
+            // This service, but not a receipt resource
+            "https://push.example.com/v1/admin",
+            "http://push.example.com/receipts/1",
+            // Hosts which aren't allowed
+            "https://example.com/receipts/1",
+            "https://evil.receipts.example.org/1",
+        ] {
+            let error = push_receipt_headers(push_receipt)
+                .assert_push_receipt_allowed(&receipt_url, &allowed_hosts)
+                .unwrap_err();
+
+            assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+            match error.kind {
+                ApiErrorKind::PushReceiptNotAllowed => {}
+                kind => panic!("Expected a push receipt not allowed error, got {:?}", kind),
+            }
+        }
+    }
+
+    /// If there is a payload, there must be a content encoding header
+    #[test]
+    fn payload_without_content_encoding() {
+        let req = TestRequest::post().to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Missing Content-Encoding header");
+    }
+
+    /// A bodyless aes128gcm notification doesn't need any crypto headers
+    #[test]
+    fn empty_body_06_encryption() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap().content_encoding,
+            Some("aes128gcm".to_string())
+        );
+    }
+
+    /// A bodyless notification skips encryption validation, even if the
+    /// headers would be invalid for a payload
+    #[test]
+    fn empty_body_skips_encryption_validation() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .header("Encryption", "salt=foo")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            false,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+    }
+
+    /// An aes128gcm payload doesn't need any crypto headers
+    #[test]
+    fn payload_06_encryption_without_crypto_headers() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+    }
+
+    /// An aes128gcm payload must not have an Encryption salt
+    #[test]
+    fn payload_06_encryption_with_salt() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .header("Encryption", "salt=foo")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(
+            result,
+            "Do not include 'salt' header in aes128gcm Encryption header",
+        );
+    }
+
+    /// Valid 01 draft encryption passes validation
+    #[test]
+    fn valid_01_encryption() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm128")
+            .header("Encryption", format!("salt={}", SALT))
+            .header("Encryption-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            NotificationHeaders {
+                ttl: None,
+                topic: None,
+                urgency: Urgency::Normal,
+                respond_async: false,
+                content_encoding: Some("aesgcm128".to_string()),
+                encryption: Some(format!("salt={}", SALT)),
+                encryption_key: Some(format!("dh={}", DH)),
+                crypto_key: None,
+                idempotency_key: None,
+                push_receipt: None
+            }
+        );
+    }
+
+    /// Valid 04 draft encryption passes validation
+    #[test]
+    fn valid_04_encryption() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}", SALT))
+            .header("Crypto-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            NotificationHeaders {
+                ttl: None,
+                topic: None,
+                urgency: Urgency::Normal,
+                respond_async: false,
+                content_encoding: Some("aesgcm".to_string()),
+                encryption: Some(format!("salt={}", SALT)),
+                encryption_key: None,
+                crypto_key: Some(format!("dh={}", DH)),
+                idempotency_key: None,
+                push_receipt: None
+            }
+        );
+    }
+
+    /// A Crypto-Key header with multiple groups passes validation when the
+    /// dh paired with the salt is valid
+    #[test]
+    fn valid_04_encryption_multiple_groups() {
+        let crypto_key = format!(
+            "keyid=p256dh;dh={},p256ecdsa=BF92zdI_AKcH5Q31_Rr-04bPqOHU_Qg6lAawHbvfQrY",
+            DH
+        );
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("keyid=p256dh;salt={}", SALT))
+            .header("Crypto-Key", crypto_key.clone())
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok(), "result = {:?}", result);
+        assert_eq!(result.unwrap().crypto_key, Some(crypto_key));
+    }
+
+    /// The dh in the group paired with the salt is validated, not the first
+    /// dh in the header
+    #[test]
+    fn invalid_04_encryption_paired_dh() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("keyid=p256dh;salt={}", SALT))
+            .header(
+                "Crypto-Key",
+                format!("keyid=other;dh={},keyid=p256dh;dh=not+base64url", DH),
+            )
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Invalid dh value in Crypto-Key header");
+    }
+
+    /// The malformed part of a Crypto-Key header is included in the error
+    #[test]
+    fn malformed_crypto_key_segment() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}", SALT))
+            .header("Crypto-Key", format!("dh={};p256ecdsa", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(
+            result,
+            "Invalid Crypto-Key header: item 'p256ecdsa' is missing '='",
+        );
+    }
+
+    /// A salt which decodes to more than 16 bytes is rejected
+    #[test]
+    fn oversized_salt() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}AQIDBA", SALT))
+            .header("Crypto-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(
+            result,
+            "Invalid salt value in Encryption header, expected 16 bytes",
+        );
+    }
+
+    /// Base64 values with the wrong amount of padding, or a length no
+    /// encoding could have, are rejected
+    #[test]
+    fn base64_url_structure() {
+        for value in &["AQID", "AQIDBA", "AQIDBA==", "AQIDBAU", "AQIDBAU=", "-_8"] {
+            assert!(is_valid_base64_url(value), "{} should be valid", value);
+        }
+
+        for value in &[
+            "",
+            "=",
+            "A",
+            "AQIDB",
+            "AQIDB=",
+            "AQID=",
+            "AQIDBA=",
+            "AQIDBA===",
+            "AQIDBAU==",
+            "AQ=D",
+            "AQ+/",
+        ] {
+            assert!(!is_valid_base64_url(value), "{} should be invalid", value);
+        }
+    }
+
+    /// A salt whose length is impossible for base64 is rejected
+    #[test]
+    fn impossible_length_salt() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}", &SALT[..21]))
+            .header("Crypto-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Invalid salt value in Encryption header");
+    }
+
+    /// A salt may be padded, but only with the right amount of padding
+    #[test]
+    fn padded_salt() {
+        for (padding, valid) in &[("==", true), ("=", false), ("===", false)] {
+            let req = TestRequest::post()
+                .header("Content-Encoding", "aesgcm")
+                .header("Encryption", format!("salt={}{}", SALT, padding))
+                .header("Crypto-Key", format!("dh={}", DH))
+                .to_http_request();
+            let result = NotificationHeaders::from_request(
+                &req,
+                true,
+                MAX_TTL,
+                MAX_HEADER_LEN,
+                &content_encodings(),
+            );
+
+            if *valid {
+                assert!(result.is_ok(), "{:?}", result);
+            } else {
+                assert_encryption_error(result, "Invalid salt value in Encryption header");
+            }
+        }
+    }
+
+    /// A dh key which decodes to less than 65 bytes is rejected
+    #[test]
+    fn short_dh() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}", SALT))
+            .header(
+                "Crypto-Key",
+                "dh=BAoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISIjJCUmJygp",
+            )
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(
+            result,
+            "Invalid dh value in Crypto-Key header, expected 65 bytes",
+        );
+    }
+
+    /// Valid 06 draft encryption passes validation
+    #[test]
+    fn valid_06_encryption() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .header("Encryption", "notsalt=foo")
+            .header("Crypto-Key", "notdh=bar")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            NotificationHeaders {
+                ttl: None,
+                topic: None,
+                urgency: Urgency::Normal,
+                respond_async: false,
+                content_encoding: Some("aes128gcm".to_string()),
+                encryption: Some("notsalt=foo".to_string()),
+                encryption_key: None,
+                crypto_key: Some("notdh=bar".to_string()),
+                idempotency_key: None,
+                push_receipt: None
+            }
+        );
+    }
+
+    /// An unknown content encoding is rejected
+    #[test]
+    fn unknown_content_encoding() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes256gcm")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Unknown Content-Encoding header");
+    }
+
+    /// 01 draft encryption requires the Encryption header
+    #[test]
+    fn missing_encryption_01() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm128")
+            .header("Encryption-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Missing Encryption header");
+    }
+
+    /// 01 draft encryption requires a salt
+    #[test]
+    fn missing_salt_01() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm128")
+            .header("Encryption", "keyid=p256dh")
+            .header("Encryption-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Missing salt value in Encryption header");
+    }
+
+    /// 01 draft encryption requires the dh in the Encryption-Key header
+    #[test]
+    fn missing_encryption_key_01() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm128")
+            .header("Encryption", format!("salt={}", SALT))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Missing Encryption-Key header");
+    }
+
+    /// 01 draft encryption must not have a dh in the Crypto-Key header
+    #[test]
+    fn stray_crypto_key_01() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm128")
+            .header("Encryption", format!("salt={}", SALT))
+            .header("Encryption-Key", format!("dh={}", DH))
+            .header("Crypto-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(
+            result,
+            "Do not include 'dh' header in aesgcm128 Crypto-Key header",
+        );
+    }
+
+    /// 04 draft encryption requires the Encryption header
+    #[test]
+    fn missing_encryption_04() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Crypto-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Missing Encryption header");
+    }
+
+    /// 04 draft encryption requires a salt
+    #[test]
+    fn missing_salt_04() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", "keyid=p256dh")
+            .header("Crypto-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Missing salt value in Encryption header");
+    }
+
+    /// 04 draft encryption uses Crypto-Key instead of Encryption-Key
+    #[test]
+    fn stray_encryption_key_04() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}", SALT))
+            .header("Encryption-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(
+            result,
+            "Encryption-Key header is not valid for aesgcm (webpush encryption draft 04), \
+             use Crypto-Key instead",
+        );
+    }
+
+    /// 04 draft encryption requires the dh if there is a Crypto-Key header
+    #[test]
+    fn missing_dh_04() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}", SALT))
+            .header("Crypto-Key", "p256ecdsa=vapid-key")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(result, "Missing dh value in Crypto-Key header");
+    }
+
+    /// 06 draft encryption must not have a dh in the Crypto-Key header
+    #[test]
+    fn stray_crypto_key_06() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .header("Crypto-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert_encryption_error(
+            result,
+            "Do not include 'dh' header in aes128gcm Crypto-Key header",
+        );
+    }
+
+    /// Build the start of an aes128gcm payload with the given record size and
+    /// key ID length, including the key ID
+    fn make_aes128gcm_header(record_size: u32, keyid_len: u8) -> Vec<u8> {
+        let mut data = vec![1; 16];
+        data.extend_from_slice(&record_size.to_be_bytes());
+        data.push(keyid_len);
+        data.extend(vec![4; usize::from(keyid_len)]);
+        data
+    }
+
+    /// A well-formed aes128gcm header block is accepted
+    #[test]
+    fn valid_aes128gcm_payload() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .to_http_request();
+        let headers = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        )
+        .unwrap();
+
+        let mut data = make_aes128gcm_header(4096, 65);
+        data.extend_from_slice(&[0; 32]);
+        assert!(headers.validate_payload(&data).is_ok());
+    }
+
+    /// A truncated aes128gcm header block is rejected
+    #[test]
+    fn truncated_aes128gcm_payload() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .to_http_request();
+        let headers = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        )
+        .unwrap();
+
+        assert_encryption_error(
+            headers.validate_payload(&[0; 20]).map(|_| headers.clone()),
+            "aes128gcm payload is too short to contain a header",
+        );
+
+        let data = make_aes128gcm_header(4096, 65);
+        assert_encryption_error(
+            headers
+                .validate_payload(&data[..40])
+                .map(|_| headers.clone()),
+            "aes128gcm payload is too short to contain the key ID",
+        );
+    }
+
+    /// An aes128gcm record size below the minimum is rejected
+    #[test]
+    fn invalid_aes128gcm_record_size() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .to_http_request();
+        let headers = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        )
+        .unwrap();
+
+        assert_encryption_error(
+            headers
+                .validate_payload(&make_aes128gcm_header(17, 0))
+                .map(|_| headers.clone()),
+            "Invalid aes128gcm record size, must be at least 18",
+        );
+    }
+
+    /// Crypto headers at the max length are accepted
+    #[test]
+    fn crypto_header_at_limit() {
+        // The extra characters are ignored by the encryption validation
+        let crypto_key = format!("dh={};p256ecdsa={}", DH, "a".repeat(MAX_HEADER_LEN));
+        let crypto_key = &crypto_key[..MAX_HEADER_LEN];
+        let req = TestRequest::post()
+            .header("TTL", "10")
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}", SALT))
+            .header("Crypto-Key", crypto_key)
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        );
+
+        assert!(result.is_ok(), "result = {:?}", result);
+    }
+
+    /// Crypto headers over the max length are rejected with a 400 and errno
+    #[test]
+    fn crypto_header_over_limit() {
+        let req = TestRequest::post()
+            .header("TTL", "10")
+            .header("Content-Encoding", "aesgcm")
+            .header("Encryption", format!("salt={}", "a".repeat(MAX_HEADER_LEN)))
+            .header("Crypto-Key", format!("dh={}", DH))
+            .to_http_request();
+        let error = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings(),
+        )
+        .unwrap_err();
+
+        assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.kind.errno(), Some(118));
+        assert_eq!(
+            error.kind.to_string(),
+            "Encryption header must not be longer than 4096 characters"
+        );
+    }
+
+    /// A disabled content encoding is rejected, while the others still work
+    #[test]
+    fn disabled_content_encoding() {
+        let content_encodings = vec!["aesgcm".to_string(), "aes128gcm".to_string()];
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm128")
+            .header("Encryption", format!("salt={}", SALT))
+            .header("Encryption-Key", format!("dh={}", DH))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings,
+        );
+        let error = result.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.kind.errno(), Some(119));
+        assert_eq!(
+            error.kind.to_string(),
+            "The aesgcm128 content encoding is no longer supported"
+        );
+
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aes128gcm")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(
+            &req,
+            true,
+            MAX_TTL,
+            MAX_HEADER_LEN,
+            &content_encodings,
+        );
+        assert!(result.is_ok(), "result = {:?}", result);
+    }
+
+    /// A disabled content encoding is allowed without a payload, since the
+    /// encryption headers are ignored
+    #[test]
+    fn disabled_content_encoding_no_data() {
+        let req = TestRequest::post()
+            .header("Content-Encoding", "aesgcm128")
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false, MAX_TTL, MAX_HEADER_LEN, &[]);
+        assert!(result.is_ok(), "result = {:?}", result);
+    }
 }
@@ -1,28 +1,29 @@
 use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::routers::{RouterError, RouterType};
 use crate::server::extractors::token_info::{ApiVersion, TokenInfo};
 use crate::server::extractors::user::validate_user;
-use crate::server::headers::crypto_key::CryptoKeyHeader;
-use crate::server::headers::vapid::{VapidHeader, VapidHeaderWithKey, VapidVersionData};
+use crate::server::extractors::vapid::{extract_public_key, Vapid};
+use crate::server::headers::vapid::{VapidHeader, VapidHeaderWithKey};
 use crate::server::{ServerState, VapidError};
 use actix_http::{Payload, PayloadStream};
 use actix_web::web::Data;
 use actix_web::{FromRequest, HttpRequest};
 use autopush_common::db::DynamoDbUser;
-use autopush_common::util::sec_since_epoch;
 use cadence::{Counted, StatsdClient};
 use futures::compat::Future01CompatExt;
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
-use jsonwebtoken::{Algorithm, DecodingKey, Validation};
 use openssl::hash::MessageDigest;
 use std::borrow::Cow;
 use uuid::Uuid;
 
 /// Extracts subscription data from `TokenInfo` and verifies auth/crypto headers
+#[derive(Clone)]
 pub struct Subscription {
     pub user: DynamoDbUser,
+    pub router_type: RouterType,
     pub channel_id: Uuid,
-    pub vapid: Option<VapidHeaderWithKey>,
+    pub vapid: Option<Vapid>,
 }
 
 impl FromRequest for Subscription {
@@ -47,7 +48,7 @@ impl FromRequest for Subscription {
 
             // Parse VAPID and extract public key.
             let vapid: Option<VapidHeaderWithKey> = parse_vapid(&token_info, &state.metrics)?
-                .map(|vapid| extract_public_key(vapid, &token_info))
+                .map(|vapid| extract_public_key(vapid, token_info.crypto_key_header.as_deref()))
                 .transpose()?;
 
             match token_info.api_version {
@@ -60,23 +61,32 @@ impl FromRequest for Subscription {
             let channel_id = Uuid::from_slice(&token[16..32])?;
             let user = state
                 .ddb
-                .get_user(&uaid)
+                .find_user(&uaid)
                 .compat()
                 .await
                 .map_err(ApiErrorKind::Database)?;
+            let user = existing_user(user)?;
             validate_user(&user, &channel_id, &state).await?;
+            let router_type = user.router_type.parse().map_err(|_| {
+                ApiErrorKind::Internal(format!("Unknown router type: {}", user.router_type))
+            })?;
 
-            // Validate the VAPID JWT token and record the version
+            // Verify the VAPID JWT token and record the version
+            let audience = state.settings.endpoint_url().origin().ascii_serialization();
+            let vapid = vapid
+                .map(|vapid| Vapid::verify(vapid, &audience))
+                .transpose()?;
             if let Some(vapid) = &vapid {
-                validate_vapid_jwt(vapid)?;
-
-                state
-                    .metrics
-                    .incr(&format!("updates.vapid.draft{:02}", vapid.vapid.version()))?;
+                vapid.check_subject(&state.settings.vapid_allowed_subs)?;
+                state.metrics.incr(&format!(
+                    "updates.vapid.draft{:02}",
+                    vapid.header.vapid.version()
+                ))?;
             }
 
             Ok(Subscription {
                 user,
+                router_type,
                 channel_id,
                 vapid,
             })
@@ -85,6 +95,15 @@ impl FromRequest for Subscription {
     }
 }
 
+/// Check that the user still exists, so a notification to a subscription
+/// which was deleted after its endpoint was created fails before routing
+fn existing_user(user: Option<DynamoDbUser>) -> ApiResult<DynamoDbUser> {
+    user.ok_or_else(|| {
+        debug!("The user was deleted before the notification was sent");
+        RouterError::UserWasDeleted.into()
+    })
+}
+
 /// Add back padding to a base64 string
 fn repad_base64(data: &str) -> Cow<'_, str> {
     let remaining_padding = data.len() % 4;
@@ -120,32 +139,6 @@ fn parse_vapid(token_info: &TokenInfo, metrics: &StatsdClient) -> ApiResult<Opti
     Ok(Some(vapid))
 }
 
-/// Extract the VAPID public key from the headers
-fn extract_public_key(vapid: VapidHeader, token_info: &TokenInfo) -> ApiResult<VapidHeaderWithKey> {
-    Ok(match vapid.version_data.clone() {
-        VapidVersionData::Version1 => {
-            // VAPID v1 stores the public key in the Crypto-Key header
-            let header = token_info.crypto_key_header.as_deref().ok_or_else(|| {
-                ApiErrorKind::InvalidEncryption("Missing Crypto-Key header".to_string())
-            })?;
-            let header_data = CryptoKeyHeader::parse(header).ok_or_else(|| {
-                ApiErrorKind::InvalidEncryption("Invalid Crypto-Key header".to_string())
-            })?;
-            let public_key = header_data.get_by_key("p256ecdsa").ok_or_else(|| {
-                ApiErrorKind::InvalidEncryption(
-                    "Missing p256ecdsa in Crypto-Key header".to_string(),
-                )
-            })?;
-
-            VapidHeaderWithKey {
-                vapid,
-                public_key: public_key.to_string(),
-            }
-        }
-        VapidVersionData::Version2 { public_key } => VapidHeaderWithKey { vapid, public_key },
-    })
-}
-
 /// `/webpush/v1/` validations
 fn version_1_validation(token: &[u8]) -> ApiResult<()> {
     if token.len() != 32 {
@@ -182,35 +175,26 @@ fn version_2_validation(token: &[u8], vapid: Option<&VapidHeaderWithKey>) -> Api
     Ok(())
 }
 
-/// Validate the VAPID JWT token. Specifically,
-/// - Check the signature
-/// - Make sure it hasn't expired
-/// - Make sure the expiration isn't too far into the future
-///
-/// This is mostly taken care of by the jsonwebtoken library
-fn validate_vapid_jwt(vapid: &VapidHeaderWithKey) -> ApiResult<()> {
-    let VapidHeaderWithKey { vapid, public_key } = vapid;
-
-    #[derive(serde::Deserialize)]
-    struct Claims {
-        exp: u64,
-    }
-
-    // Check the signature and make sure the expiration is in the future
-    let token_data = jsonwebtoken::decode::<Claims>(
-        &vapid.token,
-        &DecodingKey::from_ec_der(public_key.as_bytes()),
-        &Validation::new(Algorithm::ES256),
-    )?;
+#[cfg(test)]
+mod tests {
+    use super::existing_user;
+    use actix_web::http::StatusCode;
+    use autopush_common::db::DynamoDbUser;
 
-    // Make sure the expiration isn't too far into the future
-    let now = sec_since_epoch();
-    const ONE_DAY_IN_SECONDS: u64 = 60 * 60 * 24;
+    /// An existing user is used for routing
+    #[test]
+    fn user_exists() {
+        let user = DynamoDbUser::default();
 
-    if token_data.claims.exp - now > ONE_DAY_IN_SECONDS {
-        // The expiration is too far in the future
-        return Err(VapidError::FutureExpirationToken.into());
+        assert_eq!(existing_user(Some(user.clone())).unwrap().uaid, user.uaid);
     }
 
-    Ok(())
+    /// A deleted user is reported as gone, with errno 105
+    #[test]
+    fn user_deleted() {
+        let error = existing_user(None).unwrap_err();
+
+        assert_eq!(error.kind.status(), StatusCode::GONE);
+        assert_eq!(error.kind.errno(), Some(105));
+    }
 }
@@ -1,6 +1,7 @@
 //! User validations
 
 use crate::error::{ApiErrorKind, ApiResult};
+use crate::routers::RouterError;
 use crate::server::ServerState;
 use autopush_common::db::{DynamoDbUser, DynamoStorage};
 use cadence::{Counted, StatsdClient};
@@ -62,8 +63,9 @@ async fn validate_webpush_user(
         .await
         .map_err(ApiErrorKind::Database)?;
 
+    // The subscription was removed after its endpoint was created
     if !channel_ids.contains(channel_id) {
-        return Err(ApiErrorKind::NoSubscription.into());
+        return Err(RouterError::UserWasDeleted.into());
     }
 
     Ok(())
@@ -1,5 +1,6 @@
 use crate::server::headers::util::split_key_value;
 use std::collections::HashMap;
+use thiserror::Error;
 
 /// Parses the Crypto-Key header (and similar headers) described by
 /// http://tools.ietf.org/html/draft-ietf-httpbis-encryption-encoding-00#section-4
@@ -8,30 +9,64 @@ pub struct CryptoKeyHeader {
     sections: Vec<HashMap<String, String>>,
 }
 
+/// Describes which part of a Crypto-Key header is malformed
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum CryptoKeyError {
+    #[error("item '{0}' is missing '='")]
+    MissingEquals(String),
+
+    #[error("item '{0}' has an empty key")]
+    EmptyKey(String),
+
+    #[error("key '{0}' appears more than once in a section")]
+    DuplicateKey(String),
+}
+
 impl CryptoKeyHeader {
-    /// Parse a Crypto-Key header
+    /// Parse a Crypto-Key header, discarding the details of why it is
+    /// malformed
     pub fn parse(header: &str) -> Option<Self> {
+        Self::try_parse(header).ok()
+    }
+
+    /// Parse a Crypto-Key header. Whitespace around the separators and quotes
+    /// around values are ignored, as are empty sections and items (ex. a
+    /// trailing comma).
+    pub fn try_parse(header: &str) -> Result<Self, CryptoKeyError> {
         let mut sections = Vec::new();
 
         for section_str in header.split(',') {
             let mut section = HashMap::new();
 
             for item_str in section_str.split(';') {
-                let (key, value) = split_key_value(item_str)?;
-
-                section.insert(
-                    key.trim().to_owned(),
-                    value.trim_matches(&[' ', '"'] as &[char]).to_owned(),
-                );
+                let item_str = item_str.trim();
+                if item_str.is_empty() {
+                    continue;
+                }
+
+                let (key, value) = split_key_value(item_str)
+                    .ok_or_else(|| CryptoKeyError::MissingEquals(item_str.to_owned()))?;
+                let key = key.trim();
+                if key.is_empty() {
+                    return Err(CryptoKeyError::EmptyKey(item_str.to_owned()));
+                }
+                if section.contains_key(key) {
+                    return Err(CryptoKeyError::DuplicateKey(key.to_owned()));
+                }
+
+                section.insert(key.to_owned(), value.trim().trim_matches('"').to_owned());
             }
 
-            sections.push(section);
+            if !section.is_empty() {
+                sections.push(section);
+            }
         }
 
-        Some(Self { sections })
+        Ok(Self { sections })
     }
 
-    /// Get the value of the first item with the given key
+    /// Get the value of the first item with the given key, searching all
+    /// sections
     pub fn get_by_key(&self, key: &str) -> Option<&str> {
         for section in &self.sections {
             if let Some(value) = section.get(key) {
@@ -41,11 +76,30 @@ impl CryptoKeyHeader {
 
         None
     }
+
+    /// Get the value of an item in the section with the given key ID. If no
+    /// section has the key ID (or there is no key ID), the first item with the
+    /// key in any section is used.
+    pub fn get_by_key_in_group(&self, key: &str, keyid: Option<&str>) -> Option<&str> {
+        if let Some(keyid) = keyid {
+            let group_value = self
+                .sections
+                .iter()
+                .filter(|section| section.get("keyid").map(String::as_str) == Some(keyid))
+                .find_map(|section| section.get(key));
+
+            if let Some(value) = group_value {
+                return Some(value.as_str());
+            }
+        }
+
+        self.get_by_key(key)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::CryptoKeyHeader;
+    use super::{CryptoKeyError, CryptoKeyHeader};
 
     const TEST_HEADER: &str = "keyid=\"p256dh\";dh=\"BDw9T0eImd4ax818VcYqDK_DOhcuDswKero\
         YyNkdhYmygoLSDlSiWpuoWYUSSFxi25cyyNTR5k9Ny93DzZc0UI4\",\
@@ -87,9 +141,93 @@ mod tests {
         assert!(crypto_keys.get_by_key("unknown").is_none());
     }
 
+    /// Whitespace around separators, quotes, and empty sections are tolerated
+    #[test]
+    fn parse_with_whitespace() {
+        let crypto_keys =
+            CryptoKeyHeader::parse(" dh = \"dh-key\" ; keyid=p256dh , p256ecdsa= vapid-key ,")
+                .unwrap();
+
+        assert_eq!(crypto_keys.get_by_key("dh"), Some("dh-key"));
+        assert_eq!(crypto_keys.get_by_key("keyid"), Some("p256dh"));
+        assert_eq!(crypto_keys.get_by_key("p256ecdsa"), Some("vapid-key"));
+    }
+
+    /// The first section containing a key is used
+    #[test]
+    fn first_match_is_used() {
+        let crypto_keys = CryptoKeyHeader::parse("dh=first,p256ecdsa=key,dh=second").unwrap();
+
+        assert_eq!(crypto_keys.get_by_key("dh"), Some("first"));
+        assert_eq!(crypto_keys.get_by_key("p256ecdsa"), Some("key"));
+    }
+
+    /// The item is taken from the section with the matching key ID
+    #[test]
+    fn get_by_key_in_group() {
+        let crypto_keys = CryptoKeyHeader::parse(
+            "keyid=other;dh=other-dh,keyid=p256dh;dh=p256-dh,p256ecdsa=vapid-key",
+        )
+        .unwrap();
+
+        assert_eq!(
+            crypto_keys.get_by_key_in_group("dh", Some("p256dh")),
+            Some("p256-dh")
+        );
+        assert_eq!(
+            crypto_keys.get_by_key_in_group("dh", Some("other")),
+            Some("other-dh")
+        );
+        assert_eq!(
+            crypto_keys.get_by_key_in_group("p256ecdsa", Some("p256dh")),
+            Some("vapid-key")
+        );
+    }
+
+    /// Without a matching key ID, the first section containing the key is used
+    #[test]
+    fn get_by_key_in_group_fallback() {
+        let crypto_keys = CryptoKeyHeader::parse("dh=first,keyid=p256dh;dh=second").unwrap();
+
+        assert_eq!(crypto_keys.get_by_key_in_group("dh", None), Some("first"));
+        assert_eq!(
+            crypto_keys.get_by_key_in_group("dh", Some("unknown")),
+            Some("first")
+        );
+    }
+
     /// Parsing an invalid header (no equals sign in item) returns None
     #[test]
     fn parse_invalid() {
         assert!(CryptoKeyHeader::parse("key=value;invalid").is_none());
     }
+
+    /// An item without an equals sign is reported
+    #[test]
+    fn try_parse_missing_equals() {
+        assert_eq!(
+            CryptoKeyHeader::try_parse("key=value; invalid ").err(),
+            Some(CryptoKeyError::MissingEquals("invalid".to_string()))
+        );
+    }
+
+    /// An item with an empty key is reported
+    #[test]
+    fn try_parse_empty_key() {
+        assert_eq!(
+            CryptoKeyHeader::try_parse("dh=key,=value").err(),
+            Some(CryptoKeyError::EmptyKey("=value".to_string()))
+        );
+    }
+
+    /// A key repeated in the same section is reported, while the same key in
+    /// different sections is allowed
+    #[test]
+    fn try_parse_duplicate_key() {
+        assert_eq!(
+            CryptoKeyHeader::try_parse("dh=first; dh=second").err(),
+            Some(CryptoKeyError::DuplicateKey("dh".to_string()))
+        );
+        assert!(CryptoKeyHeader::try_parse("dh=first,dh=second").is_ok());
+    }
 }
@@ -1,12 +1,30 @@
 //! Main application server
 
-use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::error::{self, ApiError, ApiErrorKind, ApiResult, MoreInfoUrl};
+use crate::idempotency::IdempotencyCache;
 use crate::metrics;
+use crate::rate_limit::{MemoryRateLimitStore, RateLimiter, UaidRateLimiter};
+use crate::routers::adm::router::AdmRouter;
+use crate::routers::apns::router::ApnsRouter;
+use crate::routers::circuit_breaker::{BreakerPolicy, CircuitBreaker};
+use crate::routers::fcm::router::FcmRouter;
+use crate::routers::node_limiter::NodeSendLimiter;
+use crate::routers::retry::RetryPolicy;
+use crate::routers::webpush::WebPushRouter;
+use crate::routers::{RouterDispatch, RouterType};
+use crate::server::routes::admin::{
+    drop_user_messages_route, multicast_route, notify_user_route, put_broadcast_route,
+};
 use crate::server::routes::health::{
-    health_route, lb_heartbeat_route, status_route, version_route,
+    health_route, heartbeat_route, lb_heartbeat_route, router_health_route, status_route,
+    version_route,
+};
+use crate::server::routes::webpush::{
+    delete_notification_route, notification_status_route, webpush_route,
 };
-use crate::server::routes::webpush::webpush_route;
 use crate::settings::Settings;
+use crate::shutdown::{self, ShutdownCoordinator};
+use crate::stale_nodes::{self, StaleNodeScan};
 use actix_cors::Cors;
 use actix_web::{
     dev, http::StatusCode, middleware::errhandlers::ErrorHandlers, web, App, HttpServer,
@@ -15,9 +33,11 @@ use autopush_common::db::DynamoStorage;
 use cadence::StatsdClient;
 use fernet::MultiFernet;
 use std::sync::Arc;
+use std::time::Duration;
 
-mod extractors;
+pub mod extractors;
 mod headers;
+pub mod request_id;
 mod routes;
 
 pub use headers::vapid::VapidError;
@@ -29,6 +49,12 @@ pub struct ServerState {
     pub settings: Settings,
     pub fernet: Arc<MultiFernet>,
     pub ddb: DynamoStorage,
+    pub routers: Arc<RouterDispatch>,
+    /// Also registered with `routers`, for routes which use it directly
+    pub webpush_router: Arc<WebPushRouter>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub idempotency: Arc<IdempotencyCache>,
+    pub shutdown: Arc<ShutdownCoordinator>,
 }
 
 pub struct Server;
@@ -44,33 +70,160 @@ impl Server {
             metrics.clone(),
         )
         .map_err(ApiErrorKind::Database)?;
+        let idempotency = Arc::new(IdempotencyCache::new(
+            Arc::new(ddb.clone()),
+            settings.idempotency_table_name.clone(),
+            Duration::from_secs(settings.idempotency_window_sec),
+        ));
+        let http = settings
+            .http_client_builder()
+            .build()
+            .map_err(|e| ApiErrorKind::Internal(format!("Unable to build HTTP client: {}", e)))?;
+        if settings.stale_node_scan_interval_sec > 0 {
+            stale_nodes::spawn_cleanup(
+                Arc::new(ddb.clone()),
+                metrics.clone(),
+                Duration::from_secs(settings.stale_node_scan_interval_sec),
+                StaleNodeScan {
+                    max_age: Duration::from_secs(settings.stale_node_max_age_sec),
+                    page_size: settings.stale_node_scan_page_size,
+                    page_delay: Duration::from_millis(settings.stale_node_scan_page_delay_ms),
+                },
+            );
+        }
+        let webpush_router = Arc::new(WebPushRouter {
+            ddb: Arc::new(ddb.clone()),
+            metrics: metrics.clone(),
+            http: http.clone(),
+            endpoint_url: settings.endpoint_url(),
+            regional_endpoint_urls: settings.regional_endpoint_urls(),
+            max_data_bytes: settings.max_data_bytes,
+            retry_policy: RetryPolicy {
+                max_retries: settings.node_retries,
+                base_delay: Duration::from_millis(settings.node_retry_delay_ms),
+                max_jitter: Duration::from_millis(settings.node_retry_jitter_ms),
+            },
+            node_request_timeout: Duration::from_secs(settings.node_request_timeout_sec),
+            broadcast_concurrency: settings.broadcast_concurrency,
+            fernet: fernet.clone(),
+            breaker: CircuitBreaker::new(BreakerPolicy {
+                failure_threshold: settings.node_breaker_threshold,
+                failure_window: Duration::from_secs(settings.node_breaker_window_sec),
+                cooldown: Duration::from_secs(settings.node_breaker_cooldown_sec),
+            }),
+            node_limiter: NodeSendLimiter::new(
+                settings.node_max_concurrent_sends,
+                Duration::from_millis(settings.node_send_wait_ms),
+            ),
+            node_allowed_hosts: settings.node_allowed_hosts.clone(),
+            db_retry_after: Duration::from_secs(settings.db_retry_after_sec),
+            db_retry_jitter: Duration::from_secs(settings.db_retry_jitter_sec),
+            uaid_limiter: Arc::new(UaidRateLimiter::new(
+                settings.uaid_rate_limit_per_sec,
+                settings.uaid_rate_limit_burst,
+            )),
+            uaid_retry_jitter: Duration::from_secs(settings.rate_limit_retry_jitter_sec),
+            idempotency: idempotency.clone(),
+            max_channel_messages: settings.max_channel_messages,
+            evict_channel_messages: settings.evict_channel_messages,
+        });
+        let mut routers = RouterDispatch::new(metrics.clone());
+        routers.register(RouterType::WebPush, Box::new(webpush_router.clone()));
+        // Bridges without credentials are left out, so their users are
+        // rejected as not configured and the health check skips them
+        if settings.fcm.is_configured() {
+            let fcm_router = FcmRouter::new(&settings.fcm, http.clone(), metrics.clone())?;
+            routers.register(RouterType::Fcm, Box::new(fcm_router));
+        }
+        if settings.adm.is_configured() {
+            let adm_router = AdmRouter::new(&settings.adm, http, metrics.clone())?;
+            routers.register(RouterType::Adm, Box::new(adm_router));
+        }
+        if settings.apns.is_configured() {
+            // APNS only supports HTTP/2
+            let apns_http = settings
+                .http_client_builder()
+                .http2_prior_knowledge()
+                .build()
+                .map_err(|e| {
+                    ApiErrorKind::Internal(format!("Unable to build APNS client: {}", e))
+                })?;
+            let apns_router = ApnsRouter::new(&settings.apns, apns_http, metrics.clone())?;
+            routers.register(RouterType::Apns, Box::new(apns_router));
+        }
+
+        let rate_limiter = RateLimiter::new(
+            Box::new(MemoryRateLimitStore::default()),
+            settings.rate_limit_messages,
+            Duration::from_secs(settings.rate_limit_window_sec),
+            Duration::from_secs(settings.rate_limit_retry_jitter_sec),
+        );
+
+        let more_info_url = MoreInfoUrl(settings.error_docs_url.clone());
+        let shutdown = Arc::new(ShutdownCoordinator::new(metrics.clone()));
+        let shutdown_grace = Duration::from_secs(settings.shutdown_grace_sec);
         let state = ServerState {
             metrics,
             settings,
             fernet,
             ddb,
+            routers: Arc::new(routers),
+            webpush_router,
+            rate_limiter: Arc::new(rate_limiter),
+            idempotency,
+            shutdown: shutdown.clone(),
         };
 
         let server = HttpServer::new(move || {
             App::new()
                 .data(state.clone())
+                .data(more_info_url.clone())
+                .wrap_fn(error::render_more_info)
                 .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
                 .wrap(Cors::default())
+                .wrap_fn(request_id::add_request_id)
                 // Endpoints
                 .service(
                     web::resource(["/wpush/{api_version}/{token}", "/wpush/{token}"])
                         .route(web::post().to(webpush_route)),
                 )
+                .service(
+                    web::resource("/m/{message_id}")
+                        .route(web::get().to(notification_status_route))
+                        .route(web::delete().to(delete_notification_route)),
+                )
+                // Admin
+                .service(
+                    web::resource("/admin/uaid/{uaid}/messages")
+                        .route(web::delete().to(drop_user_messages_route)),
+                )
+                .service(
+                    web::resource("/admin/uaid/{uaid}/notify")
+                        .route(web::post().to(notify_user_route)),
+                )
+                .service(
+                    web::resource("/admin/broadcasts").route(web::put().to(put_broadcast_route)),
+                )
+                .service(web::resource("/admin/multicast").route(web::post().to(multicast_route)))
                 // Health checks
                 .service(web::resource("/status").route(web::get().to(status_route)))
                 .service(web::resource("/health").route(web::get().to(health_route)))
                 // Dockerflow
-                .service(web::resource("/__heartbeat__").route(web::get().to(status_route)))
+                .service(web::resource("/__heartbeat__").route(web::get().to(heartbeat_route)))
+                .service(web::resource("/__health__").route(web::get().to(router_health_route)))
                 .service(web::resource("/__lbheartbeat__").route(web::get().to(lb_heartbeat_route)))
                 .service(web::resource("/__version__").route(web::get().to(version_route)))
         })
         .bind(bind_address)?
+        // Signals are handled below, so notifications can be drained first
+        .disable_signals()
+        .shutdown_timeout(shutdown_grace.as_secs())
         .run();
+        actix_rt::spawn(shutdown::handle_signals(
+            server.clone(),
+            shutdown,
+            shutdown_grace,
+        ));
 
         Ok(server)
     }
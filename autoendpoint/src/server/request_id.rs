@@ -0,0 +1,118 @@
+//! Request IDs, which correlate the logs of a notification across the
+//! endpoint and the connection node
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage, HttpRequest};
+use std::future::Future;
+use uuid::Uuid;
+
+/// The header the request ID is read from, returned in, and forwarded to the
+/// node in
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The longest request ID accepted from the client
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// The request ID, stored in the request extensions
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Get the request ID of a request, if the middleware has run
+    pub fn of(req: &HttpRequest) -> Option<String> {
+        req.extensions().get::<RequestId>().map(|id| id.0.clone())
+    }
+
+    /// Use the client's request ID if it is safe to log and forward,
+    /// otherwise generate one
+    fn from_header(header: Option<&str>) -> Self {
+        let is_valid = |id: &&str| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+
+        RequestId(
+            header
+                .filter(is_valid)
+                .map(str::to_string)
+                .unwrap_or_else(|| Uuid::new_v4().to_simple().to_string()),
+        )
+    }
+}
+
+/// Middleware which reads or generates the request ID, stores it in the
+/// request extensions and returns it in the response
+pub fn add_request_id<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let request_id = RequestId::from_header(
+        req.headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok()),
+    );
+    req.extensions_mut().insert(request_id.clone());
+    let response = srv.call(req);
+
+    async move {
+        let mut response = response.await?;
+        if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_request_id, RequestId, REQUEST_ID_HEADER};
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    /// Respond with the request ID seen by the handler
+    async fn echo_request_id(req: HttpRequest) -> HttpResponse {
+        HttpResponse::Ok().body(RequestId::of(&req).unwrap_or_default())
+    }
+
+    /// The client's request ID is used and returned
+    #[actix_rt::test]
+    async fn client_request_id() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(add_request_id)
+                .route("/", web::get().to(echo_request_id)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "test-request-id")
+            .to_request();
+
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(
+            response.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "test-request-id"
+        );
+        assert_eq!(test::read_body(response).await, "test-request-id");
+    }
+
+    /// A request ID is generated if the client's is missing or unsafe
+    #[test]
+    fn generated_request_id() {
+        let generated = RequestId::from_header(None);
+        assert_eq!(generated.0.len(), 32);
+
+        let unsafe_id = RequestId::from_header(Some("bad id\nwith a newline"));
+        assert_ne!(unsafe_id.0, "bad id\nwith a newline");
+        assert_eq!(unsafe_id.0.len(), 32);
+    }
+}
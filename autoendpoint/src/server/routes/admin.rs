@@ -0,0 +1,570 @@
+//! Admin routes, which operators use to manage users
+
+use crate::db::DbClient;
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::logging;
+use crate::routers::webpush::{BroadcastOutcome, WebPushRouter};
+use crate::routers::RouterType;
+use crate::server::extractors::notification::{NotificationBody, NotificationBuilder};
+use crate::server::extractors::subscription::Subscription;
+use crate::server::headers::util::get_header;
+use crate::server::ServerState;
+use actix_web::web::{Data, Json};
+use actix_web::{HttpRequest, HttpResponse};
+use autopush_common::db::DynamoDbBroadcast;
+use autopush_common::util::ms_since_epoch;
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::cmp::{max, min};
+use uuid::Uuid;
+
+/// The max length of a broadcast ID
+const MAX_BROADCAST_ID_LEN: usize = 64;
+/// The max length of a broadcast version
+const MAX_BROADCAST_VERSION_LEN: usize = 128;
+/// The max number of subscriptions a multicast is sent to
+const MAX_MULTICAST_SUBSCRIPTIONS: usize = 1000;
+
+/// The body of the `PUT /admin/broadcasts` route
+#[derive(Debug, Deserialize)]
+pub struct BroadcastChange {
+    /// The name of the broadcast (ex. `remote-settings/monitor_changes`)
+    pub broadcast_id: String,
+    /// The new value of the broadcast
+    pub version: String,
+}
+
+/// A subscription in the body of the `POST /admin/multicast` route
+#[derive(Debug, Deserialize)]
+pub struct MulticastSubscription {
+    pub uaid: Uuid,
+    pub channel_id: Uuid,
+}
+
+/// The body of the `POST /admin/multicast` route
+#[derive(Debug, Deserialize)]
+pub struct Multicast {
+    pub subscriptions: Vec<MulticastSubscription>,
+    /// How long the notification is stored for users who aren't connected,
+    /// in seconds
+    pub ttl: i64,
+    pub topic: Option<String>,
+}
+
+/// Handle the `DELETE /admin/uaid/{uaid}/messages` route, which removes all
+/// the messages stored for a user (ex. when their account is deleted)
+pub async fn drop_user_messages_route(
+    req: HttpRequest,
+    state: Data<ServerState>,
+) -> ApiResult<HttpResponse> {
+    check_admin_auth(&req, &state.settings.admin_auth_key)?;
+    let uaid = path_uaid(&req)?;
+
+    drop_user_messages(&state.ddb, &uaid).await
+}
+
+/// Handle the `POST /admin/uaid/{uaid}/notify` route, which asks the user's
+/// node to check for all their stored messages (ex. after a migration). This
+/// responds with a 404 if the user is not connected, and a 503 if the node
+/// can't be reached.
+pub async fn notify_user_route(
+    req: HttpRequest,
+    state: Data<ServerState>,
+) -> ApiResult<HttpResponse> {
+    check_admin_auth(&req, &state.settings.admin_auth_key)?;
+    let uaid = path_uaid(&req)?;
+
+    notify_user(&state.ddb, &state.webpush_router, &uaid).await
+}
+
+/// Handle the `PUT /admin/broadcasts` route, which stores the new value of a
+/// broadcast and tells the connection nodes about it, so they can update
+/// their connected clients
+pub async fn put_broadcast_route(
+    req: HttpRequest,
+    change: Json<BroadcastChange>,
+    state: Data<ServerState>,
+) -> ApiResult<HttpResponse> {
+    check_admin_auth(&req, &state.settings.admin_auth_key)?;
+
+    let broadcast = put_broadcast(
+        &state.ddb,
+        &state.settings.broadcast_table_name,
+        change.into_inner(),
+    )
+    .await?;
+    let nodes_notified = state
+        .webpush_router
+        .notify_broadcast_change(&state.settings.broadcast_nodes, &broadcast)
+        .await;
+    info!(
+        "Broadcast {} changed to {}, notified {} of {} nodes",
+        broadcast.broadcast_id,
+        broadcast.version,
+        nodes_notified,
+        state.settings.broadcast_nodes.len()
+    );
+
+    Ok(HttpResponse::Ok().json(json!({
+        "broadcast_id": broadcast.broadcast_id,
+        "version": broadcast.version,
+        "nodes_notified": nodes_notified,
+    })))
+}
+
+/// Handle the `POST /admin/multicast` route, which sends the same
+/// notification, without data, to many WebPush subscriptions (ex. a service
+/// announcement). This responds with how many notifications were delivered,
+/// stored, or could not be sent because the user is gone.
+pub async fn multicast_route(
+    req: HttpRequest,
+    multicast: Json<Multicast>,
+    state: Data<ServerState>,
+) -> ApiResult<HttpResponse> {
+    check_admin_auth(&req, &state.settings.admin_auth_key)?;
+    let multicast = multicast.into_inner();
+
+    let body = multicast_body(&multicast, state.settings.max_ttl)?;
+    let subscriptions = find_subscriptions(
+        &state.ddb,
+        &multicast.subscriptions,
+        state.settings.broadcast_concurrency,
+    )
+    .await?;
+    let missing = multicast.subscriptions.len() - subscriptions.len();
+
+    let summary = state
+        .webpush_router
+        .route_broadcast(&subscriptions, &body)
+        .await;
+    let delivered = summary.count(BroadcastOutcome::Delivered);
+    let stored = summary.count(BroadcastOutcome::Stored);
+    let gone = summary.count(BroadcastOutcome::Gone) + missing;
+    let failed = summary.count(BroadcastOutcome::Failed);
+    info!(
+        "Multicast to {} subscriptions: {} delivered, {} stored, {} gone, {} failed",
+        multicast.subscriptions.len(),
+        delivered,
+        stored,
+        gone,
+        failed
+    );
+
+    Ok(HttpResponse::Ok().json(json!({
+        "delivered": delivered,
+        "stored": stored,
+        "gone": gone,
+        "failed": failed,
+    })))
+}
+
+/// Get the UAID from the admin route path
+fn path_uaid(req: &HttpRequest) -> ApiResult<Uuid> {
+    let uaid = req
+        .match_info()
+        .get("uaid")
+        .expect("{uaid} must be part of the admin path")
+        .parse()?;
+
+    Ok(uaid)
+}
+
+/// Check that the request has the admin key as its bearer token. The admin
+/// routes are disabled if there is no admin key.
+fn check_admin_auth(req: &HttpRequest, admin_auth_key: &str) -> ApiResult<()> {
+    if admin_auth_key.is_empty() {
+        return Err(ApiErrorKind::InvalidAdminAuth.into());
+    }
+
+    let token = get_header(req, "authorization").and_then(|header| {
+        let mut parts = header.splitn(2, ' ');
+        let scheme = parts.next()?;
+        let token = parts.next()?;
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            Some(token.trim())
+        } else {
+            None
+        }
+    });
+
+    match token {
+        Some(token)
+            if token.len() == admin_auth_key.len()
+                && openssl::memcmp::eq(token.as_bytes(), admin_auth_key.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => Err(ApiErrorKind::InvalidAdminAuth.into()),
+    }
+}
+
+/// Remove the user's stored messages, responding with how many were removed
+async fn drop_user_messages(ddb: &dyn DbClient, uaid: &Uuid) -> ApiResult<HttpResponse> {
+    // The user record may already be deleted, in which case any messages left
+    // are in the current message table
+    let message_month = match ddb.get_user(uaid).await {
+        Ok(user) => user.and_then(|user| user.current_month),
+        Err(e) => {
+            debug!("Unable to find the user to drop messages for: {}", e);
+            None
+        }
+    }
+    .unwrap_or_else(|| ddb.current_message_month());
+
+    let deleted = ddb
+        .drop_user_messages(uaid, &message_month)
+        .await
+        .map_err(ApiErrorKind::Database)?;
+    info!(
+        "Dropped {} stored messages for UAID {}",
+        deleted,
+        logging::log_uaid(&uaid)
+    );
+
+    Ok(HttpResponse::Ok().json(json!({ "deleted": deleted })))
+}
+
+/// Validate and store the new value of a broadcast
+async fn put_broadcast(
+    ddb: &dyn DbClient,
+    table_name: &str,
+    change: BroadcastChange,
+) -> ApiResult<DynamoDbBroadcast> {
+    validate_broadcast(&change)?;
+
+    let broadcast = DynamoDbBroadcast {
+        broadcast_id: change.broadcast_id,
+        version: change.version,
+        updated_at: ms_since_epoch(),
+    };
+    ddb.put_broadcast(table_name, broadcast.clone())
+        .await
+        .map_err(ApiErrorKind::Database)?;
+
+    Ok(broadcast)
+}
+
+/// Check that the broadcast ID and version can be sent to clients. IDs are
+/// limited to the characters Megaphone uses, such as `service/key`.
+fn validate_broadcast(change: &BroadcastChange) -> ApiResult<()> {
+    let is_valid_id = change
+        .broadcast_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '/');
+    if change.broadcast_id.is_empty()
+        || change.broadcast_id.len() > MAX_BROADCAST_ID_LEN
+        || !is_valid_id
+    {
+        return Err(ApiErrorKind::InvalidBroadcast(format!(
+            "broadcast_id must be 1 to {} letters, digits, '-', '_' or '/'",
+            MAX_BROADCAST_ID_LEN
+        ))
+        .into());
+    }
+
+    if change.version.is_empty() || change.version.len() > MAX_BROADCAST_VERSION_LEN {
+        return Err(ApiErrorKind::InvalidBroadcast(format!(
+            "version must be 1 to {} characters",
+            MAX_BROADCAST_VERSION_LEN
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Validate the multicast and build its notification. TTLs above `max_ttl`
+/// are reduced to `max_ttl`, like for other notifications.
+fn multicast_body(multicast: &Multicast, max_ttl: i64) -> ApiResult<NotificationBody> {
+    if multicast.subscriptions.len() > MAX_MULTICAST_SUBSCRIPTIONS {
+        return Err(ApiErrorKind::InvalidBroadcast(format!(
+            "a multicast may be sent to up to {} subscriptions",
+            MAX_MULTICAST_SUBSCRIPTIONS
+        ))
+        .into());
+    }
+
+    let builder = NotificationBuilder::new().ttl(min(multicast.ttl, max_ttl));
+    match &multicast.topic {
+        Some(topic) => builder.topic(topic.as_str()),
+        None => builder,
+    }
+    .build_body()
+}
+
+/// Read the users of the subscriptions, up to `concurrency` at a time.
+/// Subscriptions of users which no longer exist, or which don't use WebPush,
+/// are left out.
+async fn find_subscriptions(
+    ddb: &dyn DbClient,
+    requested: &[MulticastSubscription],
+    concurrency: usize,
+) -> ApiResult<Vec<Subscription>> {
+    let users: Vec<_> = stream::iter(requested)
+        .map(|requested| async move {
+            ddb.get_user(&requested.uaid)
+                .await
+                .map(|user| (user, requested.channel_id))
+        })
+        .buffer_unordered(max(concurrency, 1))
+        .collect()
+        .await;
+
+    let mut subscriptions = Vec::new();
+    for result in users {
+        let (user, channel_id) = result.map_err(ApiErrorKind::Database)?;
+        match user {
+            Some(user) if user.router_type == RouterType::WebPush.as_str() => {
+                subscriptions.push(Subscription {
+                    user,
+                    router_type: RouterType::WebPush,
+                    channel_id,
+                    vapid: None,
+                })
+            }
+            _ => {}
+        }
+    }
+
+    Ok(subscriptions)
+}
+
+/// Ask the user's node to check for stored messages
+async fn notify_user(
+    ddb: &dyn DbClient,
+    router: &WebPushRouter,
+    uaid: &Uuid,
+) -> ApiResult<HttpResponse> {
+    let user = ddb
+        .get_user(uaid)
+        .await
+        .map_err(|e| {
+            debug!("Unable to find the user to notify: {}", e);
+            ApiErrorKind::UserNotFound
+        })?
+        .ok_or(ApiErrorKind::UserNotFound)?;
+
+    // Only WebPush users connect to a node
+    if user.router_type != RouterType::WebPush.as_str() || !router.notify_node(&user).await? {
+        return Err(ApiErrorKind::UserNotConnected.into());
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_admin_auth, drop_user_messages, find_subscriptions, multicast_body, put_broadcast,
+        BroadcastChange, Multicast, MulticastSubscription,
+    };
+    use crate::db::mock::MockDbClient;
+    use crate::error::ApiErrorKind;
+    use actix_web::body::Body;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use actix_web::HttpResponse;
+    use autopush_common::db::DynamoDbUser;
+    use autopush_common::notification::Notification;
+    use uuid::Uuid;
+
+    const ADMIN_KEY: &str = "test-admin-key";
+
+    /// Get the JSON body of a response
+    fn body_json(response: &HttpResponse) -> serde_json::Value {
+        match response.body().as_ref() {
+            Some(Body::Bytes(bytes)) => serde_json::from_slice(bytes).unwrap(),
+            body => panic!("Expected a JSON body, got {:?}", body),
+        }
+    }
+
+    /// All of the user's stored messages are removed, and counted
+    #[actix_rt::test]
+    async fn drops_all_messages() {
+        let user = DynamoDbUser::default();
+        let ddb = MockDbClient::with_user(user.clone());
+        for version in &["message-1", "message-2", "message-3"] {
+            ddb.stored_messages.lock().unwrap().push(Notification {
+                channel_id: Uuid::new_v4(),
+                version: version.to_string(),
+                ..Default::default()
+            });
+        }
+
+        let response = drop_user_messages(&ddb, &user.uaid).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(&response), serde_json::json!({ "deleted": 3 }));
+        assert!(ddb.stored_messages().is_empty());
+    }
+
+    /// A user without stored messages has nothing dropped
+    #[actix_rt::test]
+    async fn drops_nothing() {
+        let user = DynamoDbUser::default();
+        let ddb = MockDbClient::with_user(user.clone());
+
+        let response = drop_user_messages(&ddb, &user.uaid).await.unwrap();
+        assert_eq!(body_json(&response), serde_json::json!({ "deleted": 0 }));
+    }
+
+    /// A broadcast change is stored, replacing the earlier value
+    #[actix_rt::test]
+    async fn stores_broadcast() {
+        let ddb = MockDbClient::default();
+        for version in &["v1", "v2"] {
+            let change = BroadcastChange {
+                broadcast_id: "remote-settings/monitor_changes".to_string(),
+                version: version.to_string(),
+            };
+            put_broadcast(&ddb, "broadcast", change).await.unwrap();
+        }
+
+        let broadcasts = ddb.broadcasts();
+        assert_eq!(broadcasts.len(), 1);
+        assert_eq!(
+            broadcasts[0].broadcast_id,
+            "remote-settings/monitor_changes"
+        );
+        assert_eq!(broadcasts[0].version, "v2");
+    }
+
+    /// Broadcasts with an unusable ID or version are rejected, and not stored
+    #[actix_rt::test]
+    async fn invalid_broadcast() {
+        let ddb = MockDbClient::default();
+        let long_id = "a".repeat(65);
+        let long_version = "1".repeat(129);
+
+        for (broadcast_id, version) in &[
+            ("", "v1"),
+            ("test flag", "v1"),
+            ("test?flag", "v1"),
+            (long_id.as_str(), "v1"),
+            ("test/flag", ""),
+            ("test/flag", long_version.as_str()),
+        ] {
+            let change = BroadcastChange {
+                broadcast_id: broadcast_id.to_string(),
+                version: version.to_string(),
+            };
+
+            let error = put_broadcast(&ddb, "broadcast", change).await.unwrap_err();
+            assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+            match error.kind {
+                ApiErrorKind::InvalidBroadcast(_) => {}
+                kind => panic!("Expected an invalid broadcast error, got {:?}", kind),
+            }
+        }
+        assert!(ddb.broadcasts().is_empty());
+    }
+
+    /// The admin key is accepted as a bearer token
+    #[test]
+    fn valid_admin_auth() {
+        let req = TestRequest::default()
+            .header("Authorization", format!("Bearer {}", ADMIN_KEY))
+            .to_http_request();
+
+        assert!(check_admin_auth(&req, ADMIN_KEY).is_ok());
+    }
+
+    /// Requests with a missing or wrong key are rejected, and all requests are
+    /// rejected when there is no admin key
+    #[test]
+    fn invalid_admin_auth() {
+        let wrong_key = TestRequest::default()
+            .header("Authorization", "Bearer wrong-key")
+            .to_http_request();
+        let wrong_scheme = TestRequest::default()
+            .header("Authorization", format!("Basic {}", ADMIN_KEY))
+            .to_http_request();
+        let missing = TestRequest::default().to_http_request();
+        let empty_key = TestRequest::default()
+            .header("Authorization", "Bearer ")
+            .to_http_request();
+
+        for (req, admin_auth_key) in &[
+            (wrong_key, ADMIN_KEY),
+            (wrong_scheme, ADMIN_KEY),
+            (missing, ADMIN_KEY),
+            (empty_key, ""),
+        ] {
+            let error = check_admin_auth(req, admin_auth_key).unwrap_err();
+            assert_eq!(error.kind.status(), StatusCode::UNAUTHORIZED);
+            match error.kind {
+                ApiErrorKind::InvalidAdminAuth => {}
+                kind => panic!("Expected an invalid admin auth error, got {:?}", kind),
+            }
+        }
+    }
+
+    /// Create a multicast to new subscriptions
+    fn make_multicast(subscriptions: usize, ttl: i64) -> Multicast {
+        Multicast {
+            subscriptions: (0..subscriptions)
+                .map(|_| MulticastSubscription {
+                    uaid: Uuid::new_v4(),
+                    channel_id: Uuid::new_v4(),
+                })
+                .collect(),
+            ttl,
+            topic: None,
+        }
+    }
+
+    /// A multicast's TTL is reduced to the max TTL
+    #[test]
+    fn multicast_ttl_clamped() {
+        let body = multicast_body(&make_multicast(2, 600), 60).unwrap();
+        assert_eq!(body.headers.ttl, Some(60));
+        assert!(body.data.is_none());
+    }
+
+    /// A multicast can't be sent to too many subscriptions at once
+    #[test]
+    fn multicast_too_many_subscriptions() {
+        let error = multicast_body(&make_multicast(1001, 60), 60).unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Subscriptions of WebPush users are found, with their channel ID
+    #[actix_rt::test]
+    async fn multicast_finds_webpush_users() {
+        let ddb = MockDbClient::with_user(DynamoDbUser::default());
+        let multicast = make_multicast(3, 60);
+
+        let subscriptions = find_subscriptions(&ddb, &multicast.subscriptions, 2)
+            .await
+            .unwrap();
+        assert_eq!(subscriptions.len(), 3);
+        for requested in &multicast.subscriptions {
+            assert!(subscriptions
+                .iter()
+                .any(|subscription| subscription.channel_id == requested.channel_id));
+        }
+    }
+
+    /// Subscriptions of users which are gone, or which use a bridge, are
+    /// left out
+    #[actix_rt::test]
+    async fn multicast_skips_missing_and_bridge_users() {
+        let multicast = make_multicast(2, 60);
+
+        let ddb = MockDbClient::default();
+        let subscriptions = find_subscriptions(&ddb, &multicast.subscriptions, 2)
+            .await
+            .unwrap();
+        assert!(subscriptions.is_empty());
+
+        let ddb = MockDbClient::with_user(DynamoDbUser {
+            router_type: "fcm".to_string(),
+            ..Default::default()
+        });
+        let subscriptions = find_subscriptions(&ddb, &multicast.subscriptions, 2)
+            .await
+            .unwrap();
+        assert!(subscriptions.is_empty());
+    }
+}
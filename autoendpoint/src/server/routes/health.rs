@@ -1,8 +1,12 @@
 //! Health and Dockerflow routes
 
-use actix_web::web::Json;
+use crate::db::DbClient;
+use crate::routers::RouterDispatch;
+use crate::server::ServerState;
+use actix_web::web::{Data, Json};
 use actix_web::HttpResponse;
 use serde_json::json;
+use std::collections::HashMap;
 
 /// Handle the `/health` route
 pub async fn health_route() -> Json<serde_json::Value> {
@@ -12,7 +16,7 @@ pub async fn health_route() -> Json<serde_json::Value> {
     }))
 }
 
-/// Handle the `/status` and `/__heartbeat__` routes
+/// Handle the `/status` route
 pub async fn status_route() -> Json<serde_json::Value> {
     Json(json!({
         "status": "OK",
@@ -20,6 +24,71 @@ pub async fn status_route() -> Json<serde_json::Value> {
     }))
 }
 
+/// Handle the `/__heartbeat__` route. This is used as a readiness check, so
+/// the database is checked too.
+pub async fn heartbeat_route(state: Data<ServerState>) -> HttpResponse {
+    heartbeat(&state.ddb).await
+}
+
+/// Create the heartbeat response, returning a 503 if the database can't be
+/// reached
+async fn heartbeat(ddb: &dyn DbClient) -> HttpResponse {
+    match ddb.health_check().await {
+        Ok(()) => HttpResponse::Ok().json(json!({
+            "status": "OK",
+            "database": "OK",
+            "version": env!("CARGO_PKG_VERSION"),
+        })),
+        Err(e) => {
+            error!("Database health check failed: {}", e);
+            HttpResponse::ServiceUnavailable().json(json!({
+                "status": "ERROR",
+                "database": "ERROR",
+                "version": env!("CARGO_PKG_VERSION"),
+            }))
+        }
+    }
+}
+
+/// Handle the `/__health__` route, which reports the health of each router
+pub async fn router_health_route(state: Data<ServerState>) -> HttpResponse {
+    router_health(&state.routers).await
+}
+
+/// Create the router health response, returning a 503 if any router is
+/// unhealthy
+async fn router_health(routers: &RouterDispatch) -> HttpResponse {
+    let mut healthy = true;
+    let statuses: HashMap<String, &str> = routers
+        .health_check()
+        .await
+        .into_iter()
+        .map(|(router_type, result)| {
+            let status = match result {
+                Ok(()) => "OK",
+                Err(e) => {
+                    error!("{} router health check failed: {}", router_type, e);
+                    healthy = false;
+                    "ERROR"
+                }
+            };
+
+            (router_type.to_string(), status)
+        })
+        .collect();
+
+    let body = json!({
+        "status": if healthy { "OK" } else { "DEGRADED" },
+        "routers": statuses,
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    if healthy {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
 /// Handle the `/__lbheartbeat__` route
 pub fn lb_heartbeat_route() -> HttpResponse {
     // Used by the load balancers, just return OK.
@@ -34,3 +103,133 @@ pub fn version_route() -> HttpResponse {
         .content_type("application/json")
         .body(include_str!("../../../../version.json"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{heartbeat, lb_heartbeat_route, router_health};
+    use crate::db::mock::MockDbClient;
+    use crate::error::{ApiErrorKind, ApiResult};
+    use crate::routers::{Router, RouterDispatch, RouterResponse, RouterType};
+    use crate::server::extractors::notification::Notification;
+    use actix_web::dev::Body;
+    use actix_web::http::StatusCode;
+    use actix_web::HttpResponse;
+    use async_trait::async_trait;
+    use cadence::{NopMetricSink, StatsdClient};
+    use serde_json::json;
+
+    /// A router which is healthy or not
+    struct HealthRouter(bool);
+
+    #[async_trait(?Send)]
+    impl Router for HealthRouter {
+        async fn route_notification(&self, _: &Notification) -> ApiResult<RouterResponse> {
+            panic!("HealthRouter should not route notifications")
+        }
+
+        async fn health_check(&self) -> ApiResult<()> {
+            if self.0 {
+                Ok(())
+            } else {
+                Err(ApiErrorKind::Internal("Unreachable".to_string()).into())
+            }
+        }
+    }
+
+    /// Create a dispatcher with a WebPush router and an FCM router
+    fn make_dispatch(fcm_healthy: bool) -> RouterDispatch {
+        let mut routers =
+            RouterDispatch::new(StatsdClient::from_sink("autoendpoint", NopMetricSink));
+        routers.register(RouterType::WebPush, Box::new(HealthRouter(true)));
+        routers.register(RouterType::Fcm, Box::new(HealthRouter(fcm_healthy)));
+        routers
+    }
+
+    /// Get the JSON body of a response
+    fn body_json(response: &HttpResponse) -> serde_json::Value {
+        match response.body().as_ref() {
+            Some(Body::Bytes(bytes)) => serde_json::from_slice(bytes).unwrap(),
+            body => panic!("Expected a JSON body, got {:?}", body),
+        }
+    }
+
+    /// The heartbeat is OK when the database can be reached
+    #[actix_rt::test]
+    async fn heartbeat_healthy() {
+        let response = heartbeat(&MockDbClient::default()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_json(&response),
+            json!({
+                "status": "OK",
+                "database": "OK",
+                "version": env!("CARGO_PKG_VERSION"),
+            })
+        );
+    }
+
+    /// The heartbeat reports the database when it can't be reached
+    #[actix_rt::test]
+    async fn heartbeat_database_down() {
+        let ddb = MockDbClient {
+            fail_health_check: true,
+            ..Default::default()
+        };
+        let response = heartbeat(&ddb).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body_json(&response),
+            json!({
+                "status": "ERROR",
+                "database": "ERROR",
+                "version": env!("CARGO_PKG_VERSION"),
+            })
+        );
+    }
+
+    /// The router health is OK when every router is healthy
+    #[actix_rt::test]
+    async fn routers_healthy() {
+        let response = router_health(&make_dispatch(true)).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_json(&response),
+            json!({
+                "status": "OK",
+                "routers": {
+                    "webpush": "OK",
+                    "fcm": "OK"
+                },
+                "version": env!("CARGO_PKG_VERSION"),
+            })
+        );
+    }
+
+    /// The router health is degraded when any router is unhealthy
+    #[actix_rt::test]
+    async fn routers_degraded() {
+        let response = router_health(&make_dispatch(false)).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body_json(&response),
+            json!({
+                "status": "DEGRADED",
+                "routers": {
+                    "webpush": "OK",
+                    "fcm": "ERROR"
+                },
+                "version": env!("CARGO_PKG_VERSION"),
+            })
+        );
+    }
+
+    /// The load balancer heartbeat doesn't check the database
+    #[test]
+    fn lb_heartbeat() {
+        assert_eq!(lb_heartbeat_route().status(), StatusCode::OK);
+    }
+}
@@ -1,7 +1,276 @@
+use crate::db::DbClient;
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::logging;
+use crate::routers::{RouterDispatch, RouterError};
+use crate::server::extractors::dry_run::DryRun;
+use crate::server::extractors::message_id::MessageId;
 use crate::server::extractors::notification::Notification;
+use crate::server::ServerState;
+use actix_web::web::Data;
 use actix_web::HttpResponse;
+use futures::compat::Future01CompatExt;
 
 /// Handle the `/wpush/{api_version}/{token}` and `/wpush/{token}` routes
-pub async fn webpush_route(_notification: Notification) -> HttpResponse {
-    HttpResponse::Ok().finish()
+pub async fn webpush_route(
+    notification: Notification,
+    dry_run: DryRun,
+    state: Data<ServerState>,
+) -> ApiResult<HttpResponse> {
+    // Report what would happen, without delivering or storing the
+    // notification
+    if dry_run.0 {
+        let report = state.routers.dry_run(&notification)?;
+        return Ok(HttpResponse::Ok().json(report));
+    }
+
+    // This is a retry of a notification which was already routed
+    match state.idempotency.get(&notification).await {
+        Ok(Some(response)) => {
+            debug!(
+                "Returning the response to an earlier notification with the same idempotency key"
+            );
+            return Ok(response);
+        }
+        Ok(None) => {}
+        // Route the notification anyway. It is only stored once, since its
+        // key is claimed before storing it.
+        Err(e) => debug!("Unable to check the idempotency key: {}", e),
+    }
+
+    state.rate_limiter.check(&notification.subscription)?;
+
+    // Let the notification finish routing if the server starts shutting down
+    let _route_guard = state.shutdown.start_route()?;
+    match state.routers.route(&notification).await {
+        Ok(response) => {
+            if let Err(e) = state.idempotency.insert(&notification, &response).await {
+                debug!(
+                    "Unable to remember the response for the idempotency key: {}",
+                    e
+                );
+            }
+            Ok(response.into())
+        }
+        Err(error) => {
+            // The user's bridge no longer knows about them, so remove their
+            // record. Users with a fallback router are only sent to a bridge
+            // through the fallback, which their router has already removed,
+            // so their record is kept.
+            let not_registered = match &error.kind {
+                ApiErrorKind::Router(RouterError::NotRegistered { .. }) => true,
+                _ => false,
+            };
+            if not_registered && RouterDispatch::fallback_router_type(&notification).is_none() {
+                let uaid = &notification.subscription.user.uaid;
+                debug!(
+                    "Removing user {} with an invalid bridge registration",
+                    logging::log_uaid(uaid)
+                );
+                state
+                    .ddb
+                    .drop_uaid(uaid)
+                    .compat()
+                    .await
+                    .map_err(ApiErrorKind::Database)?;
+            }
+
+            Err(error)
+        }
+    }
+}
+
+/// Handle `GET /m/{message_id}`, which reports if a notification is still
+/// waiting to be delivered
+pub async fn notification_status_route(
+    message_id: MessageId,
+    state: Data<ServerState>,
+) -> ApiResult<HttpResponse> {
+    notification_status(&state.ddb, &message_id).await
+}
+
+/// Handle the `/m/{message_id}` route, which removes a stored notification
+pub async fn delete_notification_route(
+    message_id: MessageId,
+    state: Data<ServerState>,
+) -> ApiResult<HttpResponse> {
+    delete_notification(&state.ddb, &message_id).await
+}
+
+/// Check if a notification is still stored. Notifications which were
+/// delivered, removed or have expired are not found.
+async fn notification_status(
+    ddb: &dyn DbClient,
+    message_id: &MessageId,
+) -> ApiResult<HttpResponse> {
+    let uaid = message_id.uaid();
+    debug!(
+        "Checking notification status for UAID {}",
+        logging::log_uaid(&uaid)
+    );
+
+    let message_month = message_month(ddb, message_id).await?;
+    let exists = ddb
+        .message_exists(&uaid, &message_month, message_id.sort_key())
+        .await
+        .map_err(ApiErrorKind::Database)?;
+    if !exists {
+        return Err(ApiErrorKind::MessageNotFound.into());
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Remove a stored notification. Notifications which were already delivered
+/// or removed are not found.
+///
+/// The message ID is only given to the application server which sent the
+/// notification, and is encrypted with our key, so holding a message ID which
+/// decodes authorizes the request. Message IDs which were not issued by us
+/// fail to decode and are not found.
+async fn delete_notification(
+    ddb: &dyn DbClient,
+    message_id: &MessageId,
+) -> ApiResult<HttpResponse> {
+    let uaid = message_id.uaid();
+    debug!(
+        "Deleting notification for UAID {}",
+        logging::log_uaid(&uaid)
+    );
+
+    let message_month = message_month(ddb, message_id).await?;
+    let removed = ddb
+        .remove_message(&uaid, &message_month, message_id.sort_key())
+        .await
+        .map_err(ApiErrorKind::Database)?;
+    if !removed {
+        return Err(ApiErrorKind::MessageNotFound.into());
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Get the message table the notification's user stores messages in
+async fn message_month(ddb: &dyn DbClient, message_id: &MessageId) -> ApiResult<String> {
+    let user = ddb
+        .get_user(&message_id.uaid())
+        .await
+        .map_err(|e| {
+            debug!("Unable to find the user of the notification: {}", e);
+            ApiErrorKind::MessageNotFound
+        })?
+        .ok_or(ApiErrorKind::MessageNotFound)?;
+
+    Ok(user
+        .current_month
+        .unwrap_or_else(|| ddb.current_message_month()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{delete_notification, notification_status};
+    use crate::db::mock::MockDbClient;
+    use crate::error::ApiErrorKind;
+    use crate::server::extractors::message_id::MessageId;
+    use actix_web::http::StatusCode;
+    use autopush_common::db::DynamoDbUser;
+    use autopush_common::notification::Notification;
+    use fernet::{Fernet, MultiFernet};
+    use uuid::Uuid;
+
+    /// Create a database with a stored notification, and the message ID of
+    /// the notification
+    fn make_stored_notification() -> (MockDbClient, MessageId) {
+        let user = DynamoDbUser::default();
+        let channel_id = Uuid::new_v4();
+        let ddb = MockDbClient::with_user(user.clone());
+        ddb.stored_messages.lock().unwrap().push(Notification {
+            channel_id,
+            version: "test-message-id".to_string(),
+            topic: Some("test-topic".to_string()),
+            ..Default::default()
+        });
+        let message_id = MessageId::WithTopic {
+            uaid: user.uaid,
+            channel_id,
+            topic: "test-topic".to_string(),
+        };
+
+        (ddb, message_id)
+    }
+
+    /// A stored notification is removed
+    #[actix_rt::test]
+    async fn delete_existing() {
+        let (ddb, message_id) = make_stored_notification();
+
+        let response = delete_notification(&ddb, &message_id).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(ddb.stored_messages().is_empty());
+    }
+
+    /// A notification which was already removed is not found
+    #[actix_rt::test]
+    async fn delete_missing() {
+        let (ddb, message_id) = make_stored_notification();
+        delete_notification(&ddb, &message_id).await.unwrap();
+
+        let error = delete_notification(&ddb, &message_id).await.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::NOT_FOUND);
+        match error.kind {
+            ApiErrorKind::MessageNotFound => {}
+            kind => panic!("Expected a message not found error, got {:?}", kind),
+        }
+    }
+
+    /// A stored notification is pending
+    #[actix_rt::test]
+    async fn status_pending() {
+        let (ddb, message_id) = make_stored_notification();
+
+        let response = notification_status(&ddb, &message_id).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// A delivered notification is no longer stored, so it is not found
+    #[actix_rt::test]
+    async fn status_delivered() {
+        let (ddb, message_id) = make_stored_notification();
+        ddb.stored_messages.lock().unwrap().clear();
+
+        let error = notification_status(&ddb, &message_id).await.unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// A message ID which was not encrypted with our key can't be used to
+    /// delete someone else's notification
+    #[actix_rt::test]
+    async fn delete_unauthorized() {
+        let (ddb, message_id) = make_stored_notification();
+        let our_fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        let other_fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+
+        let forged_id = message_id.encode(&other_fernet);
+        let error = MessageId::decode(&our_fernet, &forged_id).unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::NOT_FOUND);
+        assert_eq!(ddb.stored_messages().len(), 1);
+
+        // The message ID we issued is accepted
+        let issued_id = MessageId::decode(&our_fernet, &message_id.encode(&our_fernet)).unwrap();
+        let response = delete_notification(&ddb, &issued_id).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    /// A malformed message ID is not found, for both the status and delete
+    /// routes
+    #[test]
+    fn delete_malformed_id() {
+        let fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+
+        let error = MessageId::decode(&fernet, "not-a-message-id").unwrap_err();
+        assert_eq!(error.kind.status(), StatusCode::NOT_FOUND);
+        match error.kind {
+            ApiErrorKind::InvalidMessageId => {}
+            kind => panic!("Expected an invalid message ID error, got {:?}", kind),
+        }
+    }
 }
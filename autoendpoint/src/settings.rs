@@ -1,8 +1,14 @@
 //! Application settings
 
+use crate::error::DEFAULT_MORE_INFO_URL;
+use crate::routers::adm::settings::AdmSettings;
+use crate::routers::apns::settings::ApnsSettings;
+use crate::routers::fcm::settings::FcmSettings;
 use config::{Config, ConfigError, Environment, File};
 use fernet::{Fernet, MultiFernet};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
 use url::Url;
 
 const DEFAULT_PORT: u16 = 8000;
@@ -14,6 +20,11 @@ pub struct Settings {
     pub debug: bool,
     pub port: u16,
     pub host: String,
+    pub endpoint_url: String,
+    /// The endpoint URLs of each region, keyed by the region named in the
+    /// request's `X-Region` header. Regions without a URL use
+    /// `endpoint_url`.
+    pub regional_endpoint_urls: HashMap<String, String>,
     pub database_url: String,
     pub database_pool_max_size: Option<u32>,
     #[cfg(any(test, feature = "db_test"))]
@@ -21,14 +32,109 @@ pub struct Settings {
 
     pub router_table_name: String,
     pub message_table_name: String,
+    pub broadcast_table_name: String,
+    /// The table idempotency keys are kept in. Its hash key is
+    /// `idempotency_key`, and `expiry` is its TTL attribute.
+    pub idempotency_table_name: String,
 
     pub max_data_bytes: usize,
+    /// The max TTL in seconds. Larger TTLs are reduced to this.
+    pub max_ttl: i64,
+    /// The max length of the Encryption, Encryption-Key and Crypto-Key
+    /// headers
+    pub max_crypto_header_len: usize,
+    /// The content encodings notification payloads may use. The legacy
+    /// `aesgcm128` and `aesgcm` drafts can be removed to disallow them.
+    pub content_encodings: Vec<String>,
+    /// Topic prefixes which are reserved for system messages, so senders
+    /// can't use them. Empty allows any topic.
+    pub reserved_topic_prefixes: Vec<String>,
+    pub node_retries: u32,
+    pub node_retry_delay_ms: u64,
+    pub node_retry_jitter_ms: u64,
+    pub node_request_timeout_sec: u64,
+    /// How long the routers' HTTP client waits to connect
+    pub http_connect_timeout_ms: u64,
+    /// How long the routers' HTTP client waits for a response, unless the
+    /// router sets its own timeout
+    pub http_request_timeout_sec: u64,
+    /// How long idle connections are kept in the HTTP client's pool
+    pub http_pool_idle_timeout_sec: u64,
+    pub http_pool_max_idle_per_host: usize,
+    /// How often TCP keepalive probes are sent on the HTTP client's
+    /// connections. Zero disables keepalive.
+    pub http_tcp_keepalive_sec: u64,
+    pub broadcast_concurrency: usize,
+    pub node_breaker_threshold: u32,
+    pub node_breaker_window_sec: u64,
+    pub node_breaker_cooldown_sec: u64,
+    /// How many notifications may be sent to a single node at the same time.
+    /// Zero disables the limit.
+    pub node_max_concurrent_sends: usize,
+    /// How long a notification waits to be sent to a busy node before it is
+    /// stored instead
+    pub node_send_wait_ms: u64,
+    /// The hosts notifications may be sent to as nodes. An entry starting
+    /// with `.` also allows its subdomains. Empty allows any host.
+    pub node_allowed_hosts: Vec<String>,
+    /// The hosts push receipts may be sent to, besides this service's own
+    /// receipt resources. An entry starting with `.` also allows its
+    /// subdomains.
+    pub push_receipt_allowed_hosts: Vec<String>,
+    pub db_retry_after_sec: u64,
+    /// The max random jitter added to `db_retry_after_sec`, so clients
+    /// don't all retry at once after a database outage. Zero disables it.
+    pub db_retry_jitter_sec: u64,
+    /// How many non-topic messages may be stored for each channel. Zero
+    /// allows any number.
+    pub max_channel_messages: usize,
+    /// Whether the oldest messages of a full channel are removed to make
+    /// room. Otherwise new messages are rejected with a 507.
+    pub evict_channel_messages: bool,
+    /// How often to look for users whose node ID is stale. Zero disables
+    /// the search.
+    pub stale_node_scan_interval_sec: u64,
+    /// How long since a user connected before their node ID is considered
+    /// stale and removed, so notifications are stored instead
+    pub stale_node_max_age_sec: u64,
+    /// The most user records read from the router table at a time while
+    /// searching for stale node IDs
+    pub stale_node_scan_page_size: u32,
+    /// How long to wait between pages of the search, so it doesn't use up
+    /// the router table's read capacity
+    pub stale_node_scan_page_delay_ms: u64,
+    /// How long to wait for notifications being routed to finish when
+    /// shutting down
+    pub shutdown_grace_sec: u64,
     pub crypto_keys: String,
+    pub vapid_allowed_subs: Vec<String>,
+    pub rate_limit_messages: usize,
+    pub rate_limit_window_sec: u64,
+    /// The max random jitter added to the wait of rate limited senders and
+    /// users. Zero disables it.
+    pub rate_limit_retry_jitter_sec: u64,
+    /// How many notifications per second each UAID may receive via WebPush.
+    /// Zero disables the limit.
+    pub uaid_rate_limit_per_sec: f64,
+    /// How many notifications a UAID may receive at once
+    pub uaid_rate_limit_burst: u32,
+    /// The documentation linked to in error responses
+    pub error_docs_url: String,
+    /// The bearer token needed to use the admin routes. Empty disables them.
+    pub admin_auth_key: String,
+    /// The connection nodes which are told about broadcast changes, as the
+    /// URLs of their internal router API
+    pub broadcast_nodes: Vec<String>,
+    pub idempotency_window_sec: u64,
     pub human_logs: bool,
 
     pub statsd_host: Option<String>,
     pub statsd_port: u16,
     pub statsd_label: String,
+
+    pub fcm: FcmSettings,
+    pub apns: ApnsSettings,
+    pub adm: AdmSettings,
 }
 
 impl Default for Settings {
@@ -37,18 +143,69 @@ impl Default for Settings {
             debug: false,
             port: DEFAULT_PORT,
             host: "127.0.0.1".to_string(),
+            endpoint_url: "http://127.0.0.1:8000/".to_string(),
+            regional_endpoint_urls: HashMap::new(),
             database_url: "mysql://root@127.0.0.1/autopush".to_string(),
             database_pool_max_size: None,
             #[cfg(any(test, feature = "db_test"))]
             database_use_test_transactions: false,
             router_table_name: "router".to_string(),
             message_table_name: "message".to_string(),
+            broadcast_table_name: "broadcast".to_string(),
+            idempotency_table_name: "idempotency".to_string(),
             max_data_bytes: 4096,
+            max_ttl: 60 * 60 * 24 * 60,
+            max_crypto_header_len: 4096,
+            content_encodings: vec![
+                "aesgcm128".to_string(),
+                "aesgcm".to_string(),
+                "aes128gcm".to_string(),
+            ],
+            reserved_topic_prefixes: Vec::new(),
+            node_retries: 3,
+            node_retry_delay_ms: 50,
+            node_retry_jitter_ms: 25,
+            node_request_timeout_sec: 9,
+            http_connect_timeout_ms: 2000,
+            http_request_timeout_sec: 30,
+            http_pool_idle_timeout_sec: 90,
+            http_pool_max_idle_per_host: 32,
+            http_tcp_keepalive_sec: 60,
+            broadcast_concurrency: 16,
+            node_breaker_threshold: 5,
+            node_breaker_window_sec: 60,
+            node_breaker_cooldown_sec: 30,
+            node_max_concurrent_sends: 256,
+            node_send_wait_ms: 100,
+            node_allowed_hosts: Vec::new(),
+            push_receipt_allowed_hosts: Vec::new(),
+            db_retry_after_sec: 10,
+            db_retry_jitter_sec: 10,
+            max_channel_messages: 0,
+            evict_channel_messages: true,
+            stale_node_scan_interval_sec: 0,
+            stale_node_max_age_sec: 60 * 60 * 24 * 7,
+            stale_node_scan_page_size: 100,
+            stale_node_scan_page_delay_ms: 500,
+            shutdown_grace_sec: 30,
             crypto_keys: format!("[{}]", Fernet::generate_key()),
+            vapid_allowed_subs: Vec::new(),
+            rate_limit_messages: 0,
+            rate_limit_window_sec: 60,
+            rate_limit_retry_jitter_sec: 5,
+            uaid_rate_limit_per_sec: 0.0,
+            uaid_rate_limit_burst: 10,
+            error_docs_url: DEFAULT_MORE_INFO_URL.to_string(),
+            admin_auth_key: String::new(),
+            broadcast_nodes: Vec::new(),
+            idempotency_window_sec: 300,
             human_logs: false,
             statsd_host: None,
             statsd_port: 8125,
             statsd_label: "autoendpoint".to_string(),
+            fcm: FcmSettings::default(),
+            apns: ApnsSettings::default(),
+            adm: AdmSettings::default(),
         }
     }
 }
@@ -64,7 +221,9 @@ impl Settings {
         }
 
         // Merge the environment overrides
-        config.merge(Environment::with_prefix(ENV_PREFIX))?;
+        // Note: Nested settings use a double underscore as a separator, ex.
+        // `AUTOEND_FCM__SERVER_KEY`
+        config.merge(Environment::with_prefix(ENV_PREFIX).separator("__"))?;
 
         config.try_into::<Self>().or_else(|error| match error {
             // Configuration errors are not very sysop friendly, Try to make them
@@ -94,6 +253,49 @@ impl Settings {
         format!("http://{}:{} ({})", self.host, self.port, db)
     }
 
+    /// Get the URL for this endpoint server
+    pub fn endpoint_url(&self) -> Url {
+        Url::parse(&self.endpoint_url).expect("Invalid endpoint URL")
+    }
+
+    /// Get the endpoint URL of each region
+    pub fn regional_endpoint_urls(&self) -> HashMap<String, Url> {
+        self.regional_endpoint_urls
+            .iter()
+            .map(|(region, url)| {
+                let url = Url::parse(url).expect("Invalid regional endpoint URL");
+                (region.to_ascii_lowercase(), url)
+            })
+            .collect()
+    }
+
+    /// Get the URL under which this service's receipt resources live
+    /// (RFC 8030 section 6)
+    pub fn receipt_url(&self) -> Url {
+        self.endpoint_url()
+            .join("receipts/")
+            .expect("Invalid endpoint URL")
+    }
+
+    /// Configure the HTTP client used by the routers
+    pub fn http_client_builder(&self) -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(self.http_connect_timeout_ms))
+            .timeout(Duration::from_secs(self.http_request_timeout_sec))
+            .pool_idle_timeout(Duration::from_secs(self.http_pool_idle_timeout_sec))
+            .pool_max_idle_per_host(self.http_pool_max_idle_per_host)
+            .tcp_keepalive(self.http_tcp_keepalive())
+    }
+
+    /// Get the TCP keepalive interval for the HTTP client, if enabled
+    fn http_tcp_keepalive(&self) -> Option<Duration> {
+        if self.http_tcp_keepalive_sec == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.http_tcp_keepalive_sec))
+        }
+    }
+
     /// Initialize the fernet encryption instance
     pub fn make_fernet(&self) -> MultiFernet {
         if !(self.crypto_keys.starts_with('[') && self.crypto_keys.ends_with(']')) {
@@ -108,3 +310,73 @@ impl Settings {
         MultiFernet::new(fernets)
     }
 }
+
+/// Check if the host is one of the allowed hosts. An entry starting with `.`
+/// also allows its subdomains.
+pub fn is_allowed_host(host: &str, allowed_hosts: &[String]) -> bool {
+    allowed_hosts.iter().any(|allowed| {
+        if allowed.starts_with('.') {
+            host.ends_with(allowed.as_str()) || host == &allowed[1..]
+        } else {
+            host == allowed
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Settings;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    /// A keepalive interval of zero disables TCP keepalive
+    #[test]
+    fn http_tcp_keepalive() {
+        let settings = Settings {
+            http_tcp_keepalive_sec: 30,
+            ..Default::default()
+        };
+        assert_eq!(settings.http_tcp_keepalive(), Some(Duration::from_secs(30)));
+
+        let settings = Settings {
+            http_tcp_keepalive_sec: 0,
+            ..Default::default()
+        };
+        assert_eq!(settings.http_tcp_keepalive(), None);
+    }
+
+    /// Regional endpoint URLs are parsed, with the regions lowercased to
+    /// match the `X-Region` header
+    #[test]
+    fn regional_endpoint_urls() {
+        let mut settings = Settings::default();
+        settings.regional_endpoint_urls.insert(
+            "EU-West".to_string(),
+            "https://eu-west.example.com".to_string(),
+        );
+
+        let urls = settings.regional_endpoint_urls();
+        assert_eq!(urls.len(), 1);
+        assert_eq!(urls["eu-west"].as_str(), "https://eu-west.example.com/");
+    }
+
+    /// The HTTP client gives up on servers which don't respond in time
+    #[actix_rt::test]
+    async fn http_client_request_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            // Keep the connections open so the requests time out
+            let _streams: Vec<_> = listener.incoming().collect();
+        });
+        let settings = Settings {
+            http_request_timeout_sec: 1,
+            ..Default::default()
+        };
+
+        let client = settings.http_client_builder().build().unwrap();
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(error.is_timeout(), "error = {:?}", error);
+    }
+}
@@ -0,0 +1,166 @@
+//! Draining the notifications which are being routed when the server shuts
+//! down, so they are not lost part way through being stored
+
+use crate::error::{ApiErrorKind, ApiResult};
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_web::dev;
+use cadence::{Counted, StatsdClient};
+use futures::future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often the in-flight notifications are checked while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Tracks the notifications being routed, and stops new notifications from
+/// being routed once shutdown has started
+pub struct ShutdownCoordinator {
+    in_flight: AtomicUsize,
+    shutting_down: AtomicBool,
+    metrics: StatsdClient,
+}
+
+/// Marks a notification as being routed until it is dropped
+pub struct RouteGuard<'a> {
+    coordinator: &'a ShutdownCoordinator,
+}
+
+impl Drop for RouteGuard<'_> {
+    fn drop(&mut self) {
+        self.coordinator.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ShutdownCoordinator {
+    /// Create a new `ShutdownCoordinator`
+    pub fn new(metrics: StatsdClient) -> Self {
+        ShutdownCoordinator {
+            in_flight: AtomicUsize::new(0),
+            shutting_down: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    /// Start routing a notification. Once shutdown has started, an error is
+    /// returned so the sender retries against another server.
+    pub fn start_route(&self) -> ApiResult<RouteGuard<'_>> {
+        // Count the route before checking, so a drain which has already seen
+        // no routes in flight can't miss it
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = RouteGuard { coordinator: self };
+
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(ApiErrorKind::ShuttingDown.into());
+        }
+
+        Ok(guard)
+    }
+
+    /// Get how many notifications are being routed
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Stop routing new notifications, and wait up to `grace` for the
+    /// notifications in flight to finish. Returns how many notifications were
+    /// still being routed when the grace period ended.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + grace;
+
+        loop {
+            let in_flight = self.in_flight();
+            if in_flight == 0 {
+                return 0;
+            }
+
+            if Instant::now() >= deadline {
+                warn!(
+                    "Abandoning {} notifications after the shutdown grace period",
+                    in_flight
+                );
+                self.metrics
+                    .count("notification.route.abandoned", in_flight as i64)
+                    .ok();
+                return in_flight;
+            }
+
+            actix_rt::time::delay_for(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Wait for SIGTERM or SIGINT, then stop accepting connections, drain the
+/// notifications in flight and stop the server
+pub async fn handle_signals(
+    server: dev::Server,
+    coordinator: Arc<ShutdownCoordinator>,
+    grace: Duration,
+) {
+    let (mut sigterm, mut sigint) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Unable to listen for shutdown signals: {}", e);
+            return;
+        }
+    };
+    future::select(Box::pin(sigterm.recv()), Box::pin(sigint.recv())).await;
+
+    info!(
+        "Shutting down, waiting for {} notifications to be routed",
+        coordinator.in_flight()
+    );
+    server.pause().await;
+    coordinator.drain(grace).await;
+    server.stop(true).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShutdownCoordinator;
+    use crate::metrics::TestMetricSink;
+    use cadence::{NopMetricSink, StatsdClient};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A slow route is allowed to finish during shutdown, while new routes
+    /// are rejected
+    #[actix_rt::test]
+    async fn drains_slow_route() {
+        let coordinator = Arc::new(ShutdownCoordinator::new(StatsdClient::from_sink(
+            "autoendpoint",
+            NopMetricSink,
+        )));
+        let route_coordinator = coordinator.clone();
+        actix_rt::spawn(async move {
+            let _guard = route_coordinator.start_route().unwrap();
+            actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        });
+        // Let the route start
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(coordinator.in_flight(), 1);
+
+        let abandoned = coordinator.drain(Duration::from_secs(5)).await;
+        assert_eq!(abandoned, 0);
+        assert_eq!(coordinator.in_flight(), 0);
+        assert!(coordinator.start_route().is_err());
+    }
+
+    /// Routes which don't finish within the grace period are reported
+    #[actix_rt::test]
+    async fn abandons_after_grace_period() {
+        let metrics = TestMetricSink::default();
+        let coordinator = ShutdownCoordinator::new(metrics.client());
+        let _guard = coordinator.start_route().unwrap();
+
+        let abandoned = coordinator.drain(Duration::from_millis(100)).await;
+        assert_eq!(abandoned, 1);
+        assert!(metrics
+            .metrics()
+            .contains(&"autoendpoint.notification.route.abandoned:1|c".to_string()));
+    }
+}
@@ -0,0 +1,218 @@
+//! Removing the node IDs of users who have not connected for a long time.
+//! A node may die without the endpoint noticing, leaving its ID on user
+//! records. Removing the ID means notifications for the user are stored
+//! straight away, instead of first trying to send them to the node.
+
+use crate::db::DbClient;
+use autopush_common::db::DynamoDbUser;
+use autopush_common::errors::Result as DbResult;
+use autopush_common::util::ms_since_epoch;
+use cadence::{Counted, StatsdClient};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How to search for stale node IDs
+#[derive(Clone, Debug)]
+pub struct StaleNodeScan {
+    /// How long since a user connected before their node ID is stale
+    pub max_age: Duration,
+    /// The most user records read at a time
+    pub page_size: u32,
+    /// How long to wait between pages
+    pub page_delay: Duration,
+}
+
+/// Search for stale node IDs every `interval`, removing them in the
+/// background
+pub fn spawn_cleanup(
+    ddb: Arc<dyn DbClient>,
+    metrics: StatsdClient,
+    interval: Duration,
+    scan: StaleNodeScan,
+) {
+    actix_rt::spawn(async move {
+        loop {
+            actix_rt::time::delay_for(interval).await;
+
+            match remove_stale_node_ids(ddb.as_ref(), &metrics, &scan).await {
+                Ok(removed) => debug!("Removed {} stale node IDs", removed),
+                Err(e) => warn!("Error while removing stale node IDs: {}", e),
+            }
+        }
+    });
+}
+
+/// Remove the node IDs of users who last connected more than `max_age` ago.
+/// The users are found a page at a time, and each page's node IDs are removed
+/// before reading the next, so the whole table is never held in memory.
+/// Users who reconnect during the search keep their new node ID. Returns the
+/// number of node IDs removed.
+pub async fn remove_stale_node_ids(
+    ddb: &dyn DbClient,
+    metrics: &StatsdClient,
+    scan: &StaleNodeScan,
+) -> DbResult<usize> {
+    let connected_before = ms_since_epoch().saturating_sub(scan.max_age.as_millis() as u64);
+    let mut start_uaid = None;
+    let mut removed = 0;
+
+    loop {
+        let page = ddb
+            .find_stale_users(connected_before, start_uaid, i64::from(scan.page_size))
+            .await?;
+        let page_removed = remove_node_ids(ddb, page.users).await;
+        if page_removed > 0 {
+            metrics
+                .count("updates.client.host_stale", page_removed as i64)
+                .ok();
+        }
+        removed += page_removed;
+
+        start_uaid = match page.next_uaid {
+            Some(uaid) => Some(uaid),
+            None => return Ok(removed),
+        };
+        actix_rt::time::delay_for(scan.page_delay).await;
+    }
+}
+
+/// Remove the node IDs of the users, unless they have reconnected. Returns
+/// the number of node IDs removed.
+async fn remove_node_ids(ddb: &dyn DbClient, users: Vec<DynamoDbUser>) -> usize {
+    let mut removed = 0;
+    for user in users {
+        let node_id = match user.node_id {
+            Some(node_id) => node_id,
+            None => continue,
+        };
+
+        match ddb
+            .remove_node_id(&user.uaid, node_id, user.connected_at)
+            .await
+        {
+            Ok(true) => removed += 1,
+            Ok(false) => {}
+            Err(e) => debug!(
+                "Unable to remove stale node ID for UAID {}: {}",
+                user.uaid, e
+            ),
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{remove_stale_node_ids, StaleNodeScan};
+    use crate::db::mock::MockDbClient;
+    use crate::metrics::TestMetricSink;
+    use autopush_common::db::DynamoDbUser;
+    use autopush_common::util::ms_since_epoch;
+    use std::time::Duration;
+
+    const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+    /// Search for stale node IDs, reading `page_size` users at a time
+    fn make_scan(page_size: u32) -> StaleNodeScan {
+        StaleNodeScan {
+            max_age: MAX_AGE,
+            page_size,
+            page_delay: Duration::from_millis(0),
+        }
+    }
+
+    /// Create a user who connected to a node `age` ago
+    fn make_user(node_id: Option<&str>, age: Duration) -> DynamoDbUser {
+        DynamoDbUser {
+            node_id: node_id.map(str::to_string),
+            connected_at: ms_since_epoch() - age.as_millis() as u64,
+            ..Default::default()
+        }
+    }
+
+    /// Only the node IDs of users who connected before the max age are
+    /// removed
+    #[actix_rt::test]
+    async fn removes_stale_node_ids() {
+        let ddb = MockDbClient {
+            users: vec![
+                make_user(Some("http://stale1:8081"), MAX_AGE * 2),
+                make_user(Some("http://fresh:8081"), Duration::from_secs(60)),
+                make_user(
+                    Some("http://stale2:8081"),
+                    MAX_AGE + Duration::from_secs(60),
+                ),
+                make_user(None, MAX_AGE * 2),
+            ],
+            ..Default::default()
+        };
+        let metrics = TestMetricSink::default();
+
+        let removed = remove_stale_node_ids(&ddb, &metrics.client(), &make_scan(100))
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(
+            ddb.removed_node_ids(),
+            vec!["http://stale1:8081", "http://stale2:8081"]
+        );
+        assert_eq!(
+            metrics.metrics(),
+            vec!["autoendpoint.updates.client.host_stale:2|c"]
+        );
+    }
+
+    /// Users who reconnected during the search keep their new node ID
+    #[actix_rt::test]
+    async fn keeps_reconnected_users() {
+        let ddb = MockDbClient {
+            users: vec![make_user(Some("http://stale:8081"), MAX_AGE * 2)],
+            reconnected_at: Some(ms_since_epoch()),
+            ..Default::default()
+        };
+        let metrics = TestMetricSink::default();
+
+        let removed = remove_stale_node_ids(&ddb, &metrics.client(), &make_scan(100))
+            .await
+            .unwrap();
+        assert_eq!(removed, 0);
+        assert!(ddb.removed_node_ids().is_empty());
+    }
+
+    /// The users are searched a page at a time, and every page's stale node
+    /// IDs are removed
+    #[actix_rt::test]
+    async fn removes_stale_node_ids_per_page() {
+        let users = vec![
+            make_user(Some("http://stale1:8081"), MAX_AGE * 2),
+            make_user(Some("http://fresh:8081"), Duration::from_secs(60)),
+            make_user(Some("http://stale2:8081"), MAX_AGE * 2),
+        ];
+        let ddb = MockDbClient {
+            users: users.clone(),
+            ..Default::default()
+        };
+        let metrics = TestMetricSink::default();
+
+        let removed = remove_stale_node_ids(&ddb, &metrics.client(), &make_scan(2))
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(
+            ddb.removed_node_ids(),
+            vec!["http://stale1:8081", "http://stale2:8081"]
+        );
+        assert_eq!(
+            *ddb.stale_user_scans.lock().unwrap(),
+            vec![None, Some(users[1].uaid.to_simple().to_string())]
+        );
+        assert_eq!(
+            metrics.metrics(),
+            vec![
+                "autoendpoint.updates.client.host_stale:1|c",
+                "autoendpoint.updates.client.host_stale:1|c"
+            ]
+        );
+    }
+}
@@ -1,18 +1,22 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Display};
 use std::result::Result as StdResult;
+use std::time::Duration;
 use uuid::Uuid;
 
 use cadence::{Counted, StatsdClient};
 use chrono::Utc;
+use futures::future::Loop;
 use futures::{future, Future};
 use futures_backoff::retry_if;
+use futures_timer::Delay;
 use rusoto_core::RusotoError;
 use rusoto_dynamodb::{
-    AttributeValue, BatchWriteItemError, DeleteItemError, DeleteItemInput, DeleteItemOutput,
-    DynamoDb, DynamoDbClient, GetItemError, GetItemInput, GetItemOutput, ListTablesInput,
-    ListTablesOutput, PutItemError, PutItemInput, PutItemOutput, QueryError, QueryInput,
-    UpdateItemError, UpdateItemInput, UpdateItemOutput,
+    AttributeValue, BatchWriteItemError, BatchWriteItemInput, DeleteItemError, DeleteItemInput,
+    DeleteItemOutput, DeleteRequest, DynamoDb, DynamoDbClient, GetItemError, GetItemInput,
+    GetItemOutput, ListTablesInput, ListTablesOutput, PutItemError, PutItemInput, PutItemOutput,
+    QueryError, QueryInput, ScanError, ScanInput, UpdateItemError, UpdateItemInput,
+    UpdateItemOutput, WriteRequest,
 };
 
 use super::models::{DynamoDbNotification, DynamoDbUser};
@@ -39,7 +43,16 @@ retryable_error!(
     BatchWriteItemError,
     BatchWriteItemError
 );
+/// The most items DynamoDB accepts in a single batch write
+const MAX_BATCH_WRITE_ITEMS: usize = 25;
+/// The number of rounds in a row which may leave unprocessed items before
+/// giving up on deleting a user's messages
+const MAX_UNPROCESSED_ROUNDS: u32 = 8;
+/// The delay before the first retry of unprocessed items, doubled each round
+const UNPROCESSED_RETRY_DELAY: Duration = Duration::from_millis(50);
+
 retryable_error!(retryable_query_error, QueryError, QueryError);
+retryable_error!(retryable_scan_error, ScanError, ScanError);
 retryable_error!(retryable_delete_error, DeleteItemError, DeleteItemError);
 retryable_error!(retryable_getitem_error, GetItemError, GetItemError);
 retryable_error!(retryable_putitem_error, PutItemError, PutItemError);
@@ -190,6 +203,206 @@ pub fn fetch_timestamp_messages(
         })
 }
 
+/// Delete every message stored for the user, keeping their channel record.
+/// Returns the number of messages deleted.
+pub fn drop_user_messages(
+    ddb: DynamoDbClient,
+    uaid: &Uuid,
+    message_table_name: &str,
+) -> impl Future<Item = usize, Error = Error> {
+    let uaid = uaid.to_simple().to_string();
+    let table_name = message_table_name.to_string();
+
+    // Deleted messages no longer match the query, so each round queries from
+    // the start until nothing is left. Messages DynamoDB did not process are
+    // found again by the next query, after backing off. Too many rounds in a
+    // row with unprocessed messages is an error.
+    future::loop_fn((0, 0), move |(deleted, unprocessed_rounds)| {
+        let ddb = ddb.clone();
+        let table_name = table_name.clone();
+        let attr_values = hashmap! {
+            ":uaid".to_string() => val!(S => uaid.clone()),
+            // The channel record's sort key is " ", which all messages sort after
+            ":cmi".to_string() => val!(S => " "),
+        };
+        let input = QueryInput {
+            key_condition_expression: Some("uaid = :uaid AND chidmessageid > :cmi".to_string()),
+            expression_attribute_values: Some(attr_values),
+            projection_expression: Some("uaid, chidmessageid".to_string()),
+            table_name: table_name.clone(),
+            consistent_read: Some(true),
+            limit: Some(MAX_BATCH_WRITE_ITEMS as i64),
+            ..Default::default()
+        };
+        let query_ddb = ddb.clone();
+
+        retry_if(
+            move || query_ddb.query(input.clone()),
+            retryable_query_error,
+        )
+        .chain_err(|| "Error finding user messages")
+        .and_then(move |output| {
+            let delete_items: Vec<WriteRequest> = output
+                .items
+                .unwrap_or_default()
+                .into_iter()
+                .map(|key| WriteRequest {
+                    put_request: None,
+                    delete_request: Some(DeleteRequest { key }),
+                })
+                .collect();
+            if delete_items.is_empty() {
+                return future::Either::A(future::ok(Loop::Break(deleted)));
+            }
+
+            let attempted = delete_items.len();
+            let batch_input = BatchWriteItemInput {
+                request_items: hashmap! { table_name => delete_items },
+                ..Default::default()
+            };
+            let result = retry_if(
+                move || ddb.batch_write_item(batch_input.clone()),
+                retryable_batchwriteitem_error,
+            )
+            .chain_err(|| "Error deleting user messages")
+            .and_then(move |output| {
+                let unprocessed: usize = output
+                    .unprocessed_items
+                    .map_or(0, |items| items.values().map(Vec::len).sum());
+                let deleted = deleted + attempted - unprocessed;
+                if unprocessed == 0 {
+                    return future::Either::A(future::ok(Loop::Continue((deleted, 0))));
+                }
+
+                let unprocessed_rounds = unprocessed_rounds + 1;
+                if unprocessed_rounds > MAX_UNPROCESSED_ROUNDS {
+                    return future::Either::A(future::err(
+                        "Too many rounds with unprocessed user messages".into(),
+                    ));
+                }
+
+                let delay = UNPROCESSED_RETRY_DELAY * 2u32.pow(unprocessed_rounds - 1);
+                future::Either::B(
+                    Delay::new(delay)
+                        .chain_err(|| "Error waiting to delete user messages")
+                        .map(move |_| Loop::Continue((deleted, unprocessed_rounds))),
+                )
+            });
+
+            future::Either::B(result)
+        })
+    })
+}
+
+/// Find the sort keys of the unexpired, non-topic messages stored for the
+/// channel, oldest first
+pub fn fetch_channel_message_keys(
+    ddb: DynamoDbClient,
+    uaid: &Uuid,
+    channel_id: &Uuid,
+    message_table_name: &str,
+) -> impl Future<Item = Vec<String>, Error = Error> {
+    let uaid = uaid.to_simple().to_string();
+    let channel_id = channel_id.to_hyphenated().to_string();
+    let table_name = message_table_name.to_string();
+    let start: (Vec<String>, Option<HashMap<String, AttributeValue>>) = (Vec::new(), None);
+
+    future::loop_fn(start, move |(mut sort_keys, start_key)| {
+        let ddb = ddb.clone();
+        let attr_values = hashmap! {
+            ":uaid".to_string() => val!(S => uaid.clone()),
+            // Non-topic messages are sorted by their timestamp
+            ":prefix".to_string() => val!(S => "02:"),
+            ":chid".to_string() => val!(S => channel_id.clone()),
+            ":now".to_string() => val!(N => sec_since_epoch()),
+        };
+        let input = QueryInput {
+            key_condition_expression: Some(
+                "uaid = :uaid AND begins_with(chidmessageid, :prefix)".to_string(),
+            ),
+            filter_expression: Some(
+                "contains(chidmessageid, :chid) AND expiry >= :now".to_string(),
+            ),
+            expression_attribute_values: Some(attr_values),
+            projection_expression: Some("chidmessageid".to_string()),
+            table_name: table_name.clone(),
+            consistent_read: Some(true),
+            exclusive_start_key: start_key,
+            ..Default::default()
+        };
+
+        retry_if(move || ddb.query(input.clone()), retryable_query_error)
+            .chain_err(|| "Error finding channel messages")
+            .and_then(move |output| {
+                sort_keys.extend(
+                    output
+                        .items
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|mut item| item.remove("chidmessageid").and_then(|key| key.s)),
+                );
+
+                Ok(match output.last_evaluated_key {
+                    Some(key) => Loop::Continue((sort_keys, Some(key))),
+                    None => Loop::Break(sort_keys),
+                })
+            })
+    })
+}
+
+/// A page of the users found while scanning for stale node IDs
+#[derive(Debug, Default)]
+pub struct StaleUsersPage {
+    pub users: Vec<DynamoDbUser>,
+    /// The UAID to continue scanning after, or `None` if the scan is done
+    pub next_uaid: Option<String>,
+}
+
+/// Find the users who have a node ID, but last connected before
+/// `connected_before` (milliseconds since the epoch). The router table is
+/// scanned a page at a time, reading at most `limit` records, starting after
+/// `start_uaid`. A page may be empty even if the scan is not done.
+pub fn find_stale_users(
+    ddb: DynamoDbClient,
+    router_table_name: &str,
+    connected_before: u64,
+    start_uaid: Option<String>,
+    limit: i64,
+) -> impl Future<Item = StaleUsersPage, Error = Error> {
+    let attr_values = hashmap! {
+        ":connected_before".to_string() => val!(N => connected_before),
+    };
+    let input = ScanInput {
+        table_name: router_table_name.to_string(),
+        filter_expression: Some(
+            "attribute_exists(node_id) AND connected_at < :connected_before".to_string(),
+        ),
+        expression_attribute_values: Some(attr_values),
+        exclusive_start_key: start_uaid.map(|uaid| ddb_item! { uaid: s => uaid }),
+        limit: Some(limit),
+        ..Default::default()
+    };
+
+    retry_if(move || ddb.scan(input.clone()), retryable_scan_error)
+        .chain_err(|| "Error scanning for stale users")
+        .and_then(|output| {
+            let users = output
+                .items
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|item| serde_dynamodb::from_hashmap(item).ok())
+                .collect();
+            // The router table only has a hash key, so the UAID is enough to
+            // continue the scan
+            let next_uaid = output
+                .last_evaluated_key
+                .and_then(|mut key| key.remove("uaid"))
+                .and_then(|uaid| uaid.s);
+
+            Ok(StaleUsersPage { users, next_uaid })
+        })
+}
+
 pub fn drop_user(
     ddb: DynamoDbClient,
     uaid: &Uuid,
@@ -1,15 +1,17 @@
+use std::cmp::min;
 use std::collections::HashSet;
 use std::env;
 use uuid::Uuid;
 
-use cadence::StatsdClient;
+use cadence::{Counted, StatsdClient};
 use futures::{future, Future};
 use futures_backoff::retry_if;
-use rusoto_core::{HttpClient, Region};
+use rusoto_core::{HttpClient, Region, RusotoError};
 use rusoto_credential::StaticProvider;
 use rusoto_dynamodb::{
-    AttributeValue, BatchWriteItemInput, DeleteItemInput, DynamoDb, DynamoDbClient, PutRequest,
-    UpdateItemInput, UpdateItemOutput, WriteRequest,
+    AttributeValue, BatchWriteItemInput, DeleteItemError, DeleteItemInput, DescribeTableInput,
+    DynamoDb, DynamoDbClient, GetItemInput, PutItemError, PutItemInput, PutRequest,
+    UpdateItemError, UpdateItemInput, UpdateItemOutput, WriteRequest,
 };
 
 #[macro_use]
@@ -22,15 +24,25 @@ use crate::errors::*;
 use crate::notification::Notification;
 use crate::util::timing::sec_since_epoch;
 
+pub use self::commands::StaleUsersPage;
 use self::commands::{
-    retryable_batchwriteitem_error, retryable_delete_error, retryable_updateitem_error,
-    FetchMessageResponse,
+    retryable_batchwriteitem_error, retryable_delete_error, retryable_getitem_error,
+    retryable_putitem_error, retryable_updateitem_error, FetchMessageResponse,
+};
+pub use self::models::{
+    DynamoDbBroadcast, DynamoDbIdempotencyKey, DynamoDbNotification, DynamoDbUser,
 };
-pub use self::models::{DynamoDbNotification, DynamoDbUser};
 
 const MAX_EXPIRY: u64 = 2_592_000;
 const USER_RECORD_VERSION: u8 = 1;
 
+/// Get when a message with the TTL should be deleted by DynamoDB, in seconds
+/// since the epoch. A TTL of 0 expires immediately, so the message is only
+/// delivered if the user is connected.
+pub fn message_expiry(ttl: u64, now: u64) -> u64 {
+    now + min(ttl, MAX_EXPIRY)
+}
+
 /// Basic requirements for notification content to deliver to websocket client
 ///  - channelID  (the subscription website intended for)
 ///  - version    (only really utilized for notification acknowledgement in
@@ -265,12 +277,62 @@ impl DynamoStorage {
         Box::new(response)
     }
 
+    /// Check that the router table can be reached, for health checks
+    pub fn health_check(&self) -> impl Future<Item = (), Error = Error> {
+        let input = DescribeTableInput {
+            table_name: self.router_table_name.clone(),
+        };
+
+        self.ddb
+            .describe_table(input)
+            .and_then(|_| future::ok(()))
+            .chain_err(|| "Unable to describe router table")
+    }
+
     pub fn drop_uaid(&self, uaid: &Uuid) -> impl Future<Item = (), Error = Error> {
         commands::drop_user(self.ddb.clone(), uaid, &self.router_table_name)
             .and_then(|_| future::ok(()))
             .chain_err(|| "Unable to drop user record")
     }
 
+    /// Delete all the messages stored for the user in the message table.
+    /// Returns the number of messages deleted.
+    pub fn drop_user_messages(
+        &self,
+        uaid: &Uuid,
+        message_month: &str,
+    ) -> impl Future<Item = usize, Error = Error> {
+        commands::drop_user_messages(self.ddb.clone(), uaid, message_month)
+    }
+
+    /// Find the sort keys of the unexpired, non-topic messages stored for the
+    /// channel, oldest first
+    pub fn channel_message_keys(
+        &self,
+        uaid: &Uuid,
+        channel_id: &Uuid,
+        message_month: &str,
+    ) -> impl Future<Item = Vec<String>, Error = Error> {
+        commands::fetch_channel_message_keys(self.ddb.clone(), uaid, channel_id, message_month)
+    }
+
+    /// Find a page of the users who still have a node ID, but last
+    /// connected before `connected_before` (milliseconds since the epoch)
+    pub fn find_stale_users(
+        &self,
+        connected_before: u64,
+        start_uaid: Option<String>,
+        limit: i64,
+    ) -> impl Future<Item = StaleUsersPage, Error = Error> {
+        commands::find_stale_users(
+            self.ddb.clone(),
+            &self.router_table_name,
+            connected_before,
+            start_uaid,
+            limit,
+        )
+    }
+
     pub fn unregister(
         &self,
         uaid: &Uuid,
@@ -310,7 +372,279 @@ impl DynamoStorage {
             .chain_err(|| "Unable to migrate user")
     }
 
-    /// Store a batch of messages when shutting down
+    /// Store a single message. It expires `ttl` seconds after it was
+    /// received (its `timestamp`).
+    ///
+    /// Topic messages use the `01:{chid}:{topic}` sort key, so storing one
+    /// replaces any earlier message for the same channel and topic.
+    pub fn store_message(
+        &self,
+        uaid: &Uuid,
+        message_month: String,
+        message: Notification,
+    ) -> MyFuture<()> {
+        let expiry = message_expiry(message.ttl, message.timestamp);
+        self.store_message_with_expiry(uaid, message_month, message, expiry)
+    }
+
+    /// Store a single message, which DynamoDB deletes after `expiry` (seconds
+    /// since the epoch)
+    pub fn store_message_with_expiry(
+        &self,
+        uaid: &Uuid,
+        message_month: String,
+        message: Notification,
+        expiry: u64,
+    ) -> MyFuture<()> {
+        let topic = message.topic.is_some().to_string();
+        let notification = DynamoDbNotification::from_notif_with_expiry(uaid, message, expiry);
+        let item = match serde_dynamodb::to_hashmap(&notification) {
+            Ok(item) => item,
+            Err(e) => return future::err(e).chain_err(|| "Error serializing message"),
+        };
+        let ddb = self.ddb.clone();
+        let metrics = self.metrics.clone();
+        let put_item = PutItemInput {
+            item,
+            table_name: message_month,
+            ..Default::default()
+        };
+
+        retry_if(
+            move || ddb.put_item(put_item.clone()),
+            retryable_putitem_error,
+        )
+        .and_then(move |_| {
+            metrics
+                .incr_with_tags("notification.message.stored")
+                .with_tag("topic", &topic)
+                .send();
+            future::ok(())
+        })
+        .chain_err(|| "Error storing message")
+    }
+
+    /// Store a broadcast value in the broadcast table, replacing its earlier
+    /// value
+    pub fn put_broadcast(&self, table_name: &str, broadcast: DynamoDbBroadcast) -> MyFuture<()> {
+        let item = match serde_dynamodb::to_hashmap(&broadcast) {
+            Ok(item) => item,
+            Err(e) => return future::err(e).chain_err(|| "Error serializing broadcast"),
+        };
+        let ddb = self.ddb.clone();
+        let put_item = PutItemInput {
+            item,
+            table_name: table_name.to_string(),
+            ..Default::default()
+        };
+
+        retry_if(
+            move || ddb.put_item(put_item.clone()),
+            retryable_putitem_error,
+        )
+        .and_then(|_| future::ok(()))
+        .chain_err(|| "Error storing broadcast")
+    }
+
+    /// Claim an idempotency key, unless another notification has already
+    /// claimed it and the key has not expired. Returns the key as claimed by
+    /// the other notification, or `None` if this claim succeeded.
+    pub fn claim_idempotency_key(
+        &self,
+        table_name: &str,
+        key: DynamoDbIdempotencyKey,
+    ) -> MyFuture<Option<DynamoDbIdempotencyKey>> {
+        let item = match serde_dynamodb::to_hashmap(&key) {
+            Ok(item) => item,
+            Err(e) => return future::err(e).chain_err(|| "Error serializing idempotency key"),
+        };
+        let ddb = self.ddb.clone();
+        let table_name = table_name.to_string();
+        // DynamoDB may not have deleted an expired key yet
+        let put_item = PutItemInput {
+            item,
+            table_name: table_name.clone(),
+            condition_expression: Some(
+                "attribute_not_exists(idempotency_key) or expiry <= :now".to_string(),
+            ),
+            expression_attribute_values: Some(hashmap! {
+                ":now".to_string() => val!(N => sec_since_epoch())
+            }),
+            ..Default::default()
+        };
+        let self_clone = self.clone();
+        let idempotency_key = key.idempotency_key;
+
+        let response = retry_if(
+            move || ddb.put_item(put_item.clone()),
+            retryable_putitem_error,
+        )
+        .then(move |result| -> MyFuture<Option<DynamoDbIdempotencyKey>> {
+            match result {
+                Ok(_) => Box::new(future::ok(None)),
+                // The key was claimed first by another notification. If its
+                // claim was released in the meantime, there is nothing to
+                // return.
+                Err(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => {
+                    self_clone.get_idempotency_key(&table_name, idempotency_key)
+                }
+                Err(e) => future::err(e).chain_err(|| "Error claiming idempotency key"),
+            }
+        });
+        Box::new(response)
+    }
+
+    /// Get an idempotency key, or `None` if it was not claimed or has expired
+    pub fn get_idempotency_key(
+        &self,
+        table_name: &str,
+        idempotency_key: String,
+    ) -> MyFuture<Option<DynamoDbIdempotencyKey>> {
+        let ddb = self.ddb.clone();
+        let get_input = GetItemInput {
+            table_name: table_name.to_string(),
+            consistent_read: Some(true),
+            key: ddb_item! { idempotency_key: s => idempotency_key },
+            ..Default::default()
+        };
+
+        let response = retry_if(
+            move || ddb.get_item(get_input.clone()),
+            retryable_getitem_error,
+        )
+        .chain_err(|| "Error getting idempotency key")
+        .and_then(|output| {
+            let key = output
+                .item
+                .map(|item| {
+                    serde_dynamodb::from_hashmap::<DynamoDbIdempotencyKey, _>(item)
+                        .chain_err(|| "Error deserializing idempotency key")
+                })
+                .transpose()?;
+
+            Ok(key.filter(|key| !key.expired(sec_since_epoch())))
+        });
+        Box::new(response)
+    }
+
+    /// Store an idempotency key, replacing any earlier claim (ex. to add the
+    /// response once the notification has been routed)
+    pub fn put_idempotency_key(
+        &self,
+        table_name: &str,
+        key: DynamoDbIdempotencyKey,
+    ) -> MyFuture<()> {
+        let item = match serde_dynamodb::to_hashmap(&key) {
+            Ok(item) => item,
+            Err(e) => return future::err(e).chain_err(|| "Error serializing idempotency key"),
+        };
+        let ddb = self.ddb.clone();
+        let put_item = PutItemInput {
+            item,
+            table_name: table_name.to_string(),
+            ..Default::default()
+        };
+
+        retry_if(
+            move || ddb.put_item(put_item.clone()),
+            retryable_putitem_error,
+        )
+        .and_then(|_| future::ok(()))
+        .chain_err(|| "Error storing idempotency key")
+    }
+
+    /// Release a notification's claim on an idempotency key, so a retry can
+    /// claim it. Nothing is removed if another notification holds the key.
+    pub fn release_idempotency_key(
+        &self,
+        table_name: &str,
+        idempotency_key: String,
+        message_id: String,
+    ) -> MyFuture<()> {
+        let ddb = self.ddb.clone();
+        let delete_input = DeleteItemInput {
+            table_name: table_name.to_string(),
+            key: ddb_item! { idempotency_key: s => idempotency_key },
+            condition_expression: Some("message_id = :message_id".to_string()),
+            expression_attribute_values: Some(hashmap! {
+                ":message_id".to_string() => val!(S => message_id)
+            }),
+            ..Default::default()
+        };
+
+        retry_if(
+            move || ddb.delete_item(delete_input.clone()),
+            retryable_delete_error,
+        )
+        .then(|result| match result {
+            Ok(_) | Err(RusotoError::Service(DeleteItemError::ConditionalCheckFailed(_))) => Ok(()),
+            Err(e) => Err(e),
+        })
+        .chain_err(|| "Error releasing idempotency key")
+    }
+
+    /// Remove the node ID from a user's record, if it still matches the given
+    /// node ID and connection time. Returns false if the record no longer
+    /// matches, because the user has since reconnected (possibly to the same
+    /// node).
+    pub fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: String,
+        connected_at: u64,
+    ) -> MyFuture<bool> {
+        let ddb = self.ddb.clone();
+        let update_item = UpdateItemInput {
+            key: ddb_item! { uaid: s => uaid.to_simple().to_string() },
+            update_expression: Some("REMOVE node_id".to_string()),
+            condition_expression: Some("node_id = :node and connected_at = :conn".to_string()),
+            expression_attribute_values: Some(hashmap! {
+                ":node".to_string() => val!(S => node_id),
+                ":conn".to_string() => val!(N => connected_at)
+            }),
+            table_name: self.router_table_name.clone(),
+            ..Default::default()
+        };
+
+        retry_if(
+            move || ddb.update_item(update_item.clone()),
+            retryable_updateitem_error,
+        )
+        .then(|result| match result {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(e) => Err(e),
+        })
+        .chain_err(|| "Error removing node ID")
+    }
+
+    /// Remove the fallback router from a user's router data, so notifications
+    /// are no longer sent to a bridge registration which is no longer valid.
+    /// Returns false if the user has no fallback router.
+    pub fn remove_fallback_router(&self, uaid: &Uuid) -> MyFuture<bool> {
+        let ddb = self.ddb.clone();
+        let update_item = UpdateItemInput {
+            key: ddb_item! { uaid: s => uaid.to_simple().to_string() },
+            update_expression: Some("REMOVE router_data.fallback_router".to_string()),
+            condition_expression: Some("attribute_exists(router_data.fallback_router)".to_string()),
+            table_name: self.router_table_name.clone(),
+            ..Default::default()
+        };
+
+        retry_if(
+            move || ddb.update_item(update_item.clone()),
+            retryable_updateitem_error,
+        )
+        .then(|result| match result {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(e) => Err(e),
+        })
+        .chain_err(|| "Error removing fallback router")
+    }
+
+    /// Store a batch of messages when shutting down. Messages which have
+    /// already expired are dropped instead of being stored again.
     pub fn store_messages(
         &self,
         uaid: &Uuid,
@@ -318,8 +652,10 @@ impl DynamoStorage {
         messages: Vec<Notification>,
     ) -> impl Future<Item = (), Error = Error> {
         let ddb = self.ddb.clone();
+        let now = sec_since_epoch();
         let put_items: Vec<WriteRequest> = messages
             .into_iter()
+            .filter(|n| !n.expired(now))
             .filter_map(|n| {
                 serde_dynamodb::to_hashmap(&DynamoDbNotification::from_notif(uaid, n))
                     .ok()
@@ -329,12 +665,16 @@ impl DynamoStorage {
                     })
             })
             .collect();
+        // DynamoDB rejects an empty batch
+        if put_items.is_empty() {
+            return future::Either::A(future::ok(()));
+        }
         let batch_input = BatchWriteItemInput {
             request_items: hashmap! { message_month.to_string() => put_items },
             ..Default::default()
         };
 
-        retry_if(
+        let result = retry_if(
             move || ddb.batch_write_item(batch_input.clone()),
             retryable_batchwriteitem_error,
         )
@@ -344,7 +684,9 @@ impl DynamoStorage {
             err
         })
         // TODO: Use Sentry to capture/report this error
-        .chain_err(|| "Error saving notifications")
+        .chain_err(|| "Error saving notifications");
+
+        future::Either::B(result)
     }
 
     /// Delete a given notification from the database
@@ -376,6 +718,67 @@ impl DynamoStorage {
         .chain_err(|| "Error deleting notification")
     }
 
+    /// Remove the message with the sort key, if it is still stored. Returns
+    /// true if the message was removed.
+    pub fn remove_message(
+        &self,
+        table_name: &str,
+        uaid: &Uuid,
+        sort_key: String,
+    ) -> impl Future<Item = bool, Error = Error> {
+        let ddb = self.ddb.clone();
+        let delete_input = DeleteItemInput {
+            table_name: table_name.to_string(),
+            key: ddb_item! {
+               uaid: s => uaid.to_simple().to_string(),
+               chidmessageid: s => sort_key
+            },
+            return_values: Some("ALL_OLD".to_string()),
+            ..Default::default()
+        };
+
+        retry_if(
+            move || ddb.delete_item(delete_input.clone()),
+            retryable_delete_error,
+        )
+        .and_then(|output| future::ok(output.attributes.is_some()))
+        .chain_err(|| "Error removing notification")
+    }
+
+    /// Check if a notification is still stored and has not expired
+    pub fn message_exists(
+        &self,
+        table_name: &str,
+        uaid: &Uuid,
+        sort_key: String,
+    ) -> impl Future<Item = bool, Error = Error> {
+        let ddb = self.ddb.clone();
+        let get_input = GetItemInput {
+            table_name: table_name.to_string(),
+            consistent_read: Some(true),
+            key: ddb_item! {
+               uaid: s => uaid.to_simple().to_string(),
+               chidmessageid: s => sort_key
+            },
+            ..Default::default()
+        };
+
+        retry_if(
+            move || ddb.get_item(get_input.clone()),
+            retryable_getitem_error,
+        )
+        .and_then(|output| {
+            // DynamoDB may not have deleted an expired notification yet
+            let now = sec_since_epoch();
+            let exists = output
+                .item
+                .and_then(|item| serde_dynamodb::from_hashmap::<DynamoDbNotification, _>(item).ok())
+                .map_or(false, |notif| !notif.expired(now));
+            future::ok(exists)
+        })
+        .chain_err(|| "Error checking for notification")
+    }
+
     pub fn check_storage(
         &self,
         table_name: &str,
@@ -444,19 +847,28 @@ impl DynamoStorage {
     }
 
     pub fn get_user(&self, uaid: &Uuid) -> impl Future<Item = DynamoDbUser, Error = Error> {
+        self.find_user(uaid)
+            .and_then(|user| future::result(user.ok_or_else(|| "No user record found".into())))
+    }
+
+    /// Get a user record, or `None` if the user does not exist (ex. they were
+    /// deleted)
+    pub fn find_user(
+        &self,
+        uaid: &Uuid,
+    ) -> impl Future<Item = Option<DynamoDbUser>, Error = Error> {
         let ddb = self.ddb.clone();
-        let result = commands::get_uaid(ddb, uaid, &self.router_table_name).and_then(|result| {
+        commands::get_uaid(ddb, uaid, &self.router_table_name).and_then(|result| {
             future::result(
                 result
                     .item
-                    .ok_or_else(|| "No user record found".into())
-                    .and_then(|item| {
+                    .map(|item| {
                         let user = serde_dynamodb::from_hashmap(item);
                         user.chain_err(|| "Error deserializing")
-                    }),
+                    })
+                    .transpose(),
             )
-        });
-        Box::new(result)
+        })
     }
 
     /// Get the set of channel IDs for a user
@@ -1,4 +1,3 @@
-use std::cmp::min;
 use std::collections::{HashMap, HashSet};
 use std::result::Result as StdResult;
 
@@ -11,9 +10,9 @@ use uuid::Uuid;
 use crate::db::util::generate_last_connect;
 use crate::errors::*;
 use crate::notification::Notification;
-use crate::util::timing::{ms_since_epoch, sec_since_epoch};
+use crate::util::timing::ms_since_epoch;
 
-use super::{MAX_EXPIRY, USER_RECORD_VERSION};
+use super::{message_expiry, USER_RECORD_VERSION};
 
 /// Custom Uuid serializer
 ///
@@ -89,6 +88,9 @@ pub struct DynamoDbUser {
     // Current month table in the database the user is on
     #[serde(skip_serializing_if = "Option::is_none")]
     pub current_month: Option<String>,
+    // Router-specific data, such as a bridge registration token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub router_data: Option<HashMap<String, serde_json::Value>>,
 }
 
 impl Default for DynamoDbUser {
@@ -103,10 +105,46 @@ impl Default for DynamoDbUser {
             node_id: None,
             record_version: Some(USER_RECORD_VERSION),
             current_month: None,
+            router_data: None,
         }
     }
 }
 
+/// A broadcast value which connected clients are told about when it changes
+/// (ex. a feature flag)
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DynamoDbBroadcast {
+    // DynamoDB <Hash key>
+    pub broadcast_id: String,
+    // The current value of the broadcast
+    pub version: String,
+    // Time in milliseconds that the value was last changed
+    pub updated_at: u64,
+}
+
+/// An idempotency key claimed by a notification, so retries with the same key
+/// are not delivered twice
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DynamoDbIdempotencyKey {
+    // DynamoDB <Hash key>
+    // Format: {uaid}:{channel id}:{idempotency key}
+    pub idempotency_key: String,
+    // The message ID of the notification which claimed the key
+    pub message_id: String,
+    // The response to the notification (JSON), once it has been routed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    // Time in seconds since epoch when DynamoDB deletes the key
+    pub expiry: u64,
+}
+
+impl DynamoDbIdempotencyKey {
+    /// Check if DynamoDB will delete the key because it has expired
+    pub fn expired(&self, at_sec: u64) -> bool {
+        at_sec >= self.expiry
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct DynamoDbNotification {
     // DynamoDB <Hash key>
@@ -145,6 +183,9 @@ pub struct DynamoDbNotification {
     // value before sending it to storage or a connection node.
     #[serde(skip_serializing_if = "Option::is_none")]
     updateid: Option<String>,
+    // The push receipt URL provided by the application server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipt_url: Option<String>,
 }
 
 impl DynamoDbNotification {
@@ -218,19 +259,36 @@ impl DynamoDbNotification {
             data: self.data,
             headers: self.headers.map(|m| m.into()),
             sortkey_timestamp: key.sortkey_timestamp,
+            receipt_url: self.receipt_url,
         })
     }
 
+    /// Check if DynamoDB will delete the record because it has expired
+    pub fn expired(&self, at_sec: u64) -> bool {
+        at_sec >= self.expiry
+    }
+
+    /// Convert a notification into a record. The expiry is counted from when
+    /// the notification was received, so storing it again (e.g. when a node
+    /// shuts down) doesn't extend its life.
     pub fn from_notif(uaid: &Uuid, val: Notification) -> Self {
+        let expiry = message_expiry(val.ttl, val.timestamp);
+        Self::from_notif_with_expiry(uaid, val, expiry)
+    }
+
+    /// Convert a notification into a record which DynamoDB deletes after
+    /// `expiry` (seconds since the epoch)
+    pub fn from_notif_with_expiry(uaid: &Uuid, val: Notification, expiry: u64) -> Self {
         Self {
             uaid: *uaid,
             chidmessageid: val.sort_key(),
             timestamp: Some(val.timestamp),
-            expiry: sec_since_epoch() + min(val.ttl, MAX_EXPIRY),
+            expiry,
             ttl: Some(val.ttl),
             data: val.data,
             headers: val.headers.map(|h| h.into()),
             updateid: Some(val.version),
+            receipt_url: val.receipt_url,
             ..Default::default()
         }
     }
@@ -246,9 +304,73 @@ struct RangeKey {
 #[cfg(test)]
 mod tests {
     use super::DynamoDbNotification;
+    use crate::db::{message_expiry, MAX_EXPIRY};
+    use crate::notification::Notification;
     use crate::util::us_since_epoch;
     use uuid::Uuid;
 
+    const NOW: u64 = 1_600_000_000;
+
+    #[test]
+    fn test_message_expiry() {
+        assert_eq!(message_expiry(60, NOW), NOW + 60);
+        // TTL=0 messages expire immediately
+        assert_eq!(message_expiry(0, NOW), NOW);
+        assert_eq!(message_expiry(MAX_EXPIRY * 2, NOW), NOW + MAX_EXPIRY);
+    }
+
+    #[test]
+    fn test_from_notif_with_expiry() {
+        let notif = Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test-version".to_string(),
+            ttl: 120,
+            timestamp: NOW,
+            ..Default::default()
+        };
+        let uaid = Uuid::new_v4();
+
+        let record =
+            DynamoDbNotification::from_notif_with_expiry(&uaid, notif, message_expiry(120, NOW));
+        assert_eq!(record.expiry, NOW + 120);
+        assert_eq!(record.ttl, Some(120));
+    }
+
+    #[test]
+    fn test_expired() {
+        let notif = Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test-version".to_string(),
+            ttl: 60,
+            timestamp: NOW,
+            ..Default::default()
+        };
+
+        let record = DynamoDbNotification::from_notif_with_expiry(&Uuid::new_v4(), notif, NOW + 60);
+        assert!(!record.expired(NOW + 59));
+        assert!(record.expired(NOW + 60));
+    }
+
+    #[test]
+    fn test_short_ttl_expires() {
+        let notif = Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test-version".to_string(),
+            ttl: 2,
+            timestamp: NOW,
+            ..Default::default()
+        };
+
+        // The record expires at the same time as the notification, even if
+        // it is stored later
+        let record = DynamoDbNotification::from_notif(&Uuid::new_v4(), notif.clone());
+        assert_eq!(record.expiry, NOW + 2);
+        assert!(!notif.expired(NOW + 1));
+        assert!(!record.expired(NOW + 1));
+        assert!(notif.expired(NOW + 2));
+        assert!(record.expired(NOW + 2));
+    }
+
     #[test]
     fn test_parse_sort_key_ver1() {
         let chid = Uuid::new_v4();
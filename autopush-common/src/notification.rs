@@ -23,6 +23,10 @@ pub struct Notification {
     pub sortkey_timestamp: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub headers: Option<HashMap<String, String>>,
+    /// Where the application server asked to be told about delivery. This
+    /// is kept with the stored message, but not sent to the client.
+    #[serde(skip_serializing)]
+    pub receipt_url: Option<String>,
 }
 
 impl Notification {